@@ -0,0 +1,42 @@
+//! Helpers laying out the code emitted by Cretonne, shared by the runtimes that place it in
+//! memory and the ones writing it to object files.
+use cretonne::ir::{Function, JumpTable};
+use std::collections::HashMap;
+
+/// Appends the jump tables of `il_func` to its code and returns their offsets in the code. Each
+/// entry of a table is the offset of the target `Ebb` relative to the beginning of the table, on
+/// 32 bits, so that the tables do not depend on the address of the code.
+pub fn emit_jump_tables(il_func: &Function,
+                        code_buf: &mut Vec<u8>)
+                        -> Result<HashMap<JumpTable, usize>, String> {
+    let mut jt_offsets = HashMap::new();
+    for jt in il_func.jump_tables.keys() {
+        // The entries are aligned on 4 bytes.
+        while code_buf.len() % 4 != 0 {
+            code_buf.push(0);
+        }
+        let jt_offset = code_buf.len();
+        let jt_data = &il_func.jump_tables[jt];
+        for index in 0..jt_data.len() {
+            let ebb = match jt_data.get_entry(index) {
+                Some(ebb) => ebb,
+                None => return Err(format!("hole in the jump table {} of the code", jt)),
+            };
+            let entry = il_func.offsets[ebb] as i32 - jt_offset as i32;
+            for i in 0..4 {
+                code_buf.push((entry >> (8 * i)) as u8);
+            }
+        }
+        jt_offsets.insert(jt, jt_offset);
+    }
+    Ok(jt_offsets)
+}
+
+/// Writes at `offset` in `code` the distance to `target` from the end of the 32 bits field, as
+/// expected by the relative branches and calls.
+pub fn patch_relative(code: &mut [u8], offset: usize, target: usize) {
+    let delta = target as i64 - (offset as i64 + 4);
+    for i in 0..4 {
+        code[offset + i] = (delta >> (8 * i)) as u8;
+    }
+}
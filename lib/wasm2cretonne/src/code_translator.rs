@@ -13,7 +13,7 @@
 //!
 //! Some of the WebAssembly instructions need information about the runtime to be translated:
 //!
-//! - the loads and stores need the memory base address;
+//! - the loads and stores need the memory base address and the bounds checks;
 //! - the `get_global` et `set_global` instructions depends on how the globals are implemented;
 //! - `current_memory` and `grow_memory` are runtime functions;
 //! - `call_indirect` has to translate the function index into the address of where this
@@ -29,10 +29,11 @@ use cretonne::ir::condcodes::{IntCC, FloatCC};
 use cton_frontend::{ILBuilder, FunctionBuilder};
 use wasmparser::{Parser, ParserState, Operator, WasmDecoder, MemoryImmediate};
 use translation_utils::{f32_translation, f64_translation, type_to_type, translate_type, Local,
-                        GlobalIndex, FunctionIndex, SignatureIndex};
+                        GlobalIndex, FunctionIndex, SignatureIndex, MemoryIndex, TableIndex,
+                        TranslationError, memory_index};
 use std::collections::HashMap;
 use runtime::WasmRuntime;
-use std::u32;
+use std::{i32, u32};
 
 
 /// A control stack frame can be an `if`, a `block` or a `loop`, each one having the following
@@ -130,11 +131,15 @@ impl ControlStackFrame {
 /// - the depth of the two unreachable control blocks stacks, that are manipulated when translating
 ///   unreachable code;
 /// - all the `Ebb`s referenced by `br_table` instructions, because those are always reachable even
-///   if they are at a point of the code that would have been unreachable otherwise.
+///   if they are at a point of the code that would have been unreachable otherwise;
+/// - the fuel of the operators translated since fuel was last consumed, with fuel metering.
 struct TranslationState {
     last_inst_return: bool,
     phantom_unreachable_stack_depth: usize,
     real_unreachable_stack_depth: usize,
+    /// VM context argument of the function, passed along to the functions it calls.
+    vmctx: Option<Value>,
+    fuel: u32,
 }
 
 /// Holds mappings between the function and signatures indexes in the Wasm module and their
@@ -161,25 +166,27 @@ pub fn translate_function_body(parser: &mut Parser,
                                function_index: FunctionIndex,
                                sig: Signature,
                                locals: &Vec<(usize, Type)>,
-                               exports: &Option<HashMap<FunctionIndex, String>>,
+                               function_names: &Option<HashMap<FunctionIndex, String>>,
                                signatures: &Vec<Signature>,
                                functions: &Vec<SignatureIndex>,
                                il_builder: &mut ILBuilder<Local>,
                                runtime: &mut WasmRuntime)
-                               -> Result<(Function, FunctionImports), String> {
+                               -> Result<(Function, FunctionImports), TranslationError> {
     runtime.next_function();
     // First we build the Function object with its name and signature
     let mut func = Function::new();
-    let args_num: usize = sig.argument_types.len();
+    // The VM context, if any, is the last argument of the function and is not a wasm local.
+    let vmctx_type = runtime.vmctx_type();
+    let args_num: usize = sig.argument_types.len() - vmctx_type.iter().count();
     let args_types: Vec<Type> = sig.argument_types
         .iter()
         .map(|arg| arg.value_type)
         .collect();
     func.signature = sig.clone();
-    match exports {
+    match function_names {
         &None => (),
-        &Some(ref exports) => {
-            match exports.get(&function_index) {
+        &Some(ref function_names) => {
+            match function_names.get(&function_index) {
                 None => (),
                 Some(name) => func.name = FunctionName::new(name.clone()),
             }
@@ -201,6 +208,10 @@ pub fn translate_function_body(parser: &mut Parser,
             builder.declare_var(Local(i as u32), args_types[i]);
             builder.def_var(Local(i as u32), arg_value);
         }
+        let vmctx = vmctx_type.map(|_| builder.arg_value(args_num));
+        if let Some(vmctx) = vmctx {
+            runtime.set_vmctx(vmctx);
+        }
         // We also declare and initialize to 0 the local variables
         let mut local_index = args_num;
         for &(loc_count, ty) in locals {
@@ -221,7 +232,15 @@ pub fn translate_function_body(parser: &mut Parser,
             last_inst_return: false,
             phantom_unreachable_stack_depth: 0,
             real_unreachable_stack_depth: 0,
+            vmctx,
+            fuel: 0,
         };
+        runtime.translate_function_prologue(&mut builder);
+        let fuel_metering = runtime.fuel_metering();
+        let epoch_interruption = runtime.epoch_interruption();
+        if epoch_interruption {
+            runtime.translate_check_epoch(&mut builder);
+        }
         // We initialize the control stack with the implicit function block
         let end_ebb = builder.create_ebb();
         control_stack.push(ControlStackFrame::Block {
@@ -235,6 +254,7 @@ pub fn translate_function_body(parser: &mut Parser,
                            });
         // Now the main loop that reads every wasm instruction and translates it
         loop {
+            let offset = parser.current_position();
             let parser_state = parser.read();
             match *parser_state {
                 ParserState::CodeOperator(ref op) => {
@@ -246,6 +266,9 @@ pub fn translate_function_body(parser: &mut Parser,
                                                        &mut control_stack,
                                                        &mut state)
                     } else {
+                        if fuel_metering {
+                            consume_fuel(op, &mut builder, runtime, &mut state);
+                        }
                         translate_operator(op,
                                            &mut builder,
                                            runtime,
@@ -255,13 +278,25 @@ pub fn translate_function_body(parser: &mut Parser,
                                            &sig,
                                            &functions,
                                            &signatures,
-                                           &exports,
-                                           &mut func_imports)
+                                           &function_names,
+                                           &mut func_imports);
+                        // Every iteration of a loop goes through its header, where the
+                        // translation of the `loop` operator leaves the builder.
+                        if epoch_interruption {
+                            if let Operator::Loop { .. } = *op {
+                                runtime.translate_check_epoch(&mut builder);
+                            }
+                        }
                     }
                 }
 
                 ParserState::EndFunctionBody => break,
-                _ => return Err(String::from("wrong content in function body")),
+                _ => {
+                    return Err(TranslationError::InvalidBinary {
+                                   offset,
+                                   message: String::from("wrong content in function body"),
+                               })
+                }
             }
         }
         // In WebAssembly, the final return instruction is implicit so we need to build it
@@ -288,6 +323,34 @@ pub fn translate_function_body(parser: &mut Parser,
     Ok((func, func_imports))
 }
 
+/// Counts the fuel of the reachable operator `op`, one unit per operator, and emits the code
+/// consuming the fuel of the basic block that `op` ends. Since the operators are charged before
+/// leaving their basic block, the fuel consumed by an execution only depends on the code executed,
+/// and every loop iteration and every call consumes some fuel.
+fn consume_fuel(op: &Operator,
+                builder: &mut FunctionBuilder<Local>,
+                runtime: &mut WasmRuntime,
+                state: &mut TranslationState) {
+    state.fuel += 1;
+    match *op {
+        Operator::Loop { .. } |
+        Operator::If { .. } |
+        Operator::Else |
+        Operator::End |
+        Operator::Br { .. } |
+        Operator::BrIf { .. } |
+        Operator::BrTable { .. } |
+        Operator::Return |
+        Operator::Unreachable |
+        Operator::Call { .. } |
+        Operator::CallIndirect { .. } => {
+            runtime.translate_consume_fuel(builder, state.fuel);
+            state.fuel = 0;
+        }
+        _ => (),
+    }
+}
+
 /// Translates wasm operators into Cretonne IL instructions. Returns `true` if it inserted
 /// a return.
 fn translate_operator(op: &Operator,
@@ -299,7 +362,7 @@ fn translate_operator(op: &Operator,
                       sig: &Signature,
                       functions: &Vec<SignatureIndex>,
                       signatures: &Vec<Signature>,
-                      exports: &Option<HashMap<FunctionIndex, String>>,
+                      function_names: &Option<HashMap<FunctionIndex, String>>,
                       func_imports: &mut FunctionImports) {
     state.last_inst_return = false;
     // This big match treats all Wasm code operators.
@@ -610,14 +673,20 @@ fn translate_operator(op: &Operator,
          * argument referring to an index in the external functions table of the module.
          ************************************************************************************/
         Operator::Call { function_index } => {
-            let args_num = args_count(function_index as usize, functions, signatures);
+            let args_num = args_count(function_index as usize, functions, signatures) -
+                           state.vmctx.iter().count();
             let cut_index = stack.len() - args_num;
-            let call_args = stack.split_off(cut_index);
+            let mut call_args = stack.split_off(cut_index);
+            if let Some(vmctx) = state.vmctx {
+                call_args.push(runtime.translate_call_vmctx(builder,
+                                                            function_index as FunctionIndex,
+                                                            vmctx));
+            }
             let internal_function_index = find_function_import(function_index as usize,
                                                                builder,
                                                                func_imports,
                                                                functions,
-                                                               exports,
+                                                               function_names,
                                                                signatures);
             let call_inst = builder
                 .ins()
@@ -627,20 +696,22 @@ fn translate_operator(op: &Operator,
                 stack.push(*val);
             }
         }
-        Operator::CallIndirect {
-            index,
-            table_index: _,
-        } => {
+        Operator::CallIndirect { index, table_index } => {
             // index is the index of the function's signature and table_index is the index
             // of the table to search the function in
-            // TODO: have runtime support for tables
             let sigref = find_signature_import(index as usize, builder, func_imports, signatures);
-            let args_num = builder.signature(sigref).unwrap().argument_types.len();
+            let args_num = builder.signature(sigref).unwrap().argument_types.len() -
+                           state.vmctx.iter().count();
             let index_val = stack.pop().unwrap();
             let cut_index = stack.len() - args_num;
-            let call_args = stack.split_off(cut_index);
-            let ret_values =
-                runtime.translate_call_indirect(builder, sigref, index_val, call_args.as_slice());
+            let mut call_args = stack.split_off(cut_index);
+            call_args.extend(state.vmctx);
+            let ret_values = runtime.translate_call_indirect(builder,
+                                                             table_index as TableIndex,
+                                                             index as SignatureIndex,
+                                                             sigref,
+                                                             index_val,
+                                                             call_args.as_slice());
             for val in ret_values {
                 stack.push(*val);
             }
@@ -649,193 +720,146 @@ fn translate_operator(op: &Operator,
          * Memory management is handled by runtime. It is usually translated into calls to
          * special functions.
          ************************************************************************************/
-        Operator::GrowMemory { reserved: _ } => {
+        Operator::GrowMemory { reserved } => {
+            // The reserved immediate is the index of the memory
             let val = stack.pop().unwrap();
-            stack.push(runtime.translate_grow_memory(builder, val));
+            stack.push(runtime.translate_grow_memory(builder, reserved as MemoryIndex, val));
         }
-        Operator::CurrentMemory { reserved: _ } => {
-            stack.push(runtime.translate_current_memory(builder));
+        Operator::CurrentMemory { reserved } => {
+            stack.push(runtime.translate_current_memory(builder, reserved as MemoryIndex));
         }
         /******************************* Load instructions ***********************************
          * Wasm specifies an integer alignment flag but we drop it in Cretonne.
-         * The memory base address is provided by the runtime.
-         * TODO: differentiate between 32 bit and 64 bit architecture, to put the uextend or not
+         * The memory base address and the bounds checks are provided by the runtime.
+         * The memory they access is given by their memory immediate, see `memory_index`.
          ************************************************************************************/
-        Operator::I32Load8U { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+        Operator::I32Load8U { ref memory_immediate } => {
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            stack.push(builder.ins().uload8(I32, memflags, addr, memoffset))
-        }
-        Operator::I32Load16U { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 1);
+            stack.push(builder.ins().uload8(I32, MemFlags::new(), addr, memoffset))
+        }
+        Operator::I32Load16U { ref memory_immediate } => {
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            stack.push(builder.ins().uload8(I32, memflags, addr, memoffset))
-        }
-        Operator::I32Load8S { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 2);
+            stack.push(builder.ins().uload16(I32, MemFlags::new(), addr, memoffset))
+        }
+        Operator::I32Load8S { ref memory_immediate } => {
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            stack.push(builder.ins().sload8(I32, memflags, addr, memoffset))
-        }
-        Operator::I32Load16S { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 1);
+            stack.push(builder.ins().sload8(I32, MemFlags::new(), addr, memoffset))
+        }
+        Operator::I32Load16S { ref memory_immediate } => {
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            stack.push(builder.ins().sload8(I32, memflags, addr, memoffset))
-        }
-        Operator::I64Load8U { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 2);
+            stack.push(builder.ins().sload16(I32, MemFlags::new(), addr, memoffset))
+        }
+        Operator::I64Load8U { ref memory_immediate } => {
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            stack.push(builder.ins().uload8(I64, memflags, addr, memoffset))
-        }
-        Operator::I64Load16U { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 1);
+            stack.push(builder.ins().uload8(I64, MemFlags::new(), addr, memoffset))
+        }
+        Operator::I64Load16U { ref memory_immediate } => {
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            stack.push(builder.ins().uload16(I64, memflags, addr, memoffset))
-        }
-        Operator::I64Load8S { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 2);
+            stack.push(builder.ins().uload16(I64, MemFlags::new(), addr, memoffset))
+        }
+        Operator::I64Load8S { ref memory_immediate } => {
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            stack.push(builder.ins().sload8(I64, memflags, addr, memoffset))
-        }
-        Operator::I64Load16S { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 1);
+            stack.push(builder.ins().sload8(I64, MemFlags::new(), addr, memoffset))
+        }
+        Operator::I64Load16S { ref memory_immediate } => {
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            stack.push(builder.ins().sload16(I64, memflags, addr, memoffset))
-        }
-        Operator::I64Load32S { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 2);
+            stack.push(builder.ins().sload16(I64, MemFlags::new(), addr, memoffset))
+        }
+        Operator::I64Load32S { ref memory_immediate } => {
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            stack.push(builder.ins().sload32(memflags, addr, memoffset))
-        }
-        Operator::I64Load32U { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 4);
+            stack.push(builder.ins().sload32(MemFlags::new(), addr, memoffset))
+        }
+        Operator::I64Load32U { ref memory_immediate } => {
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            stack.push(builder.ins().uload32(memflags, addr, memoffset))
-        }
-        Operator::I32Load { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 4);
+            stack.push(builder.ins().uload32(MemFlags::new(), addr, memoffset))
+        }
+        Operator::I32Load { ref memory_immediate } => {
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            stack.push(builder.ins().load(I32, memflags, addr, memoffset))
-        }
-        Operator::F32Load { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 4);
+            stack.push(builder.ins().load(I32, MemFlags::new(), addr, memoffset))
+        }
+        Operator::F32Load { ref memory_immediate } => {
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            stack.push(builder.ins().load(F32, memflags, addr, memoffset))
-        }
-        Operator::I64Load { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 4);
+            stack.push(builder.ins().load(F32, MemFlags::new(), addr, memoffset))
+        }
+        Operator::I64Load { ref memory_immediate } => {
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            stack.push(builder.ins().load(I64, memflags, addr, memoffset))
-        }
-        Operator::F64Load { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 8);
+            stack.push(builder.ins().load(I64, MemFlags::new(), addr, memoffset))
+        }
+        Operator::F64Load { ref memory_immediate } => {
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            stack.push(builder.ins().load(F64, memflags, addr, memoffset))
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 8);
+            stack.push(builder.ins().load(F64, MemFlags::new(), addr, memoffset))
         }
         /****************************** Store instructions ***********************************
          * Wasm specifies an integer alignment flag but we drop it in Cretonne.
-         * The memory base address is provided by the runtime.
-         * TODO: differentiate between 32 bit and 64 bit architecture, to put the uextend or not
+         * The memory base address and the bounds checks are provided by the runtime.
+         * The memory they access is given by their memory immediate, see `memory_index`.
          ************************************************************************************/
-        Operator::I32Store { memory_immediate: MemoryImmediate { flags: _, offset } } |
-        Operator::I64Store { memory_immediate: MemoryImmediate { flags: _, offset } } |
-        Operator::F32Store { memory_immediate: MemoryImmediate { flags: _, offset } } |
-        Operator::F64Store { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+        Operator::I32Store { ref memory_immediate } |
+        Operator::F32Store { ref memory_immediate } => {
             let val = stack.pop().unwrap();
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            builder.ins().store(memflags, val, addr, memoffset);
-        }
-        Operator::I32Store8 { memory_immediate: MemoryImmediate { flags: _, offset } } |
-        Operator::I64Store8 { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 4);
+            builder.ins().store(MemFlags::new(), val, addr, memoffset);
+        }
+        Operator::I64Store { ref memory_immediate } |
+        Operator::F64Store { ref memory_immediate } => {
             let val = stack.pop().unwrap();
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            builder.ins().istore8(memflags, val, addr, memoffset);
-        }
-        Operator::I32Store16 { memory_immediate: MemoryImmediate { flags: _, offset } } |
-        Operator::I64Store16 { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 8);
+            builder.ins().store(MemFlags::new(), val, addr, memoffset);
+        }
+        Operator::I32Store8 { ref memory_immediate } |
+        Operator::I64Store8 { ref memory_immediate } => {
             let val = stack.pop().unwrap();
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            builder.ins().istore16(memflags, val, addr, memoffset);
-        }
-        Operator::I64Store32 { memory_immediate: MemoryImmediate { flags: _, offset } } => {
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 1);
+            builder.ins().istore8(MemFlags::new(), val, addr, memoffset);
+        }
+        Operator::I32Store16 { ref memory_immediate } |
+        Operator::I64Store16 { ref memory_immediate } => {
             let val = stack.pop().unwrap();
             let address_i32 = stack.pop().unwrap();
-            let base = runtime.translate_memory_base_address(builder, 0);
-            let address_i64 = builder.ins().uextend(I64, address_i32);
-            let addr = builder.ins().iadd(base, address_i64);
-            let memflags = MemFlags::new();
-            let memoffset = Offset32::new(offset as i32);
-            builder.ins().istore32(memflags, val, addr, memoffset);
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 2);
+            builder.ins().istore16(MemFlags::new(), val, addr, memoffset);
+        }
+        Operator::I64Store32 { ref memory_immediate } => {
+            let val = stack.pop().unwrap();
+            let address_i32 = stack.pop().unwrap();
+            let (addr, memoffset) =
+                translate_memory_address(builder, runtime, memory_immediate, address_i32, 4);
+            builder.ins().istore32(MemFlags::new(), val, addr, memoffset);
         }
         /****************************** Nullary Operators ************************************/
         Operator::I32Const { value } => stack.push(builder.ins().iconst(I32, value as i64)),
@@ -1302,6 +1326,35 @@ fn translate_unreachable_operator(op: &Operator,
     }
 }
 
+/// Computes the native address accessed by a load or a store of `size` bytes at the wasm address
+/// `addr_i32` plus the offset of `memarg` in the wasm memory it designates, after the bounds
+/// checks required by the runtime.
+fn translate_memory_address(builder: &mut FunctionBuilder<Local>,
+                            runtime: &WasmRuntime,
+                            memarg: &MemoryImmediate,
+                            addr_i32: Value,
+                            size: u32)
+                            -> (Value, Offset32) {
+    let memory_index = memory_index(memarg);
+    let offset = memarg.offset;
+    runtime.translate_memory_bounds_check(builder, memory_index, addr_i32, offset, size);
+    let base = runtime.translate_memory_base_address(builder, memory_index);
+    // The wasm addresses are 32 bits wide, like the native addresses of 32 bits targets.
+    let pointer_type = runtime.pointer_type();
+    let address = if pointer_type == I32 {
+        addr_i32
+    } else {
+        builder.ins().uextend(pointer_type, addr_i32)
+    };
+    let addr = builder.ins().iadd(base, address);
+    if offset > i32::MAX as u32 {
+        // The offset does not fit in the signed immediate of the memory instructions
+        (builder.ins().iadd_imm(addr, offset as i64), Offset32::new(0))
+    } else {
+        (addr, Offset32::new(offset as i32))
+    }
+}
+
 fn args_count(index: FunctionIndex,
               functions: &Vec<SignatureIndex>,
               signatures: &Vec<Signature>)
@@ -1315,7 +1368,7 @@ fn find_function_import(index: FunctionIndex,
                         builder: &mut FunctionBuilder<Local>,
                         func_imports: &mut FunctionImports,
                         functions: &Vec<SignatureIndex>,
-                        exports: &Option<HashMap<FunctionIndex, String>>,
+                        function_names: &Option<HashMap<FunctionIndex, String>>,
                         signatures: &Vec<Signature>)
                         -> FuncRef {
     match func_imports.functions.get(&index) {
@@ -1328,10 +1381,10 @@ fn find_function_import(index: FunctionIndex,
         Some(local_sig_index) => {
             let local_func_index =
                 builder.import_function(ExtFuncData {
-                                            name: match exports {
+                                            name: match function_names {
                                                 &None => FunctionName::new(""),
-                                                &Some(ref exports) => {
-                                                    match exports.get(&index) {
+                                                &Some(ref function_names) => {
+                                                    match function_names.get(&index) {
                                                         None => FunctionName::new(""),
                                                         Some(name) => {
                                                             FunctionName::new(name.clone())
@@ -1353,10 +1406,10 @@ fn find_function_import(index: FunctionIndex,
         .insert(sig_index as usize, sig_local_index);
     let local_func_index =
         builder.import_function(ExtFuncData {
-                                    name: match exports {
+                                    name: match function_names {
                                         &None => FunctionName::new(""),
-                                        &Some(ref exports) => {
-                                            match exports.get(&index) {
+                                        &Some(ref function_names) => {
+                                            match function_names.get(&index) {
                                                 None => FunctionName::new(""),
                                                 Some(name) => FunctionName::new(name.clone()),
                                             }
@@ -8,7 +8,9 @@
 //! [`wasmstandalone::StandaloneRuntime`](../wasmstandalone/struct.StandaloneRuntime.html) to be
 //! able to execute the translated code.
 //!
-//! The main function of this module is [`translate_module`](fn.translate_module.html).
+//! The main function of this module is [`translate_module`](fn.translate_module.html). It first
+//! checks the module with [`validate_module`](fn.validate_module.html), which can also be called
+//! on its own.
 
 extern crate wasmparser;
 extern crate cton_frontend;
@@ -17,11 +19,18 @@ extern crate cretonne;
 mod module_translator;
 mod translation_utils;
 mod code_translator;
+mod code_layout;
 mod runtime;
 mod sections_translator;
+mod validator;
 
-pub use module_translator::{translate_module, TranslationResult, FunctionTranslation,
-                            ImportMappings};
+pub use module_translator::{translate_module, translate_module_lazily,
+                            translate_module_in_parallel, TranslationResult,
+                            LazyTranslationResult, FunctionTranslation, ImportMappings};
 pub use runtime::{WasmRuntime, DummyRuntime};
+pub use validator::validate_module;
 pub use translation_utils::{Local, FunctionIndex, GlobalIndex, TableIndex, MemoryIndex, RawByte,
-                            MemoryAddress, SignatureIndex, Global, GlobalInit, Table, Memory};
+                            MemoryAddress, SignatureIndex, Global, GlobalInit, Table,
+                            TableElementType, Memory, Export, Names, CustomSection,
+                            TranslationError, TrapKind, PAGE_SIZE, MAX_PAGES, pointer_type};
+pub use code_layout::{emit_jump_tables, patch_relative};
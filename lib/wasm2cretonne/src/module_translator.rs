@@ -1,34 +1,54 @@
 //! Translation skeletton that traverses the whole WebAssembly module and call helper functions
 //! to deal with each part of it.
-use wasmparser::{ParserState, SectionCode, ParserInput, Parser, WasmDecoder};
+use wasmparser::{ParserState, SectionCode, ParserInput, Parser, WasmDecoder, NameEntry};
 use sections_translator::{SectionParsingError, parse_function_signatures, parse_import_section,
                           parse_function_section, parse_export_section, parse_memory_section,
                           parse_global_section, parse_table_section, parse_elements_section,
                           parse_data_section};
-use translation_utils::{type_to_type, Import, SignatureIndex, FunctionIndex, invert_hashmaps};
-use cretonne::ir::{Function, Type, FuncRef, SigRef};
+use translation_utils::{value_type, Import, Export, Names, CustomSection, SignatureIndex,
+                        FunctionIndex, Local, TranslationError, invert_hashmaps};
+use cretonne::ir::{Function, Type, FuncRef, SigRef, Signature, ArgumentType};
 use code_translator::translate_function_body;
 use cton_frontend::ILBuilder;
 use std::collections::HashMap;
+use std::cmp::min;
+use std::panic::resume_unwind;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
 use runtime::WasmRuntime;
+use validator::validate_module;
 
 /// Output of the [`translate_module`](fn.translate_module.html) function. Contains the translated
 /// functions and when present the index of the function defined as `start` of the module.
 pub struct TranslationResult {
     pub functions: Vec<FunctionTranslation>,
     pub start_index: Option<FunctionIndex>,
+    /// Signatures declared in the type section of the module, followed by the VM context
+    /// argument when the runtime uses one.
+    pub signatures: Vec<Signature>,
+    /// Index in `signatures` of the signature of each function of the function index space.
+    pub function_signatures: Vec<SignatureIndex>,
+    /// Exported functions, tables, memories and globals, by export name.
+    pub exports: HashMap<String, Export>,
+    /// Names of the module, its functions and their locals.
+    pub names: Names,
+    /// Custom sections of the module, including the name section, in the order of the module.
+    pub custom_sections: Vec<CustomSection>,
 }
 
 /// A function in a WebAssembly module can be either imported, or defined inside it. If it is
 /// defined inside it, then the translation in Cretonne IL is available as well as the mappings
-/// between Cretonne imports and indexes in the function index space.
+/// between Cretonne imports and indexes in the function index space. If it is imported, the
+/// module and field names identifying it are available.
 #[derive(Clone)]
 pub enum FunctionTranslation {
     Code {
         il: Function,
         imports: ImportMappings,
     },
-    Import(),
+    Import { module: String, field: String },
 }
 
 #[derive(Clone,Debug)]
@@ -50,34 +70,279 @@ impl ImportMappings {
     }
 }
 
+/// Output of the [`translate_module_lazily`](fn.translate_module_lazily.html) function: the module
+/// without the translation of its function bodies, which are translated one at a time by
+/// `translate_function`.
+pub struct LazyTranslationResult {
+    /// Same as the result of `translate_module`, except that `functions` only holds the imported
+    /// functions.
+    pub result: TranslationResult,
+    // Binary of the module, whose code section is parsed again to translate a function body.
+    data: Vec<u8>,
+    function_names: Option<HashMap<FunctionIndex, String>>,
+}
+
+// Reads the function bodies of the code section of a module in order, translating the ones asked
+// for and skipping the others without parsing their code.
+struct CodeReader<'a> {
+    parser: Parser<'a>,
+    // Index in the code section of the body read next.
+    next_body: usize,
+}
+
+impl<'a> CodeReader<'a> {
+    // Skips the sections of the validated module `data` up to its code section.
+    fn new(data: &'a [u8]) -> Result<CodeReader<'a>, TranslationError> {
+        let mut parser = Parser::new(data);
+        let mut next_input = ParserInput::Default;
+        loop {
+            let offset = parser.current_position();
+            match *parser.read_with_input(next_input) {
+                ParserState::BeginWasm { .. } |
+                ParserState::EndSection => next_input = ParserInput::Default,
+                ParserState::BeginSection { code: SectionCode::Code, .. } => break,
+                ParserState::BeginSection { .. } => next_input = ParserInput::SkipSection,
+                _ => return Err(invalid_binary(offset, "missing code section")),
+            }
+        }
+        Ok(CodeReader {
+               parser,
+               next_body: 0,
+           })
+    }
+
+    // Translates the body of the function `function_index`, which is the body `body_index` of the
+    // code section, after the ones read so far.
+    fn translate(&mut self,
+                 body_index: usize,
+                 function_index: FunctionIndex,
+                 lazy: &LazyTranslationResult,
+                 runtime: &mut WasmRuntime)
+                 -> Result<FunctionTranslation, TranslationError> {
+        debug_assert!(body_index >= self.next_body);
+        while self.next_body < body_index {
+            let offset = self.parser.current_position();
+            match *self.parser.read() {
+                ParserState::BeginFunctionBody { .. } => {}
+                _ => return Err(invalid_binary(offset, "missing function body")),
+            }
+            match *self.parser.read_with_input(ParserInput::SkipFunctionBody) {
+                ParserState::EndFunctionBody => {}
+                _ => return Err(invalid_binary(offset, "wrong content in the code section")),
+            }
+            self.next_body += 1;
+        }
+        let mut il_builder = ILBuilder::new();
+        let translation = translate_next_body(&mut self.parser,
+                                              function_index,
+                                              &lazy.function_names,
+                                              &lazy.result.signatures,
+                                              &lazy.result.function_signatures,
+                                              &mut il_builder,
+                                              runtime)?;
+        self.next_body += 1;
+        match translation {
+            Some(translation) => Ok(translation),
+            None => Err(invalid_binary(self.parser.current_position(), "missing function body")),
+        }
+    }
+}
+
 /// Translate a sequence of bytes forming a valid Wasm binary into a list of valid Cretonne IL
 /// [`Function`](../cretonne/ir/function/struct.Function.html).
 /// Returns the functions and also the mappings for imported functions and signature between the
-/// indexes in the wasm module and the indexes inside each functions.
+/// indexes in the wasm module and the indexes inside each functions. The module is validated
+/// first, an invalid module is reported as a `TranslationError::InvalidBinary`.
 pub fn translate_module(data: &Vec<u8>,
                         runtime: &mut WasmRuntime)
-                        -> Result<TranslationResult, String> {
+                        -> Result<TranslationResult, TranslationError> {
+    translate(data, runtime, true).map(|(result, _)| result)
+}
+
+/// Same as `translate_module`, but the function bodies are translated on up to `num_threads`
+/// threads, each with its own runtime given by `WasmRuntime::translation_runtime`. They are
+/// translated by `runtime` alone when it cannot give such runtimes. The result does not depend on
+/// the number of threads.
+pub fn translate_module_in_parallel(data: &Vec<u8>,
+                                    runtime: &mut WasmRuntime,
+                                    num_threads: usize)
+                                    -> Result<TranslationResult, TranslationError> {
+    if num_threads <= 1 {
+        return translate_module(data, runtime);
+    }
+    let lazy = Arc::new(translate_module_lazily(data, runtime)?);
+    let functions_count = lazy.result.function_signatures.len();
+    let bodies_count = functions_count - lazy.result.functions.len();
+    let mut runtimes = Vec::new();
+    while runtimes.len() < min(num_threads, bodies_count) {
+        match runtime.translation_runtime() {
+            Some(runtime) => runtimes.push(runtime),
+            None => break,
+        }
+    }
+    let next_function = Arc::new(AtomicUsize::new(lazy.result.functions.len()));
+    let (sender, receiver) = channel();
+    let workers: Vec<thread::JoinHandle<()>> = if runtimes.len() <= 1 {
+        // The bodies are translated by `runtime` alone, to which `translate_module` would
+        // declare the module again.
+        translate_bodies(&lazy, &next_function, runtime, &sender);
+        Vec::new()
+    } else {
+        runtimes
+            .into_iter()
+            .map(|mut runtime| {
+                let lazy = lazy.clone();
+                let next_function = next_function.clone();
+                let sender = sender.clone();
+                thread::spawn(move || {
+                                  translate_bodies(&lazy, &next_function, &mut *runtime, &sender)
+                              })
+            })
+            .collect()
+    };
+    drop(sender);
+    let mut results: Vec<(FunctionIndex, BodyTranslation)> = receiver.iter().collect();
+    // Every thread is joined before a panic is resumed, since the runtimes of the threads may
+    // refer to `runtime`.
+    let mut panic = None;
+    for worker in workers {
+        if let Err(payload) = worker.join() {
+            panic = panic.or(Some(payload));
+        }
+    }
+    if let Some(payload) = panic {
+        resume_unwind(payload);
+    }
+    // Whatever the order in which the bodies were translated, the error reported is the one of
+    // the first function that failed.
+    results.sort_by_key(|&(function_index, _)| function_index);
+    let mut lazy = match Arc::try_unwrap(lazy) {
+        Ok(lazy) => lazy,
+        Err(_) => panic!("the translation threads are joined"),
+    };
+    for (_, result) in results {
+        lazy.result.functions.push(result?);
+    }
+    debug_assert_eq!(lazy.result.functions.len(), functions_count);
+    Ok(lazy.result)
+}
+
+type BodyTranslation = Result<FunctionTranslation, TranslationError>;
+
+// Translates the functions whose indices are taken from `next_function` until all the bodies are
+// taken, and sends their translations with `sender`. The indices are taken in increasing order,
+// so that the code section is read once by each thread.
+fn translate_bodies(lazy: &LazyTranslationResult,
+                    next_function: &AtomicUsize,
+                    runtime: &mut WasmRuntime,
+                    sender: &Sender<(FunctionIndex, BodyTranslation)>) {
+    let first_body = lazy.result.functions.len();
+    let mut reader = CodeReader::new(&lazy.data);
+    loop {
+        let function_index = next_function.fetch_add(1, Ordering::SeqCst);
+        if function_index >= lazy.result.function_signatures.len() {
+            return;
+        }
+        let result = match reader {
+            Ok(ref mut reader) => {
+                reader.translate(function_index - first_body, function_index, lazy, runtime)
+            }
+            Err(ref err) => Err(err.clone()),
+        };
+        // The reader is left in the middle of a body by an error, which is the one reported
+        // since the functions before are translated by the other threads.
+        let failed = result.is_err();
+        if sender.send((function_index, result)).is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Same as `translate_module`, but the bodies of the functions defined by the module are not
+/// translated, only when needed with `LazyTranslationResult::translate_function` and the same
+/// runtime.
+pub fn translate_module_lazily(data: &Vec<u8>,
+                               runtime: &mut WasmRuntime)
+                               -> Result<LazyTranslationResult, TranslationError> {
+    let (result, function_names) = translate(data, runtime, false)?;
+    Ok(LazyTranslationResult {
+           result,
+           data: data.clone(),
+           function_names,
+       })
+}
+
+impl LazyTranslationResult {
+    /// Translates the body of the function `function_index` of the function index space, which
+    /// has to be defined by the module. `runtime` is the runtime the module was translated with.
+    /// The bodies before it in the code section are skipped without parsing their code.
+    pub fn translate_function(&self,
+                              function_index: FunctionIndex,
+                              runtime: &mut WasmRuntime)
+                              -> Result<FunctionTranslation, TranslationError> {
+        let body_index = self.body_index(function_index)?;
+        CodeReader::new(&self.data)?
+            .translate(body_index, function_index, self, runtime)
+    }
+
+    // Returns the index in the code section of the body of the function `function_index`.
+    fn body_index(&self, function_index: FunctionIndex) -> Result<usize, TranslationError> {
+        let bodies_count = self.result.function_signatures.len() - self.result.functions.len();
+        match function_index.checked_sub(self.result.functions.len()) {
+            Some(body_index) if body_index < bodies_count => Ok(body_index),
+            _ => {
+                Err(TranslationError::InvalidBinary {
+                        offset: 0,
+                        message: format!("function {} has no body", function_index),
+                    })
+            }
+        }
+    }
+}
+
+// Translates the module, with the bodies of its functions if `translate_bodies` is true. Also
+// returns the name of each function, given by the name section or else by its first export.
+fn translate(data: &Vec<u8>,
+             runtime: &mut WasmRuntime,
+             translate_bodies: bool)
+             -> Result<(TranslationResult, Option<HashMap<FunctionIndex, String>>),
+                       TranslationError> {
+    validate_module(data.as_slice())?;
+    let custom_sections = read_custom_sections(data)?;
+    for section in custom_sections.iter() {
+        runtime.declare_custom_section(section, section.payload(data));
+    }
+    let names = read_names(data);
     let mut parser = Parser::new(data.as_slice());
     match *parser.read() {
         ParserState::BeginWasm { .. } => {}
-        ref s @ _ => panic!("modules should begin properly: {:?}", s),
+        _ => return Err(invalid_binary(0, "missing module header")),
     }
     let mut signatures = None;
     let mut functions: Option<Vec<SignatureIndex>> = None;
-    let mut globals = Vec::new();
     let mut exports: Option<HashMap<FunctionIndex, String>> = None;
+    let mut exported_entities: HashMap<String, Export> = HashMap::new();
     let mut next_input = ParserInput::Default;
     let mut function_index: FunctionIndex = 0;
-    let mut function_imports_count = 0;
+    let mut il_functions: Vec<FunctionTranslation> = Vec::new();
     let mut start_index: Option<FunctionIndex> = None;
     loop {
+        let offset = parser.current_position();
         match *parser.read_with_input(next_input) {
             ParserState::BeginSection { code: SectionCode::Type, .. } => {
                 match parse_function_signatures(&mut parser) {
-                    Ok(sigs) => signatures = Some(sigs),
-                    Err(SectionParsingError::WrongSectionContent(s)) => {
-                        return Err(format!("wrong content in the type section: {}", s))
+                    Ok(mut sigs) => {
+                        if let Some(vmctx_type) = runtime.vmctx_type() {
+                            for sig in sigs.iter_mut() {
+                                sig.argument_types.push(ArgumentType::new(vmctx_type));
+                            }
+                        }
+                        for sig in sigs.iter() {
+                            runtime.declare_signature(sig);
+                        }
+                        signatures = Some(sigs)
                     }
+                    Err(err) => return Err(section_error(&parser, "type", err)),
                 };
                 next_input = ParserInput::Default;
             }
@@ -85,8 +350,12 @@ pub fn translate_module(data: &Vec<u8>,
                 match parse_import_section(&mut parser) {
                     Ok(imps) => {
                         for import in imps {
-                            match import {
-                                Import::Function { sig_index } => {
+                            let declared = match import {
+                                Import::Function {
+                                    sig_index,
+                                    module,
+                                    field,
+                                } => {
                                     functions = match functions {
                                         None => Some(vec![sig_index as SignatureIndex]),
                                         Some(mut funcs) => {
@@ -94,26 +363,38 @@ pub fn translate_module(data: &Vec<u8>,
                                             Some(funcs)
                                         }
                                     };
+                                    runtime.declare_function_import(sig_index as SignatureIndex,
+                                                                    &module,
+                                                                    &field);
+                                    il_functions.push(FunctionTranslation::Import {
+                                                          module,
+                                                          field,
+                                                      });
                                     function_index += 1;
+                                    Ok(())
                                 }
-                                Import::Memory(mem) => {
-                                    runtime.declare_memory(mem);
-                                }
-                                Import::Global(glob) => {
-                                    runtime.declare_global(glob.clone());
-                                    globals.push(glob);
-                                }
-                                Import::Table(tab) => {
-                                    runtime.declare_table(tab);
-                                }
-                            }
+                                Import::Memory {
+                                    memory,
+                                    module,
+                                    field,
+                                } => runtime.declare_memory_import(memory, &module, &field),
+                                Import::Global {
+                                    global,
+                                    module,
+                                    field,
+                                } => runtime.declare_global_import(global, &module, &field),
+                                Import::Table {
+                                    table,
+                                    module,
+                                    field,
+                                } => runtime.declare_table_import(table, &module, &field),
+                            };
+                            declared
+                                .map_err(|feature| TranslationError::Unsupported { feature })?;
                         }
                     }
-                    Err(SectionParsingError::WrongSectionContent(s)) => {
-                        return Err(format!("wrong content in the import section: {}", s))
-                    }
+                    Err(err) => return Err(section_error(&parser, "import", err)),
                 }
-                function_imports_count = function_index;
                 next_input = ParserInput::Default;
             }
             ParserState::BeginSection { code: SectionCode::Function, .. } => {
@@ -124,70 +405,73 @@ pub fn translate_module(data: &Vec<u8>,
                             Some(ref mut imps) => imps.extend(funcs),
                         }
                     }
-                    Err(SectionParsingError::WrongSectionContent(s)) => {
-                        return Err(format!("wrong content in the function section: {}", s))
-                    }
+                    Err(err) => return Err(section_error(&parser, "function", err)),
                 }
                 next_input = ParserInput::Default;
             }
             ParserState::BeginSection { code: SectionCode::Table, .. } => {
                 match parse_table_section(&mut parser, runtime) {
                     Ok(()) => (),
-                    Err(SectionParsingError::WrongSectionContent(s)) => {
-                        return Err(format!("wrong content in the table section: {}", s))
-                    }
+                    Err(err) => return Err(section_error(&parser, "table", err)),
                 }
             }
             ParserState::BeginSection { code: SectionCode::Memory, .. } => {
                 match parse_memory_section(&mut parser) {
                     Ok(mems) => {
                         for mem in mems {
-                            runtime.declare_memory(mem);
+                            runtime
+                                .declare_memory(mem)
+                                .map_err(|limit| TranslationError::ImplLimit { limit })?;
                         }
                     }
-                    Err(SectionParsingError::WrongSectionContent(s)) => {
-                        return Err(format!("wrong content in the memory section: {}", s))
-                    }
+                    Err(err) => return Err(section_error(&parser, "memory", err)),
                 }
                 next_input = ParserInput::Default;
             }
             ParserState::BeginSection { code: SectionCode::Global, .. } => {
                 match parse_global_section(&mut parser, runtime) {
-                    Ok(mut globs) => globals.append(&mut globs),
-                    Err(SectionParsingError::WrongSectionContent(s)) => {
-                        return Err(format!("wrong content in the global section: {}", s))
-                    }
+                    Ok(()) => (),
+                    Err(err) => return Err(section_error(&parser, "global", err)),
                 }
                 next_input = ParserInput::Default;
             }
             ParserState::BeginSection { code: SectionCode::Export, .. } => {
                 match parse_export_section(&mut parser) {
-                    Ok(exps) => exports = Some(exps),
-                    Err(SectionParsingError::WrongSectionContent(s)) => {
-                        return Err(format!("wrong content in the export section: {}", s))
+                    Ok(exps) => {
+                        // A function can be exported under several names, the first one is used
+                        // to name the translated function.
+                        let mut export_names = HashMap::new();
+                        for (name, export) in exps {
+                            if let Export::Function(index) = export {
+                                export_names.entry(index).or_insert(name.clone());
+                            }
+                            exported_entities.insert(name, export);
+                        }
+                        exports = Some(export_names)
                     }
+                    Err(err) => return Err(section_error(&parser, "export", err)),
                 }
                 next_input = ParserInput::Default;
             }
             ParserState::BeginSection { code: SectionCode::Start, .. } => {
+                let offset = parser.current_position();
                 match *parser.read() {
                     ParserState::StartSectionEntry(index) => {
                         start_index = Some(index as FunctionIndex)
                     }
-                    _ => return Err(String::from("wrong content in the start section")),
+                    _ => return Err(invalid_binary(offset, "wrong content in the start section")),
                 }
+                let offset = parser.current_position();
                 match *parser.read() {
                     ParserState::EndSection => {}
-                    _ => return Err(String::from("wrong content in the start section")),
+                    _ => return Err(invalid_binary(offset, "wrong content in the start section")),
                 }
                 next_input = ParserInput::Default;
             }
             ParserState::BeginSection { code: SectionCode::Element, .. } => {
-                match parse_elements_section(&mut parser, runtime, &globals) {
+                match parse_elements_section(&mut parser, runtime) {
                     Ok(()) => (),
-                    Err(SectionParsingError::WrongSectionContent(s)) => {
-                        return Err(format!("wrong content in the element section: {}", s))
-                    }
+                    Err(err) => return Err(section_error(&parser, "element", err)),
                 }
                 next_input = ParserInput::Default;
             }
@@ -195,24 +479,34 @@ pub fn translate_module(data: &Vec<u8>,
                 // The code section begins
                 break;
             }
+            ParserState::BeginSection { code: SectionCode::Custom { .. }, .. } => {
+                // The custom sections were read beforehand.
+                next_input = ParserInput::SkipSection;
+            }
             ParserState::EndSection => {
                 next_input = ParserInput::Default;
             }
             ParserState::EndWasm => {
-                return Ok(TranslationResult {
-                              functions: Vec::new(),
-                              start_index: None,
-                          })
+                // A module without code section still has its globals to allocate.
+                runtime.begin_translation();
+                return Ok((TranslationResult {
+                               functions: il_functions,
+                               start_index,
+                               signatures: signatures.unwrap_or(Vec::new()),
+                               function_signatures: functions.unwrap_or(Vec::new()),
+                               exports: exported_entities,
+                               names,
+                               custom_sections,
+                           },
+                           None))
             }
             ParserState::BeginSection { code: SectionCode::Data, .. } => {
-                match parse_data_section(&mut parser, runtime, &globals) {
+                match parse_data_section(&mut parser, runtime) {
                     Ok(()) => (),
-                    Err(SectionParsingError::WrongSectionContent(s)) => {
-                        return Err(format!("wrong content in the data section: {}", s))
-                    }
+                    Err(err) => return Err(section_error(&parser, "data", err)),
                 }
             }
-            _ => return Err(String::from("wrong content in the preamble")),
+            _ => return Err(invalid_binary(offset, "wrong content in the preamble")),
         };
     }
     // At this point we've entered the code section
@@ -222,67 +516,249 @@ pub fn translate_module(data: &Vec<u8>,
         Some(sigs) => sigs,
     };
     let functions = match functions {
-        None => return Err(String::from("missing a function section")),
+        None => {
+            return Err(invalid_binary(parser.current_position(), "missing a function section"))
+        }
         Some(functions) => functions,
     };
-    let mut il_functions: Vec<FunctionTranslation> = Vec::new();
-    il_functions.resize(function_imports_count, FunctionTranslation::Import());
+    let function_names = name_functions(exports, &names);
     let mut il_builder = ILBuilder::new();
     runtime.begin_translation();
-    loop {
-        let locals: Vec<(usize, Type)> = match *parser.read() {
-            ParserState::BeginFunctionBody { ref locals, .. } => {
-                locals
-                    .iter()
-                    .map(|&(index, ref ty)| {
-                             (index as usize,
-                              match type_to_type(ty) {
-                                  Ok(ty) => ty,
-                                  Err(()) => panic!("unsupported type for local variable"),
-                              })
-                         })
-                    .collect()
+    if translate_bodies {
+        while let Some(translation) = translate_next_body(&mut parser,
+                                                          function_index,
+                                                          &function_names,
+                                                          &signatures,
+                                                          &functions,
+                                                          &mut il_builder,
+                                                          runtime)? {
+            il_functions.push(translation);
+            function_index += 1;
+        }
+    } else {
+        // The bodies are skipped without reading their operators.
+        let mut next_input = ParserInput::Default;
+        loop {
+            let offset = parser.current_position();
+            match *parser.read_with_input(next_input) {
+                ParserState::BeginFunctionBody { .. } => {
+                    next_input = ParserInput::SkipFunctionBody;
+                }
+                ParserState::EndFunctionBody => next_input = ParserInput::Default,
+                ParserState::EndSection => break,
+                _ => return Err(invalid_binary(offset, "wrong content in the code section")),
             }
-            ParserState::EndSection => break,
-            _ => return Err(String::from(format!("wrong content in code section"))),
-        };
-        let signature = signatures[functions[function_index as usize] as usize].clone();
-        match translate_function_body(&mut parser,
-                                      function_index,
-                                      signature,
-                                      &locals,
-                                      &exports,
-                                      &signatures,
-                                      &functions,
-                                      &mut il_builder,
-                                      runtime) {
-            Ok((il_func, imports)) => {
-                il_functions.push(FunctionTranslation::Code {
-                                      il: il_func,
-                                      imports: invert_hashmaps(imports),
-                                  })
-            }
-            Err(s) => return Err(s),
         }
-        function_index += 1;
     }
+    let mut next_input = ParserInput::Default;
     loop {
-        match *parser.read() {
+        let offset = parser.current_position();
+        match *parser.read_with_input(next_input) {
+            ParserState::BeginSection { code: SectionCode::Custom { .. }, .. } => {
+                next_input = ParserInput::SkipSection;
+                continue;
+            }
             ParserState::BeginSection { code: SectionCode::Data, .. } => {
-                match parse_data_section(&mut parser, runtime, &globals) {
+                match parse_data_section(&mut parser, runtime) {
                     Ok(()) => (),
-                    Err(SectionParsingError::WrongSectionContent(s)) => {
-                        return Err(format!("wrong content in the data section: {}", s))
-                    }
+                    Err(err) => return Err(section_error(&parser, "data", err)),
                 }
             }
             ParserState::EndWasm => {
-                return Ok(TranslationResult {
-                              functions: il_functions,
-                              start_index,
-                          })
+                return Ok((TranslationResult {
+                               functions: il_functions,
+                               start_index,
+                               signatures,
+                               function_signatures: functions,
+                               exports: exported_entities,
+                               names,
+                               custom_sections,
+                           },
+                           function_names))
+            }
+            ParserState::Error(_) => {
+                return Err(invalid_binary(offset, "wrong content after the code section"))
             }
             _ => (),
         }
+        next_input = ParserInput::Default;
+    }
+}
+
+// Translates the function body read next by `parser`, which is the function `function_index`, or
+// returns `None` at the end of the code section.
+fn translate_next_body(parser: &mut Parser,
+                       function_index: FunctionIndex,
+                       function_names: &Option<HashMap<FunctionIndex, String>>,
+                       signatures: &Vec<Signature>,
+                       functions: &Vec<SignatureIndex>,
+                       il_builder: &mut ILBuilder<Local>,
+                       runtime: &mut WasmRuntime)
+                       -> Result<Option<FunctionTranslation>, TranslationError> {
+    let offset = parser.current_position();
+    let locals: Vec<(usize, Type)> = match *parser.read() {
+        ParserState::BeginFunctionBody { ref locals, .. } => {
+            let mut translated_locals = Vec::with_capacity(locals.len());
+            for &(count, ref ty) in locals.iter() {
+                translated_locals.push((count as usize, value_type(ty)?));
+            }
+            translated_locals
+        }
+        ParserState::EndSection => return Ok(None),
+        _ => return Err(invalid_binary(offset, "wrong content in the code section")),
+    };
+    let signature = match functions
+              .get(function_index)
+              .and_then(|&sig_index| signatures.get(sig_index)) {
+        Some(signature) => signature.clone(),
+        None => return Err(invalid_binary(offset, "function body without a valid signature")),
+    };
+    let (il_func, imports) = translate_function_body(parser,
+                                                     function_index,
+                                                     signature,
+                                                     &locals,
+                                                     function_names,
+                                                     signatures,
+                                                     functions,
+                                                     il_builder,
+                                                     runtime)?;
+    Ok(Some(FunctionTranslation::Code {
+                il: il_func,
+                imports: invert_hashmaps(imports),
+            }))
+}
+
+// Names the functions after the name section, or else after their first export.
+fn name_functions(exports: Option<HashMap<FunctionIndex, String>>,
+                  names: &Names)
+                  -> Option<HashMap<FunctionIndex, String>> {
+    if names.functions.is_empty() {
+        return exports;
+    }
+    let mut function_names = exports.unwrap_or_else(HashMap::new);
+    function_names.extend(names
+                              .functions
+                              .iter()
+                              .map(|(&index, name)| (index, name.clone())));
+    Some(function_names)
+}
+
+// Returns the custom sections of a validated module, in a pass of their own so that the runtime
+// gets them before the other declarations.
+fn read_custom_sections(data: &[u8]) -> Result<Vec<CustomSection>, TranslationError> {
+    let mut custom_sections = Vec::new();
+    let mut parser = Parser::new(data);
+    let mut next_input = ParserInput::Default;
+    // Name of the custom section being skipped, and position of its payload.
+    let mut section_name = None;
+    let mut payload_start = 0;
+    loop {
+        let offset = parser.current_position();
+        let mut section_begins = false;
+        let mut section_ends = false;
+        next_input = match *parser.read_with_input(next_input) {
+            ParserState::BeginSection { code: SectionCode::Custom { name, .. }, .. } => {
+                let name = String::from_utf8(name.to_vec())
+                    .map_err(|_| invalid_binary(offset, "invalid UTF-8 name"))?;
+                section_name = Some(name);
+                section_begins = true;
+                ParserInput::SkipSection
+            }
+            ParserState::BeginSection { .. } => ParserInput::SkipSection,
+            ParserState::EndSection => {
+                section_ends = true;
+                ParserInput::Default
+            }
+            ParserState::EndWasm => return Ok(custom_sections),
+            ParserState::Error(_) => return Err(invalid_binary(offset, "wrong section")),
+            _ => ParserInput::Default,
+        };
+        if section_begins {
+            payload_start = parser.current_position();
+        } else if section_ends {
+            if let Some(name) = section_name.take() {
+                custom_sections.push(CustomSection {
+                                         name,
+                                         range: payload_start..parser.current_position(),
+                                     });
+            }
+        }
+    }
+}
+
+// Returns the names given by the first name section, read by a parser of its own since the name
+// section follows the code whose translation uses them. The contents of the custom sections are
+// not validated, so a malformed name section is ignored as if it were absent.
+fn read_names(data: &[u8]) -> Names {
+    let mut names = Names::default();
+    let mut parser = Parser::new(data);
+    let mut next_input = ParserInput::Default;
+    let mut in_name_section = false;
+    loop {
+        next_input = match *parser.read_with_input(next_input) {
+            ParserState::BeginSection { code: SectionCode::Custom { name, .. }, .. }
+                if name == b"name" => {
+                in_name_section = true;
+                ParserInput::Default
+            }
+            ParserState::BeginSection { .. } => ParserInput::SkipSection,
+            ParserState::NameSectionEntry(ref entry) => {
+                add_name_entry(&mut names, entry);
+                ParserInput::Default
+            }
+            ParserState::EndSection if in_name_section => return names,
+            ParserState::EndWasm => return names,
+            ParserState::Error(_) => return Names::default(),
+            _ => ParserInput::Default,
+        };
+    }
+}
+
+fn add_name_entry(names: &mut Names, entry: &NameEntry) {
+    match *entry {
+        NameEntry::Module(name) => names.module = Some(name_string(name)),
+        NameEntry::Function(ref namings) => {
+            for naming in namings {
+                names
+                    .functions
+                    .insert(naming.index as FunctionIndex, name_string(naming.name));
+            }
+        }
+        NameEntry::Local(ref functions) => {
+            for function in functions {
+                let locals = function
+                    .locals
+                    .iter()
+                    .map(|naming| (naming.index, name_string(naming.name)))
+                    .collect();
+                names.locals.insert(function.index as FunctionIndex, locals);
+            }
+        }
+    }
+}
+
+// The names are only meant for debugging, so an invalid UTF-8 name is not worth rejecting.
+fn name_string(name: &[u8]) -> String {
+    String::from_utf8_lossy(name).into_owned()
+}
+
+fn invalid_binary(offset: usize, message: &str) -> TranslationError {
+    TranslationError::InvalidBinary {
+        offset,
+        message: String::from(message),
+    }
+}
+
+// Turns the error of the parsing of a section into a translation error located at the current
+// position of the parser.
+fn section_error(parser: &Parser, section: &str, err: SectionParsingError) -> TranslationError {
+    match err {
+        SectionParsingError::WrongSectionContent(message) => {
+            TranslationError::InvalidBinary {
+                offset: parser.current_position(),
+                message: format!("wrong content in the {} section: {}", section, message),
+            }
+        }
+        SectionParsingError::Unsupported(feature) => TranslationError::Unsupported { feature },
     }
 }
@@ -1,8 +1,8 @@
 use runtime::WasmRuntime;
 use translation_utils::{Local, Global, Memory, Table, GlobalIndex, TableIndex, FunctionIndex,
-                        MemoryIndex};
+                        MemoryIndex, SignatureIndex};
 use cton_frontend::FunctionBuilder;
-use cretonne::ir::{Value, InstBuilder, SigRef};
+use cretonne::ir::{Value, InstBuilder, SigRef, Signature, Type};
 use cretonne::ir::immediates::{Ieee32, Ieee64};
 use cretonne::ir::types::*;
 
@@ -11,16 +11,29 @@ use cretonne::ir::types::*;
 /// essentially here for translation debug purposes.
 pub struct DummyRuntime {
     globals: Vec<Global>,
+    pointer_type: Type,
 }
 
 impl DummyRuntime {
-    /// Allocates the runtime data structures.
+    /// Allocates the runtime data structures, for a 64 bits target.
     pub fn new() -> DummyRuntime {
-        DummyRuntime { globals: Vec::new() }
+        DummyRuntime::with_pointer_type(I64)
+    }
+
+    /// Allocates the runtime data structures, for a target whose addresses have the type
+    /// `pointer_type`, see [`pointer_type`](fn.pointer_type.html).
+    pub fn with_pointer_type(pointer_type: Type) -> DummyRuntime {
+        DummyRuntime {
+            globals: Vec::new(),
+            pointer_type,
+        }
     }
 }
 
 impl WasmRuntime for DummyRuntime {
+    fn declare_signature(&mut self, _: &Signature) {
+        // We do nothing
+    }
     fn translate_get_global(&self,
                             builder: &mut FunctionBuilder<Local>,
                             global_index: GlobalIndex)
@@ -42,14 +55,23 @@ impl WasmRuntime for DummyRuntime {
     fn translate_set_global(&self, _: &mut FunctionBuilder<Local>, _: GlobalIndex, _: Value) {
         // We do nothing
     }
-    fn translate_grow_memory(&mut self, builder: &mut FunctionBuilder<Local>, _: Value) -> Value {
+    fn translate_grow_memory(&mut self,
+                             builder: &mut FunctionBuilder<Local>,
+                             _: MemoryIndex,
+                             _: Value)
+                             -> Value {
         builder.ins().iconst(I32, -1)
     }
-    fn translate_current_memory(&mut self, builder: &mut FunctionBuilder<Local>) -> Value {
+    fn translate_current_memory(&mut self,
+                                builder: &mut FunctionBuilder<Local>,
+                                _: MemoryIndex)
+                                -> Value {
         builder.ins().iconst(I32, -1)
     }
     fn translate_call_indirect<'a>(&self,
                                    builder: &'a mut FunctionBuilder<Local>,
+                                   _: TableIndex,
+                                   _: SignatureIndex,
                                    sig_ref: SigRef,
                                    index_val: Value,
                                    call_args: &[Value])
@@ -61,22 +83,48 @@ impl WasmRuntime for DummyRuntime {
                                      builder: &mut FunctionBuilder<Local>,
                                      _: MemoryIndex)
                                      -> Value {
-        builder.ins().iconst(I64, 0)
+        builder.ins().iconst(self.pointer_type, 0)
+    }
+    fn translate_memory_bounds_check(&self,
+                                     _: &mut FunctionBuilder<Local>,
+                                     _: MemoryIndex,
+                                     _: Value,
+                                     _: u32,
+                                     _: u32) {
+        // We do nothing
     }
     fn declare_global(&mut self, global: Global) {
         self.globals.push(global);
     }
+    fn declare_global_import(&mut self, global: Global, _: &str, _: &str) -> Result<(), String> {
+        self.globals.push(global);
+        Ok(())
+    }
     fn declare_table(&mut self, _: Table) {
         //We do nothing
     }
-    fn declare_table_elements(&mut self, _: TableIndex, _: usize, _: &[FunctionIndex]) {
+    fn declare_table_import(&mut self, _: Table, _: &str, _: &str) -> Result<(), String> {
         //We do nothing
+        Ok(())
     }
-    fn declare_memory(&mut self, _: Memory) {
+    fn declare_table_elements(&mut self,
+                              _: TableIndex,
+                              _: Option<GlobalIndex>,
+                              _: usize,
+                              _: &[FunctionIndex]) {
         //We do nothing
     }
+    fn declare_memory(&mut self, _: Memory) -> Result<(), String> {
+        //We do nothing
+        Ok(())
+    }
+    fn declare_memory_import(&mut self, _: Memory, _: &str, _: &str) -> Result<(), String> {
+        //We do nothing
+        Ok(())
+    }
     fn declare_data_initialization(&mut self,
                                    _: MemoryIndex,
+                                   _: Option<GlobalIndex>,
                                    _: usize,
                                    _: &[u8])
                                    -> Result<(), String> {
@@ -90,4 +138,13 @@ impl WasmRuntime for DummyRuntime {
     fn next_function(&mut self) {
         // We do nothing
     }
+    fn pointer_type(&self) -> Type {
+        self.pointer_type
+    }
+    fn vmctx_type(&self) -> Option<Type> {
+        None
+    }
+    fn set_vmctx(&mut self, _: Value) {
+        // We do nothing
+    }
 }
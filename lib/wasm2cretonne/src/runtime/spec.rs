@@ -1,36 +1,114 @@
 //! All the runtime support necessary for the wasm to cretonne translation is formalized by the
 //! trait `WasmRuntime`.
 use cton_frontend::FunctionBuilder;
-use cretonne::ir::{Value, SigRef};
-use translation_utils::{Local, FunctionIndex, TableIndex, GlobalIndex, MemoryIndex, Global, Table,
-                        Memory};
+use cretonne::ir::{Value, SigRef, Signature, Type};
+use translation_utils::{Local, FunctionIndex, TableIndex, GlobalIndex, MemoryIndex, SignatureIndex,
+                        Global, Table, Memory, CustomSection};
 
 /// An object satisfyng the `WasmRuntime` trait can be passed as argument to the
 /// [`translate_module`](fn.translate_module.html) function. These methods should not be called
 /// by the user, they are only for the `wasm2cretonne` internal use.
 pub trait WasmRuntime {
+    /// Gives a custom section of the module and its payload to the runtime, in the order of the
+    /// module and before any other declaration. The custom sections are ignored by default.
+    fn declare_custom_section(&mut self, _: &CustomSection, _: &[u8]) {}
+    /// Declares a function signature to the runtime, in the order of the type section.
+    fn declare_signature(&mut self, sig: &Signature);
+    /// Declares a function imported as `field` from `module`, with the signature `sig_index`, in
+    /// the order of the function index space.
+    fn declare_function_import(&mut self, _: SignatureIndex, _: &str, _: &str) {}
     /// Declares a global to the runtime.
     fn declare_global(&mut self, global: Global);
+    /// Declares a global imported as `field` from `module`, provided by the host or by another
+    /// module. Fails if the runtime cannot import globals.
+    fn declare_global_import(&mut self,
+                             global: Global,
+                             module: &str,
+                             field: &str)
+                             -> Result<(), String>;
     /// Declares a table to the runtime.
     fn declare_table(&mut self, table: Table);
-    /// Fills a declared table with references to functions in the module.
+    /// Declares a table imported as `field` from `module`. Fails if the runtime cannot import
+    /// tables.
+    fn declare_table_import(&mut self,
+                            table: Table,
+                            module: &str,
+                            field: &str)
+                            -> Result<(), String>;
+    /// Fills a declared table with references to functions in the module, from the index
+    /// `offset` plus the value of the global `base` when present.
     fn declare_table_elements(&mut self,
                               table_index: TableIndex,
+                              base: Option<GlobalIndex>,
                               offset: usize,
                               elements: &[FunctionIndex]);
-    /// Declares a memory to the runtime
-    fn declare_memory(&mut self, memory: Memory);
-    /// Fills a declared memory with bytes at module instantiation.
+    /// Declares a memory to the runtime. Fails if the runtime cannot allocate it.
+    fn declare_memory(&mut self, memory: Memory) -> Result<(), String>;
+    /// Declares a memory imported as `field` from `module`. Fails if the runtime cannot import
+    /// memories.
+    fn declare_memory_import(&mut self,
+                             memory: Memory,
+                             module: &str,
+                             field: &str)
+                             -> Result<(), String>;
+    /// Fills a declared memory with bytes at module instantiation, from the address `offset`
+    /// plus the value of the global `base` when present.
     fn declare_data_initialization(&mut self,
                                    memory_index: MemoryIndex,
+                                   base: Option<GlobalIndex>,
                                    offset: usize,
                                    data: &[u8])
                                    -> Result<(), String>;
     /// Call this function after having declared all the runtime elements but prior to the
     /// function body translation.
     fn begin_translation(&mut self);
+    /// Returns a runtime translating the function bodies like this one, which can be moved to
+    /// another thread, or `None` if the bodies can only be translated by this runtime. Called
+    /// once per thread by `translate_module_in_parallel`, after `begin_translation`.
+    fn translation_runtime(&self) -> Option<Box<WasmRuntime + Send>> {
+        None
+    }
     /// Call this function between each function body translation.
     fn next_function(&mut self);
+    /// Type of the native addresses of the target, `I64` on 64 bits targets and `I32` on 32 bits
+    /// targets. The wasm addresses are extended to this type before being added to the base
+    /// address of a memory.
+    fn pointer_type(&self) -> Type;
+    /// Type of the hidden VM context argument that every function takes after its wasm
+    /// arguments, or `None` if the functions take no such argument. The VM context is passed along
+    /// by every call, including the calls to imported functions.
+    fn vmctx_type(&self) -> Option<Type>;
+    /// Gives the VM context argument of the function whose body is being translated, when
+    /// `vmctx_type` is not `None`.
+    fn set_vmctx(&mut self, vmctx: Value);
+    /// Returns the VM context argument passed to the function `function_index` by a `call`,
+    /// which is by default the VM context `vmctx` of the caller.
+    fn translate_call_vmctx(&self,
+                            _: &mut FunctionBuilder<Local>,
+                            _: FunctionIndex,
+                            vmctx: Value)
+                            -> Value {
+        vmctx
+    }
+    /// Emits the code executed when entering a function, after its locals are initialized and
+    /// before its body, for instance to check the stack. Nothing is emitted by default.
+    fn translate_function_prologue(&self, _: &mut FunctionBuilder<Local>) {}
+    /// Whether the translated code is metered, consuming one unit of fuel per operator executed
+    /// with the code emitted by `translate_consume_fuel`. No code is metered by default.
+    fn fuel_metering(&self) -> bool {
+        false
+    }
+    /// Emits the code consuming `fuel` units of fuel, which traps when the fuel runs out.
+    fn translate_consume_fuel(&self, _: &mut FunctionBuilder<Local>, _: u32) {}
+    /// Whether the translated code can be interrupted, checking the epoch with the code emitted by
+    /// `translate_check_epoch` when entering a function and at every loop iteration. No code is
+    /// interruptible by default.
+    fn epoch_interruption(&self) -> bool {
+        false
+    }
+    /// Emits the code checking whether the epoch reached the deadline set by the runtime, which
+    /// traps when it did.
+    fn translate_check_epoch(&self, _: &mut FunctionBuilder<Local>) {}
     /// Translates a `get_global` wasm instruction.
     fn translate_get_global(&self,
                             builder: &mut FunctionBuilder<Local>,
@@ -41,19 +119,39 @@ pub trait WasmRuntime {
                             builder: &mut FunctionBuilder<Local>,
                             global_index: GlobalIndex,
                             val: Value);
-    /// Translates a `grow_memory` wasm instruction. Returns the old size (in pages) of the memory.
-    fn translate_grow_memory(&mut self, builder: &mut FunctionBuilder<Local>, val: Value) -> Value;
-    /// Translates a `current_memory` wasm instruction. Returns the size in pages of the memory.
-    fn translate_current_memory(&mut self, builder: &mut FunctionBuilder<Local>) -> Value;
+    /// Translates a `grow_memory` wasm instruction growing the memory `index`. Returns the old
+    /// size (in pages) of the memory.
+    fn translate_grow_memory(&mut self,
+                             builder: &mut FunctionBuilder<Local>,
+                             index: MemoryIndex,
+                             val: Value)
+                             -> Value;
+    /// Translates a `current_memory` wasm instruction. Returns the size in pages of the memory
+    /// `index`.
+    fn translate_current_memory(&mut self,
+                                builder: &mut FunctionBuilder<Local>,
+                                index: MemoryIndex)
+                                -> Value;
     /// Returns the base address of a wasm memory as a Cretonne `Value`.
     fn translate_memory_base_address(&self,
                                      builder: &mut FunctionBuilder<Local>,
                                      index: MemoryIndex)
                                      -> Value;
+    /// Emits the code preventing a load or store of `size` bytes at the wasm address `addr` plus
+    /// `offset` from accessing memory outside of the wasm memory `index`.
+    fn translate_memory_bounds_check(&self,
+                                     builder: &mut FunctionBuilder<Local>,
+                                     index: MemoryIndex,
+                                     addr: Value,
+                                     offset: u32,
+                                     size: u32);
     /// Translates a `call_indirect` wasm instruction. It involves looking up the value contained
-    /// it the table at location `index_val` and calling the corresponding function.
+    /// it the table `table_index` at location `index_val`, checking that its signature is the
+    /// signature `sig_index` of the module and calling the corresponding function.
     fn translate_call_indirect<'a>(&self,
                                    builder: &'a mut FunctionBuilder<Local>,
+                                   table_index: TableIndex,
+                                   sig_index: SignatureIndex,
                                    sig_ref: SigRef,
                                    index_val: Value,
                                    call_args: &[Value])
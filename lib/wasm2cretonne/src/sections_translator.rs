@@ -7,20 +7,35 @@
 //! The special case of the initialize expressions for table elements offsets or global variables
 //! is handled, according to the semantics of WebAssembly, to only specific expressions that are
 //! interpreted on the fly.
-use translation_utils::{type_to_type, Import, TableIndex, FunctionIndex, GlobalIndex,
-                        SignatureIndex, MemoryIndex, Global, GlobalInit, Table, TableElementType,
-                        Memory};
+use translation_utils::{type_to_type, value_type, Import, Export, TableIndex, FunctionIndex,
+                        GlobalIndex, SignatureIndex, MemoryIndex, Global, GlobalInit, Table,
+                        TableElementType, Memory, TranslationError};
 use cretonne::ir::{Signature, ArgumentType, CallConv};
-use cretonne;
 use wasmparser::{Parser, ParserState, FuncType, ImportSectionEntryType, ExternalKind, WasmDecoder,
                  MemoryType, Operator};
 use wasmparser;
-use std::collections::HashMap;
 use std::str::from_utf8;
 use runtime::WasmRuntime;
 
 pub enum SectionParsingError {
     WrongSectionContent(String),
+    Unsupported(String),
+}
+
+impl From<TranslationError> for SectionParsingError {
+    fn from(err: TranslationError) -> SectionParsingError {
+        match err {
+            TranslationError::Unsupported { feature } => SectionParsingError::Unsupported(feature),
+            err => SectionParsingError::WrongSectionContent(err.to_string()),
+        }
+    }
+}
+
+// Decodes a name of the module, which has to be valid UTF-8.
+fn decode_name(name: &[u8]) -> Result<String, SectionParsingError> {
+    from_utf8(name)
+        .map(String::from)
+        .map_err(|_| SectionParsingError::WrongSectionContent(String::from("invalid UTF-8 name")))
 }
 
 /// Reads the Type Section of the wasm module and returns the corresponding function signatures.
@@ -36,28 +51,12 @@ pub fn parse_function_signatures(parser: &mut Parser)
                                               ref returns,
                                           }) => {
                 let mut sig = Signature::new(CallConv::Native);
-                sig.argument_types
-                    .extend(params
-                                .iter()
-                                .map(|ty| {
-                        let cret_arg: cretonne::ir::Type = match type_to_type(ty) {
-                            Ok(ty) => ty,
-                            Err(()) => panic!("only numeric types are supported in\
-                                      function signatures"),
-                        };
-                        ArgumentType::new(cret_arg)
-                    }));
-                sig.return_types
-                    .extend(returns
-                                .iter()
-                                .map(|ty| {
-                        let cret_arg: cretonne::ir::Type = match type_to_type(ty) {
-                            Ok(ty) => ty,
-                            Err(()) => panic!("only numeric types are supported in\
-                                  function signatures"),
-                        };
-                        ArgumentType::new(cret_arg)
-                    }));
+                for ty in params.iter() {
+                    sig.argument_types.push(ArgumentType::new(value_type(ty)?));
+                }
+                for ty in returns.iter() {
+                    sig.return_types.push(ArgumentType::new(value_type(ty)?));
+                }
                 signatures.push(sig);
             }
             ref s @ _ => return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s))),
@@ -72,36 +71,62 @@ pub fn parse_import_section(parser: &mut Parser) -> Result<Vec<Import>, SectionP
     loop {
         match *parser.read() {
             ParserState::ImportSectionEntry {
-                ty: ImportSectionEntryType::Function(sig), ..
-            } => imports.push(Import::Function { sig_index: sig }),
+                module,
+                field,
+                ty: ImportSectionEntryType::Function(sig),
+            } => {
+                imports.push(Import::Function {
+                                 sig_index: sig,
+                                 module: decode_name(module)?,
+                                 field: decode_name(field)?,
+                             })
+            }
             ParserState::ImportSectionEntry {
-                ty: ImportSectionEntryType::Memory(MemoryType { limits: ref memlimits }), ..
+                module,
+                field,
+                ty: ImportSectionEntryType::Memory(MemoryType { limits: ref memlimits }),
             } => {
-                imports.push(Import::Memory(Memory {
-                                                pages_count: memlimits.initial as usize,
-                                                maximum: memlimits.maximum.map(|x| x as usize),
-                                            }))
+                imports.push(Import::Memory {
+                                 memory: Memory {
+                                     pages_count: memlimits.initial as usize,
+                                     maximum: memlimits.maximum.map(|x| x as usize),
+                                 },
+                                 module: decode_name(module)?,
+                                 field: decode_name(field)?,
+                             })
             }
             ParserState::ImportSectionEntry {
-                ty: ImportSectionEntryType::Global(ref ty), ..
+                module,
+                field,
+                ty: ImportSectionEntryType::Global(ref ty),
             } => {
-                imports.push(Import::Global(Global {
-                                                ty: type_to_type(&ty.content_type).unwrap(),
-                                                mutability: ty.mutability != 0,
-                                                initializer: GlobalInit::Import(),
-                                            }));
+                imports.push(Import::Global {
+                                 global: Global {
+                                     ty: value_type(&ty.content_type)?,
+                                     mutability: ty.mutability != 0,
+                                     initializer: GlobalInit::Import(),
+                                 },
+                                 module: decode_name(module)?,
+                                 field: decode_name(field)?,
+                             });
             }
             ParserState::ImportSectionEntry {
-                ty: ImportSectionEntryType::Table(ref tab), ..
+                module,
+                field,
+                ty: ImportSectionEntryType::Table(ref tab),
             } => {
-                imports.push(Import::Table(Table {
-                                               ty: match type_to_type(&tab.element_type) {
-                                                   Ok(t) => TableElementType::Val(t),
-                                                   Err(()) => TableElementType::Func(),
-                                               },
-                                               size: tab.limits.initial as usize,
-                                               maximum: tab.limits.maximum.map(|x| x as usize),
-                                           }));
+                imports.push(Import::Table {
+                                 table: Table {
+                                     ty: match type_to_type(&tab.element_type) {
+                                         Ok(t) => TableElementType::Val(t),
+                                         Err(()) => TableElementType::Func(),
+                                     },
+                                     size: tab.limits.initial as usize,
+                                     maximum: tab.limits.maximum.map(|x| x as usize),
+                                 },
+                                 module: decode_name(module)?,
+                                 field: decode_name(field)?,
+                             });
             }
             ParserState::EndSection => break,
             ref s @ _ => return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s))),
@@ -124,10 +149,10 @@ pub fn parse_function_section(parser: &mut Parser)
     Ok(funcs)
 }
 
-/// Retrieves the names of the functions from the export section
+/// Retrieves the exports from the export section, in the order of the section.
 pub fn parse_export_section(parser: &mut Parser)
-                            -> Result<HashMap<FunctionIndex, String>, SectionParsingError> {
-    let mut exports: HashMap<FunctionIndex, String> = HashMap::new();
+                            -> Result<Vec<(String, Export)>, SectionParsingError> {
+    let mut exports: Vec<(String, Export)> = Vec::new();
     loop {
         match *parser.read() {
             ParserState::ExportSectionEntry {
@@ -135,14 +160,13 @@ pub fn parse_export_section(parser: &mut Parser)
                 ref kind,
                 index,
             } => {
-                match kind {
-                    &ExternalKind::Function => {
-                        exports.insert(index as FunctionIndex,
-                                       String::from(from_utf8(field).unwrap()));
-                        ()
-                    }
-                    _ => (),//TODO: deal with other kind of exports
-                }
+                let export = match *kind {
+                    ExternalKind::Function => Export::Function(index as FunctionIndex),
+                    ExternalKind::Table => Export::Table(index as TableIndex),
+                    ExternalKind::Memory => Export::Memory(index as MemoryIndex),
+                    ExternalKind::Global => Export::Global(index as GlobalIndex),
+                };
+                exports.push((decode_name(field)?, export));
             }
             ParserState::EndSection => break,
             ref s @ _ => return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s))),
@@ -169,11 +193,10 @@ pub fn parse_memory_section(parser: &mut Parser) -> Result<Vec<Memory>, SectionP
     Ok(memories)
 }
 
-/// Retrieves the size and maximum fields of memories from the memory section
+/// Retrieves the globals from the global section and declares them to the runtime.
 pub fn parse_global_section(parser: &mut Parser,
                             runtime: &mut WasmRuntime)
-                            -> Result<Vec<Global>, SectionParsingError> {
-    let mut globals = Vec::new();
+                            -> Result<(), SectionParsingError> {
     loop {
         let (content_type, mutability) = match *parser.read() {
             ParserState::BeginGlobalSectionEntry(ref ty) => (ty.content_type, ty.mutability),
@@ -208,23 +231,47 @@ pub fn parse_global_section(parser: &mut Parser,
             ref s @ _ => return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s))),
         }
         let global = Global {
-            ty: type_to_type(&content_type).unwrap(),
+            ty: value_type(&content_type)?,
             mutability: mutability != 0,
             initializer: initializer,
         };
-        runtime.declare_global(global.clone());
-        globals.push(global);
+        runtime.declare_global(global);
         match *parser.read() {
             ParserState::EndGlobalSectionEntry => (),
             ref s @ _ => return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s))),
         }
     }
-    Ok(globals)
+    Ok(())
+}
+
+// Reads the initializer expression giving the offset of a data or elements segment: a constant,
+// or the value of an imported global read at instantiation, returned as `base`.
+fn parse_segment_offset(parser: &mut Parser)
+                        -> Result<(Option<GlobalIndex>, usize), SectionParsingError> {
+    match *parser.read() {
+        ParserState::BeginInitExpressionBody => (),
+        ref s @ _ => return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s))),
+    };
+    let (base, offset) = match *parser.read() {
+        // The offset is an unsigned 32 bits integer.
+        ParserState::InitExpressionOperator(Operator::I32Const { value }) => {
+            (None, value as u32 as usize)
+        }
+        ParserState::InitExpressionOperator(Operator::GetGlobal { global_index }) => {
+            (Some(global_index as GlobalIndex), 0)
+        }
+        ref s @ _ => return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s))),
+    };
+    match *parser.read() {
+        ParserState::EndInitExpressionBody => (),
+        ref s @ _ => return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s))),
+    };
+    Ok((base, offset))
 }
 
+/// Retrieves the data segments from the data section and declares them to the runtime.
 pub fn parse_data_section(parser: &mut Parser,
-                          runtime: &mut WasmRuntime,
-                          globals: &Vec<Global>)
+                          runtime: &mut WasmRuntime)
                           -> Result<(), SectionParsingError> {
     loop {
         let memory_index = match *parser.read() {
@@ -232,39 +279,7 @@ pub fn parse_data_section(parser: &mut Parser,
             ParserState::EndSection => break,
             ref s @ _ => return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s))),
         };
-        match *parser.read() {
-            ParserState::BeginInitExpressionBody => (),
-            ref s @ _ => return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s))),
-        };
-        let offset = match *parser.read() {
-            ParserState::InitExpressionOperator(Operator::I32Const { value }) => {
-                if value < 0 {
-                    return Err(SectionParsingError::WrongSectionContent(String::from("negative offset value",),),);
-                } else {
-                    value as usize
-                }
-            }
-            ParserState::InitExpressionOperator(Operator::GetGlobal { global_index }) => {
-                match globals[global_index as usize].initializer {
-                    GlobalInit::I32Const(value) => {
-                        if value < 0 {
-                            return Err(SectionParsingError::WrongSectionContent(String::from("negative offset value",),),);
-                        } else {
-                            value as usize
-                        }
-                    }
-                    GlobalInit::Import() => {
-                        return Err(SectionParsingError::WrongSectionContent(String::from("imported globals not supported",),),)
-                    } // TODO: add runtime support
-                    _ => panic!("should not happen"),
-                }
-            }
-            ref s @ _ => return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s))),
-        };
-        match *parser.read() {
-            ParserState::EndInitExpressionBody => (),
-            ref s @ _ => return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s))),
-        };
+        let (base, offset) = parse_segment_offset(parser)?;
         {
             let data = match *parser.read() {
                 ParserState::DataSectionEntryBody(data) => data,
@@ -272,7 +287,10 @@ pub fn parse_data_section(parser: &mut Parser,
                     return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s)))
                 }
             };
-            match runtime.declare_data_initialization(memory_index as MemoryIndex, offset, data) {
+            match runtime.declare_data_initialization(memory_index as MemoryIndex,
+                                                      base,
+                                                      offset,
+                                                      data) {
                 Ok(()) => (),
                 Err(s) => return Err(SectionParsingError::WrongSectionContent(format!("{}", s))),
             };
@@ -308,10 +326,9 @@ pub fn parse_table_section(parser: &mut Parser,
     Ok(())
 }
 
-/// Retrieves the tables from the table section
+/// Retrieves the elements segments from the elements section and declares them to the runtime.
 pub fn parse_elements_section(parser: &mut Parser,
-                              runtime: &mut WasmRuntime,
-                              globals: &Vec<Global>)
+                              runtime: &mut WasmRuntime)
                               -> Result<(), SectionParsingError> {
     loop {
         let table_index = match *parser.read() {
@@ -319,42 +336,12 @@ pub fn parse_elements_section(parser: &mut Parser,
             ParserState::EndSection => break,
             ref s @ _ => return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s))),
         };
-        match *parser.read() {
-            ParserState::BeginInitExpressionBody => (),
-            ref s @ _ => return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s))),
-        };
-        let offset = match *parser.read() {
-            ParserState::InitExpressionOperator(Operator::I32Const { value }) => {
-                if value < 0 {
-                    return Err(SectionParsingError::WrongSectionContent(String::from("negative offset value",),),);
-                } else {
-                    value as usize
-                }
-            }
-            ParserState::InitExpressionOperator(Operator::GetGlobal { global_index }) => {
-                match globals[global_index as usize].initializer {
-                    GlobalInit::I32Const(value) => {
-                        if value < 0 {
-                            return Err(SectionParsingError::WrongSectionContent(String::from("negative offset value",),),);
-                        } else {
-                            value as usize
-                        }
-                    }
-                    GlobalInit::Import() => 0, // TODO: add runtime support
-                    _ => panic!("should not happen"),
-                }
-            }
-            ref s @ _ => return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s))),
-        };
-        match *parser.read() {
-            ParserState::EndInitExpressionBody => (),
-            ref s @ _ => return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s))),
-        };
+        let (base, offset) = parse_segment_offset(parser)?;
         match *parser.read() {
             ParserState::ElementSectionEntryBody(ref elements) => {
                 let elems: Vec<FunctionIndex> =
                     elements.iter().map(|&x| x as FunctionIndex).collect();
-                runtime.declare_table_elements(table_index, offset, elems.as_slice())
+                runtime.declare_table_elements(table_index, base, offset, elems.as_slice())
             }
             ref s @ _ => return Err(SectionParsingError::WrongSectionContent(format!("{:?}", s))),
         };
@@ -2,9 +2,98 @@
 use wasmparser;
 use cretonne;
 use std::u32;
+use std::fmt;
+use std::collections::HashMap;
+use std::ops::Range;
 use code_translator;
 use module_translator;
 
+/// Error returned by the translation of a WebAssembly module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TranslationError {
+    /// The binary is malformed or does not follow the specification, around the byte `offset`.
+    InvalidBinary { offset: usize, message: String },
+    /// The module uses a feature of WebAssembly that the translation does not support.
+    Unsupported { feature: String },
+    /// The module exceeds a limit of the implementation or of the runtime.
+    ImplLimit { limit: String },
+}
+
+impl fmt::Display for TranslationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TranslationError::InvalidBinary {
+                offset,
+                ref message,
+            } => write!(f, "invalid binary at offset {:#x}: {}", offset, message),
+            TranslationError::Unsupported { ref feature } => write!(f, "unsupported {}", feature),
+            TranslationError::ImplLimit { ref limit } => {
+                write!(f, "implementation limit exceeded: {}", limit)
+            }
+        }
+    }
+}
+
+/// Cause of a trap of the generated code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapKind {
+    /// A `trap` instruction was executed. It comes from an `unreachable` wasm instruction or from
+    /// a float to integer conversion whose result is not representable, which Cretonne expands
+    /// into a `trap`.
+    Unreachable,
+    /// Integer division by zero or integer overflow of a division.
+    IntegerArithmetic,
+    /// Access outside of the bounds of a linear memory.
+    MemoryOutOfBounds,
+    /// `call_indirect` with an index outside of the bounds of the table.
+    TableOutOfBounds,
+    /// `call_indirect` to an uninitialized table element or to a function whose signature differs
+    /// from the expected one.
+    IndirectCallTypeMismatch,
+    /// The fuel of the runtime ran out.
+    OutOfFuel,
+    /// The maximum depth of the calls was exceeded.
+    StackOverflow,
+    /// The execution was interrupted by the host.
+    Interrupted,
+}
+
+impl TrapKind {
+    /// Number identifying the trap kind when the generated code reports it to the runtime, like
+    /// `wasmstandalone_raise_trap` or `wasmaot_trap` do.
+    pub fn code(&self) -> i64 {
+        match *self {
+            TrapKind::Unreachable => 0,
+            TrapKind::IntegerArithmetic => 1,
+            TrapKind::MemoryOutOfBounds => 2,
+            TrapKind::TableOutOfBounds => 3,
+            TrapKind::IndirectCallTypeMismatch => 4,
+            TrapKind::OutOfFuel => 5,
+            TrapKind::StackOverflow => 6,
+            TrapKind::Interrupted => 7,
+        }
+    }
+
+    /// Trap kind identified by `code`, `Unreachable` for an unknown one.
+    pub fn from_code(code: i64) -> TrapKind {
+        match code {
+            1 => TrapKind::IntegerArithmetic,
+            2 => TrapKind::MemoryOutOfBounds,
+            3 => TrapKind::TableOutOfBounds,
+            4 => TrapKind::IndirectCallTypeMismatch,
+            5 => TrapKind::OutOfFuel,
+            6 => TrapKind::StackOverflow,
+            7 => TrapKind::Interrupted,
+            _ => TrapKind::Unreachable,
+        }
+    }
+}
+
+/// Size of a WebAssembly page.
+pub const PAGE_SIZE: usize = 65536;
+/// Maximum number of pages of a linear memory, which is addressable with 32 bits.
+pub const MAX_PAGES: usize = 65536;
+
 /// Index of a function (imported or defined) inside the WebAssembly module.
 pub type FunctionIndex = usize;
 /// Index of a table (imported or defined) inside the WebAssembly module.
@@ -21,12 +110,37 @@ pub type RawByte = u8;
 pub type MemoryAddress = usize;
 
 /// WebAssembly import.
-#[derive(Debug,Clone,Copy)]
+#[derive(Debug,Clone)]
 pub enum Import {
-    Function { sig_index: u32 },
-    Memory(Memory),
-    Global(Global),
-    Table(Table),
+    Function {
+        sig_index: u32,
+        module: String,
+        field: String,
+    },
+    Memory {
+        memory: Memory,
+        module: String,
+        field: String,
+    },
+    Global {
+        global: Global,
+        module: String,
+        field: String,
+    },
+    Table {
+        table: Table,
+        module: String,
+        field: String,
+    },
+}
+
+/// WebAssembly export, referring to an entity by its index in the corresponding index space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Export {
+    Function(FunctionIndex),
+    Table(TableIndex),
+    Memory(MemoryIndex),
+    Global(GlobalIndex),
 }
 
 /// WebAssembly global.
@@ -70,6 +184,33 @@ pub struct Memory {
     pub maximum: Option<usize>,
 }
 
+/// Names given to the module, its functions and their locals by the "name" custom section, which
+/// are only meant for debugging.
+#[derive(Debug, Clone, Default)]
+pub struct Names {
+    pub module: Option<String>,
+    /// Names of the functions, by index in the function index space.
+    pub functions: HashMap<FunctionIndex, String>,
+    /// Names of the locals of the functions, by function index and then by local index.
+    pub locals: HashMap<FunctionIndex, HashMap<u32, String>>,
+}
+
+/// Custom section of a module, whose contents are left to the embedder except for the name
+/// section.
+#[derive(Debug, Clone)]
+pub struct CustomSection {
+    pub name: String,
+    /// Position of the payload in the module, from the end of the name to the end of the section.
+    pub range: Range<usize>,
+}
+
+impl CustomSection {
+    /// Returns the payload of the section in `data`, the module it comes from.
+    pub fn payload<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+        &data[self.range.clone()]
+    }
+}
+
 /// Wrapper to a `get_local` and `set_local` index. They are WebAssembly's non-SSA variables.
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Local(pub u32);
@@ -100,6 +241,35 @@ pub fn type_to_type(ty: &wasmparser::Type) -> Result<cretonne::ir::Type, ()> {
     }
 }
 
+/// Translates the type of a value of the module, reporting the unsupported ones.
+pub fn value_type(ty: &wasmparser::Type) -> Result<cretonne::ir::Type, TranslationError> {
+    type_to_type(ty).map_err(|()| {
+        TranslationError::Unsupported { feature: format!("value type {:?}", ty) }
+    })
+}
+
+/// Index of the memory accessed by a load or a store. The binary format has no field for it in
+/// their memory immediate yet, so the bits of the flags above the 8 bits of the alignment hold it:
+/// the loads and stores of the current encoding access the memory 0.
+pub fn memory_index(memarg: &wasmparser::MemoryImmediate) -> MemoryIndex {
+    (memarg.flags >> 8) as MemoryIndex
+}
+
+/// Base 2 logarithm of the alignment of a load or a store, in the low bits of the flags of its
+/// memory immediate.
+pub fn memory_alignment(memarg: &wasmparser::MemoryImmediate) -> u32 {
+    memarg.flags & 0xff
+}
+
+/// Type of the native addresses of the target `isa`, `I64` for 64 bits targets and `I32` otherwise.
+pub fn pointer_type(isa: &cretonne::isa::TargetIsa) -> cretonne::ir::Type {
+    if isa.flags().is_64bit() {
+        cretonne::ir::types::I64
+    } else {
+        cretonne::ir::types::I32
+    }
+}
+
 /// Turns a `wasmparser` `f32` into a `Cretonne` one.
 pub fn f32_translation(x: wasmparser::Ieee32) -> cretonne::ir::immediates::Ieee32 {
     cretonne::ir::immediates::Ieee32::with_bits(x.bits())
@@ -118,7 +288,7 @@ pub fn translate_type(ty: wasmparser::Type) -> Result<Vec<cretonne::ir::Type>, (
         wasmparser::Type::F32 => Ok(vec![cretonne::ir::types::F32]),
         wasmparser::Type::I64 => Ok(vec![cretonne::ir::types::I64]),
         wasmparser::Type::F64 => Ok(vec![cretonne::ir::types::F64]),
-        _ => panic!("unsupported return value type"),
+        _ => Err(()),
     }
 }
 
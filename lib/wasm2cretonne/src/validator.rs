@@ -0,0 +1,880 @@
+//! Validation of WebAssembly modules, following the rules of the specification.
+//!
+//! The translation assumes that the module is valid: it does not check the types of the operands
+//! of the instructions nor the indexes they refer to. `validate_module` checks them beforehand,
+//! section by section and then for each function body by simulating the operand stack and the
+//! control stack with the types of their values instead of the values themselves.
+//!
+//! Values popped from the operand stack below an unconditional branch or an `unreachable` are
+//! of unknown type (`None`), since the code after them is never executed.
+//!
+//! Unlike the specification, a module can have several tables and several memories.
+use wasmparser::{Parser, ParserState, ParserInput, SectionCode, WasmDecoder, Operator,
+                 MemoryImmediate, ImportSectionEntryType, ExternalKind, ResizableLimits};
+use wasmparser;
+use cretonne::ir::Type;
+use cretonne::ir::types::*;
+use translation_utils::{value_type, translate_type, memory_index, memory_alignment,
+                        TranslationError, MAX_PAGES};
+use std::collections::HashSet;
+use std::str::from_utf8;
+
+struct FunctionType {
+    params: Vec<Type>,
+    returns: Vec<Type>,
+}
+
+struct Global {
+    ty: Type,
+    mutable: bool,
+}
+
+/// Declarations of the module gathered from the sections preceding the function bodies.
+struct ModuleContext {
+    types: Vec<FunctionType>,
+    /// Type index of each function of the function index space.
+    functions: Vec<u32>,
+    imported_functions: usize,
+    tables: usize,
+    memories: usize,
+    globals: Vec<Global>,
+    imported_globals: usize,
+}
+
+impl ModuleContext {
+    fn function_type(&self, function_index: u32) -> Result<&FunctionType, String> {
+        match self.functions.get(function_index as usize) {
+            Some(&type_index) => Ok(&self.types[type_index as usize]),
+            None => Err(format!("unknown function {}", function_index)),
+        }
+    }
+
+    fn check_type_index(&self, type_index: u32) -> Result<(), String> {
+        if type_index as usize >= self.types.len() {
+            return Err(format!("unknown type {}", type_index));
+        }
+        Ok(())
+    }
+}
+
+// Initialization expression being read, along with the type it should have.
+struct InitExpression {
+    expected: Type,
+    ty: Option<Type>,
+    operators: usize,
+}
+
+fn invalid(offset: usize, message: String) -> TranslationError {
+    TranslationError::InvalidBinary { offset, message }
+}
+
+fn check_limits(limits: &ResizableLimits, max: u32) -> Result<(), String> {
+    if limits.initial > max {
+        return Err(format!("initial size {} larger than {}", limits.initial, max));
+    }
+    if let Some(maximum) = limits.maximum {
+        if maximum > max {
+            return Err(format!("maximum size {} larger than {}", maximum, max));
+        }
+        if limits.initial > maximum {
+            return Err(String::from("initial size larger than the maximum size"));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the binary `data` is a valid WebAssembly module. Returns the first error found,
+/// located by its offset in the binary.
+pub fn validate_module(data: &[u8]) -> Result<(), TranslationError> {
+    let mut parser = Parser::new(data);
+    let mut module = ModuleContext {
+        types: Vec::new(),
+        functions: Vec::new(),
+        imported_functions: 0,
+        tables: 0,
+        memories: 0,
+        globals: Vec::new(),
+        imported_globals: 0,
+    };
+    let mut export_names = HashSet::new();
+    let mut pending_global: Option<Global> = None;
+    let mut init_expression: Option<InitExpression> = None;
+    let mut function_bodies = 0;
+    let mut last_section = 0;
+    let mut next_input = ParserInput::Default;
+    loop {
+        let offset = parser.current_position();
+        let mut function_body: Option<Vec<Type>> = None;
+        next_input = match *parser.read_with_input(next_input) {
+            ParserState::BeginWasm { .. } |
+            ParserState::EndSection => ParserInput::Default,
+            ParserState::BeginSection { code: SectionCode::Custom { .. }, .. } => {
+                ParserInput::SkipSection
+            }
+            ParserState::BeginSection { ref code, .. } => {
+                let order = section_order(code);
+                if order <= last_section {
+                    return Err(invalid(offset, String::from("unexpected section")));
+                }
+                last_section = order;
+                ParserInput::Default
+            }
+            ParserState::TypeSectionEntry(ref func_type) => {
+                if func_type.form != wasmparser::Type::Func {
+                    return Err(invalid(offset, String::from("type is not a function type")));
+                }
+                if func_type.returns.len() > 1 {
+                    return Err(invalid(offset, String::from("multiple return values")));
+                }
+                let mut params = Vec::new();
+                for ty in func_type.params.iter() {
+                    params.push(value_type(ty)?);
+                }
+                let mut returns = Vec::new();
+                for ty in func_type.returns.iter() {
+                    returns.push(value_type(ty)?);
+                }
+                module.types.push(FunctionType { params, returns });
+                ParserInput::Default
+            }
+            ParserState::ImportSectionEntry {
+                module: module_name,
+                field,
+                ref ty,
+            } => {
+                if from_utf8(module_name).is_err() || from_utf8(field).is_err() {
+                    return Err(invalid(offset, String::from("invalid UTF-8 name")));
+                }
+                match *ty {
+                    ImportSectionEntryType::Function(type_index) => {
+                        module
+                            .check_type_index(type_index)
+                            .map_err(|message| invalid(offset, message))?;
+                        module.functions.push(type_index);
+                        module.imported_functions += 1;
+                    }
+                    ImportSectionEntryType::Table(ref table) => {
+                        if table.element_type != wasmparser::Type::AnyFunc {
+                            return Err(invalid(offset, String::from("table must be of anyfunc")));
+                        }
+                        module.tables += 1;
+                        check_limits(&table.limits, u32::max_value())
+                            .map_err(|message| invalid(offset, message))?;
+                    }
+                    ImportSectionEntryType::Memory(ref memory) => {
+                        module.memories += 1;
+                        check_limits(&memory.limits, MAX_PAGES as u32)
+                            .map_err(|message| invalid(offset, message))?;
+                    }
+                    ImportSectionEntryType::Global(ref global) => {
+                        if global.mutability != 0 {
+                            return Err(invalid(offset,
+                                               String::from("mutable globals cannot be \
+                                                             imported")));
+                        }
+                        module
+                            .globals
+                            .push(Global {
+                                      ty: value_type(&global.content_type)?,
+                                      mutable: false,
+                                  });
+                        module.imported_globals += 1;
+                    }
+                }
+                ParserInput::Default
+            }
+            ParserState::FunctionSectionEntry(type_index) => {
+                module
+                    .check_type_index(type_index)
+                    .map_err(|message| invalid(offset, message))?;
+                module.functions.push(type_index);
+                ParserInput::Default
+            }
+            ParserState::TableSectionEntry(ref table) => {
+                if table.element_type != wasmparser::Type::AnyFunc {
+                    return Err(invalid(offset, String::from("table must be of anyfunc")));
+                }
+                module.tables += 1;
+                check_limits(&table.limits, u32::max_value())
+                    .map_err(|message| invalid(offset, message))?;
+                ParserInput::Default
+            }
+            ParserState::MemorySectionEntry(ref memory) => {
+                module.memories += 1;
+                check_limits(&memory.limits, MAX_PAGES as u32)
+                    .map_err(|message| invalid(offset, message))?;
+                ParserInput::Default
+            }
+            ParserState::BeginGlobalSectionEntry(ref global) => {
+                let ty = value_type(&global.content_type)?;
+                pending_global = Some(Global {
+                                          ty,
+                                          mutable: global.mutability != 0,
+                                      });
+                init_expression = Some(InitExpression {
+                                           expected: ty,
+                                           ty: None,
+                                           operators: 0,
+                                       });
+                ParserInput::Default
+            }
+            ParserState::EndGlobalSectionEntry => {
+                if let Some(global) = pending_global.take() {
+                    module.globals.push(global);
+                }
+                ParserInput::Default
+            }
+            ParserState::ExportSectionEntry {
+                field,
+                ref kind,
+                index,
+            } => {
+                let name = match from_utf8(field) {
+                    Ok(name) => String::from(name),
+                    Err(_) => return Err(invalid(offset, String::from("invalid UTF-8 name"))),
+                };
+                if !export_names.insert(name.clone()) {
+                    return Err(invalid(offset, format!("duplicate export name {}", name)));
+                }
+                let (count, kind_name) = match *kind {
+                    ExternalKind::Function => (module.functions.len(), "function"),
+                    ExternalKind::Table => (module.tables, "table"),
+                    ExternalKind::Memory => (module.memories, "memory"),
+                    ExternalKind::Global => (module.globals.len(), "global"),
+                };
+                if index as usize >= count {
+                    return Err(invalid(offset, format!("unknown {} {}", kind_name, index)));
+                }
+                if let ExternalKind::Global = *kind {
+                    if module.globals[index as usize].mutable {
+                        return Err(invalid(offset,
+                                           String::from("mutable globals cannot be exported")));
+                    }
+                }
+                ParserInput::Default
+            }
+            ParserState::StartSectionEntry(function_index) => {
+                let valid = {
+                    let func_type = module
+                        .function_type(function_index)
+                        .map_err(|message| invalid(offset, message))?;
+                    func_type.params.is_empty() && func_type.returns.is_empty()
+                };
+                if !valid {
+                    return Err(invalid(offset, String::from("start function must be [] -> []")));
+                }
+                ParserInput::Default
+            }
+            ParserState::BeginElementSectionEntry(table_index) => {
+                if table_index as usize >= module.tables {
+                    return Err(invalid(offset, format!("unknown table {}", table_index)));
+                }
+                init_expression = Some(InitExpression {
+                                           expected: I32,
+                                           ty: None,
+                                           operators: 0,
+                                       });
+                ParserInput::Default
+            }
+            ParserState::ElementSectionEntryBody(ref elements) => {
+                for &function_index in elements.iter() {
+                    if function_index as usize >= module.functions.len() {
+                        return Err(invalid(offset, format!("unknown function {}", function_index)));
+                    }
+                }
+                ParserInput::Default
+            }
+            ParserState::BeginDataSectionEntry(memory_index) => {
+                if memory_index as usize >= module.memories {
+                    return Err(invalid(offset, format!("unknown memory {}", memory_index)));
+                }
+                init_expression = Some(InitExpression {
+                                           expected: I32,
+                                           ty: None,
+                                           operators: 0,
+                                       });
+                ParserInput::Default
+            }
+            ParserState::InitExpressionOperator(ref op) => {
+                let ty = match *op {
+                    Operator::I32Const { .. } => I32,
+                    Operator::I64Const { .. } => I64,
+                    Operator::F32Const { .. } => F32,
+                    Operator::F64Const { .. } => F64,
+                    Operator::GetGlobal { global_index } => {
+                        // Only the imported globals are initialized at this point.
+                        if global_index as usize >= module.imported_globals {
+                            return Err(invalid(offset, format!("unknown global {}", global_index)));
+                        }
+                        module.globals[global_index as usize].ty
+                    }
+                    _ => return Err(invalid(offset, String::from("constant expression required"))),
+                };
+                if let Some(ref mut expression) = init_expression {
+                    expression.ty = Some(ty);
+                    expression.operators += 1;
+                }
+                ParserInput::Default
+            }
+            ParserState::EndInitExpressionBody => {
+                if let Some(expression) = init_expression.take() {
+                    if expression.operators != 1 {
+                        return Err(invalid(offset,
+                                           String::from("constant expression required")));
+                    }
+                    if expression.ty != Some(expression.expected) {
+                        return Err(invalid(offset,
+                                           String::from("type mismatch in constant \
+                                                         expression")));
+                    }
+                }
+                ParserInput::Default
+            }
+            ParserState::BeginFunctionBody { ref locals, .. } => {
+                if function_bodies >= module.functions.len() - module.imported_functions {
+                    return Err(invalid(offset,
+                                       String::from("more function bodies than functions")));
+                }
+                let mut body_locals = Vec::new();
+                for &(count, ref ty) in locals.iter() {
+                    let ty = value_type(ty)?;
+                    if body_locals.len() + count as usize > u32::max_value() as usize {
+                        return Err(TranslationError::ImplLimit {
+                                       limit: String::from("too many local variables"),
+                                   });
+                    }
+                    for _ in 0..count {
+                        body_locals.push(ty);
+                    }
+                }
+                function_body = Some(body_locals);
+                ParserInput::Default
+            }
+            ParserState::EndWasm => {
+                if function_bodies != module.functions.len() - module.imported_functions {
+                    return Err(invalid(offset,
+                                       String::from("function and code section have \
+                                                     inconsistent lengths")));
+                }
+                return Ok(());
+            }
+            ParserState::Error(ref err) => {
+                return Err(invalid(err.offset, String::from(err.message)))
+            }
+            _ => ParserInput::Default,
+        };
+        if let Some(locals) = function_body {
+            let function_index = (module.imported_functions + function_bodies) as u32;
+            validate_function_body(&mut parser, &module, function_index, locals)?;
+            function_bodies += 1;
+        }
+    }
+}
+
+/// Position of the non-custom sections, which appear at most once and in this order.
+fn section_order(code: &SectionCode) -> u8 {
+    match *code {
+        SectionCode::Custom { .. } => 0,
+        SectionCode::Type => 1,
+        SectionCode::Import => 2,
+        SectionCode::Function => 3,
+        SectionCode::Table => 4,
+        SectionCode::Memory => 5,
+        SectionCode::Global => 6,
+        SectionCode::Export => 7,
+        SectionCode::Start => 8,
+        SectionCode::Element => 9,
+        SectionCode::Code => 10,
+        SectionCode::Data => 11,
+    }
+}
+
+/// Kind of a frame of the control stack.
+#[derive(PartialEq, Eq)]
+enum FrameKind {
+    Function,
+    Block,
+    Loop,
+    If,
+    Else,
+}
+
+struct ControlFrame {
+    kind: FrameKind,
+    results: Vec<Type>,
+    /// Height of the operand stack at the beginning of the frame.
+    height: usize,
+    unreachable: bool,
+}
+
+/// Operand and control stacks of a function body being validated.
+struct FunctionValidator<'a> {
+    module: &'a ModuleContext,
+    locals: Vec<Type>,
+    returns: Vec<Type>,
+    operands: Vec<Option<Type>>,
+    frames: Vec<ControlFrame>,
+}
+
+fn validate_function_body(parser: &mut Parser,
+                          module: &ModuleContext,
+                          function_index: u32,
+                          body_locals: Vec<Type>)
+                          -> Result<(), TranslationError> {
+    let (mut locals, returns) = {
+        let func_type = &module.types[module.functions[function_index as usize] as usize];
+        (func_type.params.clone(), func_type.returns.clone())
+    };
+    locals.extend(body_locals);
+    let mut validator = FunctionValidator {
+        module,
+        locals,
+        returns: returns.clone(),
+        operands: Vec::new(),
+        frames: Vec::new(),
+    };
+    validator.push_frame(FrameKind::Function, returns);
+    loop {
+        let offset = parser.current_position();
+        let result = match *parser.read() {
+            ParserState::CodeOperator(ref op) => {
+                if validator.frames.is_empty() {
+                    Err(String::from("operators after the end of the function"))
+                } else {
+                    validator.operator(op)
+                }
+            }
+            ParserState::EndFunctionBody => {
+                if validator.frames.is_empty() {
+                    return Ok(());
+                }
+                Err(String::from("function body must end with end"))
+            }
+            ParserState::Error(ref err) => {
+                return Err(invalid(err.offset, String::from(err.message)))
+            }
+            _ => Err(String::from("wrong content in function body")),
+        };
+        result.map_err(|message| invalid(offset, message))?;
+    }
+}
+
+// Type of the values of a block, or `Err` for the unsupported ones.
+fn block_type(ty: wasmparser::Type) -> Result<Vec<Type>, String> {
+    translate_type(ty).map_err(|()| format!("invalid block type {:?}", ty))
+}
+
+impl<'a> FunctionValidator<'a> {
+    fn push_operand(&mut self, ty: Option<Type>) {
+        self.operands.push(ty);
+    }
+
+    fn pop_operand(&mut self) -> Result<Option<Type>, String> {
+        let frame = self.frames.last().unwrap();
+        if self.operands.len() == frame.height {
+            if frame.unreachable {
+                return Ok(None);
+            }
+            return Err(String::from("type mismatch: not enough operands"));
+        }
+        Ok(self.operands.pop().unwrap())
+    }
+
+    fn pop_expected(&mut self, expected: Type) -> Result<(), String> {
+        match self.pop_operand()? {
+            Some(ty) if ty != expected => {
+                Err(format!("type mismatch: expected {}, found {}", expected, ty))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn pop_values(&mut self, types: &[Type]) -> Result<(), String> {
+        for &ty in types.iter().rev() {
+            self.pop_expected(ty)?;
+        }
+        Ok(())
+    }
+
+    fn push_values(&mut self, types: &[Type]) {
+        for &ty in types.iter() {
+            self.push_operand(Some(ty));
+        }
+    }
+
+    fn push_frame(&mut self, kind: FrameKind, results: Vec<Type>) {
+        let height = self.operands.len();
+        self.frames
+            .push(ControlFrame {
+                      kind,
+                      results,
+                      height,
+                      unreachable: false,
+                  });
+    }
+
+    fn pop_frame(&mut self) -> Result<ControlFrame, String> {
+        let results = self.frames.last().unwrap().results.clone();
+        self.pop_values(&results)?;
+        if self.operands.len() != self.frames.last().unwrap().height {
+            return Err(String::from("type mismatch: too many operands at the end of the block"));
+        }
+        Ok(self.frames.pop().unwrap())
+    }
+
+    // Types of the values expected by a branch to the frame at `relative_depth`.
+    fn label_types(&self, relative_depth: u32) -> Result<Vec<Type>, String> {
+        if relative_depth as usize >= self.frames.len() {
+            return Err(format!("unknown label {}", relative_depth));
+        }
+        let frame = &self.frames[self.frames.len() - 1 - relative_depth as usize];
+        if frame.kind == FrameKind::Loop {
+            Ok(Vec::new())
+        } else {
+            Ok(frame.results.clone())
+        }
+    }
+
+    fn set_unreachable(&mut self) {
+        let frame = self.frames.last_mut().unwrap();
+        self.operands.truncate(frame.height);
+        frame.unreachable = true;
+    }
+
+    fn local_type(&self, local_index: u32) -> Result<Type, String> {
+        match self.locals.get(local_index as usize) {
+            Some(&ty) => Ok(ty),
+            None => Err(format!("unknown local {}", local_index)),
+        }
+    }
+
+    fn check_memory(&self, memory_index: u32) -> Result<(), String> {
+        if memory_index as usize >= self.module.memories {
+            return Err(format!("unknown memory {}", memory_index));
+        }
+        Ok(())
+    }
+
+    fn operator(&mut self, op: &Operator) -> Result<(), String> {
+        if let Some((arity, operand_type, result_type)) = numeric_operator_type(op) {
+            for _ in 0..arity {
+                self.pop_expected(operand_type)?;
+            }
+            self.push_operand(Some(result_type));
+            return Ok(());
+        }
+        if let Some((memarg, natural_alignment, ty, is_store)) = memory_operator_type(op) {
+            self.check_memory(memory_index(memarg) as u32)?;
+            if memory_alignment(memarg) > natural_alignment {
+                return Err(String::from("alignment must not be larger than natural"));
+            }
+            if is_store {
+                self.pop_expected(ty)?;
+                self.pop_expected(I32)?;
+            } else {
+                self.pop_expected(I32)?;
+                self.push_operand(Some(ty));
+            }
+            return Ok(());
+        }
+        match *op {
+            Operator::Unreachable => self.set_unreachable(),
+            Operator::Nop => {}
+            Operator::Block { ty } => {
+                let results = block_type(ty)?;
+                self.push_frame(FrameKind::Block, results);
+            }
+            Operator::Loop { ty } => {
+                let results = block_type(ty)?;
+                self.push_frame(FrameKind::Loop, results);
+            }
+            Operator::If { ty } => {
+                let results = block_type(ty)?;
+                self.pop_expected(I32)?;
+                self.push_frame(FrameKind::If, results);
+            }
+            Operator::Else => {
+                if self.frames.last().unwrap().kind != FrameKind::If {
+                    return Err(String::from("else without if"));
+                }
+                let frame = self.pop_frame()?;
+                self.push_frame(FrameKind::Else, frame.results);
+            }
+            Operator::End => {
+                let frame = self.pop_frame()?;
+                if frame.kind == FrameKind::If && !frame.results.is_empty() {
+                    return Err(String::from("type mismatch: if without else returns a value"));
+                }
+                self.push_values(&frame.results);
+            }
+            Operator::Br { relative_depth } => {
+                let types = self.label_types(relative_depth)?;
+                self.pop_values(&types)?;
+                self.set_unreachable();
+            }
+            Operator::BrIf { relative_depth } => {
+                self.pop_expected(I32)?;
+                let types = self.label_types(relative_depth)?;
+                self.pop_values(&types)?;
+                self.push_values(&types);
+            }
+            Operator::BrTable { ref table } => {
+                self.pop_expected(I32)?;
+                let (depths, default) = table.read_table();
+                let types = self.label_types(default)?;
+                for &depth in depths.iter() {
+                    if self.label_types(depth)? != types {
+                        return Err(String::from("type mismatch: br_table targets of different \
+                                                 arities"));
+                    }
+                }
+                self.pop_values(&types)?;
+                self.set_unreachable();
+            }
+            Operator::Return => {
+                let returns = self.returns.clone();
+                self.pop_values(&returns)?;
+                self.set_unreachable();
+            }
+            Operator::Call { function_index } => {
+                let (params, returns) = {
+                    let func_type = self.module.function_type(function_index)?;
+                    (func_type.params.clone(), func_type.returns.clone())
+                };
+                self.pop_values(&params)?;
+                self.push_values(&returns);
+            }
+            Operator::CallIndirect { index, table_index } => {
+                if table_index as usize >= self.module.tables {
+                    return Err(format!("unknown table {}", table_index));
+                }
+                self.module.check_type_index(index)?;
+                let (params, returns) = {
+                    let func_type = &self.module.types[index as usize];
+                    (func_type.params.clone(), func_type.returns.clone())
+                };
+                self.pop_expected(I32)?;
+                self.pop_values(&params)?;
+                self.push_values(&returns);
+            }
+            Operator::Drop => {
+                self.pop_operand()?;
+            }
+            Operator::Select => {
+                self.pop_expected(I32)?;
+                let first = self.pop_operand()?;
+                let second = self.pop_operand()?;
+                match (first, second) {
+                    (Some(x), Some(y)) if x != y => {
+                        return Err(format!("type mismatch: select of {} and {}", x, y))
+                    }
+                    (None, _) => self.push_operand(second),
+                    _ => self.push_operand(first),
+                }
+            }
+            Operator::GetLocal { local_index } => {
+                let ty = self.local_type(local_index)?;
+                self.push_operand(Some(ty));
+            }
+            Operator::SetLocal { local_index } => {
+                let ty = self.local_type(local_index)?;
+                self.pop_expected(ty)?;
+            }
+            Operator::TeeLocal { local_index } => {
+                let ty = self.local_type(local_index)?;
+                self.pop_expected(ty)?;
+                self.push_operand(Some(ty));
+            }
+            Operator::GetGlobal { global_index } => {
+                let ty = match self.module.globals.get(global_index as usize) {
+                    Some(global) => global.ty,
+                    None => return Err(format!("unknown global {}", global_index)),
+                };
+                self.push_operand(Some(ty));
+            }
+            Operator::SetGlobal { global_index } => {
+                let ty = match self.module.globals.get(global_index as usize) {
+                    Some(&Global { ty, mutable: true }) => ty,
+                    Some(_) => return Err(String::from("global is immutable")),
+                    None => return Err(format!("unknown global {}", global_index)),
+                };
+                self.pop_expected(ty)?;
+            }
+            Operator::CurrentMemory { reserved } => {
+                self.check_memory(reserved)?;
+                self.push_operand(Some(I32));
+            }
+            Operator::GrowMemory { reserved } => {
+                self.check_memory(reserved)?;
+                self.pop_expected(I32)?;
+                self.push_operand(Some(I32));
+            }
+            Operator::I32Const { .. } => self.push_operand(Some(I32)),
+            Operator::I64Const { .. } => self.push_operand(Some(I64)),
+            Operator::F32Const { .. } => self.push_operand(Some(F32)),
+            Operator::F64Const { .. } => self.push_operand(Some(F64)),
+            _ => return Err(format!("unsupported operator {:?}", op)),
+        }
+        Ok(())
+    }
+}
+
+// Returns the memory immediate, the natural alignment (as a power of two), the type of the value
+// and whether it is a store for the memory access operators.
+fn memory_operator_type(op: &Operator) -> Option<(&MemoryImmediate, u32, Type, bool)> {
+    let (memory_immediate, natural_alignment, ty, is_store) = match *op {
+        Operator::I32Load { ref memory_immediate } => (memory_immediate, 2, I32, false),
+        Operator::I64Load { ref memory_immediate } => (memory_immediate, 3, I64, false),
+        Operator::F32Load { ref memory_immediate } => (memory_immediate, 2, F32, false),
+        Operator::F64Load { ref memory_immediate } => (memory_immediate, 3, F64, false),
+        Operator::I32Load8S { ref memory_immediate } |
+        Operator::I32Load8U { ref memory_immediate } => (memory_immediate, 0, I32, false),
+        Operator::I32Load16S { ref memory_immediate } |
+        Operator::I32Load16U { ref memory_immediate } => (memory_immediate, 1, I32, false),
+        Operator::I64Load8S { ref memory_immediate } |
+        Operator::I64Load8U { ref memory_immediate } => (memory_immediate, 0, I64, false),
+        Operator::I64Load16S { ref memory_immediate } |
+        Operator::I64Load16U { ref memory_immediate } => (memory_immediate, 1, I64, false),
+        Operator::I64Load32S { ref memory_immediate } |
+        Operator::I64Load32U { ref memory_immediate } => (memory_immediate, 2, I64, false),
+        Operator::I32Store { ref memory_immediate } => (memory_immediate, 2, I32, true),
+        Operator::I64Store { ref memory_immediate } => (memory_immediate, 3, I64, true),
+        Operator::F32Store { ref memory_immediate } => (memory_immediate, 2, F32, true),
+        Operator::F64Store { ref memory_immediate } => (memory_immediate, 3, F64, true),
+        Operator::I32Store8 { ref memory_immediate } => (memory_immediate, 0, I32, true),
+        Operator::I32Store16 { ref memory_immediate } => (memory_immediate, 1, I32, true),
+        Operator::I64Store8 { ref memory_immediate } => (memory_immediate, 0, I64, true),
+        Operator::I64Store16 { ref memory_immediate } => (memory_immediate, 1, I64, true),
+        Operator::I64Store32 { ref memory_immediate } => (memory_immediate, 2, I64, true),
+        _ => return None,
+    };
+    Some((memory_immediate, natural_alignment, ty, is_store))
+}
+
+// Returns the number of operands, their type and the type of the result of the numeric
+// operators.
+fn numeric_operator_type(op: &Operator) -> Option<(usize, Type, Type)> {
+    match *op {
+        Operator::I32Eqz => Some((1, I32, I32)),
+        Operator::I64Eqz => Some((1, I64, I32)),
+        Operator::I32Eq | Operator::I32Ne | Operator::I32LtS | Operator::I32LtU |
+        Operator::I32GtS | Operator::I32GtU | Operator::I32LeS | Operator::I32LeU |
+        Operator::I32GeS | Operator::I32GeU => Some((2, I32, I32)),
+        Operator::I64Eq | Operator::I64Ne | Operator::I64LtS | Operator::I64LtU |
+        Operator::I64GtS | Operator::I64GtU | Operator::I64LeS | Operator::I64LeU |
+        Operator::I64GeS | Operator::I64GeU => Some((2, I64, I32)),
+        Operator::F32Eq | Operator::F32Ne | Operator::F32Lt | Operator::F32Gt |
+        Operator::F32Le | Operator::F32Ge => Some((2, F32, I32)),
+        Operator::F64Eq | Operator::F64Ne | Operator::F64Lt | Operator::F64Gt |
+        Operator::F64Le | Operator::F64Ge => Some((2, F64, I32)),
+        Operator::I32Clz | Operator::I32Ctz | Operator::I32Popcnt => Some((1, I32, I32)),
+        Operator::I64Clz | Operator::I64Ctz | Operator::I64Popcnt => Some((1, I64, I64)),
+        Operator::I32Add | Operator::I32Sub | Operator::I32Mul | Operator::I32DivS |
+        Operator::I32DivU | Operator::I32RemS | Operator::I32RemU | Operator::I32And |
+        Operator::I32Or | Operator::I32Xor | Operator::I32Shl | Operator::I32ShrS |
+        Operator::I32ShrU | Operator::I32Rotl | Operator::I32Rotr => Some((2, I32, I32)),
+        Operator::I64Add | Operator::I64Sub | Operator::I64Mul | Operator::I64DivS |
+        Operator::I64DivU | Operator::I64RemS | Operator::I64RemU | Operator::I64And |
+        Operator::I64Or | Operator::I64Xor | Operator::I64Shl | Operator::I64ShrS |
+        Operator::I64ShrU | Operator::I64Rotl | Operator::I64Rotr => Some((2, I64, I64)),
+        Operator::F32Abs | Operator::F32Neg | Operator::F32Ceil | Operator::F32Floor |
+        Operator::F32Trunc | Operator::F32Nearest | Operator::F32Sqrt => Some((1, F32, F32)),
+        Operator::F64Abs | Operator::F64Neg | Operator::F64Ceil | Operator::F64Floor |
+        Operator::F64Trunc | Operator::F64Nearest | Operator::F64Sqrt => Some((1, F64, F64)),
+        Operator::F32Add | Operator::F32Sub | Operator::F32Mul | Operator::F32Div |
+        Operator::F32Min | Operator::F32Max | Operator::F32Copysign => Some((2, F32, F32)),
+        Operator::F64Add | Operator::F64Sub | Operator::F64Mul | Operator::F64Div |
+        Operator::F64Min | Operator::F64Max | Operator::F64Copysign => Some((2, F64, F64)),
+        Operator::I32WrapI64 => Some((1, I64, I32)),
+        Operator::I32TruncSF32 | Operator::I32TruncUF32 => Some((1, F32, I32)),
+        Operator::I32TruncSF64 | Operator::I32TruncUF64 => Some((1, F64, I32)),
+        Operator::I64ExtendSI32 | Operator::I64ExtendUI32 => Some((1, I32, I64)),
+        Operator::I64TruncSF32 | Operator::I64TruncUF32 => Some((1, F32, I64)),
+        Operator::I64TruncSF64 | Operator::I64TruncUF64 => Some((1, F64, I64)),
+        Operator::F32ConvertSI32 | Operator::F32ConvertUI32 => Some((1, I32, F32)),
+        Operator::F32ConvertSI64 | Operator::F32ConvertUI64 => Some((1, I64, F32)),
+        Operator::F32DemoteF64 => Some((1, F64, F32)),
+        Operator::F64ConvertSI32 | Operator::F64ConvertUI32 => Some((1, I32, F64)),
+        Operator::F64ConvertSI64 | Operator::F64ConvertUI64 => Some((1, I64, F64)),
+        Operator::F64PromoteF32 => Some((1, F32, F64)),
+        Operator::I32ReinterpretF32 => Some((1, F32, I32)),
+        Operator::I64ReinterpretF64 => Some((1, F64, I64)),
+        Operator::F32ReinterpretI32 => Some((1, I32, F32)),
+        Operator::F64ReinterpretI64 => Some((1, I64, F64)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::validate_module;
+    use translation_utils::TranslationError;
+
+    // Builds a module out of its encoded sections.
+    fn module(sections: &[&[u8]]) -> Vec<u8> {
+        let mut data = vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+        for section in sections {
+            data.extend_from_slice(section);
+        }
+        data
+    }
+
+    // Module with a function of type `[] -> [i32]` whose body is `body`.
+    fn function_module(body: &[u8]) -> Vec<u8> {
+        let mut code = vec![0x0a, body.len() as u8 + 2, 0x01, body.len() as u8];
+        code.extend_from_slice(body);
+        module(&[&[0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f], &[0x03, 0x02, 0x01, 0x00], &code])
+    }
+
+    fn assert_invalid(data: &[u8], expected: &str) {
+        match validate_module(data) {
+            Err(TranslationError::InvalidBinary { ref message, .. }) => {
+                assert!(message.contains(expected),
+                        "expected \"{}\", found \"{}\"",
+                        expected,
+                        message)
+            }
+            result => panic!("expected \"{}\", found {:?}", expected, result),
+        }
+    }
+
+    #[test]
+    fn accept_valid_function() {
+        // i32.const 42
+        assert_eq!(validate_module(&function_module(&[0x00, 0x41, 0x2a, 0x0b])), Ok(()));
+    }
+
+    #[test]
+    fn reject_type_mismatch() {
+        // i64.const 0
+        assert_invalid(&function_module(&[0x00, 0x42, 0x00, 0x0b]), "type mismatch");
+    }
+
+    #[test]
+    fn reject_unknown_local() {
+        // get_local 0
+        assert_invalid(&function_module(&[0x00, 0x20, 0x00, 0x0b]), "unknown local 0");
+    }
+
+    #[test]
+    fn reject_unknown_global() {
+        // get_global 0
+        assert_invalid(&function_module(&[0x00, 0x23, 0x00, 0x0b]), "unknown global 0");
+    }
+
+    #[test]
+    fn reject_mutable_global_import() {
+        let data = module(&[&[0x02, 0x08, 0x01, 0x01, b'a', 0x01, b'g', 0x03, 0x7f, 0x01]]);
+        assert_invalid(&data, "mutable globals cannot be imported");
+    }
+
+    #[test]
+    fn reject_several_start_functions() {
+        let data = module(&[&[0x01, 0x04, 0x01, 0x60, 0x00, 0x00],
+                            &[0x03, 0x02, 0x01, 0x00],
+                            &[0x08, 0x01, 0x00],
+                            &[0x08, 0x01, 0x00],
+                            &[0x0a, 0x04, 0x01, 0x02, 0x00, 0x0b]]);
+        assert_invalid(&data, "unexpected section");
+    }
+}
@@ -0,0 +1,72 @@
+#include "wasmaot.h"
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+int wasmaot_instantiate(struct wasmaot_module *module) {
+    uint64_t i;
+    for (i = 0; i < module->memories_count; i++) {
+        struct wasmaot_memory *memory = &module->memories[i];
+        memory->length = memory->pages * WASMAOT_PAGE_SIZE;
+        /* A memory of size 0 still needs a valid base. */
+        memory->base = calloc(memory->length + 1, 1);
+        if (memory->base == NULL) {
+            return -1;
+        }
+    }
+    /* The compiler checked that the segments fit in the initial size of the memories. */
+    for (i = 0; i < module->segments_count; i++) {
+        const struct wasmaot_segment *segment = &module->segments[i];
+        memcpy(module->memories[segment->memory_index].base + segment->offset,
+               segment->data,
+               segment->length);
+    }
+    if (module->start != NULL) {
+        module->start();
+    }
+    return 0;
+}
+
+/* The memory moves when it grows: the generated code reads its base at each access. */
+int32_t wasmaot_grow_memory(struct wasmaot_memory *memory, int32_t delta) {
+    uint64_t old_pages = memory->pages;
+    uint64_t new_pages = old_pages + (uint32_t)delta;
+    uint8_t *base;
+    if (new_pages > memory->maximum) {
+        return -1;
+    }
+    base = realloc(memory->base, new_pages * WASMAOT_PAGE_SIZE + 1);
+    if (base == NULL) {
+        return -1;
+    }
+    memset(base + memory->length, 0, new_pages * WASMAOT_PAGE_SIZE - memory->length);
+    memory->base = base;
+    memory->pages = new_pages;
+    memory->length = new_pages * WASMAOT_PAGE_SIZE;
+    return (int32_t)old_pages;
+}
+
+int32_t wasmaot_current_memory(struct wasmaot_memory *memory) {
+    return (int32_t)memory->pages;
+}
+
+void wasmaot_trap(int32_t kind) {
+    const char *description;
+    switch (kind) {
+    case WASMAOT_TRAP_MEMORY_OUT_OF_BOUNDS:
+        description = "out of bounds memory access";
+        break;
+    case WASMAOT_TRAP_TABLE_OUT_OF_BOUNDS:
+        description = "undefined table element";
+        break;
+    case WASMAOT_TRAP_INDIRECT_CALL_TYPE_MISMATCH:
+        description = "indirect call type mismatch or uninitialized element";
+        break;
+    default:
+        description = "unreachable";
+        break;
+    }
+    fprintf(stderr, "wasm trap: %s\n", description);
+    abort();
+}
@@ -0,0 +1,64 @@
+/* Runtime library of the WebAssembly modules compiled ahead of time by the `wasmaot` crate.
+ *
+ * A module compiled to an object file is described by a `struct wasmaot_module`, whose symbol is
+ * chosen at compilation. `wasmaot_instantiate` has to be called once before calling the functions
+ * exported by the module, which are native functions named after their exports. */
+#ifndef WASMAOT_H
+#define WASMAOT_H
+
+#include <stdint.h>
+
+#define WASMAOT_PAGE_SIZE 65536
+
+/* Causes of the traps passed to `wasmaot_trap`, the codes of `wasm2cretonne::TrapKind`. */
+enum wasmaot_trap_kind {
+    WASMAOT_TRAP_UNREACHABLE = 0,
+    WASMAOT_TRAP_INTEGER_ARITHMETIC = 1,
+    WASMAOT_TRAP_MEMORY_OUT_OF_BOUNDS = 2,
+    WASMAOT_TRAP_TABLE_OUT_OF_BOUNDS = 3,
+    WASMAOT_TRAP_INDIRECT_CALL_TYPE_MISMATCH = 4,
+    WASMAOT_TRAP_OUT_OF_FUEL = 5,
+    WASMAOT_TRAP_STACK_OVERFLOW = 6,
+    WASMAOT_TRAP_INTERRUPTED = 7,
+};
+
+/* A linear memory. The generated code reads `base` and `length`. */
+struct wasmaot_memory {
+    uint8_t *base;
+    /* Number of accessible bytes. */
+    uint64_t length;
+    /* Initial number of pages, then current one. */
+    uint64_t pages;
+    uint64_t maximum;
+};
+
+/* Bytes copied into a memory at instantiation. */
+struct wasmaot_segment {
+    uint64_t memory_index;
+    uint64_t offset;
+    uint64_t length;
+    const uint8_t *data;
+};
+
+struct wasmaot_module {
+    uint64_t memories_count;
+    struct wasmaot_memory *memories;
+    uint64_t segments_count;
+    const struct wasmaot_segment *segments;
+    /* Start function of the module, or null. */
+    void (*start)(void);
+};
+
+/* Allocates the memories of `module`, writes its data segments and runs its start function.
+ * Returns 0 on success, -1 if a memory cannot be allocated. */
+int wasmaot_instantiate(struct wasmaot_module *module);
+
+/* Called by the generated code for `grow_memory` and `current_memory`. */
+int32_t wasmaot_grow_memory(struct wasmaot_memory *memory, int32_t delta);
+int32_t wasmaot_current_memory(struct wasmaot_memory *memory);
+
+/* Called by the generated code when a runtime check fails, with a `enum wasmaot_trap_kind`.
+ * Prints the cause of the trap and aborts. The other traps raise a SIGILL or a SIGFPE. */
+void wasmaot_trap(int32_t kind);
+
+#endif
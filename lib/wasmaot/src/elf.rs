@@ -0,0 +1,367 @@
+//! Writer of relocatable ELF64 object files for x86-64, holding a `.text` and a `.data` section
+//! with their relocations and a symbol table.
+
+// Relocation types of the x86-64 System V ABI.
+/// Absolute 64 bits address.
+pub const R_X86_64_64: u32 = 1;
+/// 32 bits offset relative to the address of the relocated field.
+pub const R_X86_64_PC32: u32 = 2;
+/// 32 bits offset to the procedure linkage table entry of the symbol, relative to the address of
+/// the relocated field. Used for calls to functions defined outside of the object.
+pub const R_X86_64_PLT32: u32 = 4;
+
+const ET_REL: u16 = 1;
+const EM_X86_64: u16 = 62;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+const HEADER_SIZE: usize = 64;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+// Indexes of the sections in the section header table.
+const TEXT_INDEX: u16 = 1;
+const DATA_INDEX: u16 = 3;
+const SYMTAB_INDEX: u16 = 6;
+const STRTAB_INDEX: u16 = 7;
+const SHSTRTAB_INDEX: u16 = 8;
+const SECTIONS_COUNT: u16 = 9;
+
+/// Section in which a symbol is defined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Text,
+    Data,
+}
+
+/// Identifier of a symbol of an `ElfObject`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SymbolId(usize);
+
+struct Symbol {
+    name: String,
+    global: bool,
+    kind: u8,
+    section: Option<Section>,
+    value: u64,
+    size: u64,
+}
+
+struct Relocation {
+    offset: u64,
+    symbol: SymbolId,
+    kind: u32,
+    addend: i64,
+}
+
+/// Contents of an object file being built.
+pub struct ElfObject {
+    /// Code of the object, aligned on 16 bytes.
+    pub text: Vec<u8>,
+    /// Data of the object, aligned on 8 bytes.
+    pub data: Vec<u8>,
+    symbols: Vec<Symbol>,
+    text_relocs: Vec<Relocation>,
+    data_relocs: Vec<Relocation>,
+}
+
+impl ElfObject {
+    /// Creates an object with empty sections. The sections have a local symbol each, returned by
+    /// `section_symbol`.
+    pub fn new() -> ElfObject {
+        let mut object = ElfObject {
+            text: Vec::new(),
+            data: Vec::new(),
+            symbols: Vec::new(),
+            text_relocs: Vec::new(),
+            data_relocs: Vec::new(),
+        };
+        for &section in [Section::Text, Section::Data].iter() {
+            object.symbols.push(Symbol {
+                                    name: String::new(),
+                                    global: false,
+                                    kind: STT_SECTION,
+                                    section: Some(section),
+                                    value: 0,
+                                    size: 0,
+                                });
+        }
+        object
+    }
+
+    /// Symbol standing for the beginning of `section`, used to refer to its contents.
+    pub fn section_symbol(&self, section: Section) -> SymbolId {
+        match section {
+            Section::Text => SymbolId(0),
+            Section::Data => SymbolId(1),
+        }
+    }
+
+    /// Defines a function of `size` bytes starting at `offset` in the code, visible from the other
+    /// objects if `global`.
+    pub fn define_function(&mut self,
+                           name: &str,
+                           global: bool,
+                           offset: usize,
+                           size: usize)
+                           -> SymbolId {
+        self.push_symbol(name, global, STT_FUNC, Some(Section::Text), offset, size)
+    }
+
+    /// Defines a global object of `size` bytes starting at `offset` in the data.
+    pub fn define_data(&mut self, name: &str, offset: usize, size: usize) -> SymbolId {
+        self.push_symbol(name, true, STT_OBJECT, Some(Section::Data), offset, size)
+    }
+
+    /// Declares a symbol defined by another object, returning the existing one if it has already
+    /// been declared.
+    pub fn declare_undefined(&mut self, name: &str) -> SymbolId {
+        match self.symbols
+                  .iter()
+                  .position(|symbol| symbol.section.is_none() && symbol.name == name) {
+            Some(index) => SymbolId(index),
+            None => self.push_symbol(name, true, STT_NOTYPE, None, 0, 0),
+        }
+    }
+
+    /// Records that the address of `symbol` plus `addend` has to be written at `offset` in the code
+    /// as a relocation of type `kind`.
+    pub fn relocate_text(&mut self, offset: usize, symbol: SymbolId, kind: u32, addend: i64) {
+        self.text_relocs
+            .push(Relocation {
+                      offset: offset as u64,
+                      symbol,
+                      kind,
+                      addend,
+                  });
+    }
+
+    /// Records that the address of `symbol` plus `addend` has to be written at `offset` in the data
+    /// as a relocation of type `kind`.
+    pub fn relocate_data(&mut self, offset: usize, symbol: SymbolId, kind: u32, addend: i64) {
+        self.data_relocs
+            .push(Relocation {
+                      offset: offset as u64,
+                      symbol,
+                      kind,
+                      addend,
+                  });
+    }
+
+    fn push_symbol(&mut self,
+                   name: &str,
+                   global: bool,
+                   kind: u8,
+                   section: Option<Section>,
+                   value: usize,
+                   size: usize)
+                   -> SymbolId {
+        self.symbols
+            .push(Symbol {
+                      name: String::from(name),
+                      global,
+                      kind,
+                      section,
+                      value: value as u64,
+                      size: size as u64,
+                  });
+        SymbolId(self.symbols.len() - 1)
+    }
+
+    /// Returns the contents of the object file.
+    pub fn write(&self) -> Vec<u8> {
+        // The local symbols have to precede the global ones in the symbol table, whose first entry
+        // is the null symbol.
+        let mut order: Vec<usize> = (0..self.symbols.len())
+            .filter(|&index| !self.symbols[index].global)
+            .collect();
+        let first_global = order.len() + 1;
+        order.extend((0..self.symbols.len()).filter(|&index| self.symbols[index].global));
+        let mut symbol_indexes = vec![0; self.symbols.len()];
+        for (position, &index) in order.iter().enumerate() {
+            symbol_indexes[index] = position + 1;
+        }
+
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; SYMBOL_SIZE];
+        for &index in order.iter() {
+            let symbol = &self.symbols[index];
+            let name_offset = if symbol.name.is_empty() {
+                0
+            } else {
+                push_string(&mut strtab, &symbol.name)
+            };
+            push_u32(&mut symtab, name_offset as u32);
+            let binding = if symbol.global { STB_GLOBAL } else { STB_LOCAL };
+            symtab.push((binding << 4) | symbol.kind);
+            symtab.push(0);
+            push_u16(&mut symtab,
+                     match symbol.section {
+                         None => 0,
+                         Some(Section::Text) => TEXT_INDEX,
+                         Some(Section::Data) => DATA_INDEX,
+                     });
+            push_u64(&mut symtab, symbol.value);
+            push_u64(&mut symtab, symbol.size);
+        }
+
+        let rela_text = write_relocations(&self.text_relocs, &symbol_indexes);
+        let rela_data = write_relocations(&self.data_relocs, &symbol_indexes);
+
+        let mut shstrtab = vec![0];
+        let names: Vec<usize> = [".text",
+                                 ".rela.text",
+                                 ".data",
+                                 ".rela.data",
+                                 ".note.GNU-stack",
+                                 ".symtab",
+                                 ".strtab",
+                                 ".shstrtab"]
+                .iter()
+                .map(|name| push_string(&mut shstrtab, name))
+                .collect();
+
+        // The contents of the sections follow the header, each aligned on 16 bytes, and are
+        // followed by the section header table.
+        let mut file = vec![0; HEADER_SIZE];
+        let mut offsets = Vec::new();
+        for contents in [&self.text,
+                         &rela_text,
+                         &self.data,
+                         &rela_data,
+                         &Vec::new(),
+                         &symtab,
+                         &strtab,
+                         &shstrtab]
+                    .iter() {
+            while file.len() % 16 != 0 {
+                file.push(0);
+            }
+            offsets.push((file.len() as u64, contents.len() as u64));
+            file.extend_from_slice(contents);
+        }
+        while file.len() % 8 != 0 {
+            file.push(0);
+        }
+        let section_headers_offset = file.len() as u64;
+        write_header(&mut file[0..HEADER_SIZE], section_headers_offset);
+
+        file.extend_from_slice(&[0; SECTION_HEADER_SIZE]);
+        let headers = [(SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, 0, 0, 16, 0),
+                       (SHT_RELA,
+                        SHF_INFO_LINK,
+                        SYMTAB_INDEX as u32,
+                        TEXT_INDEX as u32,
+                        8,
+                        RELA_SIZE as u64),
+                       (SHT_PROGBITS, SHF_WRITE | SHF_ALLOC, 0, 0, 8, 0),
+                       (SHT_RELA,
+                        SHF_INFO_LINK,
+                        SYMTAB_INDEX as u32,
+                        DATA_INDEX as u32,
+                        8,
+                        RELA_SIZE as u64),
+                       (SHT_PROGBITS, 0, 0, 0, 1, 0),
+                       (SHT_SYMTAB,
+                        0,
+                        STRTAB_INDEX as u32,
+                        first_global as u32,
+                        8,
+                        SYMBOL_SIZE as u64),
+                       (SHT_STRTAB, 0, 0, 0, 1, 0),
+                       (SHT_STRTAB, 0, 0, 0, 1, 0)];
+        for (i, &(kind, flags, link, info, align, entry_size)) in headers.iter().enumerate() {
+            let (offset, size) = offsets[i];
+            push_u32(&mut file, names[i] as u32);
+            push_u32(&mut file, kind);
+            push_u64(&mut file, flags);
+            push_u64(&mut file, 0);
+            push_u64(&mut file, offset);
+            push_u64(&mut file, size);
+            push_u32(&mut file, link);
+            push_u32(&mut file, info);
+            push_u64(&mut file, align);
+            push_u64(&mut file, entry_size);
+        }
+        file
+    }
+}
+
+fn write_header(header: &mut [u8], section_headers_offset: u64) {
+    let mut contents = vec![0x7f, b'E', b'L', b'F',
+                            2, // 64 bits
+                            1, // little endian
+                            1, // version
+                            0, // System V ABI
+                            0, 0, 0, 0, 0, 0, 0, 0];
+    push_u16(&mut contents, ET_REL);
+    push_u16(&mut contents, EM_X86_64);
+    push_u32(&mut contents, 1);
+    // No entry point nor program headers.
+    push_u64(&mut contents, 0);
+    push_u64(&mut contents, 0);
+    push_u64(&mut contents, section_headers_offset);
+    push_u32(&mut contents, 0);
+    push_u16(&mut contents, HEADER_SIZE as u16);
+    push_u16(&mut contents, 0);
+    push_u16(&mut contents, 0);
+    push_u16(&mut contents, SECTION_HEADER_SIZE as u16);
+    push_u16(&mut contents, SECTIONS_COUNT);
+    push_u16(&mut contents, SHSTRTAB_INDEX);
+    header.copy_from_slice(&contents);
+}
+
+fn write_relocations(relocs: &[Relocation], symbol_indexes: &[usize]) -> Vec<u8> {
+    let mut contents = Vec::with_capacity(relocs.len() * RELA_SIZE);
+    for reloc in relocs.iter() {
+        let SymbolId(symbol) = reloc.symbol;
+        push_u64(&mut contents, reloc.offset);
+        push_u64(&mut contents,
+                 ((symbol_indexes[symbol] as u64) << 32) | reloc.kind as u64);
+        push_u64(&mut contents, reloc.addend as u64);
+    }
+    contents
+}
+
+// Appends a null-terminated string to a string table and returns its offset in the table.
+fn push_string(table: &mut Vec<u8>, string: &str) -> usize {
+    let offset = table.len();
+    table.extend_from_slice(string.as_bytes());
+    table.push(0);
+    offset
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    for i in 0..2 {
+        buf.push((value >> (8 * i)) as u8);
+    }
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    for i in 0..4 {
+        buf.push((value >> (8 * i)) as u8);
+    }
+}
+
+/// Appends a little endian 64 bits value to `buf`.
+pub fn push_u64(buf: &mut Vec<u8>, value: u64) {
+    for i in 0..8 {
+        buf.push((value >> (8 * i)) as u8);
+    }
+}
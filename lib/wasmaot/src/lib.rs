@@ -0,0 +1,21 @@
+//! Ahead-of-time compilation of WebAssembly modules using Cretonne. The functions of a module
+//! translated with an [`AotRuntime`](struct.AotRuntime.html) are compiled by
+//! [`compile_object`](fn.compile_object.html) into a relocatable ELF object file for x86-64,
+//! which can be linked into a native executable with the system linker.
+//!
+//! The generated code does not depend on its address: the globals, tables and descriptors of the
+//! linear memories are in the data of the object, and the calls between functions and to the
+//! imported functions are relocated by the linker. The memories are allocated by the runtime
+//! library `runtime/wasmaot.c`, whose header `runtime/wasmaot.h` declares the functions to call
+//! to instantiate the module.
+
+extern crate cretonne;
+extern crate wasm2cretonne;
+extern crate cton_frontend;
+
+mod elf;
+mod object;
+mod runtime;
+
+pub use object::compile_object;
+pub use runtime::AotRuntime;
@@ -0,0 +1,369 @@
+use cretonne::Context;
+use cretonne::isa::TargetIsa;
+use cretonne::ir::{Ebb, FuncRef, JumpTable, Function, FunctionName};
+use cretonne::binemit::{RelocSink, Reloc, CodeOffset};
+use wasm2cretonne::{TranslationResult, FunctionTranslation, FunctionIndex, Export,
+                    emit_jump_tables, patch_relative};
+use std::collections::HashMap;
+use elf::{ElfObject, Section, SymbolId, R_X86_64_PC32, R_X86_64_PLT32};
+use runtime::{AotRuntime, DATA_ADDRESS_FUNCTION, GROW_MEMORY_FUNCTION, CURRENT_MEMORY_FUNCTION,
+              TRAP_FUNCTION, write_data};
+
+enum RelocTarget {
+    Ebb(Ebb),
+    Func(FuncRef),
+    JumpTable(JumpTable),
+}
+
+// Relocation sink recording the relocations of a function, resolved once all the code is laid
+// out.
+struct ObjectRelocSink {
+    relocs: Vec<(CodeOffset, RelocTarget)>,
+}
+
+impl RelocSink for ObjectRelocSink {
+    fn reloc_ebb(&mut self, offset: CodeOffset, _: Reloc, ebb: Ebb) {
+        self.relocs.push((offset, RelocTarget::Ebb(ebb)));
+    }
+    fn reloc_func(&mut self, offset: CodeOffset, _: Reloc, func: FuncRef) {
+        self.relocs.push((offset, RelocTarget::Func(func)));
+    }
+    fn reloc_jt(&mut self, offset: CodeOffset, _: Reloc, jt: JumpTable) {
+        self.relocs.push((offset, RelocTarget::JumpTable(jt)));
+    }
+}
+
+// A function of the module whose code is in the object.
+struct CompiledFunction {
+    function_index: FunctionIndex,
+    il_func: Function,
+    offset: usize,
+    size: usize,
+    relocs: Vec<(CodeOffset, RelocTarget)>,
+    functions: HashMap<FuncRef, FunctionIndex>,
+    jt_offsets: HashMap<JumpTable, usize>,
+}
+
+/// Compiles the module translated with `runtime` into a relocatable ELF object file for x86-64,
+/// whose contents are returned. `isa` has to be the 64 bits `intel` ISA, whose settings are the
+/// ones of the generated code.
+///
+/// Each function of the module is a symbol of the object: the exported ones are global symbols
+/// named after their exports, the others are local symbols named after the name section of the
+/// module, or `wasm_function_<index>` for the functions it does not name. The imported functions
+/// are undefined symbols named after the field of their import when it comes from the module
+/// `env`, and `<module>_<field>` otherwise. The module is described to the runtime library by
+/// the global symbol `module_symbol`, which has to differ between the modules linked together,
+/// to be passed to `wasmaot_instantiate` before calling the functions of the module.
+pub fn compile_object(trans_result: &TranslationResult,
+                      runtime: &AotRuntime,
+                      module_symbol: &str,
+                      isa: &TargetIsa)
+                      -> Result<Vec<u8>, String> {
+    if isa.name() != "intel" || !isa.flags().is_64bit() {
+        return Err(format!("object files can only be produced for 64 bits intel, not {}",
+                           isa.name()));
+    }
+    let mut object = ElfObject::new();
+    let text_symbol = object.section_symbol(Section::Text);
+    let mut functions: Vec<(SymbolId, i64)> = Vec::with_capacity(trans_result.functions.len());
+    let mut compiled_functions = Vec::new();
+    for (function_index, function) in trans_result.functions.iter().enumerate() {
+        let (il, imports) = match function {
+            &FunctionTranslation::Import {
+                 ref module,
+                 ref field,
+             } => {
+                functions.push((object.declare_undefined(&import_symbol(module, field)), 0));
+                continue;
+            }
+            &FunctionTranslation::Code {
+                ref il,
+                ref imports,
+                ..
+            } => (il.clone(), imports.clone()),
+        };
+        let mut context = Context::new();
+        context.func = il;
+        let code_size = context
+            .compile(isa)
+            .map_err(|e| e.to_string())? as usize;
+        let mut code_buf: Vec<u8> = Vec::with_capacity(code_size);
+        code_buf.resize(code_size, 0);
+        let mut relocsink = ObjectRelocSink { relocs: Vec::new() };
+        context.emit_to_memory(code_buf.as_mut_ptr(), &mut relocsink, isa);
+        let jt_offsets = emit_jump_tables(&context.func, &mut code_buf)?;
+        let offset = align_text(&mut object);
+        object.text.extend_from_slice(&code_buf);
+        functions.push((text_symbol, offset as i64));
+        compiled_functions.push(CompiledFunction {
+                                    function_index,
+                                    il_func: context.func,
+                                    offset,
+                                    size: code_buf.len(),
+                                    relocs: relocsink.relocs,
+                                    functions: imports.functions,
+                                    jt_offsets,
+                                });
+    }
+    // The generated code gets the address of the data of the module by calling this function:
+    // lea rax, [rip + data]; ret
+    let data_address_offset = align_text(&mut object);
+    object
+        .text
+        .extend_from_slice(&[0x48, 0x8d, 0x05, 0, 0, 0, 0, 0xc3]);
+    let data_symbol = object.section_symbol(Section::Data);
+    object.relocate_text(data_address_offset + 3, data_symbol, R_X86_64_PC32, -4);
+    object.define_function(DATA_ADDRESS_FUNCTION, false, data_address_offset, 8);
+    let runtime_functions = [GROW_MEMORY_FUNCTION, CURRENT_MEMORY_FUNCTION, TRAP_FUNCTION];
+
+    for function in compiled_functions.iter() {
+        for &(offset, ref target) in function.relocs.iter() {
+            let reloc_offset = function.offset + offset as usize;
+            match *target {
+                RelocTarget::Ebb(ebb) => {
+                    let target_offset = function.offset + function.il_func.offsets[ebb] as usize;
+                    patch_relative(&mut object.text, reloc_offset, target_offset);
+                }
+                RelocTarget::JumpTable(jt) => {
+                    let target_offset = function.offset + function.jt_offsets[&jt];
+                    patch_relative(&mut object.text, reloc_offset, target_offset);
+                }
+                RelocTarget::Func(func_ref) => {
+                    let name = &function.il_func.dfg.ext_funcs[func_ref].name;
+                    let (symbol, addend) = match function.functions.get(&func_ref) {
+                        Some(&target_index) => functions[target_index],
+                        None if *name == FunctionName::new(DATA_ADDRESS_FUNCTION) => {
+                            (text_symbol, data_address_offset as i64)
+                        }
+                        None => {
+                            match runtime_functions
+                                      .iter()
+                                      .find(|&&runtime_function| {
+                                                *name == FunctionName::new(runtime_function)
+                                            }) {
+                                Some(runtime_function) => {
+                                    (object.declare_undefined(runtime_function), 0)
+                                }
+                                None => {
+                                    return Err(format!("call to an unknown function {} in \
+                                                        function {}",
+                                                       name,
+                                                       function.function_index))
+                                }
+                            }
+                        }
+                    };
+                    if symbol == text_symbol {
+                        patch_relative(&mut object.text, reloc_offset, addend as usize);
+                    } else {
+                        object.relocate_text(reloc_offset, symbol, R_X86_64_PLT32, addend - 4);
+                    }
+                }
+            }
+        }
+    }
+
+    // A function exported under several names has a symbol for each of them.
+    let mut export_names: HashMap<FunctionIndex, Vec<&str>> = HashMap::new();
+    for (name, export) in trans_result.exports.iter() {
+        if let Export::Function(function_index) = *export {
+            export_names
+                .entry(function_index)
+                .or_insert(Vec::new())
+                .push(name.as_str());
+        }
+    }
+    for function in compiled_functions.iter() {
+        match export_names.get_mut(&function.function_index) {
+            None => {
+                let name = local_symbol(function.function_index,
+                                        trans_result.names.functions.get(&function.function_index));
+                object.define_function(&name, false, function.offset, function.size);
+            }
+            Some(names) => {
+                names.sort();
+                for name in names.iter() {
+                    object.define_function(name, true, function.offset, function.size);
+                }
+            }
+        }
+    }
+    write_data(runtime,
+               &mut object,
+               &functions,
+               &trans_result.function_signatures,
+               trans_result.start_index,
+               module_symbol)?;
+    Ok(object.write())
+}
+
+// Pads the code so that the next function starts on 16 bytes, and returns its offset.
+fn align_text(object: &mut ElfObject) -> usize {
+    while object.text.len() % 16 != 0 {
+        // int3
+        object.text.push(0xcc);
+    }
+    object.text.len()
+}
+
+// Symbol of a function that is not exported. The name section may give the same name to several
+// functions, or names that tools can't handle, so the name only follows the index of the function.
+fn local_symbol(function_index: FunctionIndex, name: Option<&String>) -> String {
+    match name {
+        None => format!("wasm_function_{}", function_index),
+        Some(name) => {
+            let name: String = name.chars()
+                .map(|c| match c {
+                         'a'...'z' | 'A'...'Z' | '0'...'9' => c,
+                         _ => '_',
+                     })
+                .collect();
+            format!("wasm_function_{}_{}", function_index, name)
+        }
+    }
+}
+
+// Symbol of the native function imported as `field` from `module`.
+fn import_symbol(module: &str, field: &str) -> String {
+    if module == "env" {
+        String::from(field)
+    } else {
+        format!("{}_{}", module, field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compile_object;
+    use runtime::AotRuntime;
+    use elf::R_X86_64_PLT32;
+    use wasm2cretonne::translate_module;
+    use cretonne::isa;
+    use cretonne::settings::{self, Configurable};
+
+    // Exports as "f" a function calling the function imported as "g" from "env", and defines a
+    // function returning 42 without exporting it.
+    fn module() -> Vec<u8> {
+        let mut data = vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+        for section in [&[0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f][..],
+                        &[0x02, 0x09, 0x01, 0x03, b'e', b'n', b'v', 0x01, b'g', 0x00, 0x00],
+                        &[0x03, 0x03, 0x02, 0x00, 0x00],
+                        &[0x07, 0x05, 0x01, 0x01, b'f', 0x00, 0x01],
+                        &[0x0a, 0x0b, 0x02, 0x04, 0x00, 0x10, 0x00, 0x0b, 0x04, 0x00, 0x41, 0x2a,
+                          0x0b]]
+                    .iter() {
+            data.extend_from_slice(section);
+        }
+        data
+    }
+
+    fn read_u16(file: &[u8], offset: usize) -> usize {
+        (0..2).fold(0, |value, i| value | (file[offset + i] as usize) << (8 * i))
+    }
+
+    fn read_u32(file: &[u8], offset: usize) -> usize {
+        (0..4).fold(0, |value, i| value | (file[offset + i] as usize) << (8 * i))
+    }
+
+    fn read_u64(file: &[u8], offset: usize) -> usize {
+        (0..8).fold(0, |value, i| value | (file[offset + i] as usize) << (8 * i))
+    }
+
+    // Returns the null-terminated string at `offset` in a string table.
+    fn read_string(table: &[u8], offset: usize) -> String {
+        let end = offset + table[offset..].iter().position(|&c| c == 0).unwrap();
+        String::from_utf8(table[offset..end].to_vec()).unwrap()
+    }
+
+    // Name, type, contents and linked section of a section of an object file.
+    struct SectionHeader<'a> {
+        name: String,
+        kind: usize,
+        contents: &'a [u8],
+        link: usize,
+    }
+
+    // Contents of the section whose header starts at `header` in `file`.
+    fn section_contents(file: &[u8], header: usize) -> &[u8] {
+        let offset = read_u64(file, header + 24);
+        &file[offset..offset + read_u64(file, header + 32)]
+    }
+
+    fn section_headers(file: &[u8]) -> Vec<SectionHeader> {
+        let headers_offset = read_u64(file, 0x28);
+        let header_size = read_u16(file, 0x3a);
+        let count = read_u16(file, 0x3c);
+        let shstrtab = section_contents(file, headers_offset + read_u16(file, 0x3e) * header_size);
+        (0..count)
+            .map(|index| {
+                     let header = headers_offset + index * header_size;
+                     SectionHeader {
+                         name: read_string(shstrtab, read_u32(file, header)),
+                         kind: read_u32(file, header + 4),
+                         contents: section_contents(file, header),
+                         link: read_u32(file, header + 40),
+                     }
+                 })
+            .collect()
+    }
+
+    #[test]
+    fn write_sections_and_symbols() {
+        let mut runtime = AotRuntime::new();
+        let translation = translate_module(&module(), &mut runtime).unwrap();
+        let mut shared_builder = settings::builder();
+        shared_builder.enable("is_64bit").unwrap();
+        let isa = isa::lookup("intel")
+            .unwrap()
+            .finish(settings::Flags::new(&shared_builder));
+        let file = compile_object(&translation, &runtime, "test_module", &*isa).unwrap();
+
+        // Relocatable object for x86-64.
+        assert_eq!(&file[0..6], &[0x7f, b'E', b'L', b'F', 2, 1]);
+        assert_eq!(read_u16(&file, 0x10), 1);
+        assert_eq!(read_u16(&file, 0x12), 62);
+        let sections = section_headers(&file);
+        let names: Vec<&str> = sections.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names,
+                   vec!["",
+                        ".text",
+                        ".rela.text",
+                        ".data",
+                        ".rela.data",
+                        ".note.GNU-stack",
+                        ".symtab",
+                        ".strtab",
+                        ".shstrtab"]);
+        assert!(!sections[1].contents.is_empty());
+
+        // Name, binding and section index of each symbol, after the null symbol.
+        let symtab = &sections[6];
+        assert_eq!(symtab.kind, 2);
+        let strtab = sections[symtab.link].contents;
+        let symbols: Vec<(String, u8, usize)> = symtab
+            .contents
+            .chunks(24)
+            .map(|symbol| {
+                     (read_string(strtab, read_u32(symbol, 0)),
+                      symbol[4] >> 4,
+                      read_u16(symbol, 6))
+                 })
+            .collect();
+        assert!(symbols.contains(&(String::from("f"), 1, 1)));
+        assert!(symbols.contains(&(String::from("wasm_function_2"), 0, 1)));
+        assert!(symbols.contains(&(String::from("g"), 1, 0)));
+        assert!(symbols.contains(&(String::from("test_module"), 1, 3)));
+
+        // The call to the imported function goes through the procedure linkage table.
+        let relocated: Vec<(&str, usize)> = sections[2]
+            .contents
+            .chunks(24)
+            .map(|reloc| {
+                     let info = read_u64(reloc, 8);
+                     (symbols[info >> 32].0.as_str(), info & 0xffffffff)
+                 })
+            .collect();
+        assert!(relocated.contains(&("g", R_X86_64_PLT32 as usize)));
+    }
+}
@@ -0,0 +1,493 @@
+use wasm2cretonne::{Local, FunctionIndex, GlobalIndex, TableIndex, MemoryIndex, SignatureIndex,
+                    Global, GlobalInit, Table, Memory, WasmRuntime, TrapKind,
+                    PAGE_SIZE, MAX_PAGES};
+use cton_frontend::FunctionBuilder;
+use cretonne::ir::{MemFlags, Value, InstBuilder, SigRef, Signature, ArgumentType, CallConv,
+                   FuncRef, ExtFuncData, FunctionName, Type};
+use cretonne::ir::types::*;
+use cretonne::ir::condcodes::IntCC;
+use cretonne::ir::immediates::Offset32;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use elf::{ElfObject, Section, SymbolId, R_X86_64_64, push_u64};
+
+/// Function emitted with the code of the module, returning the address of its data.
+pub const DATA_ADDRESS_FUNCTION: &str = "wasmaot_data_address";
+/// Function of the runtime library growing a memory.
+pub const GROW_MEMORY_FUNCTION: &str = "wasmaot_grow_memory";
+/// Function of the runtime library returning the number of pages of a memory.
+pub const CURRENT_MEMORY_FUNCTION: &str = "wasmaot_current_memory";
+/// Function of the runtime library called when the generated code traps.
+pub const TRAP_FUNCTION: &str = "wasmaot_trap";
+
+// Layout of a `struct wasmaot_memory` of the runtime library, whose base and length are set when
+// the memory is allocated.
+const MEMORY_BASE_OFFSET: usize = 0;
+const MEMORY_LENGTH_OFFSET: usize = 8;
+const MEMORY_PAGES_OFFSET: usize = 16;
+const MEMORY_MAXIMUM_OFFSET: usize = 24;
+const MEMORY_SIZE: usize = 32;
+
+// A table entry is the address of the function followed by the identifier of its signature.
+const TABLE_ENTRY_SIZE: usize = 16;
+const NO_SIGNATURE: u64 = !0;
+
+struct TableSegment {
+    table_index: TableIndex,
+    offset: usize,
+    elements: Vec<FunctionIndex>,
+}
+
+struct DataSegment {
+    memory_index: MemoryIndex,
+    offset: usize,
+    data: Vec<u8>,
+}
+
+/// Runtime producing code independent of its location in memory, to be written to an object
+/// file by [`compile_object`](fn.compile_object.html). The globals, memories descriptors and
+/// tables are laid out in the data of the object, whose address each function gets once by
+/// calling a function emitted with it. The memories are allocated by the runtime library when
+/// the module is instantiated.
+///
+/// Only functions can be imported, they are linked with the native functions named after the
+/// field of the import for the module `env`, and `<module>_<field>` for the other modules.
+pub struct AotRuntime {
+    signatures: Vec<Signature>,
+    globals: Vec<Global>,
+    globals_offsets: Vec<usize>,
+    memories: Vec<Memory>,
+    memories_offset: usize,
+    tables: Vec<Table>,
+    tables_offsets: Vec<usize>,
+    table_segments: Vec<TableSegment>,
+    data_segments: Vec<DataSegment>,
+    // Size of the data laid out by `begin_translation`, the segments come after it.
+    data_size: usize,
+    // Runtime functions imported in the function being translated.
+    functions: RefCell<HashMap<&'static str, FuncRef>>,
+    // Address of the data of the module, computed by the prologue of the function being
+    // translated.
+    data: Cell<Option<Value>>,
+}
+
+impl AotRuntime {
+    /// Creates an empty runtime.
+    pub fn new() -> AotRuntime {
+        AotRuntime {
+            signatures: Vec::new(),
+            globals: Vec::new(),
+            globals_offsets: Vec::new(),
+            memories: Vec::new(),
+            memories_offset: 0,
+            tables: Vec::new(),
+            tables_offsets: Vec::new(),
+            table_segments: Vec::new(),
+            data_segments: Vec::new(),
+            data_size: 0,
+            functions: RefCell::new(HashMap::new()),
+            data: Cell::new(None),
+        }
+    }
+
+    // Identifier of the signature `sig_index` in the tables: the index of the first signature of
+    // the module structurally equal to it.
+    fn signature_id(&self, sig_index: SignatureIndex) -> usize {
+        let sig = &self.signatures[sig_index];
+        self.signatures
+            .iter()
+            .position(|other| {
+                          other.argument_types == sig.argument_types &&
+                          other.return_types == sig.return_types
+                      })
+            .unwrap()
+    }
+
+    // Imports the function `name` of the runtime in the function being translated.
+    fn runtime_function(&self,
+                        builder: &mut FunctionBuilder<Local>,
+                        name: &'static str,
+                        argument_types: &[Type],
+                        return_types: &[Type])
+                        -> FuncRef {
+        if let Some(&func_ref) = self.functions.borrow().get(name) {
+            return func_ref;
+        }
+        let sig_ref = builder.import_signature(Signature {
+                                                   call_conv: CallConv::Native,
+                                                   argument_bytes: None,
+                                                   argument_types: argument_types
+                                                       .iter()
+                                                       .map(|&ty| ArgumentType::new(ty))
+                                                       .collect(),
+                                                   return_types: return_types
+                                                       .iter()
+                                                       .map(|&ty| ArgumentType::new(ty))
+                                                       .collect(),
+                                               });
+        let func_ref = builder.import_function(ExtFuncData {
+                                                   name: FunctionName::new(name),
+                                                   signature: sig_ref,
+                                               });
+        self.functions.borrow_mut().insert(name, func_ref);
+        func_ref
+    }
+
+    // Returns the address of the data of the module in the function being translated.
+    fn data_address(&self) -> Value {
+        self.data.get().expect("data address computed by the prologue")
+    }
+
+    // Emits the computation of the address of the `struct wasmaot_memory` of a memory.
+    fn memory_pointer(&self, builder: &mut FunctionBuilder<Local>, index: MemoryIndex) -> Value {
+        let data_val = self.data_address();
+        builder
+            .ins()
+            .iadd_imm(data_val, (self.memories_offset + index * MEMORY_SIZE) as i64)
+    }
+
+    // Inserts a conditional branch to a new `Ebb` raising the trap `kind`, and continues the
+    // translation in a new `Ebb`.
+    fn trap_if_icmp(&self,
+                    builder: &mut FunctionBuilder<Local>,
+                    kind: TrapKind,
+                    cond: IntCC,
+                    x: Value,
+                    y: Value) {
+        let trap_ebb = builder.create_ebb();
+        let continue_ebb = builder.create_ebb();
+        builder.ins().br_icmp(cond, x, y, trap_ebb, &[]);
+        builder.ins().jump(continue_ebb, &[]);
+        builder.seal_block(trap_ebb);
+        builder.seal_block(continue_ebb);
+        builder.switch_to_block(trap_ebb, &[]);
+        let func_ref = self.runtime_function(builder, TRAP_FUNCTION, &[I32], &[]);
+        let code_val = builder.ins().iconst(I32, kind.code());
+        builder.ins().call(func_ref, &[code_val]);
+        builder.ins().trap();
+        builder.switch_to_block(continue_ebb, &[]);
+    }
+}
+
+fn unsupported_import(kind: &str, module: &str, field: &str) -> String {
+    format!("import of the {} {}.{} in a module compiled ahead of time",
+            kind,
+            module,
+            field)
+}
+
+/// This trait is useful for
+/// [`wasm2cretonne::translatemodule`](../wasm2cretonne/fn.translate_module.html) because it
+/// tells how to translate runtime-dependent wasm instructions. These functions should not be
+/// called by the user.
+impl WasmRuntime for AotRuntime {
+    fn declare_signature(&mut self, sig: &Signature) {
+        self.signatures.push(sig.clone());
+    }
+    fn declare_global(&mut self, global: Global) {
+        self.globals.push(global);
+    }
+    fn declare_global_import(&mut self,
+                             _: Global,
+                             module: &str,
+                             field: &str)
+                             -> Result<(), String> {
+        Err(unsupported_import("global", module, field))
+    }
+    fn declare_table(&mut self, table: Table) {
+        self.tables.push(table);
+    }
+    fn declare_table_import(&mut self, _: Table, module: &str, field: &str) -> Result<(), String> {
+        Err(unsupported_import("table", module, field))
+    }
+    fn declare_table_elements(&mut self,
+                              table_index: TableIndex,
+                              base: Option<GlobalIndex>,
+                              offset: usize,
+                              elements: &[FunctionIndex]) {
+        // The base of a segment is an imported global, whose import is rejected.
+        debug_assert!(base.is_none());
+        self.table_segments
+            .push(TableSegment {
+                      table_index,
+                      offset,
+                      elements: elements.to_vec(),
+                  });
+    }
+    fn declare_memory(&mut self, memory: Memory) -> Result<(), String> {
+        if memory.pages_count > MAX_PAGES || memory.maximum.map_or(false, |max| max > MAX_PAGES) {
+            return Err(format!("memory larger than {} pages", MAX_PAGES));
+        }
+        self.memories.push(memory);
+        Ok(())
+    }
+    fn declare_memory_import(&mut self,
+                             _: Memory,
+                             module: &str,
+                             field: &str)
+                             -> Result<(), String> {
+        Err(unsupported_import("memory", module, field))
+    }
+    fn declare_data_initialization(&mut self,
+                                   memory_index: MemoryIndex,
+                                   base: Option<GlobalIndex>,
+                                   offset: usize,
+                                   data: &[u8])
+                                   -> Result<(), String> {
+        debug_assert!(base.is_none());
+        self.data_segments
+            .push(DataSegment {
+                      memory_index,
+                      offset,
+                      data: data.to_vec(),
+                  });
+        Ok(())
+    }
+    fn begin_translation(&mut self) {
+        // Each global takes 8 bytes, followed by the memories and the tables.
+        self.globals_offsets = (0..self.globals.len()).map(|index| index * 8).collect();
+        self.memories_offset = self.globals.len() * 8;
+        let mut offset = self.memories_offset + self.memories.len() * MEMORY_SIZE;
+        self.tables_offsets.clear();
+        for table in self.tables.iter() {
+            self.tables_offsets.push(offset);
+            offset += table.size * TABLE_ENTRY_SIZE;
+        }
+        self.data_size = offset;
+    }
+    fn next_function(&mut self) {
+        self.functions.borrow_mut().clear();
+        self.data.set(None);
+    }
+    fn pointer_type(&self) -> Type {
+        // The object files are for x86-64.
+        I64
+    }
+    fn vmctx_type(&self) -> Option<Type> {
+        // The data of the module is found through `DATA_ADDRESS_FUNCTION` instead.
+        None
+    }
+    fn set_vmctx(&mut self, _: Value) {}
+    fn translate_function_prologue(&self, builder: &mut FunctionBuilder<Local>) {
+        // Cretonne has no instruction giving the address of a symbol, so the address of the data
+        // is returned by a call, made once in the entry block which dominates all its uses.
+        let func_ref = self.runtime_function(builder, DATA_ADDRESS_FUNCTION, &[], &[I64]);
+        let call_inst = builder.ins().call(func_ref, &[]);
+        self.data
+            .set(Some(*builder.inst_results(call_inst).first().unwrap()));
+    }
+    fn translate_get_global(&self,
+                            builder: &mut FunctionBuilder<Local>,
+                            global_index: GlobalIndex)
+                            -> Value {
+        let ty = self.globals[global_index].ty;
+        let data_val = self.data_address();
+        let offset = Offset32::new(self.globals_offsets[global_index] as i32);
+        builder.ins().load(ty, MemFlags::new(), data_val, offset)
+    }
+    fn translate_set_global(&self,
+                            builder: &mut FunctionBuilder<Local>,
+                            global_index: GlobalIndex,
+                            val: Value) {
+        let data_val = self.data_address();
+        let offset = Offset32::new(self.globals_offsets[global_index] as i32);
+        builder.ins().store(MemFlags::new(), val, data_val, offset);
+    }
+    fn translate_memory_base_address(&self,
+                                     builder: &mut FunctionBuilder<Local>,
+                                     memory_index: MemoryIndex)
+                                     -> Value {
+        let memory_val = self.memory_pointer(builder, memory_index);
+        builder
+            .ins()
+            .load(I64,
+                  MemFlags::new(),
+                  memory_val,
+                  Offset32::new(MEMORY_BASE_OFFSET as i32))
+    }
+    fn translate_memory_bounds_check(&self,
+                                     builder: &mut FunctionBuilder<Local>,
+                                     memory_index: MemoryIndex,
+                                     addr: Value,
+                                     offset: u32,
+                                     size: u32) {
+        let memory_val = self.memory_pointer(builder, memory_index);
+        let length_val = builder
+            .ins()
+            .load(I64,
+                  MemFlags::new(),
+                  memory_val,
+                  Offset32::new(MEMORY_LENGTH_OFFSET as i32));
+        let addr_i64 = builder.ins().uextend(I64, addr);
+        let end_val = builder.ins().iadd_imm(addr_i64, offset as i64 + size as i64);
+        self.trap_if_icmp(builder,
+                          TrapKind::MemoryOutOfBounds,
+                          IntCC::UnsignedGreaterThan,
+                          end_val,
+                          length_val);
+    }
+    fn translate_grow_memory(&mut self,
+                             builder: &mut FunctionBuilder<Local>,
+                             memory_index: MemoryIndex,
+                             pages: Value)
+                             -> Value {
+        let func_ref = self.runtime_function(builder, GROW_MEMORY_FUNCTION, &[I64, I32], &[I32]);
+        let memory_val = self.memory_pointer(builder, memory_index);
+        let call_inst = builder.ins().call(func_ref, &[memory_val, pages]);
+        *builder.inst_results(call_inst).first().unwrap()
+    }
+    fn translate_current_memory(&mut self,
+                                builder: &mut FunctionBuilder<Local>,
+                                memory_index: MemoryIndex)
+                                -> Value {
+        let func_ref = self.runtime_function(builder, CURRENT_MEMORY_FUNCTION, &[I64], &[I32]);
+        let memory_val = self.memory_pointer(builder, memory_index);
+        let call_inst = builder.ins().call(func_ref, &[memory_val]);
+        *builder.inst_results(call_inst).first().unwrap()
+    }
+    fn translate_call_indirect<'a>(&self,
+                                   builder: &'a mut FunctionBuilder<Local>,
+                                   table_index: TableIndex,
+                                   sig_index: SignatureIndex,
+                                   sig_ref: SigRef,
+                                   index_val: Value,
+                                   call_args: &[Value])
+                                   -> &'a [Value] {
+        let index_i64 = builder.ins().uextend(I64, index_val);
+        let length_val = builder
+            .ins()
+            .iconst(I64, self.tables[table_index].size as i64);
+        self.trap_if_icmp(builder,
+                          TrapKind::TableOutOfBounds,
+                          IntCC::UnsignedGreaterThanOrEqual,
+                          index_i64,
+                          length_val);
+        let data_val = self.data_address();
+        let base_val = builder
+            .ins()
+            .iadd_imm(data_val, self.tables_offsets[table_index] as i64);
+        let offset_val = builder
+            .ins()
+            .imul_imm(index_i64, TABLE_ENTRY_SIZE as i64);
+        let table_entry_addr_val = builder.ins().iadd(base_val, offset_val);
+        let entry_sig_val = builder
+            .ins()
+            .load(I64, MemFlags::new(), table_entry_addr_val, Offset32::new(8));
+        let sig_val = builder
+            .ins()
+            .iconst(I64, self.signature_id(sig_index) as i64);
+        self.trap_if_icmp(builder,
+                          TrapKind::IndirectCallTypeMismatch,
+                          IntCC::NotEqual,
+                          entry_sig_val,
+                          sig_val);
+        let entry_addr_val = builder
+            .ins()
+            .load(I64, MemFlags::new(), table_entry_addr_val, Offset32::new(0));
+        let call_inst = builder
+            .ins()
+            .call_indirect(sig_ref, entry_addr_val, call_args);
+        builder.inst_results(call_inst)
+    }
+}
+
+/// Writes the data of the module translated with `runtime` in `object`: its globals, memories
+/// and tables, then its data segments and the `struct wasmaot_module` describing it, defined as
+/// `module_symbol`. `functions` gives the symbol and the offset to it of each function of the
+/// function index space, and `functions_signatures` the index of their signature.
+pub fn write_data(runtime: &AotRuntime,
+                  object: &mut ElfObject,
+                  functions: &[(SymbolId, i64)],
+                  functions_signatures: &[SignatureIndex],
+                  start_index: Option<FunctionIndex>,
+                  module_symbol: &str)
+                  -> Result<(), String> {
+    let data_symbol = object.section_symbol(Section::Data);
+    let mut data = vec![0; runtime.data_size];
+    for (global_index, global) in runtime.globals.iter().enumerate() {
+        let value = match global.initializer {
+            GlobalInit::I32Const(val) => val as u32 as u64,
+            GlobalInit::I64Const(val) => val as u64,
+            GlobalInit::F32Const(val) => val as u64,
+            GlobalInit::F64Const(val) => val,
+            // Imported globals are not supported.
+            GlobalInit::Import() |
+            GlobalInit::GlobalRef(_) => 0,
+        };
+        let mut bytes = Vec::new();
+        push_u64(&mut bytes, value);
+        let offset = runtime.globals_offsets[global_index];
+        data[offset..offset + 8].copy_from_slice(&bytes);
+    }
+    for (memory_index, memory) in runtime.memories.iter().enumerate() {
+        let offset = runtime.memories_offset + memory_index * MEMORY_SIZE;
+        let mut bytes = Vec::new();
+        push_u64(&mut bytes, memory.pages_count as u64);
+        push_u64(&mut bytes, memory.maximum.unwrap_or(MAX_PAGES) as u64);
+        data[offset + MEMORY_PAGES_OFFSET..offset + MEMORY_MAXIMUM_OFFSET + 8]
+            .copy_from_slice(&bytes);
+    }
+    for (table_index, table) in runtime.tables.iter().enumerate() {
+        let mut bytes = Vec::new();
+        push_u64(&mut bytes, NO_SIGNATURE);
+        for entry in 0..table.size {
+            let offset = runtime.tables_offsets[table_index] + entry * TABLE_ENTRY_SIZE + 8;
+            data[offset..offset + 8].copy_from_slice(&bytes);
+        }
+    }
+    // The tables are filled statically, their addresses relocated by the linker.
+    for segment in runtime.table_segments.iter() {
+        if segment.offset + segment.elements.len() > runtime.tables[segment.table_index].size {
+            return Err(String::from("elements segment does not fit"));
+        }
+        for (i, &function_index) in segment.elements.iter().enumerate() {
+            let offset = runtime.tables_offsets[segment.table_index] +
+                         (segment.offset + i) * TABLE_ENTRY_SIZE;
+            let mut bytes = Vec::new();
+            let sig_index = functions_signatures[function_index];
+            push_u64(&mut bytes, runtime.signature_id(sig_index) as u64);
+            data[offset + 8..offset + 16].copy_from_slice(&bytes);
+            let (symbol, addend) = functions[function_index];
+            object.relocate_data(offset, symbol, R_X86_64_64, addend);
+        }
+    }
+    // The data segments are copied by the runtime library into the memories it allocates.
+    let mut segments_offsets = Vec::with_capacity(runtime.data_segments.len());
+    for segment in runtime.data_segments.iter() {
+        let memory = &runtime.memories[segment.memory_index];
+        if segment.offset + segment.data.len() > memory.pages_count * PAGE_SIZE {
+            return Err(String::from("data segment does not fit"));
+        }
+        segments_offsets.push(data.len());
+        data.extend_from_slice(&segment.data);
+        while data.len() % 8 != 0 {
+            data.push(0);
+        }
+    }
+    let segments_offset = data.len();
+    for (segment, &segment_offset) in runtime.data_segments.iter().zip(segments_offsets.iter()) {
+        push_u64(&mut data, segment.memory_index as u64);
+        push_u64(&mut data, segment.offset as u64);
+        push_u64(&mut data, segment.data.len() as u64);
+        object.relocate_data(data.len(), data_symbol, R_X86_64_64, segment_offset as i64);
+        push_u64(&mut data, 0);
+    }
+    let module_offset = data.len();
+    push_u64(&mut data, runtime.memories.len() as u64);
+    object.relocate_data(data.len(),
+                         data_symbol,
+                         R_X86_64_64,
+                         runtime.memories_offset as i64);
+    push_u64(&mut data, 0);
+    push_u64(&mut data, runtime.data_segments.len() as u64);
+    object.relocate_data(data.len(), data_symbol, R_X86_64_64, segments_offset as i64);
+    push_u64(&mut data, 0);
+    if let Some(start_index) = start_index {
+        let (symbol, addend) = functions[start_index];
+        object.relocate_data(data.len(), symbol, R_X86_64_64, addend);
+    }
+    push_u64(&mut data, 0);
+    object.define_data(module_symbol, module_offset, data.len() - module_offset);
+    object.data = data;
+    Ok(())
+}
@@ -0,0 +1,5 @@
+extern crate gcc;
+
+fn main() {
+    gcc::compile_library("libwasmstandalone_traps.a", &["src/traps.c"]);
+}
@@ -0,0 +1,678 @@
+//! On-disk cache of the modules compiled with the `VmContextRuntime`, whose code does not depend
+//! on the addresses of the instance data. An entry is looked up by a hash of the WebAssembly
+//! module and of the Cretonne settings, so that a module is compiled again when either changes.
+//! Since different modules can have the same hash, an entry also holds the module and the
+//! settings it was compiled from, which are compared with the ones looked up.
+use wasm2cretonne::{translate_module_in_parallel, FunctionIndex, GlobalIndex, Global, GlobalInit,
+                    Table, TableElementType, Memory, Export};
+use cretonne::ir::{Signature, ArgumentType, CallConv, Type};
+use cretonne::ir::types::*;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use execution::{compile_functions, CompileOptions, CompiledModule, CompiledFunction,
+                ImportResolver};
+use vmctx::{VmContextRuntime, VmModule, link_vm_module, encode_runtime, decode_runtime};
+
+const MAGIC: &[u8] = b"\0wasmstandalone";
+
+// Has to be incremented whenever the encoding, the translation or the layout of the VM context
+// changes, so that the entries written by previous versions are ignored.
+const FORMAT_VERSION: u32 = 6;
+
+/// Directory holding the compiled code of modules, along with what is needed to link and
+/// instantiate them. Corrupt or stale entries are ignored and replaced.
+pub struct ModuleCache {
+    directory: PathBuf,
+}
+
+impl ModuleCache {
+    /// Creates a cache stored in `directory`, which is created when the first entry is saved.
+    pub fn new<P: Into<PathBuf>>(directory: P) -> ModuleCache {
+        ModuleCache { directory: directory.into() }
+    }
+
+    /// Returns the module whose binary is `data`, loaded from the cache if it has been compiled
+    /// before with the same target ISA and settings as `options`, translated with a
+    /// `VmContextRuntime` and compiled otherwise. The imported functions are looked up with
+    /// `resolver`.
+    pub fn compile(&self,
+                   data: &Vec<u8>,
+                   resolver: &ImportResolver,
+                   options: &CompileOptions)
+                   -> Result<VmModule, String> {
+        let target = options.target_description()?;
+        let key = cache_key(data, &target);
+        let path = self.directory.join(format!("{:016x}.cwasm", key));
+        if let Some((runtime, compiled_module)) = load_entry(&path, key, &target, data) {
+            return link_vm_module(runtime, &compiled_module, resolver);
+        }
+        let mut runtime = VmContextRuntime::new();
+        let translation = translate_module_in_parallel(data, &mut runtime, options.num_threads)
+            .map_err(|err| err.to_string())?;
+        let compiled_module = compile_functions(&translation, options)?;
+        // Failing to save the entry only means that the module will be compiled again.
+        let _ = encode_entry(key, &target, data, &runtime, &compiled_module)
+            .and_then(|bytes| self.save_entry(&path, key, &bytes));
+        link_vm_module(runtime, &compiled_module, resolver)
+    }
+
+    // Writes the entry to a temporary file first, so that other processes never read a partially
+    // written entry.
+    fn save_entry(&self, path: &Path, key: u64, bytes: &[u8]) -> Result<(), String> {
+        fs::create_dir_all(&self.directory)
+            .map_err(|err| err.to_string())?;
+        let tmp_path = self.directory
+            .join(format!("{:016x}.{}.tmp", key, process::id()));
+        File::create(&tmp_path)
+            .and_then(|mut file| file.write_all(bytes))
+            .and_then(|()| fs::rename(&tmp_path, path))
+            .map_err(|err| {
+                         let _ = fs::remove_file(&tmp_path);
+                         err.to_string()
+                     })
+    }
+}
+
+// Returns the contents of the entry at `path`, if it exists and is valid.
+fn load_entry(path: &Path,
+              key: u64,
+              target: &str,
+              data: &[u8])
+              -> Option<(VmContextRuntime, CompiledModule)> {
+    let mut bytes = Vec::new();
+    match File::open(path).and_then(|mut file| file.read_to_end(&mut bytes)) {
+        Ok(_) => (),
+        Err(_) => return None,
+    }
+    decode_entry(&bytes, key, target, data).ok()
+}
+
+// The compiled module is encoded before the runtime, whose declarations are checked against it
+// when decoding.
+fn encode_entry(key: u64,
+                target: &str,
+                data: &[u8],
+                runtime: &VmContextRuntime,
+                compiled_module: &CompiledModule)
+                -> Result<Vec<u8>, String> {
+    let mut encoder = Encoder::new();
+    encoder.bytes.extend_from_slice(MAGIC);
+    encoder.u32(FORMAT_VERSION);
+    encoder.u64(key);
+    encoder.string(target);
+    encoder.bytes(data);
+    encode_compiled_module(compiled_module, &mut encoder)?;
+    encode_runtime(runtime, &mut encoder)?;
+    let checksum = fnv1a(FNV_OFFSET_BASIS, &encoder.bytes);
+    encoder.u64(checksum);
+    Ok(encoder.bytes)
+}
+
+fn decode_entry(bytes: &[u8],
+                key: u64,
+                target: &str,
+                data: &[u8])
+                -> Result<(VmContextRuntime, CompiledModule), String> {
+    if bytes.len() < MAGIC.len() + 8 || !bytes.starts_with(MAGIC) {
+        return Err(String::from("not a cache entry"));
+    }
+    let (contents, checksum) = bytes.split_at(bytes.len() - 8);
+    if fnv1a(FNV_OFFSET_BASIS, contents) != Decoder::new(checksum).u64()? {
+        return Err(String::from("corrupt cache entry"));
+    }
+    let mut decoder = Decoder::new(&contents[MAGIC.len()..]);
+    if decoder.u32()? != FORMAT_VERSION || decoder.u64()? != key || decoder.string()? != target ||
+       decoder.bytes()? != data {
+        return Err(String::from("stale cache entry"));
+    }
+    let compiled_module = decode_compiled_module(&mut decoder)?;
+    let runtime = decode_runtime(&mut decoder, &compiled_module)?;
+    if decoder.position != decoder.bytes.len() {
+        return Err(String::from("trailing bytes in cache entry"));
+    }
+    Ok((runtime, compiled_module))
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// 64 bits FNV-1a hash of `bytes`, starting from `hash`.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash,
+              |hash, &byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+// Hashes the module with the version of the format and the description of the target.
+fn cache_key(data: &[u8], target: &str) -> u64 {
+    let mut encoder = Encoder::new();
+    encoder.u32(FORMAT_VERSION);
+    encoder.string(target);
+    fnv1a(fnv1a(FNV_OFFSET_BASIS, &encoder.bytes), data)
+}
+
+fn encode_compiled_module(compiled_module: &CompiledModule,
+                          encoder: &mut Encoder)
+                          -> Result<(), String> {
+    encoder.usize(compiled_module.functions.len());
+    for function in compiled_module.functions.iter() {
+        match *function {
+            CompiledFunction::Import {
+                ref module,
+                ref field,
+            } => {
+                encoder.u8(0);
+                encoder.string(module);
+                encoder.string(field);
+            }
+            CompiledFunction::Local {
+                ref code,
+                ref relocs,
+            } => {
+                encoder.u8(1);
+                encoder.bytes(code);
+                encoder.usize(relocs.len());
+                for &(offset, function_index) in relocs.iter() {
+                    encoder.u32(offset);
+                    encoder.usize(function_index);
+                }
+            }
+        }
+    }
+    encoder.usize(compiled_module.signatures.len());
+    for sig in compiled_module.signatures.iter() {
+        encoder.signature(sig)?;
+    }
+    for &sig_index in compiled_module.functions_signatures.iter() {
+        encoder.usize(sig_index);
+    }
+    // The exports are sorted so that a module is always encoded the same way.
+    let mut exports: Vec<(&String, &Export)> = compiled_module.exports.iter().collect();
+    exports.sort_by_key(|&(name, _)| name);
+    encoder.usize(exports.len());
+    for (name, export) in exports {
+        encoder.string(name);
+        let (kind, index) = match *export {
+            Export::Function(index) => (0, index),
+            Export::Table(index) => (1, index),
+            Export::Memory(index) => (2, index),
+            Export::Global(index) => (3, index),
+        };
+        encoder.u8(kind);
+        encoder.usize(index);
+    }
+    encoder.option_usize(compiled_module.start_index);
+    let mut function_names: Vec<(&FunctionIndex, &String)> =
+        compiled_module.function_names.iter().collect();
+    function_names.sort();
+    encoder.usize(function_names.len());
+    for (&function_index, name) in function_names {
+        encoder.usize(function_index);
+        encoder.string(name);
+    }
+    Ok(())
+}
+
+fn decode_compiled_module(decoder: &mut Decoder) -> Result<CompiledModule, String> {
+    let functions_count = decoder.usize()?;
+    let mut functions = Vec::new();
+    for _ in 0..functions_count {
+        functions.push(match decoder.u8()? {
+                           0 => {
+                               CompiledFunction::Import {
+                                   module: decoder.string()?,
+                                   field: decoder.string()?,
+                               }
+                           }
+                           1 => {
+                               let code = decoder.bytes()?;
+                               let mut relocs = Vec::new();
+                               for _ in 0..decoder.usize()? {
+                                   relocs.push((decoder.u32()?,
+                                                decoder.function_index(functions_count)?));
+                               }
+                               CompiledFunction::Local { code, relocs }
+                           }
+                           _ => return Err(String::from("invalid function")),
+                       });
+    }
+    let mut signatures = Vec::new();
+    for _ in 0..decoder.usize()? {
+        signatures.push(decoder.signature()?);
+    }
+    let mut functions_signatures = Vec::new();
+    for _ in 0..functions_count {
+        let sig_index = decoder.usize()?;
+        if sig_index >= signatures.len() {
+            return Err(String::from("invalid signature index"));
+        }
+        functions_signatures.push(sig_index);
+    }
+    let mut exports = HashMap::new();
+    for _ in 0..decoder.usize()? {
+        let name = decoder.string()?;
+        let export = match decoder.u8()? {
+            0 => Export::Function(decoder.function_index(functions_count)?),
+            1 => Export::Table(decoder.usize()?),
+            2 => Export::Memory(decoder.usize()?),
+            3 => Export::Global(decoder.usize()?),
+            _ => return Err(String::from("invalid export")),
+        };
+        exports.insert(name, export);
+    }
+    let start_index = match decoder.option_usize()? {
+        Some(index) if index >= functions_count => {
+            return Err(String::from("invalid start function"))
+        }
+        start_index => start_index,
+    };
+    let mut function_names = HashMap::new();
+    for _ in 0..decoder.usize()? {
+        let function_index = decoder.function_index(functions_count)?;
+        function_names.insert(function_index, decoder.string()?);
+    }
+    Ok(CompiledModule {
+           functions,
+           signatures,
+           functions_signatures,
+           exports,
+           start_index,
+           function_names,
+       })
+}
+
+/// Little-endian encoding of the contents of a cache entry.
+pub struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn new() -> Encoder {
+        Encoder { bytes: Vec::new() }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        for i in 0..4 {
+            self.bytes.push((value >> (8 * i)) as u8);
+        }
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        for i in 0..8 {
+            self.bytes.push((value >> (8 * i)) as u8);
+        }
+    }
+
+    pub fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    pub fn option_usize(&mut self, value: Option<usize>) {
+        match value {
+            None => self.u8(0),
+            Some(value) => {
+                self.u8(1);
+                self.usize(value);
+            }
+        }
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.usize(bytes.len());
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn string(&mut self, string: &str) {
+        self.bytes(string.as_bytes());
+    }
+
+    pub fn option_import(&mut self, import: &Option<(String, String)>) {
+        match *import {
+            None => self.u8(0),
+            Some((ref module, ref field)) => {
+                self.u8(1);
+                self.string(module);
+                self.string(field);
+            }
+        }
+    }
+
+    pub fn value_type(&mut self, ty: Type) -> Result<(), String> {
+        self.u8(match ty {
+                    I32 => 0,
+                    I64 => 1,
+                    F32 => 2,
+                    F64 => 3,
+                    _ => return Err(format!("not a WebAssembly type: {}", ty)),
+                });
+        Ok(())
+    }
+
+    // The signatures are the ones of WebAssembly functions, whose arguments and return values
+    // have no attribute other than their type.
+    pub fn signature(&mut self, sig: &Signature) -> Result<(), String> {
+        self.usize(sig.argument_types.len());
+        for arg in sig.argument_types.iter() {
+            self.value_type(arg.value_type)?;
+        }
+        self.usize(sig.return_types.len());
+        for ret in sig.return_types.iter() {
+            self.value_type(ret.value_type)?;
+        }
+        Ok(())
+    }
+
+    pub fn global(&mut self, global: &Global) -> Result<(), String> {
+        self.value_type(global.ty)?;
+        self.bool(global.mutability);
+        match global.initializer {
+            GlobalInit::I32Const(value) => {
+                self.u8(0);
+                self.u32(value as u32);
+            }
+            GlobalInit::I64Const(value) => {
+                self.u8(1);
+                self.u64(value as u64);
+            }
+            GlobalInit::F32Const(value) => {
+                self.u8(2);
+                self.u32(value);
+            }
+            GlobalInit::F64Const(value) => {
+                self.u8(3);
+                self.u64(value);
+            }
+            GlobalInit::Import() => self.u8(4),
+            GlobalInit::GlobalRef(index) => {
+                self.u8(5);
+                self.usize(index);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn table(&mut self, table: &Table) -> Result<(), String> {
+        match table.ty {
+            TableElementType::Func() => self.u8(0),
+            TableElementType::Val(ty) => {
+                self.u8(1);
+                self.value_type(ty)?;
+            }
+        }
+        self.usize(table.size);
+        self.option_usize(table.maximum);
+        Ok(())
+    }
+
+    pub fn memory(&mut self, memory: &Memory) {
+        self.usize(memory.pages_count);
+        self.option_usize(memory.maximum);
+    }
+}
+
+/// Decoding of the contents of a cache entry encoded by an `Encoder`, failing on invalid data.
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(bytes: &'a [u8]) -> Decoder<'a> {
+        Decoder { bytes, position: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if len > self.bytes.len() - self.position {
+            return Err(String::from("truncated cache entry"));
+        }
+        let bytes = &self.bytes[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(String::from("invalid boolean")),
+        }
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(self.take(4)?
+               .iter()
+               .rev()
+               .fold(0, |value, &byte| (value << 8) | byte as u32))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(self.take(8)?
+               .iter()
+               .rev()
+               .fold(0, |value, &byte| (value << 8) | byte as u64))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, String> {
+        let value = self.u64()?;
+        if value > usize::max_value() as u64 {
+            return Err(String::from("invalid size"));
+        }
+        Ok(value as usize)
+    }
+
+    pub fn option_usize(&mut self) -> Result<Option<usize>, String> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.usize()?)),
+            _ => Err(String::from("invalid option")),
+        }
+    }
+
+    pub fn function_index(&mut self, functions_count: usize) -> Result<FunctionIndex, String> {
+        let index = self.usize()?;
+        if index >= functions_count {
+            return Err(String::from("invalid function index"));
+        }
+        Ok(index)
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.usize()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    pub fn string(&mut self) -> Result<String, String> {
+        String::from_utf8(self.bytes()?).map_err(|_| String::from("invalid string"))
+    }
+
+    pub fn option_import(&mut self) -> Result<Option<(String, String)>, String> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some((self.string()?, self.string()?))),
+            _ => Err(String::from("invalid import")),
+        }
+    }
+
+    pub fn value_type(&mut self) -> Result<Type, String> {
+        match self.u8()? {
+            0 => Ok(I32),
+            1 => Ok(I64),
+            2 => Ok(F32),
+            3 => Ok(F64),
+            _ => Err(String::from("invalid type")),
+        }
+    }
+
+    pub fn signature(&mut self) -> Result<Signature, String> {
+        let mut argument_types = Vec::new();
+        for _ in 0..self.usize()? {
+            argument_types.push(ArgumentType::new(self.value_type()?));
+        }
+        let mut return_types = Vec::new();
+        for _ in 0..self.usize()? {
+            return_types.push(ArgumentType::new(self.value_type()?));
+        }
+        Ok(Signature {
+               call_conv: CallConv::Native,
+               argument_bytes: None,
+               argument_types,
+               return_types,
+           })
+    }
+
+    pub fn global(&mut self) -> Result<Global, String> {
+        let ty = self.value_type()?;
+        let mutability = self.bool()?;
+        let initializer = match self.u8()? {
+            0 => GlobalInit::I32Const(self.u32()? as i32),
+            1 => GlobalInit::I64Const(self.u64()? as i64),
+            2 => GlobalInit::F32Const(self.u32()?),
+            3 => GlobalInit::F64Const(self.u64()?),
+            4 => GlobalInit::Import(),
+            5 => GlobalInit::GlobalRef(self.usize()? as GlobalIndex),
+            _ => return Err(String::from("invalid global initializer")),
+        };
+        Ok(Global {
+               ty,
+               mutability,
+               initializer,
+           })
+    }
+
+    pub fn table(&mut self) -> Result<Table, String> {
+        let ty = match self.u8()? {
+            0 => TableElementType::Func(),
+            1 => TableElementType::Val(self.value_type()?),
+            _ => return Err(String::from("invalid table element type")),
+        };
+        Ok(Table {
+               ty,
+               size: self.usize()?,
+               maximum: self.option_usize()?,
+           })
+    }
+
+    pub fn memory(&mut self) -> Result<Memory, String> {
+        Ok(Memory {
+               pages_count: self.usize()?,
+               maximum: self.option_usize()?,
+           })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_entry, decode_entry, cache_key, Encoder, Decoder};
+    use wasm2cretonne::{translate_module, Table, TableElementType};
+    use cretonne::ir::types::*;
+    use std::collections::HashMap;
+    use execution::{compile_functions, CompileOptions, CompiledModule};
+    use vmctx::{VmContextRuntime, decode_runtime};
+
+    const TARGET: &str = "target";
+
+    // Exports as "f" a function returning 42.
+    fn module() -> Vec<u8> {
+        vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60, 0x00, 0x01,
+             0x7f, 0x03, 0x02, 0x01, 0x00, 0x07, 0x05, 0x01, 0x01, b'f', 0x00, 0x00, 0x0a, 0x06,
+             0x01, 0x04, 0x00, 0x41, 0x2a, 0x0b]
+    }
+
+    fn entry(data: &[u8]) -> Vec<u8> {
+        let mut runtime = VmContextRuntime::new();
+        let translation = translate_module(&data.to_vec(), &mut runtime).unwrap();
+        let compiled_module = compile_functions(&translation, &CompileOptions::default()).unwrap();
+        encode_entry(cache_key(data, TARGET),
+                     TARGET,
+                     data,
+                     &runtime,
+                     &compiled_module)
+                .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = module();
+        let bytes = entry(&data);
+        let key = cache_key(&data, TARGET);
+        let (runtime, compiled_module) = decode_entry(&bytes, key, TARGET, &data).unwrap();
+        assert_eq!(encode_entry(key, TARGET, &data, &runtime, &compiled_module).unwrap(),
+                   bytes);
+    }
+
+    #[test]
+    fn reject_other_module() {
+        let data = module();
+        let bytes = entry(&data);
+        let key = cache_key(&data, TARGET);
+        let mut other = data.clone();
+        *other.last_mut().unwrap() = 0x0c;
+        assert!(decode_entry(&bytes, key, TARGET, &other).is_err());
+        assert!(decode_entry(&bytes, key, "other target", &data).is_err());
+    }
+
+    #[test]
+    fn reject_corrupt_entry() {
+        let data = module();
+        let mut bytes = entry(&data);
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 1;
+        assert!(decode_entry(&bytes, cache_key(&data, TARGET), TARGET, &data).is_err());
+    }
+
+    #[test]
+    fn reject_invalid_function_index() {
+        // A table whose single element is the function 0 of a module without functions.
+        let mut encoder = Encoder::new();
+        encoder.usize(0);
+        encoder.usize(0);
+        encoder.usize(0);
+        encoder.usize(1);
+        encoder
+            .table(&Table {
+                        ty: TableElementType::Func(),
+                        size: 1,
+                        maximum: None,
+                    })
+            .unwrap();
+        encoder.option_import(&None);
+        encoder.usize(0);
+        encoder.usize(1);
+        encoder.usize(0);
+        encoder.option_usize(None);
+        encoder.usize(0);
+        encoder.usize(1);
+        encoder.usize(0);
+        encoder.usize(0);
+        let compiled_module = CompiledModule {
+            functions: Vec::new(),
+            signatures: Vec::new(),
+            functions_signatures: Vec::new(),
+            exports: HashMap::new(),
+            start_index: None,
+            function_names: HashMap::new(),
+        };
+        assert!(decode_runtime(&mut Decoder::new(&encoder.bytes), &compiled_module).is_err());
+    }
+
+    #[test]
+    fn reject_non_wasm_type() {
+        assert!(Encoder::new().value_type(B1).is_err());
+    }
+}
@@ -6,228 +6,768 @@ use cretonne::verifier;
 use cretonne::settings::Configurable;
 use cretonne::result::CtonError;
 use cretonne::ir::entities::AnyEntity;
-use cretonne::ir::{self, Ebb, FuncRef, JumpTable, Function};
+use cretonne::ir::{self, Ebb, FuncRef, JumpTable, Function, Signature, Type};
+use cretonne::ir::types::*;
 use cretonne::binemit::{RelocSink, Reloc, CodeOffset};
-use wasm2cretonne::{TranslationResult, FunctionTranslation, ImportMappings, FunctionIndex};
+use wasm2cretonne::{TranslationResult, FunctionTranslation, ImportMappings, FunctionIndex,
+                    SignatureIndex, Export, Global, Table, Memory, emit_jump_tables,
+                    patch_relative, pointer_type};
 use std::mem::transmute;
 use region::Protection;
 use region::protect;
 use std::collections::HashMap;
-use std::ptr::write_unaligned;
-use std::fmt::Write;
-
-type RelocRef = u16;
+use std::ptr::{self, write_unaligned};
+use std::fmt::{self, Write};
+use std::cmp::max;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::panic::resume_unwind;
+use std::thread;
+use num_cpus;
+use traps::{Trap, CodeLookup, catch_traps};
+use stack::StackLimit;
+use standalone::{StandaloneRuntime, FunctionTable, instantiate};
+use memory::LinearMemory;
+use trampoline::{TrampolineFn, emit_trampoline};
 
 // Implementation of a relocation sink that just saves all the information for later
 struct StandaloneRelocSink {
-    ebbs: HashMap<RelocRef, (Ebb, CodeOffset)>,
-    funcs: HashMap<RelocRef, (FuncRef, CodeOffset)>,
-    jts: HashMap<RelocRef, (JumpTable, CodeOffset)>,
-}
-
-// Contains all the metadata necessary to perform relocations
-enum FunctionMetaData {
-    Import(),
-    Local {
-        relocs: StandaloneRelocSink,
-        imports: ImportMappings,
-        il_func: Function,
-    },
+    ebbs: Vec<(Ebb, CodeOffset)>,
+    funcs: Vec<(FuncRef, CodeOffset)>,
+    jts: Vec<(JumpTable, CodeOffset)>,
 }
 
 impl RelocSink for StandaloneRelocSink {
-    fn reloc_ebb(&mut self, offset: CodeOffset, reloc: Reloc, ebb: Ebb) {
-        self.ebbs.insert(reloc.0, (ebb, offset));
+    fn reloc_ebb(&mut self, offset: CodeOffset, _: Reloc, ebb: Ebb) {
+        self.ebbs.push((ebb, offset));
     }
-    fn reloc_func(&mut self, offset: CodeOffset, reloc: Reloc, func: FuncRef) {
-        self.funcs.insert(reloc.0, (func, offset));
+    fn reloc_func(&mut self, offset: CodeOffset, _: Reloc, func: FuncRef) {
+        self.funcs.push((func, offset));
     }
-    fn reloc_jt(&mut self, offset: CodeOffset, reloc: Reloc, jt: JumpTable) {
-        self.jts.insert(reloc.0, (jt, offset));
+    fn reloc_jt(&mut self, offset: CodeOffset, _: Reloc, jt: JumpTable) {
+        self.jts.push((jt, offset));
     }
 }
 
 impl StandaloneRelocSink {
     fn new() -> StandaloneRelocSink {
         StandaloneRelocSink {
-            ebbs: HashMap::new(),
-            funcs: HashMap::new(),
-            jts: HashMap::new(),
+            ebbs: Vec::new(),
+            funcs: Vec::new(),
+            jts: Vec::new(),
+        }
+    }
+}
+
+/// A function of a module, compiled but not yet linked to the other functions.
+#[derive(Debug, Clone)]
+pub enum CompiledFunction {
+    /// Imported function, looked up when linking.
+    Import { module: String, field: String },
+    /// Machine code of a function followed by its jump tables. The branches inside the function
+    /// are already resolved, whereas the calls to other functions are relocations: the offset in
+    /// the code of a 32 bits address relative to the end of the field, and the index of the
+    /// called function.
+    Local {
+        code: Vec<u8>,
+        relocs: Vec<(CodeOffset, FunctionIndex)>,
+    },
+}
+
+/// The compiled functions of a module along with the signatures and exports needed to call them.
+/// Unlike the translated module, it can be saved to a [`ModuleCache`](struct.ModuleCache.html).
+#[derive(Debug, Clone)]
+pub struct CompiledModule {
+    pub functions: Vec<CompiledFunction>,
+    pub signatures: Vec<Signature>,
+    pub functions_signatures: Vec<SignatureIndex>,
+    pub exports: HashMap<String, Export>,
+    pub start_index: Option<FunctionIndex>,
+    /// Names of the functions given by the name section of the module.
+    pub function_names: HashMap<FunctionIndex, String>,
+}
+
+/// Options of the compilation of the functions of a module.
+///
+/// The settings are written `name` to enable a boolean setting or `name=value`, and are applied in
+/// order, after the ones given by the other fields.
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    /// Number of threads compiling functions in parallel. The compiled code is the same whatever
+    /// the number of threads.
+    pub num_threads: usize,
+    /// Name of the target ISA: `intel`, `riscv`, `arm32` or `arm64`. The code can only be
+    /// executed for `intel` in 64 bits.
+    pub isa: String,
+    /// Settings of the target ISA, such as `haswell` for `intel`.
+    pub isa_settings: Vec<String>,
+    /// Whether Cretonne verifies the functions between its passes.
+    pub enable_verifier: bool,
+    /// Optimization level of Cretonne: `default`, `best` or `fastest`.
+    pub opt_level: String,
+    /// Other shared settings of Cretonne. `is_64bit` is set after the word size of the ISA,
+    /// `arm32` being the only 32 bits one, unless these settings say otherwise.
+    pub settings: Vec<String>,
+}
+
+impl Default for CompileOptions {
+    /// Compiles for 64 bits Intel processors from Haswell with the verifier, with one thread per
+    /// CPU.
+    fn default() -> CompileOptions {
+        CompileOptions {
+            num_threads: num_cpus::get(),
+            isa: String::from("intel"),
+            isa_settings: vec![String::from("haswell")],
+            enable_verifier: true,
+            opt_level: String::from("default"),
+            settings: Vec::new(),
         }
     }
 }
 
+impl CompileOptions {
+    /// Creates the target ISA with these options.
+    pub fn target_isa(&self) -> Result<Box<TargetIsa>, String> {
+        let mut shared_builder = settings::builder();
+        let enable_verifier = if self.enable_verifier { "true" } else { "false" };
+        configure(&mut shared_builder, "enable_verifier", enable_verifier)?;
+        configure(&mut shared_builder, "opt_level", &self.opt_level)?;
+        let is_64bit = if self.isa == "arm32" { "false" } else { "true" };
+        configure(&mut shared_builder, "is_64bit", is_64bit)?;
+        apply_settings(&mut shared_builder, &self.settings)?;
+        let mut isa_builder = isa::lookup(&self.isa)
+            .map_err(|_| format!("unsupported target ISA {}", self.isa))?;
+        apply_settings(&mut isa_builder, &self.isa_settings)?;
+        Ok(isa_builder.finish(settings::Flags::new(&shared_builder)))
+    }
+
+    /// Describes the target ISA and the Cretonne settings of these options. Code compiled with
+    /// different settings has a different description.
+    pub fn target_description(&self) -> Result<String, String> {
+        let isa = self.target_isa()?;
+        Ok(format!("{} {}\n{}", isa.name(), self.isa_settings.join(","), isa.flags()))
+    }
+}
+
+// Applies the settings written `name` or `name=value` to `config`. Enabling a setting by its name
+// also works for presets, like `haswell`.
+fn apply_settings(config: &mut Configurable, settings: &[String]) -> Result<(), String> {
+    for setting in settings.iter() {
+        match setting.find('=') {
+            None => {
+                config
+                    .enable(setting)
+                    .map_err(|err| format!("invalid setting {}: {:?}", setting, err))?
+            }
+            Some(position) => configure(config, &setting[..position], &setting[position + 1..])?,
+        }
+    }
+    Ok(())
+}
+
+fn configure(config: &mut Configurable, name: &str, value: &str) -> Result<(), String> {
+    config
+        .set(name, value)
+        .map_err(|err| format!("invalid setting {}={}: {:?}", name, value, err))
+}
+
+/// Provides the native functions, the globals, the tables and the memories imported by a module.
+/// Resolved functions are called by the generated code with the native calling convention
+/// (`extern "C"` in Rust), the WebAssembly types `i32`, `i64`, `f32` and `f64` mapping to `i32`,
+/// `i64`, `f32` and `f64`. The resolved globals, tables and memories are used in place by the
+/// generated code, so they must outlive it.
+pub trait ImportResolver {
+    /// Returns the address of the function imported as `field` from `module`, which should have
+    /// the signature `signature`.
+    fn resolve_function(&self,
+                        module: &str,
+                        field: &str,
+                        signature: &Signature)
+                        -> Result<*const u8, String>;
+    /// Returns the VM context passed to the function imported as `field` from `module` by the code
+    /// compiled with the `VmContextRuntime`, null for the native functions which ignore it.
+    fn resolve_function_vmctx(&self, _: &str, _: &str) -> Result<*const usize, String> {
+        Ok(ptr::null())
+    }
+    /// Returns the address of the value of the global imported as `field` from `module`, which
+    /// should match the type and mutability of `global`.
+    fn resolve_global(&self, module: &str, field: &str, _: &Global) -> Result<*mut u8, String> {
+        Err(format!("unknown import {}.{}", module, field))
+    }
+    /// Returns the table imported as `field` from `module`, which should be within the limits of
+    /// `table`.
+    fn resolve_table(&self,
+                     module: &str,
+                     field: &str,
+                     _: &Table)
+                     -> Result<*mut FunctionTable, String> {
+        Err(format!("unknown import {}.{}", module, field))
+    }
+    /// Returns the memory imported as `field` from `module`, which should be within the limits of
+    /// `memory`.
+    fn resolve_memory(&self,
+                      module: &str,
+                      field: &str,
+                      _: &Memory)
+                      -> Result<*mut LinearMemory, String> {
+        Err(format!("unknown import {}.{}", module, field))
+    }
+}
+
+/// Import resolver for modules that do not import anything.
+pub struct NoImports;
+
+impl ImportResolver for NoImports {
+    fn resolve_function(&self,
+                        module: &str,
+                        field: &str,
+                        _: &Signature)
+                        -> Result<*const u8, String> {
+        Err(format!("unknown import {}.{}", module, field))
+    }
+}
+
 /// Structure containing the compiled code of the functions, ready to be executed.
 pub struct ExecutableCode {
     functions_code: Vec<Vec<u8>>,
-    start_index: FunctionIndex,
+    functions_signatures: Vec<SignatureIndex>,
+    signatures: Vec<Signature>,
+    /// Entry trampoline of each signature, used to call the functions from Rust.
+    trampolines: Vec<Vec<u8>>,
+    exports: HashMap<String, Export>,
+    start_index: Option<FunctionIndex>,
+    function_names: HashMap<FunctionIndex, String>,
+    /// Stack limit of the runtime the code was translated with, set when entering the code.
+    stack_limit: Option<Arc<StackLimit>>,
 }
 
-/// Executes a module that has been translated with the `StandaloneRuntime` runtime implementation.
-pub fn compile_module(trans_result: &TranslationResult) -> Result<ExecutableCode, String> {
-    let mut shared_builder = settings::builder();
-    shared_builder
-        .enable("enable_verifier")
-        .expect("Missing enable_verifier setting");
-    shared_builder
-        .set("is_64bit", "1")
-        .expect("Missing 64bits setting");
-    let isa = match isa::lookup("intel") {
-        Err(_) => {
-            panic!() // The target ISA is not available.
+/// A WebAssembly value passed as argument to or returned by an exported function.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+}
+
+/// Error returned by the execution of a function of the module.
+#[derive(Debug, Clone)]
+pub enum ExecutionError {
+    /// The function could not be called, because it does not exist or the arguments given do not
+    /// match its signature.
+    Invalid(String),
+    /// The execution of the function trapped.
+    Trap(Trap),
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExecutionError::Invalid(ref message) => write!(f, "{}", message),
+            ExecutionError::Trap(ref trap) => write!(f, "{}", trap),
         }
-        Ok(mut isa_builder) => {
-            isa_builder
-                .enable("haswell")
-                .expect("Missing haswell setting");
-            isa_builder.finish(settings::Flags::new(&shared_builder))
+    }
+}
+
+impl ExecutableCode {
+    /// Returns the address and the signature of the function exported under the name `name`. An
+    /// `ImportResolver` can use it to provide the function to another module.
+    pub fn exported_function(&self, name: &str) -> Option<(*const u8, &Signature)> {
+        match self.exports.get(name) {
+            Some(&Export::Function(index)) => {
+                Some((self.functions_code[index].as_ptr(),
+                      &self.signatures[self.functions_signatures[index]]))
+            }
+            _ => None,
         }
-    };
-    let mut functions_metatada = Vec::new();
-    let mut functions_code = Vec::new();
+    }
+
+    /// Returns the entity exported under the name `name`.
+    pub fn export(&self, name: &str) -> Option<Export> {
+        self.exports.get(name).cloned()
+    }
+
+    /// Returns the address of the code of the function `function_index`.
+    pub fn function_address(&self, function_index: FunctionIndex) -> *const u8 {
+        self.functions_code[function_index].as_ptr()
+    }
+
+    /// Returns the signature of the function `function_index`.
+    pub fn function_signature(&self, function_index: FunctionIndex) -> &Signature {
+        &self.signatures[self.functions_signatures[function_index]]
+    }
+
+    /// Returns the name of the function `function_index` given by the name section, if any.
+    pub fn function_name(&self, function_index: FunctionIndex) -> Option<&str> {
+        self.function_names
+            .get(&function_index)
+            .map(|name| name.as_str())
+    }
+}
+
+impl CodeLookup for ExecutableCode {
+    fn lookup(&self, pc: usize) -> Option<(FunctionIndex, usize)> {
+        self.functions_code
+            .iter()
+            .position(|code| {
+                          let start = code.as_ptr() as usize;
+                          start <= pc && pc < start + code.len()
+                      })
+            .map(|index| (index, pc - self.functions_code[index].as_ptr() as usize))
+    }
+
+    fn function_name(&self, function_index: FunctionIndex) -> Option<&str> {
+        ExecutableCode::function_name(self, function_index)
+    }
+
+    fn stack_limits(&self) -> Vec<&StackLimit> {
+        self.stack_limit.iter().map(|stack_limit| &**stack_limit).collect()
+    }
+}
+
+impl Value {
+    /// Returns the Cretonne type corresponding to the value.
+    pub fn value_type(&self) -> Type {
+        match *self {
+            Value::I32(_) => I32,
+            Value::I64(_) => I64,
+            Value::F32(_) => F32,
+            Value::F64(_) => F64,
+        }
+    }
+}
+
+/// Executes a module that has been translated with the `StandaloneRuntime` runtime implementation.
+/// The imports are looked up with `resolver`, then the globals of `runtime` are initialized and
+/// its tables and memories are filled with the elements and data segments of the module.
+pub fn compile_module(trans_result: &TranslationResult,
+                      runtime: &mut StandaloneRuntime,
+                      resolver: &ImportResolver,
+                      options: &CompileOptions)
+                      -> Result<ExecutableCode, String> {
+    let compiled_module = compile_functions(trans_result, options)?;
+    let functions_code = link_functions(&compiled_module, resolver)?;
+    let functions_addresses: Vec<*const u8> = functions_code
+        .iter()
+        .map(|code_buf| code_buf.as_ptr())
+        .collect();
+    instantiate(runtime,
+                resolver,
+                &functions_addresses,
+                &trans_result.function_signatures,
+                None)?;
+    make_executable(&compiled_module, functions_code, runtime.stack_limit())
+}
+
+// Creates the target ISA of `options`, checking that its code can be executed here.
+pub fn executable_isa(options: &CompileOptions) -> Result<Box<TargetIsa>, String> {
+    let isa = options.target_isa()?;
+    if isa.name() != "intel" || !isa.flags().is_64bit() {
+        return Err(format!("code compiled for {} cannot be executed, only 64 bits intel is \
+                            supported",
+                           isa.name()));
+    }
+    // The runtimes of this crate translate the native addresses as `I64`.
+    debug_assert_eq!(pointer_type(&*isa), I64);
+    Ok(isa)
+}
+
+/// Compiles the functions of a translated module, on `options.num_threads` threads. Their code is
+/// not linked yet, see `link_functions`.
+pub fn compile_functions(trans_result: &TranslationResult,
+                         options: &CompileOptions)
+                         -> Result<CompiledModule, String> {
+    let mut functions = Vec::with_capacity(trans_result.functions.len());
+    let mut jobs = Vec::new();
     for (function_index, function) in trans_result.functions.iter().enumerate() {
-        let mut context = Context::new();
-        let (il, imports) = match function {
-            &FunctionTranslation::Import() => {
-                if trans_result.start_index.is_some() &&
-                   trans_result.start_index.unwrap() == function_index {
-                    return Err(String::from("start function should not be an import"));
-                } else {
-                    functions_code.push(Vec::new());
-                    functions_metatada.push(FunctionMetaData::Import());
-                    continue;
-                }
+        match function {
+            &FunctionTranslation::Import {
+                ref module,
+                ref field,
+            } => {
+                functions.push(Some(CompiledFunction::Import {
+                                        module: module.clone(),
+                                        field: field.clone(),
+                                    }));
             }
             &FunctionTranslation::Code {
                 ref il,
                 ref imports,
                 ..
-            } => (il.clone(), imports.clone()),
-        };
-        verify_function(&il, None).unwrap();
-        context.func = il;
-        let code_size = context
-            .compile(&*isa)
-            .map_err(|e| pretty_error(&context.func, Some(&*isa), e))? as
-                        usize;
-        if code_size == 0 {
-            return Err(String::from("no code generated by Cretonne"));
-        }
-        let mut code_buf: Vec<u8> = Vec::with_capacity(code_size);
-        code_buf.resize(code_size, 0);
-        let mut relocsink = StandaloneRelocSink::new();
-        context.emit_to_memory(code_buf.as_mut_ptr(), &mut relocsink, &*isa);
-        functions_metatada.push(FunctionMetaData::Local {
-                                    relocs: relocsink,
-                                    imports: imports,
-                                    il_func: context.func,
-                                });
-        functions_code.push(code_buf);
-    }
-    relocate(&functions_metatada, &mut functions_code);
-    // After having emmitted the code to memory, we deal with relocations
-    match trans_result.start_index {
-        None => Err(String::from("No start function defined, aborting execution")),
-        Some(index) => {
-            Ok(ExecutableCode {
-                   functions_code,
-                   start_index: index,
-               })
+            } => {
+                functions.push(None);
+                jobs.push((function_index, il.clone(), imports.clone()));
+            }
         }
     }
+    let isa = executable_isa(options)?;
+    let num_threads = max(1, options.num_threads);
+    let mut results = if num_threads == 1 || jobs.len() <= 1 {
+        jobs.into_iter()
+            .map(|(function_index, il, imports)| {
+                     (function_index, compile_function(&*isa, il, &imports))
+                 })
+            .collect()
+    } else {
+        compile_in_parallel(jobs, num_threads, options)?
+    };
+    // Whatever the order in which the functions were compiled, the error reported is the one of
+    // the first function that failed.
+    results.sort_by_key(|&(function_index, _)| function_index);
+    for (function_index, result) in results {
+        functions[function_index] = Some(result?);
+    }
+    Ok(CompiledModule {
+           functions: functions
+               .into_iter()
+               .map(|function| function.expect("every function is compiled"))
+               .collect(),
+           signatures: trans_result.signatures.clone(),
+           functions_signatures: trans_result.function_signatures.clone(),
+           exports: trans_result.exports.clone(),
+           start_index: trans_result.start_index,
+           function_names: trans_result.names.functions.clone(),
+       })
 }
 
-// Jumps to the code region of memory and execute the start function of the module.
-pub fn execute(exec: ExecutableCode) -> Result<(), String> {
-    let code_buf = &exec.functions_code[exec.start_index];
-    unsafe {
-        match protect(code_buf.as_ptr(),
-                      code_buf.len(),
-                      Protection::ReadWriteExecute) {
-            Ok(()) => (),
-            Err(err) => {
-                return Err(format!("failed to give executable permission to code: {}",
-                                   err.description()))
-            }
-        };
-        // Rather than writing inline assembly to jump to the code region, we use the fact that
-        // the Rust ABI for calling a function with no arguments and no return matches the one of
-        // the generated code.Thanks to this, we can transmute the code region into a first-class
-        // Rust function and call it.
-        // TODO: the Rust callee-saved registers will be overwritten by the executed code, inline
-        // assembly spilling these registers to the stack and restoring them after the call is
-        // needed.
-        let start_func = transmute::<_, fn()>(code_buf.as_ptr());
-        // The code below saves the Intel callee-saved registers. It is not activate because
-        // inline ASM is not supported in the release version of the Rust compiler.
-        /*asm!("push rax
-              push rcx
-              push rdx
-              push rsi
-              push rdi
-              push r8
-              push r9
-              push r10
-              push r11
-        " :::: "intel", "volatile");*/
-        start_func();
-        /*asm!("pop r11
-              pop r10
-              pop r9
-              pop r8
-              pop rdi
-              pop rsi
-              pop rdx
-              pop rcx
-              pop rax
-      " :::: "intel", "volatile");*/
-        Ok(())
-    }
-}
-
-/// Performs the relocations inside the function bytecode, provided the necessary metadata
-fn relocate(functions_metatada: &Vec<FunctionMetaData>, functions_code: &mut Vec<Vec<u8>>) {
-    // The relocations are relative to the relocation's address plus four bytes
-    for (func_index, function_in_memory) in functions_metatada.iter().enumerate() {
-        match function_in_memory {
-            &FunctionMetaData::Import() => continue,
-            &FunctionMetaData::Local {
-                ref relocs,
-                ref imports,
-                ref il_func,
-            } => {
-                for (_, &(func_ref, offset)) in relocs.funcs.iter() {
-                    let target_func_index = imports.functions[&func_ref];
-                    let target_func_address: isize = functions_code[target_func_index].as_ptr() as
-                                                     isize;
-                    unsafe {
-                        let reloc_address: isize = functions_code[func_index]
-                            .as_mut_ptr()
-                            .offset(offset as isize + 4) as
-                                                   isize;
-                        let reloc_delta_i32: i32 = (target_func_address - reloc_address) as i32;
-                        write_unaligned(reloc_address as *mut i32, reloc_delta_i32);
+// Compiles the functions of `jobs` on `num_threads` threads, which take the next function to
+// compile from a shared queue. The results are returned in no particular order. The panic of a
+// thread is resumed with its payload once all the threads are joined.
+fn compile_in_parallel(jobs: Vec<(FunctionIndex, Function, ImportMappings)>,
+                       num_threads: usize,
+                       options: &CompileOptions)
+                       -> Result<Vec<(FunctionIndex, Result<CompiledFunction, String>)>, String> {
+    let jobs_count = jobs.len();
+    let queue = Arc::new(Mutex::new(jobs.into_iter()));
+    let (sender, receiver) = channel();
+    let workers: Vec<thread::JoinHandle<()>> = (0..num_threads)
+        .map(|_| {
+            let queue = queue.clone();
+            let sender = sender.clone();
+            let options = options.clone();
+            thread::spawn(move || {
+                // The ISA is not shared between threads.
+                let isa = options.target_isa().expect("valid target ISA");
+                loop {
+                    let job = queue.lock().unwrap().next();
+                    let (function_index, il, imports) = match job {
+                        None => break,
+                        Some(job) => job,
+                    };
+                    let result = compile_function(&*isa, il, &imports);
+                    if sender.send((function_index, result)).is_err() {
+                        break;
                     }
                 }
-                for (_, &(ebb, offset)) in relocs.ebbs.iter() {
-                    unsafe {
-                        let reloc_address: isize = functions_code[func_index]
-                            .as_mut_ptr()
-                            .offset(offset as isize + 4) as
-                                                   isize;
-                        let target_ebb_address: isize =
-                            functions_code[func_index]
-                                .as_ptr()
-                                .offset(il_func.offsets[ebb] as isize) as
-                            isize;
-                        let reloc_delta_i32: i32 = (target_ebb_address - reloc_address) as i32;
-                        write_unaligned(reloc_address as *mut i32, reloc_delta_i32);
-                    }
-                }
-                // TODO: deal with jumptable relocations
-            }
+            })
+        })
+        .collect();
+    drop(sender);
+    let results: Vec<(FunctionIndex, Result<CompiledFunction, String>)> =
+        receiver.iter().collect();
+    let mut panic = None;
+    for worker in workers {
+        if let Err(payload) = worker.join() {
+            panic = panic.or(Some(payload));
         }
     }
+    if let Some(payload) = panic {
+        resume_unwind(payload);
+    }
+    debug_assert_eq!(results.len(), jobs_count);
+    Ok(results)
+}
+
+// Compiles a function and performs the relocations that do not depend on the address of its code.
+pub fn compile_function(isa: &TargetIsa,
+                    il: Function,
+                    imports: &ImportMappings)
+                    -> Result<CompiledFunction, String> {
+    if isa.flags().enable_verifier() {
+        verify_function(&il, None).map_err(|err| pretty_verifier_error(&il, None, err))?;
+    }
+    let mut context = Context::new();
+    context.func = il;
+    let code_size = context
+        .compile(isa)
+        .map_err(|e| pretty_error(&context.func, Some(isa), e))? as usize;
+    if code_size == 0 {
+        return Err(String::from("no code generated by Cretonne"));
+    }
+    let mut code_buf: Vec<u8> = Vec::with_capacity(code_size);
+    code_buf.resize(code_size, 0);
+    let mut relocsink = StandaloneRelocSink::new();
+    context.emit_to_memory(code_buf.as_mut_ptr(), &mut relocsink, isa);
+    let jt_offsets = emit_jump_tables(&context.func, &mut code_buf)?;
+    let relocs = relocate_local(&relocsink, imports, &context.func, &jt_offsets, &mut code_buf);
+    Ok(CompiledFunction::Local {
+           code: code_buf,
+           relocs,
+       })
+}
+
+/// Resolves the imported functions of a compiled module with `resolver`, and performs the
+/// relocations of the calls between functions. Returns the code of each function of the function
+/// index space.
+pub fn link_functions(compiled_module: &CompiledModule,
+                      resolver: &ImportResolver)
+                      -> Result<Vec<Vec<u8>>, String> {
+    let mut functions_code = Vec::with_capacity(compiled_module.functions.len());
+    for (function_index, function) in compiled_module.functions.iter().enumerate() {
+        functions_code.push(match *function {
+                                CompiledFunction::Import {
+                                    ref module,
+                                    ref field,
+                                } => {
+                                    let sig_index =
+                                        compiled_module.functions_signatures[function_index];
+                                    let signature = &compiled_module.signatures[sig_index];
+                                    let address =
+                                        resolver.resolve_function(module, field, signature)?;
+                                    import_stub(address)
+                                }
+                                CompiledFunction::Local { ref code, .. } => code.clone(),
+                            });
+    }
+    // After having emmitted the code to memory, we deal with relocations
+    relocate(compiled_module, &mut functions_code)?;
+    Ok(functions_code)
+}
+
+/// Gives the executable permission to the compiled code of the functions of a module, and emits
+/// the trampolines used to call them. The code checks the stack against `stack_limit`, if any.
+pub fn make_executable(compiled_module: &CompiledModule,
+                       functions_code: Vec<Vec<u8>>,
+                       stack_limit: Option<Arc<StackLimit>>)
+                       -> Result<ExecutableCode, String> {
+    executable_code(functions_code,
+                    compiled_module.functions_signatures.clone(),
+                    compiled_module.signatures.clone(),
+                    compiled_module.exports.clone(),
+                    compiled_module.start_index,
+                    compiled_module.function_names.clone(),
+                    stack_limit)
+}
+
+// Makes `functions_code` executable, and emits the trampolines used to call it.
+pub fn executable_code(functions_code: Vec<Vec<u8>>,
+                       functions_signatures: Vec<SignatureIndex>,
+                       signatures: Vec<Signature>,
+                       exports: HashMap<String, Export>,
+                       start_index: Option<FunctionIndex>,
+                       function_names: HashMap<FunctionIndex, String>,
+                       stack_limit: Option<Arc<StackLimit>>)
+                       -> Result<ExecutableCode, String> {
+    // Every function can be called, directly or by another function, so all the code has to be
+    // executable.
+    let trampolines: Vec<Vec<u8>> = signatures.iter().map(emit_trampoline).collect();
+    for code_buf in functions_code.iter().chain(trampolines.iter()) {
+        protect_code(code_buf)?;
+    }
+    Ok(ExecutableCode {
+           functions_code,
+           functions_signatures,
+           signatures,
+           trampolines,
+           exports,
+           start_index,
+           function_names,
+           stack_limit,
+       })
+}
+
+// Gives the executable permission to generated code.
+pub fn protect_code(code_buf: &[u8]) -> Result<(), String> {
+    unsafe {
+        protect(code_buf.as_ptr(),
+                code_buf.len(),
+                Protection::ReadWriteExecute)
+                .map_err(|err| {
+                             format!("failed to give executable permission to code: {}",
+                                     err.description())
+                         })
+    }
+}
+
+// Jumps to the code region of memory and execute the start function of the module, if any.
+pub fn execute(exec: &ExecutableCode) -> Result<(), ExecutionError> {
+    execute_in(exec, exec)
+}
+
+// Same as `execute`, the traps being located in the code with `lookup`.
+pub fn execute_in(exec: &ExecutableCode, lookup: &CodeLookup) -> Result<(), ExecutionError> {
+    match exec.start_index {
+        None => Ok(()),
+        Some(index) => call_function_in(exec, lookup, index, &[]).map(|_| ()),
+    }
+}
+
+/// Calls the function exported by the module under the name `export_name` with the arguments
+/// `args`, and returns the values it returned. The number and types of the arguments have to
+/// match the signature of the function. If the execution traps, the trap is returned as an error
+/// and the module can still be used.
+pub fn invoke(exec: &ExecutableCode,
+              export_name: &str,
+              args: Vec<Value>)
+              -> Result<Vec<Value>, ExecutionError> {
+    invoke_in(exec, exec, export_name, args)
+}
+
+// Same as `invoke`, the traps being located in the code with `lookup`.
+pub fn invoke_in(exec: &ExecutableCode,
+                 lookup: &CodeLookup,
+                 export_name: &str,
+                 args: Vec<Value>)
+                 -> Result<Vec<Value>, ExecutionError> {
+    let function_index = match exec.exports.get(export_name) {
+        Some(&Export::Function(index)) => index,
+        _ => {
+            return Err(ExecutionError::Invalid(format!("no exported function named \"{}\"",
+                                                       export_name)))
+        }
+    };
+    call_function_in(exec, lookup, function_index, args.as_slice())
+}
+
+// Calls the function at `function_index` in the function index space after having checked the
+// arguments against its signature.
+pub fn call_function(exec: &ExecutableCode,
+                     function_index: FunctionIndex,
+                     args: &[Value])
+                     -> Result<Vec<Value>, ExecutionError> {
+    call_function_in(exec, exec, function_index, args)
+}
+
+// Same as `call_function`, the traps being located in the code with `lookup`, which knows about
+// code that `exec` does not contain.
+pub fn call_function_in(exec: &ExecutableCode,
+                        lookup: &CodeLookup,
+                        function_index: FunctionIndex,
+                        args: &[Value])
+                        -> Result<Vec<Value>, ExecutionError> {
+    let sig_index = exec.functions_signatures[function_index];
+    let signature = &exec.signatures[sig_index];
+    if signature.argument_types.len() != args.len() {
+        return Err(ExecutionError::Invalid(format!("function {} expects {} arguments, {} given",
+                                                   function_index,
+                                                   signature.argument_types.len(),
+                                                   args.len())));
+    }
+    for (i, (arg, arg_type)) in args.iter()
+            .zip(signature.argument_types.iter())
+            .enumerate() {
+        if arg.value_type() != arg_type.value_type {
+            return Err(ExecutionError::Invalid(format!("argument {} of function {} should be \
+                                                        of type {}, got {:?}",
+                                                       i,
+                                                       function_index,
+                                                       arg_type.value_type,
+                                                       arg)));
+        }
+    }
+    // The trampoline reads the arguments from the buffer, and writes the return value in its
+    // first slot.
+    let mut values: Vec<u64> = args.iter()
+        .map(|arg| match *arg {
+                 Value::I32(val) => val as i64 as u64,
+                 Value::I64(val) => val as u64,
+                 Value::F32(val) => unsafe { transmute::<f32, u32>(val) as u64 },
+                 Value::F64(val) => unsafe { transmute(val) },
+             })
+        .collect();
+    if values.is_empty() {
+        values.push(0);
+    }
+    let code = exec.functions_code[function_index].as_ptr();
+    let trampoline: TrampolineFn = unsafe { transmute(exec.trampolines[sig_index].as_ptr()) };
+    let values_ptr = values.as_mut_ptr();
+    catch_traps(lookup, || trampoline(code, values_ptr))
+        .map_err(ExecutionError::Trap)?;
+    match signature.return_types.first().map(|ret| ret.value_type) {
+        None => Ok(Vec::new()),
+        Some(I32) => Ok(vec![Value::I32(values[0] as i32)]),
+        Some(I64) => Ok(vec![Value::I64(values[0] as i64)]),
+        Some(F32) => Ok(vec![Value::F32(unsafe { transmute(values[0] as u32) })]),
+        Some(F64) => Ok(vec![Value::F64(unsafe { transmute(values[0]) })]),
+        Some(ty) => Err(ExecutionError::Invalid(format!("unsupported return type {}", ty))),
+    }
+}
+
+// Code standing for an imported function in the function index space: it jumps to the native
+// function at `address`, which can be anywhere in the address space whereas calls between
+// functions use 32 bits relative addresses.
+pub fn import_stub(address: *const u8) -> Vec<u8> {
+    // movabs rax, address
+    let mut code = vec![0x48, 0xb8];
+    let address = address as u64;
+    for i in 0..8 {
+        code.push((address >> (8 * i)) as u8);
+    }
+    // jmp rax
+    code.extend_from_slice(&[0xff, 0xe0]);
+    code
+}
+
+// Performs the relocations of the branches to the `Ebb`s and jump tables of `il_func` in its code,
+// which do not depend on the address of the code, and returns the relocations of its calls.
+fn relocate_local(relocs: &StandaloneRelocSink,
+                  imports: &ImportMappings,
+                  il_func: &Function,
+                  jt_offsets: &HashMap<JumpTable, usize>,
+                  code_buf: &mut [u8])
+                  -> Vec<(CodeOffset, FunctionIndex)> {
+    for &(ebb, offset) in relocs.ebbs.iter() {
+        patch_relative(code_buf, offset as usize, il_func.offsets[ebb] as usize);
+    }
+    for &(jt, offset) in relocs.jts.iter() {
+        patch_relative(code_buf, offset as usize, jt_offsets[&jt]);
+    }
+    let mut calls: Vec<(CodeOffset, FunctionIndex)> = relocs
+        .funcs
+        .iter()
+        .map(|&(func_ref, offset)| (offset, imports.functions[&func_ref]))
+        .collect();
+    calls.sort();
+    calls
+}
+
+/// Performs the relocations of the calls between functions, checking them since they may come from
+/// a cache.
+fn relocate(compiled_module: &CompiledModule,
+            functions_code: &mut Vec<Vec<u8>>)
+            -> Result<(), String> {
+    let functions_addresses: Vec<*const u8> = functions_code
+        .iter()
+        .map(|code_buf| code_buf.as_ptr())
+        .collect();
+    for (func_index, function) in compiled_module.functions.iter().enumerate() {
+        if let CompiledFunction::Local { ref relocs, .. } = *function {
+            relocate_calls(&mut functions_code[func_index], relocs, &functions_addresses)
+                .map_err(|message| format!("{} in function {}", message, func_index))?;
+        }
+    }
+    Ok(())
+}
+
+// Performs the relocations `relocs` of the calls of a function whose code is `code_buf`, the
+// functions of the function index space being at `functions_addresses`. The relocations are
+// relative to the relocation's address plus four bytes.
+pub fn relocate_calls(code_buf: &mut [u8],
+                      relocs: &[(CodeOffset, FunctionIndex)],
+                      functions_addresses: &[*const u8])
+                      -> Result<(), String> {
+    for &(offset, target_func_index) in relocs.iter() {
+        if offset as usize + 8 > code_buf.len() || target_func_index >= functions_addresses.len() {
+            return Err(String::from("invalid relocation"));
+        }
+        let target_func_address: isize = functions_addresses[target_func_index] as isize;
+        unsafe {
+            let reloc_address: isize = code_buf.as_mut_ptr().offset(offset as isize + 4) as isize;
+            let reloc_delta_i32: i32 = (target_func_address - reloc_address) as i32;
+            write_unaligned(reloc_address as *mut i32, reloc_delta_i32);
+        }
+    }
+    Ok(())
 }
 
 /// Pretty-print a verifier error.
@@ -254,3 +794,30 @@ pub fn pretty_error(func: &ir::Function, isa: Option<&TargetIsa>, err: CtonError
         err.to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{compile_module, invoke, CompileOptions, NoImports, Value};
+    use wasm2cretonne::translate_module_in_parallel;
+    use standalone::StandaloneRuntime;
+    use testing::module;
+
+    #[test]
+    fn translate_and_compile_on_several_threads() {
+        let options = CompileOptions {
+            num_threads: 4,
+            ..CompileOptions::default()
+        };
+        // Exports as "f" a function calling a function returning 42.
+        let data = module(&[&[0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f],
+                            &[0x03, 0x03, 0x02, 0x00, 0x00],
+                            &[0x07, 0x05, 0x01, 0x01, b'f', 0x00, 0x00],
+                            &[0x0a, 0x0b, 0x02, 0x04, 0x00, 0x10, 0x01, 0x0b, 0x04, 0x00, 0x41,
+                              0x2a, 0x0b]]);
+        let mut runtime = StandaloneRuntime::new();
+        let translation = translate_module_in_parallel(&data, &mut runtime, options.num_threads)
+            .unwrap();
+        let code = compile_module(&translation, &mut runtime, &NoImports, &options).unwrap();
+        assert_eq!(invoke(&code, "f", vec![]).unwrap(), vec![Value::I32(42)]);
+    }
+}
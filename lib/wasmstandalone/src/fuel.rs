@@ -0,0 +1,171 @@
+//! Fuel metering of the generated code. The code translated with fuel metering consumes one unit
+//! of fuel per WebAssembly operator executed, charged at the end of each basic block, so that the
+//! fuel consumed only depends on the code executed. When the fuel runs out, the generated code
+//! calls `wasmstandalone_out_of_fuel`, which lets the host add fuel to go on or stop the execution
+//! with a trap.
+use wasm2cretonne::Local;
+use cton_frontend::FunctionBuilder;
+use cretonne::ir::{MemFlags, Value, InstBuilder};
+use cretonne::ir::types::*;
+use cretonne::ir::condcodes::IntCC;
+use cretonne::ir::immediates::Offset32;
+use std::cmp::{max, min};
+use std::i64;
+use std::mem::transmute;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use traps::{TrapKind, defer_panic};
+use standalone::call_if_icmp;
+
+/// Function called when the fuel runs out. It returns the fuel to add to let the execution go on,
+/// or `None` to stop it with a `TrapKind::OutOfFuel` trap. A panic of the handler also stops the
+/// execution, and is resumed once the generated code has been left.
+pub type FuelHandler = Box<FnMut() -> Option<u64>>;
+
+// The remaining fuel comes first since the generated code updates it at the address of the
+// counter, which is boxed by the runtime.
+#[repr(C)]
+pub struct FuelCounter {
+    // Becomes negative when the code consumed more fuel than there was left.
+    remaining: i64,
+    handler: Option<FuelHandler>,
+}
+
+impl FuelCounter {
+    pub fn new(fuel: u64) -> FuelCounter {
+        FuelCounter {
+            remaining: to_i64(fuel),
+            handler: None,
+        }
+    }
+
+    pub fn remaining(&self) -> u64 {
+        max(self.remaining, 0) as u64
+    }
+
+    pub fn add(&mut self, fuel: u64) {
+        self.remaining = self.remaining.saturating_add(to_i64(fuel));
+    }
+
+    pub fn set_handler(&mut self, handler: FuelHandler) {
+        self.handler = Some(handler);
+    }
+}
+
+fn to_i64(fuel: u64) -> i64 {
+    min(fuel, i64::MAX as u64) as i64
+}
+
+// Called by the generated code when the remaining fuel of `counter` became negative. Returns 1
+// when the handler added enough fuel to go on, and 0 when the execution has to trap, in which
+// case no fuel remains.
+extern "C" fn wasmstandalone_out_of_fuel(counter: *mut FuelCounter) -> i32 {
+    let counter = unsafe { &mut *counter };
+    while counter.remaining < 0 {
+        let fuel = match counter.handler {
+            // Unwinding through the generated code is undefined behavior.
+            Some(ref mut handler) => {
+                match catch_unwind(AssertUnwindSafe(|| handler())) {
+                    Ok(fuel) => fuel,
+                    Err(payload) => {
+                        defer_panic(payload);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        match fuel {
+            Some(fuel) if fuel > 0 => counter.add(fuel),
+            _ => {
+                counter.remaining = 0;
+                return 0;
+            }
+        }
+    }
+    1
+}
+
+// Emits the code consuming `fuel` units of the fuel of the `FuelCounter` at `counter_val`.
+pub fn translate_consume_fuel(builder: &mut FunctionBuilder<Local>, counter_val: Value, fuel: u32) {
+    let remaining_val = builder
+        .ins()
+        .load(I64, MemFlags::new(), counter_val, Offset32::new(0));
+    let remaining_val = builder.ins().iadd_imm(remaining_val, -(fuel as i64));
+    builder
+        .ins()
+        .store(MemFlags::new(), remaining_val, counter_val, Offset32::new(0));
+    let zero_val = builder.ins().iconst(I64, 0);
+    let out_of_fuel_addr: i64 = unsafe { transmute(wasmstandalone_out_of_fuel as *const u8) };
+    call_if_icmp(builder,
+                 TrapKind::OutOfFuel,
+                 IntCC::SignedLessThan,
+                 remaining_val,
+                 zero_val,
+                 out_of_fuel_addr,
+                 counter_val);
+}
+
+#[cfg(test)]
+mod tests {
+    use standalone::StandaloneRuntime;
+    use execution::{invoke, ExecutionError, Value};
+    use traps::TrapKind;
+    use testing::{module, compile};
+
+    // Exports as "f" a function calling a function returning 42. Each of them consumes 2 units of
+    // fuel: `call` and `end` for the first one, `i32.const` and `end` for the second one.
+    fn calling_module() -> Vec<u8> {
+        module(&[&[0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f],
+                 &[0x03, 0x03, 0x02, 0x00, 0x00],
+                 &[0x07, 0x05, 0x01, 0x01, b'f', 0x00, 0x00],
+                 &[0x0a, 0x0b, 0x02, 0x04, 0x00, 0x10, 0x01, 0x0b, 0x04, 0x00, 0x41, 0x2a, 0x0b]])
+    }
+
+    #[test]
+    fn consume_one_unit_per_operator() {
+        let mut runtime = StandaloneRuntime::new();
+        runtime.enable_fuel_metering(10);
+        let code = compile(&calling_module(), &mut runtime);
+        assert_eq!(invoke(&code, "f", vec![]).unwrap(), vec![Value::I32(42)]);
+        assert_eq!(runtime.fuel(), Some(6));
+        assert_eq!(invoke(&code, "f", vec![]).unwrap(), vec![Value::I32(42)]);
+        assert_eq!(runtime.fuel(), Some(2));
+    }
+
+    #[test]
+    fn trap_out_of_fuel() {
+        let mut runtime = StandaloneRuntime::new();
+        runtime.enable_fuel_metering(3);
+        let code = compile(&calling_module(), &mut runtime);
+        match invoke(&code, "f", vec![]) {
+            Err(ExecutionError::Trap(trap)) => assert_eq!(trap.kind, TrapKind::OutOfFuel),
+            _ => panic!("the function did not run out of fuel"),
+        }
+        assert_eq!(runtime.fuel(), Some(0));
+        runtime.add_fuel(4);
+        assert_eq!(invoke(&code, "f", vec![]).unwrap(), vec![Value::I32(42)]);
+        assert_eq!(runtime.fuel(), Some(0));
+    }
+
+    #[test]
+    fn refill_from_handler() {
+        let mut runtime = StandaloneRuntime::new();
+        runtime.enable_fuel_metering(0);
+        runtime.set_fuel_handler(Box::new(|| Some(3)));
+        let code = compile(&calling_module(), &mut runtime);
+        assert_eq!(invoke(&code, "f", vec![]).unwrap(), vec![Value::I32(42)]);
+        // The handler added 3 units at the `call` of "f", consumed by the call and by the second
+        // function, then 3 units at the `end` of "f".
+        assert_eq!(runtime.fuel(), Some(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "no more fuel")]
+    fn resume_panic_of_handler() {
+        let mut runtime = StandaloneRuntime::new();
+        runtime.enable_fuel_metering(0);
+        runtime.set_fuel_handler(Box::new(|| panic!("no more fuel")));
+        let code = compile(&calling_module(), &mut runtime);
+        let _ = invoke(&code, "f", vec![]);
+    }
+}
@@ -0,0 +1,143 @@
+//! Interruption of the generated code from another thread. The code translated with epoch
+//! interruption compares, when entering a function and at every loop iteration, the epoch of its
+//! runtime with a deadline, and traps with `TrapKind::Interrupted` once the epoch reached it. An
+//! `InterruptHandle` bumps the epoch from any thread, which lets a watchdog stop a runaway module
+//! without the cost of metering every operator.
+//!
+//! Each trap consumes the interruption that caused it: before trapping, the generated code calls
+//! `wasmstandalone_interrupted`, which moves the deadline one epoch past the current one, so the
+//! code executed afterwards runs normally until the next interruption.
+use wasm2cretonne::Local;
+use cton_frontend::FunctionBuilder;
+use cretonne::ir::{MemFlags, Value, InstBuilder};
+use cretonne::ir::types::*;
+use cretonne::ir::condcodes::IntCC;
+use cretonne::ir::immediates::Offset32;
+use std::mem::{size_of, transmute};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use traps::TrapKind;
+use standalone::call_if_icmp;
+
+// Shared by a runtime with its interrupt handles. The generated code reads both fields as 64 bits
+// integers at the address of the `Epoch`.
+#[repr(C)]
+pub struct Epoch {
+    current: AtomicUsize,
+    // Epoch from which the generated code traps.
+    deadline: AtomicUsize,
+}
+
+impl Epoch {
+    pub fn new() -> Arc<Epoch> {
+        Arc::new(Epoch {
+                     current: AtomicUsize::new(0),
+                     deadline: AtomicUsize::new(1),
+                 })
+    }
+
+    pub fn set_deadline(&self, epochs: usize) {
+        let current = self.current.load(Ordering::SeqCst);
+        self.deadline
+            .store(current.saturating_add(epochs), Ordering::SeqCst);
+    }
+}
+
+/// Interrupts the code of a runtime from any thread, see
+/// `StandaloneRuntime::enable_epoch_interruption`.
+#[derive(Clone)]
+pub struct InterruptHandle {
+    epoch: Arc<Epoch>,
+}
+
+impl InterruptHandle {
+    pub fn new(epoch: Arc<Epoch>) -> InterruptHandle {
+        InterruptHandle { epoch }
+    }
+
+    /// Increments the epoch of the runtime. The code executing when the epoch reaches the
+    /// deadline traps with `TrapKind::Interrupted` at its next function entry or loop iteration,
+    /// or the next code executed if none is. The trap resets the deadline to the next epoch.
+    pub fn interrupt(&self) {
+        self.epoch.current.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+// Called by the generated code when the current epoch of `epoch` reached its deadline. Returns 0
+// to trap, once the deadline has been moved to the next epoch.
+extern "C" fn wasmstandalone_interrupted(epoch: *const Epoch) -> i32 {
+    unsafe { (*epoch).set_deadline(1) };
+    0
+}
+
+// Emits the code trapping when the current epoch of the `Epoch` at `epoch_val` reached its
+// deadline.
+pub fn translate_check_epoch(builder: &mut FunctionBuilder<Local>, epoch_val: Value) {
+    let current_val = builder
+        .ins()
+        .load(I64, MemFlags::new(), epoch_val, Offset32::new(0));
+    let deadline_val = builder
+        .ins()
+        .load(I64,
+              MemFlags::new(),
+              epoch_val,
+              Offset32::new(size_of::<AtomicUsize>() as i32));
+    let interrupted_addr: i64 = unsafe { transmute(wasmstandalone_interrupted as *const u8) };
+    call_if_icmp(builder,
+                 TrapKind::Interrupted,
+                 IntCC::UnsignedGreaterThanOrEqual,
+                 current_val,
+                 deadline_val,
+                 interrupted_addr,
+                 epoch_val);
+}
+
+#[cfg(test)]
+mod tests {
+    use standalone::StandaloneRuntime;
+    use execution::{invoke, ExecutableCode, ExecutionError, Value};
+    use traps::TrapKind;
+    use testing::{module, compile};
+    use std::thread;
+    use std::time::Duration;
+
+    // Exports as "f" a function looping forever and as "g" a function returning 42.
+    fn looping_module() -> Vec<u8> {
+        module(&[&[0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f],
+                 &[0x03, 0x03, 0x02, 0x00, 0x00],
+                 &[0x07, 0x09, 0x02, 0x01, b'f', 0x00, 0x00, 0x01, b'g', 0x00, 0x01],
+                 &[0x0a, 0x0f, 0x02, 0x08, 0x00, 0x03, 0x40, 0x0c, 0x00, 0x0b, 0x00, 0x0b, 0x04,
+                   0x00, 0x41, 0x2a, 0x0b]])
+    }
+
+    fn assert_interrupted(code: &ExecutableCode, name: &str) {
+        match invoke(code, name, vec![]) {
+            Err(ExecutionError::Trap(trap)) => assert_eq!(trap.kind, TrapKind::Interrupted),
+            _ => panic!("the function was not interrupted"),
+        }
+    }
+
+    #[test]
+    fn interrupt_loop_from_another_thread() {
+        let mut runtime = StandaloneRuntime::new();
+        runtime.enable_epoch_interruption();
+        let code = compile(&looping_module(), &mut runtime);
+        let handle = runtime.interrupt_handle().unwrap();
+        let watchdog = thread::spawn(move || {
+                                         thread::sleep(Duration::from_millis(10));
+                                         handle.interrupt();
+                                     });
+        assert_interrupted(&code, "f");
+        watchdog.join().unwrap();
+    }
+
+    #[test]
+    fn consume_interruption_with_trap() {
+        let mut runtime = StandaloneRuntime::new();
+        runtime.enable_epoch_interruption();
+        let code = compile(&looping_module(), &mut runtime);
+        runtime.interrupt_handle().unwrap().interrupt();
+        assert_interrupted(&code, "g");
+        assert_eq!(invoke(&code, "g", vec![]).unwrap(), vec![Value::I32(42)]);
+    }
+}
@@ -0,0 +1,354 @@
+//! Lazy compilation: the functions of a module are translated and compiled on their first call,
+//! so that the functions that are never called cost nothing.
+//!
+//! Every function defined by the module has an entry, a small piece of code whose address is the
+//! address of the function for the callers, the tables and the exports. At first, the entry jumps
+//! to a thunk that compiles the function, then patches the entry so that it jumps directly to the
+//! compiled code from then on, and finally jumps to the compiled code itself. When the compilation
+//! fails, the thunk jumps instead to a `ud2` instruction of the entry, so that the call traps like
+//! any other code and the error is returned once the execution is unwound.
+use wasm2cretonne::{translate_module_lazily, LazyTranslationResult, FunctionTranslation,
+                    FunctionIndex, Export};
+use cretonne::isa::TargetIsa;
+use std::cell::{RefCell, Ref};
+use std::ptr::copy_nonoverlapping;
+use execution::{executable_isa, compile_function, relocate_calls, protect_code, executable_code,
+                import_stub, call_function_in, CompileOptions, CompiledFunction, ExecutableCode,
+                ExecutionError, ImportResolver, Value};
+use standalone::{StandaloneRuntime, instantiate};
+use traps::CodeLookup;
+use stack::StackLimit;
+
+// Size of the entry of a function, enough for its initial and its final code.
+const ENTRY_SIZE: usize = 32;
+// Offset in the entry of the `ud2` instruction executed when the compilation fails.
+const FAILURE_OFFSET: usize = 28;
+
+// The state of the lazy compilation, which the thunk passes to `wasmstandalone_lazy_compile`.
+struct LazyState {
+    translation: LazyTranslationResult,
+    runtime: RefCell<StandaloneRuntime>,
+    isa: Box<TargetIsa>,
+    // Code shared by the entries of the functions that are not compiled yet.
+    thunk: Vec<u8>,
+    // Address of the entry of each function of the function index space.
+    entries: Vec<*const u8>,
+    // Code of each function compiled so far.
+    bodies: RefCell<Vec<Option<Vec<u8>>>>,
+    // Error of the last compilation that failed.
+    error: RefCell<Option<String>>,
+}
+
+impl LazyState {
+    // Translates and compiles the function `function_index`, and returns the address of its code.
+    fn compile(&self, function_index: FunctionIndex) -> Result<*const u8, String> {
+        let translation = self.translation
+            .translate_function(function_index, &mut *self.runtime.borrow_mut())
+            .map_err(|err| err.to_string())?;
+        let (il, imports) = match translation {
+            FunctionTranslation::Code { il, imports } => (il, imports),
+            FunctionTranslation::Import { .. } => {
+                return Err(format!("function {} is imported", function_index))
+            }
+        };
+        let (mut code, relocs) = match compile_function(&*self.isa, il, &imports)? {
+            CompiledFunction::Local { code, relocs } => (code, relocs),
+            CompiledFunction::Import { .. } => {
+                return Err(format!("function {} is imported", function_index))
+            }
+        };
+        // The calls go through the entries of the functions, which never move.
+        relocate_calls(&mut code, &relocs, &self.entries)?;
+        protect_code(&code)?;
+        let address = code.as_ptr();
+        let jump = emit_jump(address);
+        unsafe {
+            copy_nonoverlapping(jump.as_ptr(), self.entries[function_index] as *mut u8, jump.len());
+        }
+        self.bodies.borrow_mut()[function_index] = Some(code);
+        Ok(address)
+    }
+}
+
+impl CodeLookup for LazyState {
+    fn lookup(&self, pc: usize) -> Option<(FunctionIndex, usize)> {
+        self.bodies
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, body)| body.as_ref().map(|code| (index, code)))
+            .find(|&(_, code)| {
+                      let start = code.as_ptr() as usize;
+                      start <= pc && pc < start + code.len()
+                  })
+            .map(|(index, code)| (index, pc - code.as_ptr() as usize))
+    }
+
+    fn function_name(&self, function_index: FunctionIndex) -> Option<&str> {
+        self.translation
+            .result
+            .names
+            .functions
+            .get(&function_index)
+            .map(|name| name.as_str())
+    }
+}
+
+// Called by the thunk with the state of the module and the index of the function to compile.
+// Returns the address of the code of the function or, when its compilation fails, the address of
+// the `ud2` instruction of its entry, the error being kept for `LazyModule::call`.
+extern "C" fn wasmstandalone_lazy_compile(state: *const LazyState,
+                                          function_index: u32)
+                                          -> *const u8 {
+    let state = unsafe { &*state };
+    let function_index = function_index as FunctionIndex;
+    match state.compile(function_index) {
+        Ok(address) => address,
+        Err(message) => {
+            *state.error.borrow_mut() = Some(message);
+            unsafe { state.entries[function_index].offset(FAILURE_OFFSET as isize) }
+        }
+    }
+}
+
+/// A module whose functions are translated and compiled on their first call, see
+/// [`compile_lazily`](fn.compile_lazily.html).
+pub struct LazyModule {
+    state: Box<LazyState>,
+    code: ExecutableCode,
+}
+
+/// Prepares the lazy compilation of the module `data` with `runtime`, which is instantiated: the
+/// imports are looked up with `resolver`, the globals are initialized and the tables and memories
+/// are filled with the elements and data segments of the module. Only the functions that are
+/// called are translated and compiled, one at a time with the target of `options`.
+pub fn compile_lazily(data: &Vec<u8>,
+                      runtime: StandaloneRuntime,
+                      resolver: &ImportResolver,
+                      options: &CompileOptions)
+                      -> Result<LazyModule, String> {
+    let isa = executable_isa(options)?;
+    let mut runtime = runtime;
+    let translation = translate_module_lazily(data, &mut runtime)
+        .map_err(|err| err.to_string())?;
+    let functions_count = translation.result.function_signatures.len();
+    let thunk = emit_thunk(wasmstandalone_lazy_compile as *const u8);
+    protect_code(&thunk)?;
+    let mut state = Box::new(LazyState {
+                                 translation,
+                                 runtime: RefCell::new(StandaloneRuntime::new()),
+                                 isa,
+                                 thunk,
+                                 entries: Vec::with_capacity(functions_count),
+                                 bodies: RefCell::new(vec![None; functions_count]),
+                                 error: RefCell::new(None),
+                             });
+    let mut functions_code = Vec::with_capacity(functions_count);
+    for function_index in 0..functions_count {
+        let entry = match state.translation.result.functions.get(function_index) {
+            Some(&FunctionTranslation::Import {
+                      ref module,
+                      ref field,
+                  }) => {
+                let sig_index = state.translation.result.function_signatures[function_index];
+                let signature = &state.translation.result.signatures[sig_index];
+                import_stub(resolver.resolve_function(module, field, signature)?)
+            }
+            Some(&FunctionTranslation::Code { .. }) => {
+                return Err(String::from("function translated before its first call"))
+            }
+            None => emit_entry(function_index, &*state, state.thunk.as_ptr()),
+        };
+        functions_code.push(entry);
+    }
+    state.entries = functions_code
+        .iter()
+        .map(|entry| entry.as_ptr())
+        .collect();
+    instantiate(&mut runtime,
+                resolver,
+                &state.entries,
+                &state.translation.result.function_signatures,
+                None)?;
+    let stack_limit = runtime.stack_limit();
+    state.runtime = RefCell::new(runtime);
+    let code = {
+        let result = &state.translation.result;
+        executable_code(functions_code,
+                        result.function_signatures.clone(),
+                        result.signatures.clone(),
+                        result.exports.clone(),
+                        result.start_index,
+                        result.names.functions.clone(),
+                        stack_limit)?
+    };
+    Ok(LazyModule { state, code })
+}
+
+impl LazyModule {
+    /// Executes the start function of the module, if any.
+    pub fn execute(&self) -> Result<(), ExecutionError> {
+        match self.state.translation.result.start_index {
+            None => Ok(()),
+            Some(index) => self.call(index, &[]).map(|_| ()),
+        }
+    }
+
+    /// Calls the function exported under the name `name`, see [`invoke`](fn.invoke.html). The
+    /// failure of the compilation of a function is reported as `ExecutionError::Invalid`.
+    pub fn invoke(&self, name: &str, args: Vec<Value>) -> Result<Vec<Value>, ExecutionError> {
+        match self.code.export(name) {
+            Some(Export::Function(index)) => self.call(index, &args),
+            _ => {
+                Err(ExecutionError::Invalid(format!("no exported function named \"{}\"", name)))
+            }
+        }
+    }
+
+    /// Returns whether the function `function_index` has been compiled, the imported functions
+    /// never being compiled.
+    pub fn is_compiled(&self, function_index: FunctionIndex) -> bool {
+        self.state.bodies.borrow()[function_index].is_some()
+    }
+
+    /// Runtime holding the globals, tables and memories of the module. It must not be borrowed
+    /// while a function is executing.
+    pub fn runtime(&self) -> Ref<StandaloneRuntime> {
+        self.state.runtime.borrow()
+    }
+
+    /// Compiled code of the module, in which each function defined by the module is its entry.
+    pub fn code(&self) -> &ExecutableCode {
+        &self.code
+    }
+
+    fn call(&self,
+            function_index: FunctionIndex,
+            args: &[Value])
+            -> Result<Vec<Value>, ExecutionError> {
+        let result = call_function_in(&self.code, self, function_index, args);
+        match self.state.error.borrow_mut().take() {
+            None => result,
+            Some(message) => Err(ExecutionError::Invalid(message)),
+        }
+    }
+}
+
+impl CodeLookup for LazyModule {
+    fn lookup(&self, pc: usize) -> Option<(FunctionIndex, usize)> {
+        self.state
+            .lookup(pc)
+            .or_else(|| self.code.lookup(pc))
+    }
+
+    fn function_name(&self, function_index: FunctionIndex) -> Option<&str> {
+        self.code.function_name(function_index)
+    }
+
+    fn stack_limits(&self) -> Vec<&StackLimit> {
+        self.code.stack_limits()
+    }
+}
+
+// Emits the initial code of the entry of the function `function_index`, which jumps to the thunk
+// with the function index in r10 and the state in r11, followed by the `ud2` instruction to which
+// the thunk jumps when the compilation fails.
+fn emit_entry(function_index: FunctionIndex, state: *const LazyState, thunk: *const u8) -> Vec<u8> {
+    let mut code = Vec::with_capacity(ENTRY_SIZE);
+    // mov r10d, function_index
+    code.extend_from_slice(&[0x41, 0xba]);
+    push_u32(&mut code, function_index as u32);
+    // movabs r11, state
+    code.extend_from_slice(&[0x49, 0xbb]);
+    push_u64(&mut code, state as u64);
+    // movabs rax, thunk; jmp rax
+    code.extend_from_slice(&emit_jump(thunk));
+    debug_assert_eq!(code.len(), FAILURE_OFFSET);
+    // ud2
+    code.extend_from_slice(&[0x0f, 0x0b]);
+    code.resize(ENTRY_SIZE, 0xcc);
+    code
+}
+
+// Emits a jump to `target`, from anywhere in the address space. The scratch register rax holds no
+// argument.
+fn emit_jump(target: *const u8) -> Vec<u8> {
+    // movabs rax, target
+    let mut code = vec![0x48, 0xb8];
+    push_u64(&mut code, target as u64);
+    // jmp rax
+    code.extend_from_slice(&[0xff, 0xe0]);
+    code
+}
+
+// Emits the thunk calling `compile` with the state and the function index given by the entry,
+// then jumping to the code it returns. The registers holding the arguments of the function are
+// saved around the call, the arguments on the stack are left untouched.
+fn emit_thunk(compile: *const u8) -> Vec<u8> {
+    let mut code = Vec::new();
+    // push rbp; mov rbp, rsp
+    code.extend_from_slice(&[0x55, 0x48, 0x89, 0xe5]);
+    // push rdi; push rsi; push rdx; push rcx; push r8; push r9
+    code.extend_from_slice(&[0x57, 0x56, 0x52, 0x51, 0x41, 0x50, 0x41, 0x51]);
+    // sub rsp, 64, which leaves the stack 16 bytes aligned
+    code.extend_from_slice(&[0x48, 0x83, 0xec, 0x40]);
+    for i in 0..8 {
+        // movsd [rsp + 8 * i], xmmi
+        code.extend_from_slice(&[0xf2, 0x0f, 0x11, 0x44 | i << 3, 0x24, 8 * i]);
+    }
+    // mov rdi, r11; mov esi, r10d
+    code.extend_from_slice(&[0x4c, 0x89, 0xdf, 0x44, 0x89, 0xd6]);
+    // movabs rax, compile; call rax
+    code.extend_from_slice(&[0x48, 0xb8]);
+    push_u64(&mut code, compile as u64);
+    code.extend_from_slice(&[0xff, 0xd0]);
+    // mov r11, rax
+    code.extend_from_slice(&[0x49, 0x89, 0xc3]);
+    for i in 0..8 {
+        // movsd xmmi, [rsp + 8 * i]
+        code.extend_from_slice(&[0xf2, 0x0f, 0x10, 0x44 | i << 3, 0x24, 8 * i]);
+    }
+    // add rsp, 64
+    code.extend_from_slice(&[0x48, 0x83, 0xc4, 0x40]);
+    // pop r9; pop r8; pop rcx; pop rdx; pop rsi; pop rdi; pop rbp
+    code.extend_from_slice(&[0x41, 0x59, 0x41, 0x58, 0x59, 0x5a, 0x5e, 0x5f, 0x5d]);
+    // jmp r11
+    code.extend_from_slice(&[0x41, 0xff, 0xe3]);
+    code
+}
+
+fn push_u32(code: &mut Vec<u8>, val: u32) {
+    for i in 0..4 {
+        code.push((val >> (8 * i)) as u8);
+    }
+}
+
+fn push_u64(code: &mut Vec<u8>, val: u64) {
+    for i in 0..8 {
+        code.push((val >> (8 * i)) as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compile_lazily;
+    use standalone::StandaloneRuntime;
+    use execution::{CompileOptions, NoImports, Value};
+
+    #[test]
+    fn compile_called_functions() {
+        // Exports as "f" a function calling the second function, the third being never called.
+        let data = vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60,
+                        0x00, 0x01, 0x7f, 0x03, 0x04, 0x03, 0x00, 0x00, 0x00, 0x07, 0x05, 0x01,
+                        0x01, b'f', 0x00, 0x00, 0x0a, 0x10, 0x03, 0x04, 0x00, 0x10, 0x01, 0x0b,
+                        0x04, 0x00, 0x41, 0x2a, 0x0b, 0x04, 0x00, 0x41, 0x07, 0x0b];
+        let module = compile_lazily(&data,
+                                    StandaloneRuntime::new(),
+                                    &NoImports,
+                                    &CompileOptions::default())
+                .unwrap();
+        assert!(!module.is_compiled(0));
+        assert_eq!(module.invoke("f", vec![]).unwrap(), vec![Value::I32(42)]);
+        assert!(module.is_compiled(0) && module.is_compiled(1) && !module.is_compiled(2));
+    }
+}
@@ -2,14 +2,50 @@
 //! `get_global`, `set_global`, `current_memory`, `grow_memory`, `call_indirect` that hardcode in
 //! the translation the base addresses of regions of memory that will hold the globals, tables and
 //! linear memories.
+//!
+//! Several modules can be linked together with a [`Linker`](struct.Linker.html).
+//!
+//! The [`VmContextRuntime`](struct.VmContextRuntime.html) hardcodes no address instead: the code
+//! finds the data of the instance through a VM context pointer passed to every function, so that
+//! a module compiled once can be instantiated many times. Such modules can be kept across runs in
+//! a [`ModuleCache`](struct.ModuleCache.html).
+//!
+//! With [`compile_lazily`](fn.compile_lazily.html), the functions are only translated and compiled
+//! on their first call.
 
 extern crate cretonne;
 extern crate wasm2cretonne;
 extern crate cton_frontend;
 extern crate region;
+extern crate libc;
+extern crate num_cpus;
+#[macro_use]
+extern crate lazy_static;
 
+mod cache;
 mod execution;
+mod fuel;
+mod interrupt;
+mod lazy;
+mod linker;
+mod memory;
+mod stack;
 mod standalone;
+mod trampoline;
+mod traps;
+mod vmctx;
+#[cfg(test)]
+mod testing;
 
-pub use execution::{compile_module, execute, ExecutableCode};
-pub use standalone::StandaloneRuntime;
+pub use execution::{compile_module, execute, invoke, CompileOptions, ExecutableCode,
+                    ExecutionError, Value, ImportResolver, NoImports};
+pub use traps::{Trap, TrapKind};
+pub use fuel::FuelHandler;
+pub use interrupt::InterruptHandle;
+pub use stack::DEFAULT_MAX_STACK_SIZE;
+pub use standalone::{StandaloneRuntime, BoundsCheck, FunctionTable};
+pub use memory::{LinearMemory, PAGE_SIZE};
+pub use linker::{Linker, Instance, table_matches_import, memory_matches_import};
+pub use vmctx::{VmContextRuntime, VmModule, VmInstance, compile_vm_module};
+pub use cache::ModuleCache;
+pub use lazy::{LazyModule, compile_lazily};
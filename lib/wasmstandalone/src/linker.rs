@@ -0,0 +1,415 @@
+//! Linking of modules together: the functions, globals, tables and memories exported by a module
+//! registered under a name can be imported by the modules instantiated after it, which then share
+//! them with it.
+use wasm2cretonne::{translate_module_in_parallel, Export, FunctionIndex, Global, Table, Memory};
+use cretonne::ir::Signature;
+use cretonne::ir::types::*;
+use std::collections::HashMap;
+use std::ptr::read;
+use execution::{compile_module, execute_in, invoke_in, CompileOptions, ExecutableCode,
+                ExecutionError, ImportResolver, Value};
+use standalone::{StandaloneRuntime, FunctionTable};
+use memory::LinearMemory;
+use traps::CodeLookup;
+use stack::StackLimit;
+
+/// A module translated, compiled and instantiated by a `Linker`.
+pub struct Instance {
+    // The compiled code refers to the globals, tables and memories of the runtime.
+    runtime: StandaloneRuntime,
+    code: ExecutableCode,
+}
+
+impl Instance {
+    /// Returns the current value of the global exported under the name `name`.
+    pub fn global_value(&self, name: &str) -> Option<Value> {
+        let global_index = match self.code.export(name) {
+            Some(Export::Global(index)) => index,
+            _ => return None,
+        };
+        let (address, global) = self.runtime.global(global_index);
+        unsafe {
+            match global.ty {
+                I32 => Some(Value::I32(read(address as *const i32))),
+                I64 => Some(Value::I64(read(address as *const i64))),
+                F32 => Some(Value::F32(read(address as *const f32))),
+                F64 => Some(Value::F64(read(address as *const f64))),
+                _ => None,
+            }
+        }
+    }
+
+    /// Runtime holding the globals, tables and memories of the instance.
+    pub fn runtime(&self) -> &StandaloneRuntime {
+        &self.runtime
+    }
+
+    /// Compiled code of the instance.
+    pub fn code(&self) -> &ExecutableCode {
+        &self.code
+    }
+}
+
+// Locates the traps in the code of all the instances of `linker`, since the code of `instance`
+// calls the functions of the other instances through its imports and the shared tables. The
+// function index of a trap is relative to the instance whose code trapped.
+struct LinkedCode<'a> {
+    linker: &'a Linker,
+    instance: &'a Instance,
+}
+
+impl<'a> CodeLookup for LinkedCode<'a> {
+    fn lookup(&self, pc: usize) -> Option<(FunctionIndex, usize)> {
+        self.instance
+            .code
+            .lookup(pc)
+            .or_else(|| {
+                         self.linker
+                             .instances
+                             .iter()
+                             .chain(self.linker.failed.iter())
+                             .filter_map(|instance| instance.code.lookup(pc))
+                             .next()
+                     })
+    }
+
+    fn function_name(&self, function_index: FunctionIndex) -> Option<&str> {
+        self.instance.code.function_name(function_index)
+    }
+
+    fn stack_limits(&self) -> Vec<&StackLimit> {
+        let mut stack_limits = self.instance.code.stack_limits();
+        for instance in self.linker.instances.iter().chain(self.linker.failed.iter()) {
+            stack_limits.extend(instance.code.stack_limits());
+        }
+        stack_limits
+    }
+}
+
+/// Instantiates modules, resolving their imports with the exports of the registered instances.
+/// The imports from a module that is not registered are resolved by the host resolver.
+pub struct Linker {
+    host: Box<ImportResolver>,
+    options: CompileOptions,
+    instances: Vec<Instance>,
+    // Instances whose start function trapped after they wrote their functions in the tables of
+    // other instances, which can still call them.
+    failed: Vec<Instance>,
+    registered: HashMap<String, usize>,
+    max_stack_size: Option<usize>,
+}
+
+impl Linker {
+    /// Creates a linker resolving the imports from unregistered modules with `host`.
+    pub fn new(host: Box<ImportResolver>) -> Linker {
+        Linker::with_options(host, CompileOptions::default())
+    }
+
+    /// Creates a linker compiling the modules with `options`.
+    pub fn with_options(host: Box<ImportResolver>, options: CompileOptions) -> Linker {
+        Linker {
+            host,
+            options,
+            instances: Vec::new(),
+            failed: Vec::new(),
+            registered: HashMap::new(),
+            max_stack_size: None,
+        }
+    }
+
+    /// Limits the native stack used by the code of the modules instantiated afterwards, see
+    /// [`StandaloneRuntime::set_max_stack_size`](struct.StandaloneRuntime.html). The stack is not
+    /// limited by default.
+    pub fn set_max_stack_size(&mut self, size: Option<usize>) {
+        self.max_stack_size = size;
+    }
+
+    /// Translates, compiles and instantiates the module `data`, executes its start function and
+    /// returns the index of the instance. When the start function traps, the instance is dropped,
+    /// unless it wrote its functions in the tables of other instances: it is then kept alive for
+    /// them but cannot be accessed or registered.
+    pub fn instantiate(&mut self, data: &Vec<u8>) -> Result<usize, ExecutionError> {
+        let mut runtime = StandaloneRuntime::new();
+        if let Some(size) = self.max_stack_size {
+            runtime.set_max_stack_size(size);
+        }
+        let translation =
+            translate_module_in_parallel(data, &mut runtime, self.options.num_threads)
+                .map_err(|err| ExecutionError::Invalid(err.to_string()))?;
+        let code = compile_module(&translation, &mut runtime, &*self, &self.options)
+            .map_err(ExecutionError::Invalid)?;
+        let instance = Instance { runtime, code };
+        let result = execute_in(&instance.code,
+                                &LinkedCode {
+                                     linker: self,
+                                     instance: &instance,
+                                 });
+        if let Err(err) = result {
+            if instance.runtime.writes_imported_tables() {
+                self.failed.push(instance);
+            }
+            return Err(err);
+        }
+        self.instances.push(instance);
+        Ok(self.instances.len() - 1)
+    }
+
+    /// Makes the exports of the instance `index` importable from the module `name`.
+    pub fn register(&mut self, name: &str, index: usize) {
+        self.registered.insert(String::from(name), index);
+    }
+
+    /// Returns the instance `index`.
+    pub fn instance(&self, index: usize) -> &Instance {
+        &self.instances[index]
+    }
+
+    /// Calls the function exported under the name `name` by the instance `index`, see
+    /// [`invoke`](fn.invoke.html). The traps in the functions it imports from other instances are
+    /// returned as well.
+    pub fn invoke(&self,
+                  index: usize,
+                  name: &str,
+                  args: Vec<Value>)
+                  -> Result<Vec<Value>, ExecutionError> {
+        let instance = &self.instances[index];
+        invoke_in(&instance.code,
+                  &LinkedCode {
+                       linker: self,
+                       instance,
+                   },
+                  name,
+                  args)
+    }
+
+    // Returns the registered instance `module` and its export `field`.
+    fn export(&self, module: &str, field: &str) -> Result<(&Instance, Export), String> {
+        let instance = &self.instances[self.registered[module]];
+        match instance.code.export(field) {
+            Some(export) => Ok((instance, export)),
+            None => Err(format!("unknown import {}.{}", module, field)),
+        }
+    }
+}
+
+fn incompatible_import(module: &str, field: &str) -> String {
+    format!("incompatible import type for {}.{}", module, field)
+}
+
+// An entity of `size` elements that can grow up to `maximum` satisfies the limits of an import
+// when it is at least as large as their minimum and cannot grow beyond their maximum.
+fn limits_match(size: usize,
+                maximum: Option<usize>,
+                import_size: usize,
+                import_maximum: Option<usize>)
+                -> bool {
+    size >= import_size &&
+    match import_maximum {
+        None => true,
+        Some(import_maximum) => maximum.map_or(false, |maximum| maximum <= import_maximum),
+    }
+}
+
+/// Checks that `table` can be imported with the declaration `import`.
+pub fn table_matches_import(table: &FunctionTable, import: &Table) -> bool {
+    limits_match(table.size(), table.maximum(), import.size, import.maximum)
+}
+
+/// Checks that `memory` can be imported with the declaration `import`.
+pub fn memory_matches_import(memory: &LinearMemory, import: &Memory) -> bool {
+    limits_match(memory.pages(),
+                 memory.maximum(),
+                 import.pages_count,
+                 import.maximum)
+}
+
+impl ImportResolver for Linker {
+    fn resolve_function(&self,
+                        module: &str,
+                        field: &str,
+                        signature: &Signature)
+                        -> Result<*const u8, String> {
+        if !self.registered.contains_key(module) {
+            return self.host.resolve_function(module, field, signature);
+        }
+        let (instance, _) = self.export(module, field)?;
+        match instance.code.exported_function(field) {
+            Some((address, export_signature)) => {
+                if export_signature.argument_types == signature.argument_types &&
+                   export_signature.return_types == signature.return_types {
+                    Ok(address)
+                } else {
+                    Err(incompatible_import(module, field))
+                }
+            }
+            None => Err(incompatible_import(module, field)),
+        }
+    }
+
+    fn resolve_global(&self,
+                      module: &str,
+                      field: &str,
+                      global: &Global)
+                      -> Result<*mut u8, String> {
+        if !self.registered.contains_key(module) {
+            return self.host.resolve_global(module, field, global);
+        }
+        match self.export(module, field)? {
+            (instance, Export::Global(index)) => {
+                let (address, exported) = instance.runtime.global(index);
+                if exported.ty == global.ty && exported.mutability == global.mutability {
+                    Ok(address)
+                } else {
+                    Err(incompatible_import(module, field))
+                }
+            }
+            _ => Err(incompatible_import(module, field)),
+        }
+    }
+
+    fn resolve_table(&self,
+                     module: &str,
+                     field: &str,
+                     table: &Table)
+                     -> Result<*mut FunctionTable, String> {
+        if !self.registered.contains_key(module) {
+            return self.host.resolve_table(module, field, table);
+        }
+        match self.export(module, field)? {
+            (instance, Export::Table(index)) => {
+                let exported = instance.runtime.table(index);
+                if table_matches_import(unsafe { &*exported }, table) {
+                    Ok(exported)
+                } else {
+                    Err(incompatible_import(module, field))
+                }
+            }
+            _ => Err(incompatible_import(module, field)),
+        }
+    }
+
+    fn resolve_memory(&self,
+                      module: &str,
+                      field: &str,
+                      memory: &Memory)
+                      -> Result<*mut LinearMemory, String> {
+        if !self.registered.contains_key(module) {
+            return self.host.resolve_memory(module, field, memory);
+        }
+        match self.export(module, field)? {
+            (instance, Export::Memory(index)) => {
+                let exported = instance.runtime.memory(index);
+                if memory_matches_import(unsafe { &*exported }, memory) {
+                    Ok(exported)
+                } else {
+                    Err(incompatible_import(module, field))
+                }
+            }
+            _ => Err(incompatible_import(module, field)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Linker;
+    use execution::{ExecutionError, NoImports, Value};
+    use traps::TrapKind;
+    use testing::module;
+
+    // Exports as "f" a function returning 42.
+    fn exporting_module() -> Vec<u8> {
+        module(&[&[0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f],
+                 &[0x03, 0x02, 0x01, 0x00],
+                 &[0x07, 0x05, 0x01, 0x01, b'f', 0x00, 0x00],
+                 &[0x0a, 0x06, 0x01, 0x04, 0x00, 0x41, 0x2a, 0x0b]])
+    }
+
+    // Exports as "g" a function calling the function imported as `field` from "a".
+    fn importing_module(field: u8) -> Vec<u8> {
+        module(&[&[0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f],
+                 &[0x02, 0x07, 0x01, 0x01, b'a', 0x01, field, 0x00, 0x00],
+                 &[0x03, 0x02, 0x01, 0x00],
+                 &[0x07, 0x05, 0x01, 0x01, b'g', 0x00, 0x01],
+                 &[0x0a, 0x06, 0x01, 0x04, 0x00, 0x10, 0x00, 0x0b]])
+    }
+
+    #[test]
+    fn call_imported_function() {
+        let mut linker = Linker::new(Box::new(NoImports));
+        let exporting = linker.instantiate(&exporting_module()).unwrap();
+        linker.register("a", exporting);
+        let importing = linker.instantiate(&importing_module(b'f')).unwrap();
+        assert_eq!(linker.invoke(importing, "g", vec![]).unwrap(),
+                   vec![Value::I32(42)]);
+    }
+
+    #[test]
+    fn trap_in_imported_function() {
+        let mut linker = Linker::new(Box::new(NoImports));
+        // Exports as "t" a function executing `unreachable`.
+        let trapping = module(&[&[0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f],
+                                &[0x03, 0x02, 0x01, 0x00],
+                                &[0x07, 0x05, 0x01, 0x01, b't', 0x00, 0x00],
+                                &[0x0a, 0x05, 0x01, 0x03, 0x00, 0x00, 0x0b]]);
+        let exporting = linker.instantiate(&trapping).unwrap();
+        linker.register("a", exporting);
+        let importing = linker.instantiate(&importing_module(b't')).unwrap();
+        match linker.invoke(importing, "g", vec![]) {
+            Err(ExecutionError::Trap(trap)) => assert_eq!(trap.kind, TrapKind::Unreachable),
+            _ => panic!("the imported function did not trap"),
+        }
+    }
+
+    #[test]
+    fn reject_unknown_import() {
+        let mut linker = Linker::new(Box::new(NoImports));
+        let exporting = linker.instantiate(&exporting_module()).unwrap();
+        linker.register("a", exporting);
+        match linker.instantiate(&importing_module(b'h')) {
+            Err(ExecutionError::Invalid(_)) => (),
+            _ => panic!("the unknown import was resolved"),
+        }
+    }
+
+    #[test]
+    fn instantiate_module_without_code() {
+        let mut linker = Linker::new(Box::new(NoImports));
+        // Exports as "x" an immutable i32 global initialized to 7.
+        let globals = module(&[&[0x06, 0x06, 0x01, 0x7f, 0x00, 0x41, 0x07, 0x0b],
+                               &[0x07, 0x05, 0x01, 0x01, b'x', 0x03, 0x00]]);
+        let index = linker.instantiate(&globals).unwrap();
+        assert_eq!(linker.instance(index).global_value("x"), Some(Value::I32(7)));
+    }
+
+    #[test]
+    fn access_several_memories() {
+        let mut linker = Linker::new(Box::new(NoImports));
+        // Exports as "f" a function storing 7 at the address 0 of the memory 1, then returning
+        // the difference between the values at the address 0 of the memories 1 and 0.
+        let memories = module(&[&[0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f],
+                                &[0x03, 0x02, 0x01, 0x00],
+                                &[0x05, 0x05, 0x02, 0x00, 0x01, 0x00, 0x01],
+                                &[0x07, 0x05, 0x01, 0x01, b'f', 0x00, 0x00],
+                                &[0x0a, 0x18, 0x01, 0x16, 0x00, 0x41, 0x00, 0x41, 0x07, 0x36,
+                                  0x82, 0x02, 0x00, 0x41, 0x00, 0x28, 0x82, 0x02, 0x00, 0x41,
+                                  0x00, 0x28, 0x02, 0x00, 0x6b, 0x0b]]);
+        let index = linker.instantiate(&memories).unwrap();
+        assert_eq!(linker.invoke(index, "f", vec![]).unwrap(), vec![Value::I32(7)]);
+    }
+
+    #[test]
+    fn drop_instance_whose_start_traps() {
+        let mut linker = Linker::new(Box::new(NoImports));
+        let trapping = module(&[&[0x01, 0x04, 0x01, 0x60, 0x00, 0x00],
+                                &[0x03, 0x02, 0x01, 0x00],
+                                &[0x08, 0x01, 0x00],
+                                &[0x0a, 0x05, 0x01, 0x03, 0x00, 0x00, 0x0b]]);
+        match linker.instantiate(&trapping) {
+            Err(ExecutionError::Trap(trap)) => assert_eq!(trap.kind, TrapKind::Unreachable),
+            _ => panic!("the start function did not trap"),
+        }
+        assert!(linker.instances.is_empty() && linker.failed.is_empty());
+        assert_eq!(linker.instantiate(&exporting_module()).unwrap(), 0);
+    }
+}
@@ -0,0 +1,144 @@
+//! Linear memories of the standalone runtime. A linear memory is a region of virtual memory
+//! reserved once and for all at creation, whose accessible part grows by pages. Hence its base
+//! address never changes and can be hardcoded in the generated code. The code of a module
+//! importing the memory reads the base address and the size in the `LinearMemory` structure.
+pub use wasm2cretonne::{PAGE_SIZE, MAX_PAGES};
+use libc;
+use std::cmp::max;
+use std::ptr;
+use std::slice;
+
+/// Offset of the base address in a `LinearMemory`, read by the generated code.
+pub const BASE_OFFSET: i32 = 0;
+/// Offset of the number of accessible bytes in a `LinearMemory`, read by the generated code.
+pub const LENGTH_OFFSET: i32 = 8;
+
+/// A linear memory backed by a reserved region of virtual memory.
+#[repr(C)]
+pub struct LinearMemory {
+    base: *mut u8,
+    /// Number of accessible bytes, read by the generated code when checking bounds explicitly.
+    length: u64,
+    reserved: usize,
+    maximum: Option<usize>,
+}
+
+impl LinearMemory {
+    /// Reserves `reserved` bytes of virtual memory and makes the first `pages` accessible.
+    pub fn new(pages: usize,
+               maximum: Option<usize>,
+               reserved: usize)
+               -> Result<LinearMemory, String> {
+        if pages * PAGE_SIZE > reserved {
+            return Err(String::from("memory initial size exceeds its reservation"));
+        }
+        // `mmap` cannot map an empty region, and the base address of a memory that cannot grow
+        // must still be valid, so at least one inaccessible page is reserved.
+        let reserved = max(reserved, PAGE_SIZE);
+        let base = unsafe {
+            libc::mmap(ptr::null_mut(),
+                       reserved,
+                       libc::PROT_NONE,
+                       libc::MAP_PRIVATE | libc::MAP_ANON | libc::MAP_NORESERVE,
+                       -1,
+                       0)
+        };
+        if base == libc::MAP_FAILED {
+            return Err(format!("failed to reserve {} bytes for the memory", reserved));
+        }
+        let mut memory = LinearMemory {
+            base: base as *mut u8,
+            length: 0,
+            reserved,
+            maximum,
+        };
+        memory.commit(pages)?;
+        Ok(memory)
+    }
+
+    // Makes the first `pages` pages of the memory readable and writable.
+    fn commit(&mut self, pages: usize) -> Result<(), String> {
+        let new_length = pages * PAGE_SIZE;
+        if new_length > self.reserved {
+            return Err(String::from("memory size exceeds its reservation"));
+        }
+        if new_length > self.length as usize {
+            let result = unsafe {
+                libc::mprotect(self.base.offset(self.length as isize) as *mut libc::c_void,
+                               new_length - self.length as usize,
+                               libc::PROT_READ | libc::PROT_WRITE)
+            };
+            if result != 0 {
+                return Err(String::from("failed to make the memory accessible"));
+            }
+        }
+        self.length = new_length as u64;
+        Ok(())
+    }
+
+    /// Grows the memory by `delta` pages and returns its previous size in pages, or `None` if the
+    /// new size would exceed the maximum of the memory.
+    pub fn grow(&mut self, delta: usize) -> Option<usize> {
+        let pages = self.pages();
+        let new_pages = match pages.checked_add(delta) {
+            Some(new_pages) if new_pages <= self.maximum.unwrap_or(MAX_PAGES) => new_pages,
+            _ => return None,
+        };
+        match self.commit(new_pages) {
+            Ok(()) => Some(pages),
+            Err(_) => None,
+        }
+    }
+
+    /// Base address of the memory.
+    pub fn base(&self) -> *mut u8 {
+        self.base
+    }
+
+    /// Address of the number of accessible bytes of the memory.
+    pub fn length_address(&self) -> *const u64 {
+        &self.length
+    }
+
+    /// Current size of the memory, in pages.
+    pub fn pages(&self) -> usize {
+        self.length as usize / PAGE_SIZE
+    }
+
+    /// Maximum size of the memory, in pages.
+    pub fn maximum(&self) -> Option<usize> {
+        self.maximum
+    }
+
+    /// Accessible contents of the memory.
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.base, self.length as usize) }
+    }
+
+    /// Accessible contents of the memory.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.base, self.length as usize) }
+    }
+}
+
+impl Drop for LinearMemory {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, self.reserved);
+        }
+    }
+}
+
+/// Implementation of `grow_memory` called by the generated code. Returns the previous size of the
+/// memory in pages, or -1 if it cannot grow.
+pub extern "C" fn wasmstandalone_grow_memory(memory: *mut LinearMemory, delta: u32) -> i32 {
+    match unsafe { (*memory).grow(delta as usize) } {
+        Some(pages) => pages as i32,
+        None => -1,
+    }
+}
+
+/// Implementation of `current_memory` called by the generated code.
+pub extern "C" fn wasmstandalone_current_memory(memory: *const LinearMemory) -> i32 {
+    unsafe { (*memory).pages() as i32 }
+}
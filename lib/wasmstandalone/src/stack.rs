@@ -0,0 +1,129 @@
+//! Limit of the native stack used by the generated code, so that a deep or infinite recursion
+//! traps with `TrapKind::StackOverflow` instead of overflowing the stack of the thread. The limit
+//! is opt-in per instance, with `StandaloneRuntime::set_max_stack_size` or
+//! `VmInstance::set_max_stack_size`: the prologue of every function then compares the stack
+//! pointer with the lowest stack address the code can reach, read in the `StackLimit` of the
+//! instance. That address is set when a thread enters the code of the instance from outside of it
+//! and cleared when the thread leaves it, so an instance must be executed by one thread at a time.
+use cton_frontend::FunctionBuilder;
+use cretonne::ir::{MemFlags, Value, InstBuilder, StackSlotData, StackSlotKind};
+use cretonne::ir::types::*;
+use cretonne::ir::condcodes::IntCC;
+use cretonne::ir::immediates::Offset32;
+use wasm2cretonne::Local;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use traps::TrapKind;
+use standalone::trap_if_icmp;
+
+/// Suggested size of the native stack that the generated code of an instance can use.
+pub const DEFAULT_MAX_STACK_SIZE: usize = 512 * 1024;
+
+// Offset of the lowest stack address in a `StackLimit`, read by the generated code.
+pub const STACK_LIMIT_OFFSET: i32 = 0;
+
+/// Stack limit of an instance, whose address is known to its code.
+#[repr(C)]
+pub struct StackLimit {
+    // Lowest stack address the code can use, 0 while no thread executes it.
+    limit: AtomicUsize,
+    // Size of the stack the code can use, 0 when it is not limited.
+    max_size: AtomicUsize,
+}
+
+impl StackLimit {
+    pub fn new(max_size: usize) -> StackLimit {
+        StackLimit {
+            limit: AtomicUsize::new(0),
+            max_size: AtomicUsize::new(max_size),
+        }
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size.load(Ordering::Relaxed)
+    }
+
+    // Applies to the next calls into the code, not to the ones already executing.
+    pub fn set_max_size(&self, size: usize) {
+        self.max_size.store(size, Ordering::Relaxed);
+    }
+
+    // Sets the limit `max_size` bytes below `stack_pointer`, unless it was set by an outer call
+    // into the code. Returns whether it did, in which case `leave` clears it once the call
+    // returns.
+    pub fn enter(&self, stack_pointer: usize) -> bool {
+        let max_size = self.max_size();
+        if max_size == 0 {
+            return false;
+        }
+        let limit = stack_pointer.saturating_sub(max_size);
+        self.limit.compare_and_swap(0, limit, Ordering::Relaxed) == 0
+    }
+
+    pub fn leave(&self) {
+        self.limit.store(0, Ordering::Relaxed);
+    }
+}
+
+// Address of a local variable of the caller, close enough to the stack pointer.
+#[inline(always)]
+pub fn stack_pointer() -> usize {
+    let marker = 0u8;
+    &marker as *const u8 as usize
+}
+
+// Emits the comparison of the stack pointer with the limit of the `StackLimit` at
+// `stack_limit_val`, which traps below it. Without limit, the comparison with 0 never traps.
+pub fn translate_check_stack(builder: &mut FunctionBuilder<Local>,
+                             vmctx: Option<Value>,
+                             stack_limit_val: Value) {
+    // The address of a slot in the frame of the function stands for its stack pointer.
+    let slot = builder.create_stack_slot(StackSlotData::new(StackSlotKind::Local, 8));
+    let stack_pointer_val = builder.ins().stack_addr(I64, slot, Offset32::new(0));
+    let limit_val = builder
+        .ins()
+        .load(I64,
+              MemFlags::new(),
+              stack_limit_val,
+              Offset32::new(STACK_LIMIT_OFFSET));
+    trap_if_icmp(builder,
+                 vmctx,
+                 TrapKind::StackOverflow,
+                 IntCC::UnsignedLessThan,
+                 stack_pointer_val,
+                 limit_val);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DEFAULT_MAX_STACK_SIZE;
+    use std::sync::atomic::Ordering;
+    use standalone::StandaloneRuntime;
+    use execution::{invoke, ExecutionError};
+    use traps::TrapKind;
+    use testing::{module, compile};
+
+    // Exports as "f" a function calling itself.
+    fn recursive_module() -> Vec<u8> {
+        module(&[&[0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f],
+                 &[0x03, 0x02, 0x01, 0x00],
+                 &[0x07, 0x05, 0x01, 0x01, b'f', 0x00, 0x00],
+                 &[0x0a, 0x06, 0x01, 0x04, 0x00, 0x10, 0x00, 0x0b]])
+    }
+
+    #[test]
+    fn trap_infinite_recursion() {
+        let mut runtime = StandaloneRuntime::new();
+        runtime.set_max_stack_size(DEFAULT_MAX_STACK_SIZE);
+        let code = compile(&recursive_module(), &mut runtime);
+        // The limit is cleared after the trap and set again by the next call.
+        for _ in 0..2 {
+            match invoke(&code, "f", vec![]) {
+                Err(ExecutionError::Trap(trap)) => {
+                    assert_eq!(trap.kind, TrapKind::StackOverflow)
+                }
+                _ => panic!("the recursion did not trap"),
+            }
+        }
+        assert_eq!(runtime.stack_limit().unwrap().limit.load(Ordering::Relaxed), 0);
+    }
+}
@@ -1,24 +1,30 @@
-use wasm2cretonne::{Local, FunctionIndex, GlobalIndex, TableIndex, MemoryIndex, RawByte,
-                    MemoryAddress, Global, GlobalInit, Table, Memory, WasmRuntime};
+use wasm2cretonne::{Local, FunctionIndex, GlobalIndex, TableIndex, MemoryIndex, SignatureIndex,
+                    RawByte, MemoryAddress, Global, GlobalInit, Table, Memory, WasmRuntime};
 use cton_frontend::FunctionBuilder;
-use cretonne::ir::{MemFlags, Value, InstBuilder, SigRef, FuncRef, ExtFuncData, FunctionName,
-                   Signature, ArgumentType, CallConv};
+use cretonne::ir::{MemFlags, Value, InstBuilder, SigRef, Signature, ArgumentType, CallConv,
+                   Type};
 use cretonne::ir::types::*;
 use cretonne::ir::condcodes::IntCC;
 use cretonne::ir::immediates::Offset32;
-use std::mem::transmute;
+use std::mem::{size_of, transmute};
 use std::ptr::copy_nonoverlapping;
-use std::ptr::write;
-
-#[derive(Clone, Debug)]
-enum TableElement {
-    Trap(),
-    Function(FunctionIndex),
-}
+use std::ptr::{read, write};
+use std::slice;
+use std::sync::{Arc, Mutex};
+use memory::{LinearMemory, PAGE_SIZE, MAX_PAGES, BASE_OFFSET, LENGTH_OFFSET,
+             wasmstandalone_grow_memory, wasmstandalone_current_memory};
+use traps::{TrapKind, wasmstandalone_raise_trap};
+use fuel::{FuelCounter, FuelHandler, translate_consume_fuel};
+use interrupt::{Epoch, InterruptHandle, translate_check_epoch};
+use stack::{StackLimit, translate_check_stack};
+use execution::ImportResolver;
+use vmctx::{VMCTX_ENTRY_SIZE, RAISE_TRAP_ENTRY};
 
 struct GlobalInfo {
     global: Global,
     offset: usize,
+    /// Module and field names of an imported global, whose data is the address of its value.
+    import: Option<(String, String)>,
 }
 
 struct GlobalsData {
@@ -26,18 +32,124 @@ struct GlobalsData {
     info: Vec<GlobalInfo>,
 }
 
-struct TableData {
-    data: Vec<MemoryAddress>,
-    elements: Vec<TableElement>,
-    info: Table,
+/// Entry of a table read by the generated code for `call_indirect`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TableEntry {
+    address: MemoryAddress,
+    /// Identifier of the signature of the function, `NO_SIGNATURE` for an uninitialized element.
+    signature: usize,
+    /// VM context of the instance of the function, passed to it instead of the one of the caller
+    /// by the code of the `VmContextRuntime`.
+    vmctx: usize,
 }
 
-struct MemoryData {
-    data: Vec<RawByte>,
-    info: Memory,
+const NO_SIGNATURE: usize = !0;
+
+// Offsets of the fields of a `FunctionTable` read by the generated code.
+pub const TABLE_BASE_OFFSET: i32 = 0;
+pub const TABLE_LENGTH_OFFSET: i32 = 8;
+
+/// A table of functions, which can be shared between modules. The code of a module importing the
+/// table reads the address and the number of its entries at the beginning of the structure.
+#[repr(C)]
+pub struct FunctionTable {
+    base: *mut TableEntry,
+    length: u64,
+    entries: Vec<TableEntry>,
+    maximum: Option<usize>,
 }
 
-const PAGE_SIZE: usize = 65536;
+impl FunctionTable {
+    /// Creates a table of `size` uninitialized elements.
+    pub fn new(size: usize, maximum: Option<usize>) -> FunctionTable {
+        let mut entries = vec![TableEntry {
+                                   address: 0,
+                                   signature: NO_SIGNATURE,
+                                   vmctx: 0,
+                               };
+                               size];
+        FunctionTable {
+            base: entries.as_mut_ptr(),
+            length: size as u64,
+            entries,
+            maximum,
+        }
+    }
+
+    /// Number of elements of the table.
+    pub fn size(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Maximum number of elements of the table.
+    pub fn maximum(&self) -> Option<usize> {
+        self.maximum
+    }
+}
+
+// An entity imported from another module. Its address is only known once the imports are
+// resolved, so the generated code reads it in `address`, which does not move.
+struct ImportSlot {
+    module: String,
+    field: String,
+    address: Box<usize>,
+}
+
+impl ImportSlot {
+    fn new(module: &str, field: &str) -> ImportSlot {
+        ImportSlot {
+            module: String::from(module),
+            field: String::from(field),
+            address: Box::new(0),
+        }
+    }
+
+    // Address where the generated code reads the address of the entity.
+    fn slot_address(&self) -> i64 {
+        &*self.address as *const usize as i64
+    }
+}
+
+enum MemoryData {
+    Defined(LinearMemory),
+    Imported(Memory, ImportSlot),
+}
+
+enum TableData {
+    Defined(FunctionTable),
+    Imported(Table, ImportSlot),
+}
+
+// The segments are written at instantiation, once the imported globals, tables and memories
+// are resolved.
+struct TableSegment {
+    table_index: TableIndex,
+    base: Option<GlobalIndex>,
+    offset: usize,
+    elements: Vec<FunctionIndex>,
+}
+
+struct DataSegment {
+    memory_index: MemoryIndex,
+    base: Option<GlobalIndex>,
+    offset: usize,
+    data: Vec<u8>,
+}
+
+/// Strategy used to prevent the generated code from accessing memory outside of the wasm linear
+/// memories.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoundsCheck {
+    /// Each load and store compares the accessed address with the current size of the memory and
+    /// traps if it is out of bounds.
+    Explicit,
+    /// Each memory reserves the 4 GiB addressable with 32 bits followed by 4 GiB of guard pages
+    /// covering the largest offset, and one more guard page covering the size of the access.
+    /// Accesses are not checked but out-of-bounds ones fault. Imported memories are always
+    /// checked explicitly.
+    GuardPages,
+}
 
 /// Object containing the standalone runtime information. To be passed after creation as argument
 /// to [`wasm2cretonne::translatemodule`](../wasm2cretonne/fn.translate_module.html).
@@ -45,14 +157,55 @@ pub struct StandaloneRuntime {
     globals: GlobalsData,
     tables: Vec<TableData>,
     memories: Vec<MemoryData>,
+    signatures: Vec<Signature>,
+    table_segments: Vec<TableSegment>,
+    data_segments: Vec<DataSegment>,
+    bounds_check: BoundsCheck,
     instantiated: bool,
-    has_current_memory: Option<FuncRef>,
-    has_grow_memory: Option<FuncRef>,
+    fuel: Option<Box<FuelCounter>>,
+    epoch: Option<Arc<Epoch>>,
+    stack_limit: Option<Arc<StackLimit>>,
+    // Addresses read by the translation, known once it began.
+    addresses: Option<RuntimeAddresses>,
+    // Signatures of the runtime functions, imported once per translated function.
+    current_memory_sig: Option<SigRef>,
+    grow_memory_sig: Option<SigRef>,
+}
+
+lazy_static! {
+    // Argument and return types of the signatures met so far, interned once each.
+    static ref SIGNATURES: Mutex<Vec<(Vec<ArgumentType>, Vec<ArgumentType>)>> =
+        Mutex::new(Vec::new());
+}
+
+// Identifier of a signature shared by all the modules, so that `call_indirect` can check the
+// signature of a function whatever module it comes from. Two structurally equal signatures have
+// the same identifier.
+pub fn signature_id(sig: &Signature) -> usize {
+    let mut signatures = SIGNATURES.lock().unwrap();
+    match signatures
+              .iter()
+              .position(|&(ref argument_types, ref return_types)| {
+                            *argument_types == sig.argument_types &&
+                            *return_types == sig.return_types
+                        }) {
+        Some(id) => id,
+        None => {
+            signatures.push((sig.argument_types.clone(), sig.return_types.clone()));
+            signatures.len() - 1
+        }
+    }
 }
 
 impl StandaloneRuntime {
-    /// Allocates the runtime data structures.
+    /// Allocates the runtime data structures, with explicit bounds checks of memory accesses.
     pub fn new() -> StandaloneRuntime {
+        StandaloneRuntime::with_bounds_check(BoundsCheck::Explicit)
+    }
+
+    /// Allocates the runtime data structures, using `bounds_check` to confine the memory accesses
+    /// of the generated code.
+    pub fn with_bounds_check(bounds_check: BoundsCheck) -> StandaloneRuntime {
         StandaloneRuntime {
             globals: GlobalsData {
                 data: Vec::new(),
@@ -60,204 +213,708 @@ impl StandaloneRuntime {
             },
             tables: Vec::new(),
             memories: Vec::new(),
+            signatures: Vec::new(),
+            table_segments: Vec::new(),
+            data_segments: Vec::new(),
+            bounds_check,
             instantiated: false,
-            has_current_memory: None,
-            has_grow_memory: None,
+            fuel: None,
+            epoch: None,
+            stack_limit: None,
+            addresses: None,
+            current_memory_sig: None,
+            grow_memory_sig: None,
+        }
+    }
+
+    /// Enables the fuel metering of the code translated afterwards, starting with `fuel` units of
+    /// fuel. Each WebAssembly operator executed consumes one unit of fuel, and the execution traps
+    /// with `TrapKind::OutOfFuel` when the fuel runs out, unless the handler set with
+    /// `set_fuel_handler` adds more.
+    pub fn enable_fuel_metering(&mut self, fuel: u64) {
+        debug_assert!(!self.instantiated);
+        self.fuel = Some(Box::new(FuelCounter::new(fuel)));
+    }
+
+    /// Returns the remaining fuel, or `None` without fuel metering.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel.as_ref().map(|counter| counter.remaining())
+    }
+
+    /// Adds `fuel` units of fuel, for instance to call a function again after it ran out of
+    /// fuel. Does nothing without fuel metering.
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(ref mut counter) = self.fuel {
+            counter.add(fuel);
         }
     }
+
+    /// Sets the function called when the fuel runs out, which can add fuel to go on instead of
+    /// trapping. Does nothing without fuel metering.
+    pub fn set_fuel_handler(&mut self, handler: FuelHandler) {
+        if let Some(ref mut counter) = self.fuel {
+            counter.set_handler(handler);
+        }
+    }
+
+    /// Enables the interruption of the code translated afterwards with the handles returned by
+    /// `interrupt_handle`. The functions check the epoch of the runtime when they are entered and
+    /// at every loop iteration, and trap with `TrapKind::Interrupted` once it reached the
+    /// deadline, which is one epoch ahead initially and after each such trap.
+    pub fn enable_epoch_interruption(&mut self) {
+        debug_assert!(!self.instantiated);
+        self.epoch = Some(Epoch::new());
+    }
+
+    /// Returns a handle interrupting the code of the runtime from any thread, or `None` without
+    /// epoch interruption.
+    pub fn interrupt_handle(&self) -> Option<InterruptHandle> {
+        self.epoch
+            .as_ref()
+            .map(|epoch| InterruptHandle::new(epoch.clone()))
+    }
+
+    /// Sets the deadline `epochs` epochs after the current one, for instance to let the code run
+    /// until several interruptions. Does nothing without epoch interruption.
+    pub fn set_epoch_deadline(&mut self, epochs: usize) {
+        if let Some(ref epoch) = self.epoch {
+            epoch.set_deadline(epochs);
+        }
+    }
+
+    /// Limits the native stack that the code uses to `size` bytes below the stack pointer of the
+    /// thread entering it, beyond which the execution traps with `TrapKind::StackOverflow`. The
+    /// stack of the thread must be larger, since the frames of its caller are on it too. Enables
+    /// the check of the stack pointer in the prologue of the functions translated afterwards, see
+    /// `DEFAULT_MAX_STACK_SIZE` for a suggested size. Once enabled, the size can be changed for
+    /// the next calls into the code.
+    pub fn set_max_stack_size(&mut self, size: usize) {
+        match self.stack_limit {
+            Some(ref stack_limit) => stack_limit.set_max_size(size),
+            None => {
+                debug_assert!(!self.instantiated);
+                self.stack_limit = Some(Arc::new(StackLimit::new(size)));
+            }
+        }
+    }
+
+    /// Returns the size of the native stack the code can use, or `None` without limit.
+    pub fn max_stack_size(&self) -> Option<usize> {
+        self.stack_limit
+            .as_ref()
+            .map(|stack_limit| stack_limit.max_size())
+    }
+
+    // Stack limit set when entering the code, if any.
+    pub fn stack_limit(&self) -> Option<Arc<StackLimit>> {
+        self.stack_limit.clone()
+    }
+
+    // Takes the snapshot of the addresses read by the translation, once they are all allocated.
+    fn runtime_addresses(&self) -> RuntimeAddresses {
+        RuntimeAddresses {
+            globals_data: self.globals.data.as_ptr() as i64,
+            globals: self.globals
+                .info
+                .iter()
+                .map(|info| {
+                         GlobalAddress {
+                             ty: info.global.ty,
+                             offset: info.offset as i32,
+                             imported: info.import.is_some(),
+                         }
+                     })
+                .collect(),
+            memories: self.memories
+                .iter()
+                .map(|memory| match *memory {
+                         MemoryData::Defined(ref memory) => {
+                             MemoryAddresses::Defined {
+                                 memory: memory as *const LinearMemory as i64,
+                                 base: memory.base() as i64,
+                                 length: memory.length_address() as i64,
+                             }
+                         }
+                         MemoryData::Imported(_, ref slot) => {
+                             MemoryAddresses::Imported { slot: slot.slot_address() }
+                         }
+                     })
+                .collect(),
+            tables: self.tables
+                .iter()
+                .map(|table| match *table {
+                         TableData::Defined(ref table) => {
+                             TableAddresses::Defined {
+                                 base: table.base as i64,
+                                 length: table.length as i64,
+                             }
+                         }
+                         TableData::Imported(_, ref slot) => {
+                             TableAddresses::Imported { slot: slot.slot_address() }
+                         }
+                     })
+                .collect(),
+            signature_ids: self.signatures.iter().map(signature_id).collect(),
+            bounds_check: self.bounds_check,
+            fuel_counter: self.fuel
+                .as_ref()
+                .map(|counter| &**counter as *const FuelCounter as i64),
+            epoch: self.epoch
+                .as_ref()
+                .map(|epoch| &**epoch as *const Epoch as i64),
+            stack_limit: self.stack_limit
+                .as_ref()
+                .map(|stack_limit| &**stack_limit as *const StackLimit as i64),
+        }
+    }
+
+    fn addresses(&self) -> &RuntimeAddresses {
+        self.addresses
+            .as_ref()
+            .expect("the translation began")
+    }
 }
 
-/// This trait is useful for
-/// [`wasm2cretonne::translatemodule`](../wasm2cretonne/fn.translate_module.html) because it
-/// tells how to translate runtime-dependent wasm instructions. These functions should not be
-/// called by the user.
-impl WasmRuntime for StandaloneRuntime {
+// Global as read by the generated code: the globals data holds at `offset` its value, or the
+// address of its value when it is imported.
+#[derive(Clone, Copy)]
+struct GlobalAddress {
+    ty: Type,
+    offset: i32,
+    imported: bool,
+}
+
+// Addresses of the `LinearMemory` of a defined memory, of its base and of its length, or of the
+// slot holding the address of the `LinearMemory` of an imported memory.
+#[derive(Clone, Copy)]
+enum MemoryAddresses {
+    Defined { memory: i64, base: i64, length: i64 },
+    Imported { slot: i64 },
+}
+
+// Address of the entries of a defined table and their number, or address of the slot holding the
+// address of the `FunctionTable` of an imported table.
+#[derive(Clone, Copy)]
+enum TableAddresses {
+    Defined { base: i64, length: i64 },
+    Imported { slot: i64 },
+}
+
+// What the translation of the function bodies reads in a `StandaloneRuntime`: the addresses of
+// its globals, memories, tables, fuel counter, epoch and stack limit, which do not move once the
+// translation began, and the identifiers of its signatures. Being plain data, a copy of it
+// translates bodies on other threads without sharing the runtime.
+#[derive(Clone)]
+struct RuntimeAddresses {
+    globals_data: i64,
+    globals: Vec<GlobalAddress>,
+    memories: Vec<MemoryAddresses>,
+    tables: Vec<TableAddresses>,
+    signature_ids: Vec<usize>,
+    bounds_check: BoundsCheck,
+    fuel_counter: Option<i64>,
+    epoch: Option<i64>,
+    stack_limit: Option<i64>,
+}
+
+impl RuntimeAddresses {
+    // Emits the computation of the address and the offset of the value of a global.
+    fn translate_global_address(&self,
+                                builder: &mut FunctionBuilder<Local>,
+                                global_index: GlobalIndex)
+                                -> (Value, Offset32) {
+        let global = &self.globals[global_index];
+        let addr_val = builder.ins().iconst(I64, self.globals_data);
+        let offset = Offset32::new(global.offset);
+        if global.imported {
+            let value_addr_val = builder.ins().load(I64, MemFlags::new(), addr_val, offset);
+            (value_addr_val, Offset32::new(0))
+        } else {
+            (addr_val, offset)
+        }
+    }
+
     fn translate_get_global(&self,
                             builder: &mut FunctionBuilder<Local>,
                             global_index: GlobalIndex)
                             -> Value {
-        debug_assert!(self.instantiated);
-        let ty = self.globals.info[global_index as usize].global.ty;
-        let offset = self.globals.info[global_index as usize].offset;
-        let memflags = MemFlags::new();
-        let memoffset = Offset32::new(offset as i32);
-        let addr: i64 = unsafe { transmute(self.globals.data.as_ptr()) };
-        let addr_val = builder.ins().iconst(I64, addr);
-        builder.ins().load(ty, memflags, addr_val, memoffset)
+        let ty = self.globals[global_index].ty;
+        let (addr_val, memoffset) = self.translate_global_address(builder, global_index);
+        builder.ins().load(ty, MemFlags::new(), addr_val, memoffset)
     }
+
     fn translate_set_global(&self,
                             builder: &mut FunctionBuilder<Local>,
                             global_index: GlobalIndex,
                             val: Value) {
-        let offset = self.globals.info[global_index as usize].offset;
-        let memflags = MemFlags::new();
-        let memoffset = Offset32::new(offset as i32);
-        let addr: i64 = unsafe { transmute(self.globals.data.as_ptr()) };
-        let addr_val = builder.ins().iconst(I64, addr);
-        builder.ins().store(memflags, val, addr_val, memoffset);
+        let (addr_val, memoffset) = self.translate_global_address(builder, global_index);
+        builder.ins().store(MemFlags::new(), val, addr_val, memoffset);
+    }
+
+    // Emits the computation of the address of the `LinearMemory` of a memory.
+    fn translate_memory_pointer(&self,
+                                builder: &mut FunctionBuilder<Local>,
+                                memory_index: MemoryIndex)
+                                -> Value {
+        match self.memories[memory_index] {
+            // The memories are not moved after the beginning of the translation, so their
+            // address can be passed to the runtime functions.
+            MemoryAddresses::Defined { memory, .. } => builder.ins().iconst(I64, memory),
+            MemoryAddresses::Imported { slot } => load_import_slot(builder, slot),
+        }
     }
+
     fn translate_memory_base_address(&self,
                                      builder: &mut FunctionBuilder<Local>,
                                      memory_index: MemoryIndex)
                                      -> Value {
-        let addr: i64 = unsafe { transmute(self.memories[memory_index].data.as_ptr()) };
-        builder.ins().iconst(I64, addr)
+        match self.memories[memory_index] {
+            MemoryAddresses::Defined { base, .. } => builder.ins().iconst(I64, base),
+            MemoryAddresses::Imported { slot } => {
+                let memory_val = load_import_slot(builder, slot);
+                builder
+                    .ins()
+                    .load(I64, MemFlags::new(), memory_val, Offset32::new(BASE_OFFSET))
+            }
+        }
     }
-    fn translate_grow_memory(&mut self,
+
+    fn translate_memory_bounds_check(&self,
+                                     builder: &mut FunctionBuilder<Local>,
+                                     memory_index: MemoryIndex,
+                                     addr: Value,
+                                     offset: u32,
+                                     size: u32) {
+        let length_val = match self.memories[memory_index] {
+            MemoryAddresses::Defined { .. } if self.bounds_check == BoundsCheck::GuardPages => {
+                // Out-of-bounds accesses hit the guard pages.
+                return;
+            }
+            MemoryAddresses::Defined { length, .. } => {
+                let length_addr_val = builder.ins().iconst(I64, length);
+                builder
+                    .ins()
+                    .load(I64, MemFlags::new(), length_addr_val, Offset32::new(0))
+            }
+            // The memory of another module is not necessarily followed by guard pages.
+            MemoryAddresses::Imported { slot } => {
+                let memory_val = load_import_slot(builder, slot);
+                builder
+                    .ins()
+                    .load(I64, MemFlags::new(), memory_val, Offset32::new(LENGTH_OFFSET))
+            }
+        };
+        // The address and offset are 32 bits unsigned integers so their sum with the size of the
+        // access does not overflow in 64 bits.
+        let addr_i64 = builder.ins().uextend(I64, addr);
+        let end_val = builder.ins().iadd_imm(addr_i64, offset as i64 + size as i64);
+        trap_if_icmp(builder,
+                     None,
+                     TrapKind::MemoryOutOfBounds,
+                     IntCC::UnsignedGreaterThan,
+                     end_val,
+                     length_val);
+    }
+
+    // Emits the call to `wasmstandalone_grow_memory`, whose signature is imported in `sig` once
+    // per function.
+    fn translate_grow_memory(&self,
                              builder: &mut FunctionBuilder<Local>,
+                             sig: &mut Option<SigRef>,
+                             memory_index: MemoryIndex,
                              pages: Value)
                              -> Value {
-        debug_assert!(self.instantiated);
-        let grow_mem_func = match self.has_grow_memory {
-            Some(grow_mem_func) => grow_mem_func,
+        let sig_ref = match *sig {
+            Some(sig_ref) => sig_ref,
             None => {
-                let sig_ref =
-                    builder.import_signature(Signature {
-                                                 call_conv: CallConv::Native,
-                                                 argument_bytes: None,
-                                                 argument_types: vec![ArgumentType::new(I32)],
-                                                 return_types: vec![ArgumentType::new(I32)],
-                                             });
-                builder.import_function(ExtFuncData {
-                                            name: FunctionName::new("grow_memory"),
-                                            signature: sig_ref,
-                                        })
+                builder.import_signature(Signature {
+                                             call_conv: CallConv::Native,
+                                             argument_bytes: None,
+                                             argument_types: vec![ArgumentType::new(I64),
+                                                                  ArgumentType::new(I32)],
+                                             return_types: vec![ArgumentType::new(I32)],
+                                         })
             }
         };
-        self.has_grow_memory = Some(grow_mem_func);
-        let call_inst = builder.ins().call(grow_mem_func, &[pages]);
+        *sig = Some(sig_ref);
+        let memory_val = self.translate_memory_pointer(builder, memory_index);
+        let grow_mem_addr: i64 = unsafe { transmute(wasmstandalone_grow_memory as *const u8) };
+        let grow_mem_val = builder.ins().iconst(I64, grow_mem_addr);
+        let call_inst = builder
+            .ins()
+            .call_indirect(sig_ref, grow_mem_val, &[memory_val, pages]);
         *builder.inst_results(call_inst).first().unwrap()
     }
-    fn translate_current_memory(&mut self, builder: &mut FunctionBuilder<Local>) -> Value {
-        debug_assert!(self.instantiated);
-        let cur_mem_func = match self.has_current_memory {
-            Some(cur_mem_func) => cur_mem_func,
+
+    // Emits the call to `wasmstandalone_current_memory`, whose signature is imported in `sig`
+    // once per function.
+    fn translate_current_memory(&self,
+                                builder: &mut FunctionBuilder<Local>,
+                                sig: &mut Option<SigRef>,
+                                memory_index: MemoryIndex)
+                                -> Value {
+        let sig_ref = match *sig {
+            Some(sig_ref) => sig_ref,
             None => {
-                let sig_ref = builder.import_signature(Signature {
-                                                           call_conv: CallConv::Native,
-                                                           argument_bytes: None,
-                                                           argument_types: Vec::new(),
-                                                           return_types:
-                                                               vec![ArgumentType::new(I32)],
-                                                       });
-                builder.import_function(ExtFuncData {
-                                            name: FunctionName::new("current_memory"),
-                                            signature: sig_ref,
-                                        })
+                builder.import_signature(Signature {
+                                             call_conv: CallConv::Native,
+                                             argument_bytes: None,
+                                             argument_types: vec![ArgumentType::new(I64)],
+                                             return_types: vec![ArgumentType::new(I32)],
+                                         })
             }
         };
-        self.has_current_memory = Some(cur_mem_func);
-        let call_inst = builder.ins().call(cur_mem_func, &[]);
+        *sig = Some(sig_ref);
+        let memory_val = self.translate_memory_pointer(builder, memory_index);
+        let cur_mem_addr: i64 = unsafe { transmute(wasmstandalone_current_memory as *const u8) };
+        let cur_mem_val = builder.ins().iconst(I64, cur_mem_addr);
+        let call_inst = builder
+            .ins()
+            .call_indirect(sig_ref, cur_mem_val, &[memory_val]);
         *builder.inst_results(call_inst).first().unwrap()
     }
+
     fn translate_call_indirect<'a>(&self,
                                    builder: &'a mut FunctionBuilder<Local>,
+                                   table_index: TableIndex,
+                                   sig_index: SignatureIndex,
                                    sig_ref: SigRef,
                                    index_val: Value,
                                    call_args: &[Value])
                                    -> &'a [Value] {
-        let trap_ebb = builder.create_ebb();
-        let continue_ebb = builder.create_ebb();
-        let size_val = builder.ins().iconst(I32, self.tables[0].info.size as i64);
-        let zero_val = builder.ins().iconst(I32, 0);
-        builder
-            .ins()
-            .br_icmp(IntCC::UnsignedLessThan, index_val, zero_val, trap_ebb, &[]);
-        builder
-            .ins()
-            .br_icmp(IntCC::UnsignedGreaterThanOrEqual,
-                     index_val,
-                     size_val,
-                     trap_ebb,
-                     &[]);
-        builder.seal_block(trap_ebb);
-        let offset_val = builder.ins().imul_imm(index_val, 4);
-        let base_table_addr: i64 = unsafe { transmute(self.tables[0].data.as_ptr()) };
-        let table_addr_val = builder.ins().iconst(I32, base_table_addr);
-        let table_entry_addr_val = builder.ins().iadd(table_addr_val, offset_val);
-        let memflags = MemFlags::new();
-        let memoffset = Offset32::new(0);
-        let table_entry_val = builder
+        let (base_val, length_val) = match self.tables[table_index] {
+            TableAddresses::Defined { base, length } => {
+                (builder.ins().iconst(I64, base), builder.ins().iconst(I64, length))
+            }
+            TableAddresses::Imported { slot } => {
+                let table_val = load_import_slot(builder, slot);
+                (builder
+                     .ins()
+                     .load(I64, MemFlags::new(), table_val, Offset32::new(TABLE_BASE_OFFSET)),
+                 builder
+                     .ins()
+                     .load(I64, MemFlags::new(), table_val, Offset32::new(TABLE_LENGTH_OFFSET)))
+            }
+        };
+        let sig_id_val = builder
             .ins()
-            .load(I32, memflags, table_entry_addr_val, memoffset);
-        let call_inst = builder
+            .iconst(I64, self.signature_ids[sig_index] as i64);
+        translate_table_call(builder,
+                             None,
+                             base_val,
+                             length_val,
+                             sig_id_val,
+                             sig_ref,
+                             index_val,
+                             call_args)
+    }
+
+    fn translate_function_prologue(&self, builder: &mut FunctionBuilder<Local>) {
+        if let Some(stack_limit) = self.stack_limit {
+            let stack_limit_val = builder.ins().iconst(I64, stack_limit);
+            translate_check_stack(builder, None, stack_limit_val);
+        }
+    }
+
+    fn translate_consume_fuel(&self, builder: &mut FunctionBuilder<Local>, fuel: u32) {
+        let counter = self.fuel_counter.expect("fuel metering enabled");
+        let counter_val = builder.ins().iconst(I64, counter);
+        translate_consume_fuel(builder, counter_val, fuel);
+    }
+
+    fn translate_check_epoch(&self, builder: &mut FunctionBuilder<Local>) {
+        let epoch = self.epoch.expect("epoch interruption enabled");
+        let epoch_val = builder.ins().iconst(I64, epoch);
+        translate_check_epoch(builder, epoch_val);
+    }
+}
+
+// Emits the load of the address of an imported entity from its slot at `slot_addr`.
+fn load_import_slot(builder: &mut FunctionBuilder<Local>, slot_addr: i64) -> Value {
+    let slot_val = builder.ins().iconst(I64, slot_addr);
+    builder
+        .ins()
+        .load(I64, MemFlags::new(), slot_val, Offset32::new(0))
+}
+
+// Inserts a conditional branch to a new `Ebb` raising a trap of kind `kind`, and continues the
+// translation in a new `Ebb`. With a VM context `vmctx`, the address of the function raising the
+// trap is read from it instead of being hardcoded.
+pub fn trap_if_icmp(builder: &mut FunctionBuilder<Local>,
+                    vmctx: Option<Value>,
+                    kind: TrapKind,
+                    cond: IntCC,
+                    x: Value,
+                    y: Value) {
+    let trap_ebb = builder.create_ebb();
+    let continue_ebb = builder.create_ebb();
+    builder.ins().br_icmp(cond, x, y, trap_ebb, &[]);
+    builder.ins().jump(continue_ebb, &[]);
+    builder.seal_block(trap_ebb);
+    builder.seal_block(continue_ebb);
+    builder.switch_to_block(trap_ebb, &[]);
+    raise_trap(builder, vmctx, kind);
+    builder.switch_to_block(continue_ebb, &[]);
+}
+
+// Inserts a conditional branch to a new `Ebb` calling the runtime function at `function_addr` with
+// `arg`, and continues the translation in a new `Ebb`. The execution traps with `kind` when the
+// function returns 0, so the checks of the generated code can let the host decide whether to
+// go on while keeping the call out of the common path.
+pub fn call_if_icmp(builder: &mut FunctionBuilder<Local>,
+                    kind: TrapKind,
+                    cond: IntCC,
+                    x: Value,
+                    y: Value,
+                    function_addr: i64,
+                    arg: Value) {
+    let call_ebb = builder.create_ebb();
+    let continue_ebb = builder.create_ebb();
+    builder.ins().br_icmp(cond, x, y, call_ebb, &[]);
+    builder.ins().jump(continue_ebb, &[]);
+    builder.seal_block(call_ebb);
+    builder.switch_to_block(call_ebb, &[]);
+    let function_val = builder.ins().iconst(I64, function_addr);
+    translate_checked_call(builder, None, kind, function_val, &[arg]);
+    builder.ins().jump(continue_ebb, &[]);
+    builder.seal_block(continue_ebb);
+    builder.switch_to_block(continue_ebb, &[]);
+}
+
+// Calls the runtime function at `function_val` with the 64 bits arguments `args`, and traps with
+// `kind` when it returns 0.
+fn translate_checked_call(builder: &mut FunctionBuilder<Local>,
+                          vmctx: Option<Value>,
+                          kind: TrapKind,
+                          function_val: Value,
+                          args: &[Value]) {
+    let sig_ref = builder.import_signature(Signature {
+                                               call_conv: CallConv::Native,
+                                               argument_bytes: None,
+                                               argument_types: args.iter()
+                                                   .map(|_| ArgumentType::new(I64))
+                                                   .collect(),
+                                               return_types: vec![ArgumentType::new(I32)],
+                                           });
+    let call_inst = builder.ins().call_indirect(sig_ref, function_val, args);
+    let result_val = *builder.inst_results(call_inst).first().unwrap();
+    let false_val = builder.ins().iconst(I32, 0);
+    trap_if_icmp(builder, vmctx, kind, IntCC::Equal, result_val, false_val);
+}
+
+// Calls `wasmstandalone_raise_trap`, which does not return. The call is followed by a `trap`
+// instruction terminating the `Ebb`.
+fn raise_trap(builder: &mut FunctionBuilder<Local>, vmctx: Option<Value>, kind: TrapKind) {
+    let sig_ref = builder.import_signature(Signature {
+                                               call_conv: CallConv::Native,
+                                               argument_bytes: None,
+                                               argument_types: vec![ArgumentType::new(I32)],
+                                               return_types: Vec::new(),
+                                           });
+    let raise_trap_val = match vmctx {
+        None => {
+            let raise_trap_addr: i64 =
+                unsafe { transmute(wasmstandalone_raise_trap as *const u8) };
+            builder.ins().iconst(I64, raise_trap_addr)
+        }
+        Some(vmctx) => {
+            builder
+                .ins()
+                .load(I64,
+                      MemFlags::new(),
+                      vmctx,
+                      Offset32::new((RAISE_TRAP_ENTRY * VMCTX_ENTRY_SIZE) as i32))
+        }
+    };
+    let kind_val = builder.ins().iconst(I32, kind.code());
+    builder
+        .ins()
+        .call_indirect(sig_ref, raise_trap_val, &[kind_val]);
+    builder.ins().trap();
+}
+
+// Emits the lookup of the element `index_val` of a table whose entries start at `base_val` and
+// whose number of elements is `length_val`, the check of its signature identifier against
+// `sig_id_val` and the call to it. The traps are raised as in `trap_if_icmp`.
+pub fn translate_table_call<'a>(builder: &'a mut FunctionBuilder<Local>,
+                                vmctx: Option<Value>,
+                                base_val: Value,
+                                length_val: Value,
+                                sig_id_val: Value,
+                                sig_ref: SigRef,
+                                index_val: Value,
+                                call_args: &[Value])
+                                -> &'a [Value] {
+    let index_i64 = builder.ins().uextend(I64, index_val);
+    trap_if_icmp(builder,
+                 vmctx,
+                 TrapKind::TableOutOfBounds,
+                 IntCC::UnsignedGreaterThanOrEqual,
+                 index_i64,
+                 length_val);
+    let offset_val = builder
+        .ins()
+        .imul_imm(index_i64, size_of::<TableEntry>() as i64);
+    let table_entry_addr_val = builder.ins().iadd(base_val, offset_val);
+    let entry_sig_val = builder
+        .ins()
+        .load(I64,
+              MemFlags::new(),
+              table_entry_addr_val,
+              Offset32::new(size_of::<MemoryAddress>() as i32));
+    trap_if_icmp(builder,
+                 vmctx,
+                 TrapKind::IndirectCallTypeMismatch,
+                 IntCC::NotEqual,
+                 entry_sig_val,
+                 sig_id_val);
+    let entry_addr_val = builder
+        .ins()
+        .load(I64, MemFlags::new(), table_entry_addr_val, Offset32::new(0));
+    let mut call_args = call_args.to_vec();
+    if vmctx.is_some() {
+        // The function may come from another instance sharing the table.
+        let entry_vmctx_val = builder
             .ins()
-            .call_indirect(sig_ref, table_entry_val, call_args);
-        builder.ins().jump(continue_ebb, &[]);
-        builder.seal_block(continue_ebb);
-        builder.switch_to_block(trap_ebb, &[]);
-        builder.ins().trap();
-        builder.switch_to_block(continue_ebb, &[]);
-        builder.inst_results(call_inst)
+            .load(I64,
+                  MemFlags::new(),
+                  table_entry_addr_val,
+                  Offset32::new((size_of::<MemoryAddress>() + size_of::<usize>()) as i32));
+        *call_args.last_mut().unwrap() = entry_vmctx_val;
+    }
+    let call_inst = builder
+        .ins()
+        .call_indirect(sig_ref, entry_addr_val, &call_args);
+    builder.inst_results(call_inst)
+}
+
+/// This trait is useful for
+/// [`wasm2cretonne::translatemodule`](../wasm2cretonne/fn.translate_module.html) because it
+/// tells how to translate runtime-dependent wasm instructions. These functions should not be
+/// called by the user.
+impl WasmRuntime for StandaloneRuntime {
+    fn declare_signature(&mut self, sig: &Signature) {
+        debug_assert!(!self.instantiated);
+        self.signatures.push(sig.clone());
+    }
+    fn translate_get_global(&self,
+                            builder: &mut FunctionBuilder<Local>,
+                            global_index: GlobalIndex)
+                            -> Value {
+        self.addresses()
+            .translate_get_global(builder, global_index)
+    }
+    fn translate_set_global(&self,
+                            builder: &mut FunctionBuilder<Local>,
+                            global_index: GlobalIndex,
+                            val: Value) {
+        self.addresses()
+            .translate_set_global(builder, global_index, val)
+    }
+    fn translate_memory_base_address(&self,
+                                     builder: &mut FunctionBuilder<Local>,
+                                     memory_index: MemoryIndex)
+                                     -> Value {
+        self.addresses()
+            .translate_memory_base_address(builder, memory_index)
+    }
+    fn translate_memory_bounds_check(&self,
+                                     builder: &mut FunctionBuilder<Local>,
+                                     memory_index: MemoryIndex,
+                                     addr: Value,
+                                     offset: u32,
+                                     size: u32) {
+        self.addresses()
+            .translate_memory_bounds_check(builder, memory_index, addr, offset, size)
+    }
+    fn translate_grow_memory(&mut self,
+                             builder: &mut FunctionBuilder<Local>,
+                             memory_index: MemoryIndex,
+                             pages: Value)
+                             -> Value {
+        let mut sig = self.grow_memory_sig;
+        let pages = self.addresses()
+            .translate_grow_memory(builder, &mut sig, memory_index, pages);
+        self.grow_memory_sig = sig;
+        pages
+    }
+    fn translate_current_memory(&mut self,
+                                builder: &mut FunctionBuilder<Local>,
+                                memory_index: MemoryIndex)
+                                -> Value {
+        let mut sig = self.current_memory_sig;
+        let pages = self.addresses()
+            .translate_current_memory(builder, &mut sig, memory_index);
+        self.current_memory_sig = sig;
+        pages
+    }
+    fn translate_call_indirect<'a>(&self,
+                                   builder: &'a mut FunctionBuilder<Local>,
+                                   table_index: TableIndex,
+                                   sig_index: SignatureIndex,
+                                   sig_ref: SigRef,
+                                   index_val: Value,
+                                   call_args: &[Value])
+                                   -> &'a [Value] {
+        self.addresses()
+            .translate_call_indirect(builder,
+                                     table_index,
+                                     sig_index,
+                                     sig_ref,
+                                     index_val,
+                                     call_args)
     }
 
     fn begin_translation(&mut self) {
         debug_assert!(!self.instantiated);
         self.instantiated = true;
-        // At instantiation, we allocate memory for the globals, the memories and the tables
-        // First the globals
+        // At instantiation, we allocate memory for the globals. They are initialized once the
+        // imports are resolved.
         let mut globals_data_size = 0;
         for globalinfo in self.globals.info.iter_mut() {
             globalinfo.offset = globals_data_size;
-            globals_data_size += globalinfo.global.ty.bytes() as usize;
+            globals_data_size += match globalinfo.import {
+                None => globalinfo.global.ty.bytes() as usize,
+                Some(_) => size_of::<usize>(),
+            };
         }
         self.globals.data.resize(globals_data_size as usize, 0);
-        for globalinfo in self.globals.info.iter() {
-            match globalinfo.global.initializer {
-                GlobalInit::I32Const(val) => unsafe {
-                    write(self.globals
-                              .data
-                              .as_mut_ptr()
-                              .offset(globalinfo.offset as isize) as
-                          *mut i32,
-                          val)
-                },
-                GlobalInit::I64Const(val) => unsafe {
-                    write(self.globals
-                              .data
-                              .as_mut_ptr()
-                              .offset(globalinfo.offset as isize) as
-                          *mut i64,
-                          val)
-                },
-                GlobalInit::F32Const(val) => unsafe {
-                    write(self.globals
-                              .data
-                              .as_mut_ptr()
-                              .offset(globalinfo.offset as isize) as
-                          *mut f32,
-                          transmute(val))
-                },
-                GlobalInit::F64Const(val) => unsafe {
-                    write(self.globals
-                              .data
-                              .as_mut_ptr()
-                              .offset(globalinfo.offset as isize) as
-                          *mut f64,
-                          transmute(val))
-                },
-                GlobalInit::Import() => {
-                    // We don't initialize, this is inter-module linking
-                    // TODO: support inter-module imports
-                }
-                GlobalInit::GlobalRef(index) => {
-                    let ref_offset = self.globals.info[index].offset;
-                    let size = globalinfo.global.ty.bytes();
-                    unsafe {
-                        let dst = self.globals
-                            .data
-                            .as_mut_ptr()
-                            .offset(globalinfo.offset as isize);
-                        let src = self.globals.data.as_ptr().offset(ref_offset as isize);
-                        copy_nonoverlapping(src, dst, size as usize)
-                    }
-                }
-            }
-        }
+        self.addresses = Some(self.runtime_addresses());
     }
     fn next_function(&mut self) {
-        self.has_current_memory = None;
-        self.has_grow_memory = None;
+        self.current_memory_sig = None;
+        self.grow_memory_sig = None;
+    }
+    fn translation_runtime(&self) -> Option<Box<WasmRuntime + Send>> {
+        Some(Box::new(BodyTranslator {
+                          addresses: self.addresses().clone(),
+                          current_memory_sig: None,
+                          grow_memory_sig: None,
+                      }))
+    }
+    fn pointer_type(&self) -> Type {
+        // The code can only be compiled for 64 bits Intel, see `executable_isa`.
+        I64
+    }
+    fn vmctx_type(&self) -> Option<Type> {
+        // The addresses are hardcoded in the code instead.
+        None
+    }
+    fn set_vmctx(&mut self, _: Value) {}
+    fn translate_function_prologue(&self, builder: &mut FunctionBuilder<Local>) {
+        self.addresses().translate_function_prologue(builder)
+    }
+    fn fuel_metering(&self) -> bool {
+        self.fuel.is_some()
+    }
+    fn translate_consume_fuel(&self, builder: &mut FunctionBuilder<Local>, fuel: u32) {
+        self.addresses().translate_consume_fuel(builder, fuel)
+    }
+    fn epoch_interruption(&self) -> bool {
+        self.epoch.is_some()
+    }
+    fn translate_check_epoch(&self, builder: &mut FunctionBuilder<Local>) {
+        self.addresses().translate_check_epoch(builder)
     }
     fn declare_global(&mut self, global: Global) {
         debug_assert!(!self.instantiated);
@@ -266,67 +923,399 @@ impl WasmRuntime for StandaloneRuntime {
             .push(GlobalInfo {
                       global: global,
                       offset: 0,
+                      import: None,
+                  });
+    }
+    fn declare_global_import(&mut self,
+                             global: Global,
+                             module: &str,
+                             field: &str)
+                             -> Result<(), String> {
+        debug_assert!(!self.instantiated);
+        self.globals
+            .info
+            .push(GlobalInfo {
+                      global: global,
+                      offset: 0,
+                      import: Some((String::from(module), String::from(field))),
                   });
+        Ok(())
     }
     fn declare_table(&mut self, table: Table) {
         debug_assert!(!self.instantiated);
-        let mut elements_vec = Vec::with_capacity(table.size as usize);
-        elements_vec.resize(table.size as usize, TableElement::Trap());
-        let mut addresses_vec = Vec::with_capacity(table.size as usize);
-        addresses_vec.resize(table.size as usize, 0);
         self.tables
-            .push(TableData {
-                      info: table,
-                      data: addresses_vec,
-                      elements: elements_vec,
-                  });
+            .push(TableData::Defined(FunctionTable::new(table.size, table.maximum)));
+    }
+    fn declare_table_import(&mut self,
+                            table: Table,
+                            module: &str,
+                            field: &str)
+                            -> Result<(), String> {
+        debug_assert!(!self.instantiated);
+        self.tables
+            .push(TableData::Imported(table, ImportSlot::new(module, field)));
+        Ok(())
     }
     fn declare_table_elements(&mut self,
                               table_index: TableIndex,
+                              base: Option<GlobalIndex>,
                               offset: usize,
                               elements: &[FunctionIndex]) {
         debug_assert!(!self.instantiated);
-        for (i, elt) in elements.iter().enumerate() {
-            self.tables[table_index].elements[offset as usize + i] = TableElement::Function(*elt);
+        self.table_segments
+            .push(TableSegment {
+                      table_index,
+                      base,
+                      offset,
+                      elements: elements.to_vec(),
+                  });
+    }
+    fn declare_memory(&mut self, memory: Memory) -> Result<(), String> {
+        debug_assert!(!self.instantiated);
+        if memory.pages_count > MAX_PAGES || memory.maximum.map_or(false, |max| max > MAX_PAGES) {
+            return Err(format!("memory larger than {} pages", MAX_PAGES));
         }
+        // The memory never moves, so it reserves the space it can grow into.
+        let reserved = match self.bounds_check {
+            BoundsCheck::Explicit => memory.maximum.unwrap_or(MAX_PAGES) * PAGE_SIZE,
+            // An access at the largest address plus the largest offset ends up to 7 bytes past
+            // the 8 GiB.
+            BoundsCheck::GuardPages => (2 * MAX_PAGES + 1) * PAGE_SIZE,
+        };
+        let linear_memory = LinearMemory::new(memory.pages_count, memory.maximum, reserved)?;
+        self.memories.push(MemoryData::Defined(linear_memory));
+        Ok(())
     }
-    fn declare_memory(&mut self, memory: Memory) {
+    fn declare_memory_import(&mut self,
+                             memory: Memory,
+                             module: &str,
+                             field: &str)
+                             -> Result<(), String> {
         debug_assert!(!self.instantiated);
-        let mut memory_vec = Vec::with_capacity(memory.pages_count as usize * PAGE_SIZE);
-        memory_vec.resize(memory.pages_count as usize * PAGE_SIZE, 0);
         self.memories
-            .push(MemoryData {
-                      info: memory,
-                      data: memory_vec,
-                  });
+            .push(MemoryData::Imported(memory, ImportSlot::new(module, field)));
+        Ok(())
     }
     fn declare_data_initialization(&mut self,
                                    memory_index: MemoryIndex,
+                                   base: Option<GlobalIndex>,
                                    offset: usize,
                                    data: &[u8])
                                    -> Result<(), String> {
-        if offset + data.len() > self.memories[memory_index].info.pages_count * PAGE_SIZE {
-            return Err(String::from("initialization data out of bounds"));
+        self.data_segments
+            .push(DataSegment {
+                      memory_index,
+                      base,
+                      offset,
+                      data: data.to_vec(),
+                  });
+        Ok(())
+    }
+}
+
+// Translates function bodies on another thread like the `StandaloneRuntime` it was created by,
+// from a copy of the addresses it read. The declarations are all made to the runtime itself.
+struct BodyTranslator {
+    addresses: RuntimeAddresses,
+    current_memory_sig: Option<SigRef>,
+    grow_memory_sig: Option<SigRef>,
+}
+
+impl WasmRuntime for BodyTranslator {
+    fn declare_signature(&mut self, _: &Signature) {
+        unreachable!()
+    }
+    fn declare_global(&mut self, _: Global) {
+        unreachable!()
+    }
+    fn declare_global_import(&mut self, _: Global, _: &str, _: &str) -> Result<(), String> {
+        unreachable!()
+    }
+    fn declare_table(&mut self, _: Table) {
+        unreachable!()
+    }
+    fn declare_table_import(&mut self, _: Table, _: &str, _: &str) -> Result<(), String> {
+        unreachable!()
+    }
+    fn declare_table_elements(&mut self,
+                              _: TableIndex,
+                              _: Option<GlobalIndex>,
+                              _: usize,
+                              _: &[FunctionIndex]) {
+        unreachable!()
+    }
+    fn declare_memory(&mut self, _: Memory) -> Result<(), String> {
+        unreachable!()
+    }
+    fn declare_memory_import(&mut self, _: Memory, _: &str, _: &str) -> Result<(), String> {
+        unreachable!()
+    }
+    fn declare_data_initialization(&mut self,
+                                   _: MemoryIndex,
+                                   _: Option<GlobalIndex>,
+                                   _: usize,
+                                   _: &[u8])
+                                   -> Result<(), String> {
+        unreachable!()
+    }
+    fn begin_translation(&mut self) {
+        unreachable!()
+    }
+    fn next_function(&mut self) {
+        self.current_memory_sig = None;
+        self.grow_memory_sig = None;
+    }
+    fn pointer_type(&self) -> Type {
+        // As for the runtime.
+        I64
+    }
+    fn vmctx_type(&self) -> Option<Type> {
+        None
+    }
+    fn set_vmctx(&mut self, _: Value) {}
+    fn translate_function_prologue(&self, builder: &mut FunctionBuilder<Local>) {
+        self.addresses.translate_function_prologue(builder)
+    }
+    fn fuel_metering(&self) -> bool {
+        self.addresses.fuel_counter.is_some()
+    }
+    fn translate_consume_fuel(&self, builder: &mut FunctionBuilder<Local>, fuel: u32) {
+        self.addresses.translate_consume_fuel(builder, fuel)
+    }
+    fn epoch_interruption(&self) -> bool {
+        self.addresses.epoch.is_some()
+    }
+    fn translate_check_epoch(&self, builder: &mut FunctionBuilder<Local>) {
+        self.addresses.translate_check_epoch(builder)
+    }
+    fn translate_get_global(&self,
+                            builder: &mut FunctionBuilder<Local>,
+                            global_index: GlobalIndex)
+                            -> Value {
+        self.addresses.translate_get_global(builder, global_index)
+    }
+    fn translate_set_global(&self,
+                            builder: &mut FunctionBuilder<Local>,
+                            global_index: GlobalIndex,
+                            val: Value) {
+        self.addresses
+            .translate_set_global(builder, global_index, val)
+    }
+    fn translate_grow_memory(&mut self,
+                             builder: &mut FunctionBuilder<Local>,
+                             memory_index: MemoryIndex,
+                             pages: Value)
+                             -> Value {
+        self.addresses
+            .translate_grow_memory(builder, &mut self.grow_memory_sig, memory_index, pages)
+    }
+    fn translate_current_memory(&mut self,
+                                builder: &mut FunctionBuilder<Local>,
+                                memory_index: MemoryIndex)
+                                -> Value {
+        self.addresses
+            .translate_current_memory(builder, &mut self.current_memory_sig, memory_index)
+    }
+    fn translate_memory_base_address(&self,
+                                     builder: &mut FunctionBuilder<Local>,
+                                     memory_index: MemoryIndex)
+                                     -> Value {
+        self.addresses
+            .translate_memory_base_address(builder, memory_index)
+    }
+    fn translate_memory_bounds_check(&self,
+                                     builder: &mut FunctionBuilder<Local>,
+                                     memory_index: MemoryIndex,
+                                     addr: Value,
+                                     offset: u32,
+                                     size: u32) {
+        self.addresses
+            .translate_memory_bounds_check(builder, memory_index, addr, offset, size)
+    }
+    fn translate_call_indirect<'a>(&self,
+                                   builder: &'a mut FunctionBuilder<Local>,
+                                   table_index: TableIndex,
+                                   sig_index: SignatureIndex,
+                                   sig_ref: SigRef,
+                                   index_val: Value,
+                                   call_args: &[Value])
+                                   -> &'a [Value] {
+        self.addresses
+            .translate_call_indirect(builder,
+                                     table_index,
+                                     sig_index,
+                                     sig_ref,
+                                     index_val,
+                                     call_args)
+    }
+}
+
+/// Instantiates the module translated with `runtime` once its code is laid out: resolves its
+/// imported globals, tables and memories with `resolver`, initializes its globals and writes the
+/// elements and data segments. `functions_signatures` gives the index in the module of the
+/// signature of each function, and `vmctx` the VM context of the instance when it is compiled
+/// with the `VmContextRuntime`. Nothing is written if a segment does not fit.
+pub fn instantiate(runtime: &mut StandaloneRuntime,
+                   resolver: &ImportResolver,
+                   functions_addresses: &[*const u8],
+                   functions_signatures: &[SignatureIndex],
+                   vmctx: Option<*const usize>)
+                   -> Result<(), String> {
+    runtime.resolve_imports(resolver)?;
+    runtime.initialize_globals();
+    let mut table_starts = Vec::with_capacity(runtime.table_segments.len());
+    for segment in runtime.table_segments.iter() {
+        let start = segment.offset + runtime.segment_base(segment.base);
+        let table = unsafe { &*runtime.table(segment.table_index) };
+        if start + segment.elements.len() > table.size() {
+            return Err(String::from("elements segment does not fit"));
+        }
+        table_starts.push(start);
+    }
+    let mut data_starts = Vec::with_capacity(runtime.data_segments.len());
+    for segment in runtime.data_segments.iter() {
+        let start = segment.offset + runtime.segment_base(segment.base);
+        let memory = unsafe { &*runtime.memory(segment.memory_index) };
+        if start + segment.data.len() > memory.as_slice().len() {
+            return Err(String::from("data segment does not fit"));
+        }
+        data_starts.push(start);
+    }
+    for (segment, start) in runtime.table_segments.iter().zip(table_starts) {
+        let table = unsafe { &mut *runtime.table(segment.table_index) };
+        for (i, &function_index) in segment.elements.iter().enumerate() {
+            let sig = &runtime.signatures[functions_signatures[function_index]];
+            table.entries[start + i] = TableEntry {
+                address: functions_addresses[function_index] as MemoryAddress,
+                signature: signature_id(sig),
+                vmctx: vmctx.map_or(0, |vmctx| vmctx as usize),
+            };
+        }
+    }
+    for (segment, start) in runtime.data_segments.iter().zip(data_starts) {
+        let memory = unsafe { &mut *runtime.memory(segment.memory_index) };
+        memory.as_mut_slice()[start..start + segment.data.len()].copy_from_slice(&segment.data);
+    }
+    Ok(())
+}
+
+impl StandaloneRuntime {
+    // Writes the addresses of the imported entities in their slots.
+    fn resolve_imports(&mut self, resolver: &ImportResolver) -> Result<(), String> {
+        for info in self.globals.info.iter() {
+            if let Some((ref module, ref field)) = info.import {
+                let address = resolver.resolve_global(module, field, &info.global)?;
+                unsafe {
+                    write(self.globals
+                              .data
+                              .as_mut_ptr()
+                              .offset(info.offset as isize) as *mut usize,
+                          address as usize)
+                };
+            }
+        }
+        for table in self.tables.iter_mut() {
+            if let TableData::Imported(ref info, ref mut slot) = *table {
+                let address = resolver.resolve_table(&slot.module, &slot.field, info)?;
+                *slot.address = address as usize;
+            }
+        }
+        for memory in self.memories.iter_mut() {
+            if let MemoryData::Imported(ref info, ref mut slot) = *memory {
+                let address = resolver.resolve_memory(&slot.module, &slot.field, info)?;
+                *slot.address = address as usize;
+            }
         }
-        self.memories[memory_index].data[offset..offset + data.len()].copy_from_slice(data);
         Ok(())
     }
+
+    // Initializes the globals defined by the module, after the imported ones are resolved.
+    fn initialize_globals(&mut self) {
+        for global_index in 0..self.globals.info.len() {
+            let global = self.globals.info[global_index].global;
+            let (dst, _) = self.global(global_index);
+            unsafe {
+                match global.initializer {
+                    GlobalInit::I32Const(val) => write(dst as *mut i32, val),
+                    GlobalInit::I64Const(val) => write(dst as *mut i64, val),
+                    GlobalInit::F32Const(val) => write(dst as *mut u32, val),
+                    GlobalInit::F64Const(val) => write(dst as *mut u64, val),
+                    GlobalInit::Import() => {
+                        // The value belongs to the module exporting the global.
+                    }
+                    GlobalInit::GlobalRef(index) => {
+                        let (src, _) = self.global(index);
+                        copy_nonoverlapping(src, dst, global.ty.bytes() as usize)
+                    }
+                }
+            }
+        }
+    }
+
+    // Value of the global `base` of a segment, an unsigned offset.
+    fn segment_base(&self, base: Option<GlobalIndex>) -> usize {
+        match base {
+            None => 0,
+            Some(global_index) => {
+                let (address, _) = self.global(global_index);
+                unsafe { read(address as *const u32) as usize }
+            }
+        }
+    }
+}
+
+/// Access to the globals, tables and memories of the module, for example to provide them to the
+/// modules importing them. The imported ones are only available after instantiation.
+impl StandaloneRuntime {
+    /// Returns the address of the value of the global `global_index`, and its declaration.
+    pub fn global(&self, global_index: GlobalIndex) -> (*mut u8, &Global) {
+        let info = &self.globals.info[global_index];
+        let data = unsafe { self.globals.data.as_ptr().offset(info.offset as isize) };
+        let address = match info.import {
+            None => data as *mut u8,
+            Some(_) => unsafe { read(data as *const usize) as *mut u8 },
+        };
+        (address, &info.global)
+    }
+    /// Returns the table `table_index`.
+    pub fn table(&self, table_index: TableIndex) -> *mut FunctionTable {
+        match self.tables[table_index] {
+            TableData::Defined(ref table) => table as *const FunctionTable as *mut FunctionTable,
+            TableData::Imported(_, ref slot) => *slot.address as *mut FunctionTable,
+        }
+    }
+    /// Returns the memory `memory_index`.
+    pub fn memory(&self, memory_index: MemoryIndex) -> *mut LinearMemory {
+        match self.memories[memory_index] {
+            MemoryData::Defined(ref memory) => memory as *const LinearMemory as *mut LinearMemory,
+            MemoryData::Imported(_, ref slot) => *slot.address as *mut LinearMemory,
+        }
+    }
+    /// Whether the instantiation writes elements in tables imported from other modules, which
+    /// then refer to the code of this one.
+    pub fn writes_imported_tables(&self) -> bool {
+        self.table_segments
+            .iter()
+            .any(|segment| match self.tables[segment.table_index] {
+                     TableData::Imported(..) => true,
+                     TableData::Defined(_) => false,
+                 })
+    }
 }
 
 /// Convenience functions for the user to be called after execution for debug purposes.
 impl StandaloneRuntime {
     /// Returns a slice of the contents of allocated linear memory.
     pub fn inspect_memory(&self, memory_index: usize, address: usize, len: usize) -> &[u8] {
-        &self.memories
-             .get(memory_index)
-             .expect(format!("no memory for index {}", memory_index).as_str())
-             .data
-             [address..address + len]
+        if memory_index >= self.memories.len() {
+            panic!("no memory for index {}", memory_index);
+        }
+        let memory = unsafe { &*self.memory(memory_index) };
+        &memory.as_slice()[address..address + len]
     }
     /// Shows the value of a global variable.
     pub fn inspect_global(&self, global_index: usize) -> &[u8] {
-        let (offset, len) = (self.globals.info[global_index].offset,
-                             self.globals.info[global_index].global.ty.bytes() as usize);
-        &self.globals.data[offset..offset + len]
+        let (address, global) = self.global(global_index);
+        unsafe { slice::from_raw_parts(address, global.ty.bytes() as usize) }
     }
 }
@@ -0,0 +1,19 @@
+//! Helpers shared by the tests of the crate.
+use wasm2cretonne::translate_module;
+use execution::{compile_module, CompileOptions, ExecutableCode, NoImports};
+use standalone::StandaloneRuntime;
+
+// Builds a module out of its encoded sections.
+pub fn module(sections: &[&[u8]]) -> Vec<u8> {
+    let mut data = vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+    for section in sections {
+        data.extend_from_slice(section);
+    }
+    data
+}
+
+// Translates and compiles the module `data` with `runtime`, which is instantiated without imports.
+pub fn compile(data: &Vec<u8>, runtime: &mut StandaloneRuntime) -> ExecutableCode {
+    let translation = translate_module(data, runtime).unwrap();
+    compile_module(&translation, runtime, &NoImports, &CompileOptions::default()).unwrap()
+}
@@ -0,0 +1,86 @@
+//! Entry trampolines, through which Rust code calls the generated code.
+//!
+//! The generated code does not preserve the registers that the System V ABI requires a callee to
+//! save, and its arguments depend on its signature. A trampoline, specific to a signature, is a
+//! native function following the System V ABI, of type `TrampolineFn`, that saves these
+//! registers, loads the arguments of the generated function from a buffer, calls it and stores
+//! its return value at the beginning of the buffer.
+use cretonne::ir::Signature;
+use cretonne::ir::types::*;
+
+/// Type of the trampolines: the first argument is the address of the function to call, the
+/// second points to a buffer with one 64 bits slot per argument, and at least one slot.
+pub type TrampolineFn = extern "C" fn(*const u8, *mut u64);
+
+// Registers encodings, integer arguments registers in the order of the calling convention.
+const INT_ARGS_REGS: [u8; 6] = [7 /* rdi */, 6 /* rsi */, 2 /* rdx */, 1 /* rcx */,
+                                8 /* r8 */, 9 /* r9 */];
+const FLOAT_ARGS_REGS: usize = 8;
+
+/// Emits the code of the trampoline for functions of signature `sig`.
+pub fn emit_trampoline(sig: &Signature) -> Vec<u8> {
+    let mut code = Vec::new();
+    // push rbp; mov rbp, rsp
+    code.extend_from_slice(&[0x55, 0x48, 0x89, 0xe5]);
+    // push rbx; push r12; push r13; push r14; push r15
+    code.extend_from_slice(&[0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+    // mov r12, rsi (the values buffer); mov r13, rdi (the callee)
+    code.extend_from_slice(&[0x49, 0x89, 0xf4, 0x49, 0x89, 0xfd]);
+    // Assigns the arguments to registers or to the stack.
+    let (mut int_count, mut float_count) = (0, 0);
+    let mut stack_args = Vec::new();
+    for (i, arg) in sig.argument_types.iter().enumerate() {
+        let disp = 8 * i as u32;
+        if arg.value_type == F32 || arg.value_type == F64 {
+            if float_count < FLOAT_ARGS_REGS {
+                // movss/movsd xmm, [r12 + disp]
+                let prefix = if arg.value_type == F32 { 0xf3 } else { 0xf2 };
+                let modrm = 0x84 | (float_count as u8) << 3;
+                code.extend_from_slice(&[prefix, 0x41, 0x0f, 0x10, modrm, 0x24]);
+                push_u32(&mut code, disp);
+                float_count += 1;
+                continue;
+            }
+        } else if int_count < INT_ARGS_REGS.len() {
+            // mov reg, [r12 + disp]
+            let reg = INT_ARGS_REGS[int_count];
+            code.extend_from_slice(&[0x49 | (reg >> 3) << 2, 0x8b, 0x84 | (reg & 7) << 3, 0x24]);
+            push_u32(&mut code, disp);
+            int_count += 1;
+            continue;
+        }
+        stack_args.push(disp);
+    }
+    // The stack is 16 bytes aligned at the call, the pushes above left it 8 bytes off.
+    if stack_args.len() % 2 == 0 {
+        // sub rsp, 8
+        code.extend_from_slice(&[0x48, 0x83, 0xec, 0x08]);
+    }
+    for &disp in stack_args.iter().rev() {
+        // push qword [r12 + disp]
+        code.extend_from_slice(&[0x41, 0xff, 0xb4, 0x24]);
+        push_u32(&mut code, disp);
+    }
+    // call r13
+    code.extend_from_slice(&[0x41, 0xff, 0xd5]);
+    match sig.return_types.first().map(|ret| ret.value_type) {
+        None => {}
+        // movss [r12], xmm0
+        Some(F32) => code.extend_from_slice(&[0xf3, 0x41, 0x0f, 0x11, 0x04, 0x24]),
+        // movsd [r12], xmm0
+        Some(F64) => code.extend_from_slice(&[0xf2, 0x41, 0x0f, 0x11, 0x04, 0x24]),
+        // mov [r12], rax
+        Some(_) => code.extend_from_slice(&[0x49, 0x89, 0x04, 0x24]),
+    }
+    // lea rsp, [rbp - 40]
+    code.extend_from_slice(&[0x48, 0x8d, 0x65, 0xd8]);
+    // pop r15; pop r14; pop r13; pop r12; pop rbx; pop rbp; ret
+    code.extend_from_slice(&[0x41, 0x5f, 0x41, 0x5e, 0x41, 0x5d, 0x41, 0x5c, 0x5b, 0x5d, 0xc3]);
+    code
+}
+
+fn push_u32(code: &mut Vec<u8>, val: u32) {
+    for i in 0..4 {
+        code.push((val >> (8 * i)) as u8);
+    }
+}
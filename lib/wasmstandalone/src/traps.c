@@ -0,0 +1,49 @@
+/* Non-local exits out of the generated code when it traps. The Rust side can't use setjmp and
+ * longjmp directly, so the calls into the generated code go through these functions. The
+ * thread-local variables use the initial-exec model so that the signal handler can read them
+ * without allocating. */
+#include <setjmp.h>
+
+#define WASMSTANDALONE_TLS static __thread __attribute__((tls_model("initial-exec")))
+
+/* Jump buffer of the innermost call into the generated code of the thread. */
+WASMSTANDALONE_TLS sigjmp_buf *wasmstandalone_jmp_buf = 0;
+/* State of the innermost call, owned by the Rust side. */
+WASMSTANDALONE_TLS void *wasmstandalone_state = 0;
+
+void wasmstandalone_record_trap(int kind, void *pc);
+
+/* Calls `callback(payload)` with `state` as state of the call and returns 1, or returns 0 if the
+ * code trapped. */
+int wasmstandalone_call(void (*callback)(void *), void *payload, void *state) {
+    sigjmp_buf buf;
+    sigjmp_buf *previous = wasmstandalone_jmp_buf;
+    void *previous_state = wasmstandalone_state;
+    wasmstandalone_jmp_buf = &buf;
+    wasmstandalone_state = state;
+    if (sigsetjmp(buf, 1) != 0) {
+        wasmstandalone_jmp_buf = previous;
+        wasmstandalone_state = previous_state;
+        return 0;
+    }
+    callback(payload);
+    wasmstandalone_jmp_buf = previous;
+    wasmstandalone_state = previous_state;
+    return 1;
+}
+
+/* Returns the state of the innermost call into the generated code, or 0 outside of it. */
+void *wasmstandalone_current_state(void) {
+    return wasmstandalone_state;
+}
+
+/* Returns to the innermost call of `wasmstandalone_call`. */
+void wasmstandalone_unwind(void) {
+    siglongjmp(*wasmstandalone_jmp_buf, 1);
+}
+
+/* Called by the generated code to trap: the return address identifies the trapping code. */
+void wasmstandalone_raise_trap(int kind) {
+    wasmstandalone_record_trap(kind, __builtin_return_address(0));
+    wasmstandalone_unwind();
+}
@@ -0,0 +1,354 @@
+//! Recovery from the traps of the generated code.
+//!
+//! The generated code traps either with a hardware exception (illegal instruction, arithmetic
+//! error or memory fault), caught by the signal handlers installed here, or by calling
+//! `wasmstandalone_raise_trap` from the runtime checks. In both cases the location of the trap is
+//! recorded and the execution resumes where the generated code was called, thanks to the
+//! `setjmp`/`longjmp` helpers of `traps.c`. The handlers run on an alternate signal stack of the
+//! thread, so that a trap is still caught when the stack of the thread is exhausted.
+use libc::{self, c_int, c_void};
+use wasm2cretonne::FunctionIndex;
+pub use wasm2cretonne::TrapKind;
+use std::any::Any;
+use std::fmt;
+use std::mem;
+use std::ptr;
+use std::panic::resume_unwind;
+use std::sync::{Once, ONCE_INIT};
+use stack::{StackLimit, stack_pointer};
+
+/// A trap of the generated code, located by the function in which it happened and the offset of
+/// the trapping instruction in the code of this function. The name of the function, if any, is
+/// given by the `CodeLookup` of the code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trap {
+    pub kind: TrapKind,
+    pub function_index: FunctionIndex,
+    pub offset: usize,
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self.kind {
+            TrapKind::Unreachable => "unreachable code or invalid conversion to integer",
+            TrapKind::IntegerArithmetic => "integer divide by zero or integer overflow",
+            TrapKind::MemoryOutOfBounds => "out of bounds memory access",
+            TrapKind::TableOutOfBounds => "undefined table element",
+            TrapKind::IndirectCallTypeMismatch => {
+                "indirect call type mismatch or uninitialized element"
+            }
+            TrapKind::OutOfFuel => "all fuel consumed",
+            TrapKind::StackOverflow => "call stack exhausted",
+            TrapKind::Interrupted => "interrupted",
+        };
+        write!(f,
+               "trap: {} in function {} at offset {:#x}",
+               description,
+               self.function_index,
+               self.offset)
+    }
+}
+
+/// Gives the location of a native code address inside the generated code.
+pub trait CodeLookup {
+    /// Returns the index of the function containing `pc` and the offset of `pc` in its code.
+    fn lookup(&self, pc: usize) -> Option<(FunctionIndex, usize)>;
+    /// Returns the name of the function `function_index`, if any.
+    fn function_name(&self, function_index: FunctionIndex) -> Option<&str>;
+    /// Returns the stack limits of the instances the code belongs to, set when a thread enters
+    /// it. The code of the instances without limit has none.
+    fn stack_limits(&self) -> Vec<&StackLimit> {
+        Vec::new()
+    }
+}
+
+extern "C" {
+    fn wasmstandalone_call(callback: extern "C" fn(*mut c_void),
+                           payload: *mut c_void,
+                           state: *mut c_void)
+                           -> c_int;
+    fn wasmstandalone_current_state() -> *mut c_void;
+    fn wasmstandalone_unwind() -> !;
+    /// Function to call from the generated code to trap, with a `TrapKind` code as argument.
+    pub fn wasmstandalone_raise_trap(kind: c_int) -> !;
+}
+
+// State of the innermost call into the generated code of the thread: the code being executed, the
+// trap it caused and the panic of a host function it called. The signal handler finds it through
+// `traps.c` rather than in a Rust thread-local variable, whose access is not async-signal-safe.
+struct CallState<'a> {
+    code: &'a CodeLookup,
+    trap: Option<Trap>,
+    panic: Option<Box<Any + Send>>,
+}
+
+#[no_mangle]
+pub extern "C" fn wasmstandalone_record_trap(kind: c_int, pc: *const u8) {
+    record_trap(TrapKind::from_code(kind as i64), pc as usize);
+}
+
+// Records the trap if `pc` is inside of the generated code being executed.
+fn record_trap(kind: TrapKind, pc: usize) -> bool {
+    let state = unsafe { wasmstandalone_current_state() as *mut CallState };
+    if state.is_null() {
+        return false;
+    }
+    let state = unsafe { &mut *state };
+    match state.code.lookup(pc) {
+        None => false,
+        Some((function_index, offset)) => {
+            state.trap = Some(Trap {
+                                  kind,
+                                  function_index,
+                                  offset,
+                              });
+            true
+        }
+    }
+}
+
+// Keeps the panic `payload` of a host function called by the generated code, which must then trap
+// so that the panic is resumed by `catch_traps` outside of the generated code.
+pub fn defer_panic(payload: Box<Any + Send>) {
+    let state = unsafe { wasmstandalone_current_state() as *mut CallState };
+    assert!(!state.is_null(), "host function called outside of the generated code");
+    unsafe { (*state).panic = Some(payload) };
+}
+
+/// Calls `callback`, which executes the generated `code`, and returns `Err` if the code trapped.
+/// `callback` must not own values with destructors since they are not run when trapping. The panic
+/// of a host function that made the code trap is resumed. The stack limits of the code that are not
+/// set yet are set from the current stack pointer for the duration of the call.
+pub fn catch_traps<F>(code: &CodeLookup, callback: F) -> Result<(), Trap>
+    where F: FnMut()
+{
+    install_handlers();
+    ALT_STACK.with(|_| ());
+    extern "C" fn call_closure<F: FnMut()>(payload: *mut c_void) {
+        unsafe { (*(payload as *mut F))() }
+    }
+    let mut callback = callback;
+    let mut state = CallState {
+        code,
+        trap: None,
+        panic: None,
+    };
+    let stack_pointer = stack_pointer();
+    let entered: Vec<&StackLimit> = code.stack_limits()
+        .into_iter()
+        .filter(|stack_limit| stack_limit.enter(stack_pointer))
+        .collect();
+    let completed = unsafe {
+        wasmstandalone_call(call_closure::<F>,
+                            &mut callback as *mut F as *mut c_void,
+                            &mut state as *mut CallState as *mut c_void)
+    };
+    for stack_limit in entered {
+        stack_limit.leave();
+    }
+    if completed != 0 {
+        Ok(())
+    } else {
+        if let Some(payload) = state.panic.take() {
+            resume_unwind(payload);
+        }
+        Err(state.trap.take().expect("trap without location"))
+    }
+}
+
+const TRAP_SIGNALS: [c_int; 4] = [libc::SIGILL, libc::SIGFPE, libc::SIGSEGV, libc::SIGBUS];
+static INSTALL_HANDLERS: Once = ONCE_INIT;
+static mut PREVIOUS_HANDLERS: [Option<libc::sigaction>; 4] = [None, None, None, None];
+
+fn install_handlers() {
+    INSTALL_HANDLERS.call_once(|| for (i, &signum) in TRAP_SIGNALS.iter().enumerate() {
+                                   unsafe { install_handler(i, signum) }
+                               });
+}
+
+unsafe fn install_handler(index: usize, signum: c_int) {
+    let mut handler: libc::sigaction = mem::zeroed();
+    // The handler may jump out of itself, so the signal must not stay blocked.
+    handler.sa_flags = libc::SA_SIGINFO | libc::SA_NODEFER | libc::SA_ONSTACK;
+    handler.sa_sigaction = trap_handler as usize;
+    libc::sigemptyset(&mut handler.sa_mask);
+    let mut previous: libc::sigaction = mem::zeroed();
+    if libc::sigaction(signum, &handler, &mut previous) == 0 {
+        PREVIOUS_HANDLERS[index] = Some(previous);
+    }
+}
+
+// Size of the alternate signal stack of the threads executing the generated code.
+const ALT_STACK_SIZE: usize = 64 * 1024;
+
+thread_local! {
+    static ALT_STACK: Option<AltStack> = unsafe { AltStack::install() };
+}
+
+// Alternate signal stack of a thread, freed when the thread exits. Without it, the handlers run on
+// the stack of the thread, so the traps are still caught unless this stack is exhausted.
+struct AltStack {
+    memory: *mut c_void,
+    previous: libc::stack_t,
+}
+
+impl AltStack {
+    // Installs a new alternate signal stack, unless the thread already has one big enough.
+    unsafe fn install() -> Option<AltStack> {
+        let mut previous: libc::stack_t = mem::zeroed();
+        if libc::sigaltstack(ptr::null(), &mut previous) != 0 {
+            return None;
+        }
+        if previous.ss_flags & libc::SS_DISABLE == 0 && previous.ss_size >= ALT_STACK_SIZE {
+            return None;
+        }
+        let memory = libc::mmap(ptr::null_mut(),
+                                ALT_STACK_SIZE,
+                                libc::PROT_READ | libc::PROT_WRITE,
+                                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                                -1,
+                                0);
+        if memory == libc::MAP_FAILED {
+            return None;
+        }
+        let stack = libc::stack_t {
+            ss_sp: memory,
+            ss_flags: 0,
+            ss_size: ALT_STACK_SIZE,
+        };
+        if libc::sigaltstack(&stack, ptr::null_mut()) != 0 {
+            libc::munmap(memory, ALT_STACK_SIZE);
+            return None;
+        }
+        Some(AltStack { memory, previous })
+    }
+}
+
+impl Drop for AltStack {
+    fn drop(&mut self) {
+        unsafe {
+            // The previous stack is restored only if ours is still the one installed.
+            let mut current: libc::stack_t = mem::zeroed();
+            libc::sigaltstack(ptr::null(), &mut current);
+            if current.ss_sp == self.memory {
+                libc::sigaltstack(&self.previous, ptr::null_mut());
+            }
+            libc::munmap(self.memory, ALT_STACK_SIZE);
+        }
+    }
+}
+
+extern "C" fn trap_handler(signum: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    let kind = match signum {
+        libc::SIGFPE => TrapKind::IntegerArithmetic,
+        libc::SIGSEGV | libc::SIGBUS => TrapKind::MemoryOutOfBounds,
+        _ => TrapKind::Unreachable,
+    };
+    if record_trap(kind, unsafe { faulting_pc(context) }) {
+        unsafe { wasmstandalone_unwind() }
+    }
+    // The signal does not come from the generated code: it gets the treatment of the previous
+    // handler, ours staying installed for the next traps.
+    let index = TRAP_SIGNALS.iter().position(|&s| s == signum).unwrap();
+    unsafe { call_previous_handler(index, signum, info, context) }
+}
+
+unsafe fn call_previous_handler(index: usize,
+                                signum: c_int,
+                                info: *mut libc::siginfo_t,
+                                context: *mut c_void) {
+    let previous = match PREVIOUS_HANDLERS[index] {
+        Some(ref previous) => previous,
+        None => return default_action(signum),
+    };
+    match previous.sa_sigaction {
+        libc::SIG_DFL => default_action(signum),
+        libc::SIG_IGN => {}
+        handler if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+            let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) =
+                mem::transmute(handler);
+            handler(signum, info, context)
+        }
+        handler => {
+            let handler: extern "C" fn(c_int) = mem::transmute(handler);
+            handler(signum)
+        }
+    }
+}
+
+// The default action of the trap signals terminates the process, so it is fine to uninstall our
+// handler to let the kernel take it.
+unsafe fn default_action(signum: c_int) {
+    libc::signal(signum, libc::SIG_DFL);
+    libc::raise(signum);
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn faulting_pc(context: *mut c_void) -> usize {
+    // Index of the instruction pointer in the general registers of the context
+    const REG_RIP: usize = 16;
+    let context = context as *const libc::ucontext_t;
+    (*context).uc_mcontext.gregs[REG_RIP] as usize
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+compile_error!("the location of the traps can only be read on 64 bits Linux");
+
+#[cfg(test)]
+mod tests {
+    use super::TrapKind;
+    use standalone::StandaloneRuntime;
+    use execution::{invoke, ExecutionError};
+    use testing::{module, compile};
+    use std::thread;
+
+    // Exports as "f" a function of type `[] -> [i32]` with the encoded `body`, and a memory of one
+    // page.
+    fn function_module(body: &[u8]) -> Vec<u8> {
+        let mut code = vec![0x0a, body.len() as u8 + 2, 0x01, body.len() as u8];
+        code.extend_from_slice(body);
+        module(&[&[0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f],
+                 &[0x03, 0x02, 0x01, 0x00],
+                 &[0x05, 0x03, 0x01, 0x00, 0x01],
+                 &[0x07, 0x05, 0x01, 0x01, b'f', 0x00, 0x00],
+                 &code])
+    }
+
+    fn trap_kind(body: &[u8]) -> TrapKind {
+        let mut runtime = StandaloneRuntime::new();
+        let code = compile(&function_module(body), &mut runtime);
+        match invoke(&code, "f", vec![]) {
+            Err(ExecutionError::Trap(trap)) => trap.kind,
+            _ => panic!("the function did not trap"),
+        }
+    }
+
+    #[test]
+    fn trap_out_of_bounds_memory_access() {
+        // i32.load of the address 65536
+        let body = [0x00, 0x41, 0x80, 0x80, 0x04, 0x28, 0x02, 0x00, 0x0b];
+        assert_eq!(trap_kind(&body), TrapKind::MemoryOutOfBounds);
+    }
+
+    #[test]
+    fn trap_unreachable() {
+        assert_eq!(trap_kind(&[0x00, 0x00, 0x0b]), TrapKind::Unreachable);
+    }
+
+    #[test]
+    fn trap_integer_divide_by_zero() {
+        // i32.div_s of 1 by 0
+        let body = [0x00, 0x41, 0x01, 0x41, 0x00, 0x6d, 0x0b];
+        assert_eq!(trap_kind(&body), TrapKind::IntegerArithmetic);
+    }
+
+    #[test]
+    fn trap_on_several_threads() {
+        let threads: Vec<thread::JoinHandle<TrapKind>> = (0..4)
+            .map(|_| thread::spawn(|| trap_kind(&[0x00, 0x00, 0x0b])))
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), TrapKind::Unreachable);
+        }
+    }
+}
@@ -0,0 +1,745 @@
+//! Position-independent runtime: the generated code finds the globals, memories and tables of
+//! the instance through a VM context pointer passed as hidden argument to every function, instead
+//! of having their addresses hardcoded. A module is compiled once and instantiated as many times
+//! as needed, each instance having its own VM context.
+use wasm2cretonne::{Local, FunctionIndex, GlobalIndex, TableIndex, MemoryIndex, SignatureIndex,
+                    Global, GlobalInit, Table, Memory, WasmRuntime, TranslationResult, Export};
+use cton_frontend::FunctionBuilder;
+use cretonne::ir::{MemFlags, Value, InstBuilder, SigRef, Signature, ArgumentType, CallConv, Type};
+use cretonne::ir::types::*;
+use cretonne::ir::condcodes::IntCC;
+use cretonne::ir::immediates::Offset32;
+use std::mem;
+use memory::{BASE_OFFSET, LENGTH_OFFSET, wasmstandalone_grow_memory,
+             wasmstandalone_current_memory};
+use traps::{TrapKind, CodeLookup, wasmstandalone_raise_trap};
+use stack::{StackLimit, translate_check_stack};
+use execution::{compile_functions, link_functions, make_executable, call_function_in,
+                CompileOptions, CompiledModule, CompiledFunction, ExecutableCode, ExecutionError,
+                ImportResolver, Value as WasmValue};
+use cache::{Encoder, Decoder};
+use standalone::{StandaloneRuntime, TABLE_BASE_OFFSET, TABLE_LENGTH_OFFSET, instantiate,
+                 signature_id, trap_if_icmp, translate_table_call};
+
+// The VM context of an instance is an array of words: the addresses of the runtime functions and
+// of the `StackLimit` of the instance, the identifier in the process of each signature of the
+// module, the address of the value of each global, the `LinearMemory` of each memory, the
+// `FunctionTable` of each table, then the VM context passed to each imported function. The code
+// does not contain any address, so that it can be cached.
+pub const VMCTX_ENTRY_SIZE: usize = 8;
+pub const RAISE_TRAP_ENTRY: usize = 0;
+const GROW_MEMORY_ENTRY: usize = 1;
+const CURRENT_MEMORY_ENTRY: usize = 2;
+const STACK_LIMIT_ENTRY: usize = 3;
+const RUNTIME_FUNCTIONS_ENTRIES: usize = 4;
+
+struct Segment<T> {
+    index: usize,
+    base: Option<GlobalIndex>,
+    offset: usize,
+    contents: Vec<T>,
+}
+
+/// Runtime translating the code of a module once for all its instances. To be passed after
+/// creation as argument to
+/// [`wasm2cretonne::translatemodule`](../wasm2cretonne/fn.translate_module.html), then to
+/// [`compile_vm_module`](fn.compile_vm_module.html).
+///
+/// Every function takes the VM context of its instance as last argument, the imported functions
+/// included: the calls to an imported function or through a table pass the VM context of the
+/// instance of the callee, which may differ from the one of the caller.
+pub struct VmContextRuntime {
+    signatures: Vec<Signature>,
+    function_imports: Vec<(String, String)>,
+    globals: Vec<(Global, Option<(String, String)>)>,
+    tables: Vec<(Table, Option<(String, String)>)>,
+    memories: Vec<(Memory, Option<(String, String)>)>,
+    table_segments: Vec<Segment<FunctionIndex>>,
+    data_segments: Vec<Segment<u8>>,
+    // VM context argument of the function being translated.
+    vmctx: Option<Value>,
+    // Signatures of the runtime functions, imported once per translated function.
+    current_memory_sig: Option<SigRef>,
+    grow_memory_sig: Option<SigRef>,
+}
+
+impl VmContextRuntime {
+    /// Creates an empty runtime.
+    pub fn new() -> VmContextRuntime {
+        VmContextRuntime {
+            signatures: Vec::new(),
+            function_imports: Vec::new(),
+            globals: Vec::new(),
+            tables: Vec::new(),
+            memories: Vec::new(),
+            table_segments: Vec::new(),
+            data_segments: Vec::new(),
+            vmctx: None,
+            current_memory_sig: None,
+            grow_memory_sig: None,
+        }
+    }
+
+    // Emits the load of the entry `index` of the VM context.
+    fn load_vmctx_entry(&self, builder: &mut FunctionBuilder<Local>, index: usize) -> Value {
+        let vmctx = self.vmctx.expect("the VM context is set before translating a function");
+        builder
+            .ins()
+            .load(I64,
+                  MemFlags::new(),
+                  vmctx,
+                  Offset32::new((index * VMCTX_ENTRY_SIZE) as i32))
+    }
+
+    // Index of the VM context entry of the first global.
+    fn globals_entry(&self) -> usize {
+        RUNTIME_FUNCTIONS_ENTRIES + self.signatures.len()
+    }
+
+    fn memory_pointer(&self, builder: &mut FunctionBuilder<Local>, index: MemoryIndex) -> Value {
+        let entry = self.globals_entry() + self.globals.len() + index;
+        self.load_vmctx_entry(builder, entry)
+    }
+
+    fn table_pointer(&self, builder: &mut FunctionBuilder<Local>, index: TableIndex) -> Value {
+        let entry = self.globals_entry() + self.globals.len() + self.memories.len() + index;
+        self.load_vmctx_entry(builder, entry)
+    }
+
+    // Index of the VM context entry of the first imported function.
+    fn function_imports_entry(&self) -> usize {
+        self.globals_entry() + self.globals.len() + self.memories.len() + self.tables.len()
+    }
+
+    // Emits a call to the runtime function of the VM context entry `function_entry`, taking the
+    // memory `index` and possibly `arg`.
+    fn call_memory_function(&self,
+                            builder: &mut FunctionBuilder<Local>,
+                            sig_ref: SigRef,
+                            function_entry: usize,
+                            index: MemoryIndex,
+                            arg: Option<Value>)
+                            -> Value {
+        let memory_val = self.memory_pointer(builder, index);
+        let function_val = self.load_vmctx_entry(builder, function_entry);
+        let mut args = vec![memory_val];
+        args.extend(arg);
+        let call_inst = builder.ins().call_indirect(sig_ref, function_val, &args);
+        *builder.inst_results(call_inst).first().unwrap()
+    }
+
+    // Creates the standalone runtime holding the globals, memories and tables of an instance.
+    fn instance_runtime(&self) -> Result<StandaloneRuntime, String> {
+        let mut runtime = StandaloneRuntime::new();
+        for sig in self.signatures.iter() {
+            runtime.declare_signature(sig);
+        }
+        for &(global, ref import) in self.globals.iter() {
+            match *import {
+                None => runtime.declare_global(global),
+                Some((ref module, ref field)) => {
+                    runtime.declare_global_import(global, module, field)?
+                }
+            }
+        }
+        for &(table, ref import) in self.tables.iter() {
+            match *import {
+                None => runtime.declare_table(table),
+                Some((ref module, ref field)) => {
+                    runtime.declare_table_import(table, module, field)?
+                }
+            }
+        }
+        for &(memory, ref import) in self.memories.iter() {
+            match *import {
+                None => runtime.declare_memory(memory)?,
+                Some((ref module, ref field)) => {
+                    runtime.declare_memory_import(memory, module, field)?
+                }
+            }
+        }
+        for segment in self.table_segments.iter() {
+            runtime.declare_table_elements(segment.index,
+                                           segment.base,
+                                           segment.offset,
+                                           &segment.contents);
+        }
+        for segment in self.data_segments.iter() {
+            runtime
+                .declare_data_initialization(segment.index,
+                                             segment.base,
+                                             segment.offset,
+                                             &segment.contents)?;
+        }
+        runtime.begin_translation();
+        Ok(runtime)
+    }
+}
+
+// Writes the declarations recorded by `runtime` to a cache entry.
+pub fn encode_runtime(runtime: &VmContextRuntime, encoder: &mut Encoder) -> Result<(), String> {
+    encoder.usize(runtime.signatures.len());
+    for sig in runtime.signatures.iter() {
+        encoder.signature(sig)?;
+    }
+    encoder.usize(runtime.function_imports.len());
+    for &(ref module, ref field) in runtime.function_imports.iter() {
+        encoder.string(module);
+        encoder.string(field);
+    }
+    encoder.usize(runtime.globals.len());
+    for &(ref global, ref import) in runtime.globals.iter() {
+        encoder.global(global)?;
+        encoder.option_import(import);
+    }
+    encoder.usize(runtime.tables.len());
+    for &(ref table, ref import) in runtime.tables.iter() {
+        encoder.table(table)?;
+        encoder.option_import(import);
+    }
+    encoder.usize(runtime.memories.len());
+    for &(ref memory, ref import) in runtime.memories.iter() {
+        encoder.memory(memory);
+        encoder.option_import(import);
+    }
+    encoder.usize(runtime.table_segments.len());
+    for segment in runtime.table_segments.iter() {
+        encoder.usize(segment.index);
+        encoder.option_usize(segment.base);
+        encoder.usize(segment.offset);
+        encoder.usize(segment.contents.len());
+        for &function_index in segment.contents.iter() {
+            encoder.usize(function_index);
+        }
+    }
+    encoder.usize(runtime.data_segments.len());
+    for segment in runtime.data_segments.iter() {
+        encoder.usize(segment.index);
+        encoder.option_usize(segment.base);
+        encoder.usize(segment.offset);
+        encoder.bytes(&segment.contents);
+    }
+    Ok(())
+}
+
+// Reads the declarations written by `encode_runtime` into a new runtime. They are checked against
+// each other and against the functions of `compiled_module`, so that a corrupt entry is rejected
+// instead of failing the instantiation.
+pub fn decode_runtime(decoder: &mut Decoder,
+                      compiled_module: &CompiledModule)
+                      -> Result<VmContextRuntime, String> {
+    let mut runtime = VmContextRuntime::new();
+    for _ in 0..decoder.usize()? {
+        runtime.signatures.push(decoder.signature()?);
+    }
+    if runtime.signatures.len() != compiled_module.signatures.len() {
+        return Err(String::from("invalid signatures"));
+    }
+    for _ in 0..decoder.usize()? {
+        runtime
+            .function_imports
+            .push((decoder.string()?, decoder.string()?));
+    }
+    let imported_functions = compiled_module
+        .functions
+        .iter()
+        .take_while(|function| match **function {
+                        CompiledFunction::Import { .. } => true,
+                        CompiledFunction::Local { .. } => false,
+                    })
+        .count();
+    if runtime.function_imports.len() != imported_functions {
+        return Err(String::from("invalid function imports"));
+    }
+    for _ in 0..decoder.usize()? {
+        let global = decoder.global()?;
+        let import = decoder.option_import()?;
+        // A global can only be initialized with the value of a previous one.
+        if let GlobalInit::GlobalRef(index) = global.initializer {
+            if index >= runtime.globals.len() {
+                return Err(String::from("invalid global initializer"));
+            }
+        }
+        runtime.globals.push((global, import));
+    }
+    for _ in 0..decoder.usize()? {
+        runtime
+            .tables
+            .push((decoder.table()?, decoder.option_import()?));
+    }
+    for _ in 0..decoder.usize()? {
+        runtime
+            .memories
+            .push((decoder.memory()?, decoder.option_import()?));
+    }
+    for _ in 0..decoder.usize()? {
+        let index = decoder.usize()?;
+        let base = decoder.option_usize()?;
+        let offset = decoder.usize()?;
+        let mut contents = Vec::new();
+        for _ in 0..decoder.usize()? {
+            contents.push(decoder.function_index(compiled_module.functions.len())?);
+        }
+        if index >= runtime.tables.len() || !valid_segment_base(&runtime, base) {
+            return Err(String::from("invalid elements segment"));
+        }
+        runtime
+            .table_segments
+            .push(Segment {
+                      index,
+                      base,
+                      offset,
+                      contents,
+                  });
+    }
+    for _ in 0..decoder.usize()? {
+        let index = decoder.usize()?;
+        let base = decoder.option_usize()?;
+        let offset = decoder.usize()?;
+        let contents = decoder.bytes()?;
+        if index >= runtime.memories.len() || !valid_segment_base(&runtime, base) {
+            return Err(String::from("invalid data segment"));
+        }
+        runtime
+            .data_segments
+            .push(Segment {
+                      index,
+                      base,
+                      offset,
+                      contents,
+                  });
+    }
+    Ok(runtime)
+}
+
+// The base of a segment is an `i32` global.
+fn valid_segment_base(runtime: &VmContextRuntime, base: Option<GlobalIndex>) -> bool {
+    base.map_or(true, |global_index| {
+        global_index < runtime.globals.len() && runtime.globals[global_index].0.ty == I32
+    })
+}
+
+/// This trait is useful for
+/// [`wasm2cretonne::translatemodule`](../wasm2cretonne/fn.translate_module.html) because it
+/// tells how to translate runtime-dependent wasm instructions. These functions should not be
+/// called by the user.
+impl WasmRuntime for VmContextRuntime {
+    fn declare_signature(&mut self, sig: &Signature) {
+        self.signatures.push(sig.clone());
+    }
+    fn declare_function_import(&mut self, _: SignatureIndex, module: &str, field: &str) {
+        self.function_imports
+            .push((String::from(module), String::from(field)));
+    }
+    fn declare_global(&mut self, global: Global) {
+        self.globals.push((global, None));
+    }
+    fn declare_global_import(&mut self,
+                             global: Global,
+                             module: &str,
+                             field: &str)
+                             -> Result<(), String> {
+        self.globals
+            .push((global, Some((String::from(module), String::from(field)))));
+        Ok(())
+    }
+    fn declare_table(&mut self, table: Table) {
+        self.tables.push((table, None));
+    }
+    fn declare_table_import(&mut self,
+                            table: Table,
+                            module: &str,
+                            field: &str)
+                            -> Result<(), String> {
+        self.tables
+            .push((table, Some((String::from(module), String::from(field)))));
+        Ok(())
+    }
+    fn declare_table_elements(&mut self,
+                              table_index: TableIndex,
+                              base: Option<GlobalIndex>,
+                              offset: usize,
+                              elements: &[FunctionIndex]) {
+        self.table_segments
+            .push(Segment {
+                      index: table_index,
+                      base,
+                      offset,
+                      contents: elements.to_vec(),
+                  });
+    }
+    fn declare_memory(&mut self, memory: Memory) -> Result<(), String> {
+        // The memories are allocated by each instance.
+        self.memories.push((memory, None));
+        Ok(())
+    }
+    fn declare_memory_import(&mut self,
+                             memory: Memory,
+                             module: &str,
+                             field: &str)
+                             -> Result<(), String> {
+        self.memories
+            .push((memory, Some((String::from(module), String::from(field)))));
+        Ok(())
+    }
+    fn declare_data_initialization(&mut self,
+                                   memory_index: MemoryIndex,
+                                   base: Option<GlobalIndex>,
+                                   offset: usize,
+                                   data: &[u8])
+                                   -> Result<(), String> {
+        self.data_segments
+            .push(Segment {
+                      index: memory_index,
+                      base,
+                      offset,
+                      contents: data.to_vec(),
+                  });
+        Ok(())
+    }
+    fn begin_translation(&mut self) {
+        // Nothing is allocated before instantiation.
+    }
+    fn next_function(&mut self) {
+        self.vmctx = None;
+        self.current_memory_sig = None;
+        self.grow_memory_sig = None;
+    }
+    fn translation_runtime(&self) -> Option<Box<WasmRuntime + Send>> {
+        // The translation of the bodies only depends on the declarations, not on the segments.
+        Some(Box::new(VmContextRuntime {
+                          signatures: self.signatures.clone(),
+                          function_imports: self.function_imports.clone(),
+                          globals: self.globals.clone(),
+                          tables: self.tables.clone(),
+                          memories: self.memories.clone(),
+                          ..VmContextRuntime::new()
+                      }))
+    }
+    fn pointer_type(&self) -> Type {
+        // The code can only be compiled for 64 bits Intel, see `executable_isa`.
+        I64
+    }
+    fn vmctx_type(&self) -> Option<Type> {
+        Some(self.pointer_type())
+    }
+    fn set_vmctx(&mut self, vmctx: Value) {
+        self.vmctx = Some(vmctx);
+    }
+    fn translate_call_vmctx(&self,
+                            builder: &mut FunctionBuilder<Local>,
+                            function_index: FunctionIndex,
+                            vmctx: Value)
+                            -> Value {
+        if function_index < self.function_imports.len() {
+            // The imported function may belong to another instance.
+            let entry = self.function_imports_entry() + function_index;
+            self.load_vmctx_entry(builder, entry)
+        } else {
+            vmctx
+        }
+    }
+    fn translate_function_prologue(&self, builder: &mut FunctionBuilder<Local>) {
+        // The instances without stack limit keep it at 0.
+        let stack_limit_val = self.load_vmctx_entry(builder, STACK_LIMIT_ENTRY);
+        translate_check_stack(builder, self.vmctx, stack_limit_val);
+    }
+    fn translate_get_global(&self,
+                            builder: &mut FunctionBuilder<Local>,
+                            global_index: GlobalIndex)
+                            -> Value {
+        let ty = self.globals[global_index].0.ty;
+        let entry = self.globals_entry() + global_index;
+        let addr_val = self.load_vmctx_entry(builder, entry);
+        builder
+            .ins()
+            .load(ty, MemFlags::new(), addr_val, Offset32::new(0))
+    }
+    fn translate_set_global(&self,
+                            builder: &mut FunctionBuilder<Local>,
+                            global_index: GlobalIndex,
+                            val: Value) {
+        let entry = self.globals_entry() + global_index;
+        let addr_val = self.load_vmctx_entry(builder, entry);
+        builder
+            .ins()
+            .store(MemFlags::new(), val, addr_val, Offset32::new(0));
+    }
+    fn translate_memory_base_address(&self,
+                                     builder: &mut FunctionBuilder<Local>,
+                                     memory_index: MemoryIndex)
+                                     -> Value {
+        let memory_val = self.memory_pointer(builder, memory_index);
+        builder
+            .ins()
+            .load(I64, MemFlags::new(), memory_val, Offset32::new(BASE_OFFSET))
+    }
+    fn translate_memory_bounds_check(&self,
+                                     builder: &mut FunctionBuilder<Local>,
+                                     memory_index: MemoryIndex,
+                                     addr: Value,
+                                     offset: u32,
+                                     size: u32) {
+        let memory_val = self.memory_pointer(builder, memory_index);
+        let length_val = builder
+            .ins()
+            .load(I64, MemFlags::new(), memory_val, Offset32::new(LENGTH_OFFSET));
+        let addr_i64 = builder.ins().uextend(I64, addr);
+        let end_val = builder.ins().iadd_imm(addr_i64, offset as i64 + size as i64);
+        trap_if_icmp(builder,
+                     self.vmctx,
+                     TrapKind::MemoryOutOfBounds,
+                     IntCC::UnsignedGreaterThan,
+                     end_val,
+                     length_val);
+    }
+    fn translate_grow_memory(&mut self,
+                             builder: &mut FunctionBuilder<Local>,
+                             memory_index: MemoryIndex,
+                             pages: Value)
+                             -> Value {
+        let sig_ref = match self.grow_memory_sig {
+            Some(sig_ref) => sig_ref,
+            None => {
+                builder.import_signature(Signature {
+                                             call_conv: CallConv::Native,
+                                             argument_bytes: None,
+                                             argument_types: vec![ArgumentType::new(I64),
+                                                                  ArgumentType::new(I32)],
+                                             return_types: vec![ArgumentType::new(I32)],
+                                         })
+            }
+        };
+        self.grow_memory_sig = Some(sig_ref);
+        self.call_memory_function(builder,
+                                  sig_ref,
+                                  GROW_MEMORY_ENTRY,
+                                  memory_index,
+                                  Some(pages))
+    }
+    fn translate_current_memory(&mut self,
+                                builder: &mut FunctionBuilder<Local>,
+                                memory_index: MemoryIndex)
+                                -> Value {
+        let sig_ref = match self.current_memory_sig {
+            Some(sig_ref) => sig_ref,
+            None => {
+                builder.import_signature(Signature {
+                                             call_conv: CallConv::Native,
+                                             argument_bytes: None,
+                                             argument_types: vec![ArgumentType::new(I64)],
+                                             return_types: vec![ArgumentType::new(I32)],
+                                         })
+            }
+        };
+        self.current_memory_sig = Some(sig_ref);
+        self.call_memory_function(builder,
+                                  sig_ref,
+                                  CURRENT_MEMORY_ENTRY,
+                                  memory_index,
+                                  None)
+    }
+    fn translate_call_indirect<'a>(&self,
+                                   builder: &'a mut FunctionBuilder<Local>,
+                                   table_index: TableIndex,
+                                   sig_index: SignatureIndex,
+                                   sig_ref: SigRef,
+                                   index_val: Value,
+                                   call_args: &[Value])
+                                   -> &'a [Value] {
+        let table_val = self.table_pointer(builder, table_index);
+        let base_val = builder
+            .ins()
+            .load(I64, MemFlags::new(), table_val, Offset32::new(TABLE_BASE_OFFSET));
+        let length_val = builder
+            .ins()
+            .load(I64, MemFlags::new(), table_val, Offset32::new(TABLE_LENGTH_OFFSET));
+        let sig_id_val = self.load_vmctx_entry(builder, RUNTIME_FUNCTIONS_ENTRIES + sig_index);
+        translate_table_call(builder,
+                             self.vmctx,
+                             base_val,
+                             length_val,
+                             sig_id_val,
+                             sig_ref,
+                             index_val,
+                             call_args)
+    }
+}
+
+/// A module compiled once with the `VmContextRuntime`, which can be instantiated many times.
+pub struct VmModule {
+    runtime: VmContextRuntime,
+    code: ExecutableCode,
+    functions_signatures: Vec<SignatureIndex>,
+    start_index: Option<FunctionIndex>,
+}
+
+/// Compiles a module translated with `runtime`. The imported functions are looked up with
+/// `resolver` and shared by all the instances.
+pub fn compile_vm_module(trans_result: &TranslationResult,
+                         runtime: VmContextRuntime,
+                         resolver: &ImportResolver,
+                         options: &CompileOptions)
+                         -> Result<VmModule, String> {
+    let compiled_module = compile_functions(trans_result, options)?;
+    link_vm_module(runtime, &compiled_module, resolver)
+}
+
+// Links the functions of a module compiled with `runtime`, looking up its imported functions with
+// `resolver`.
+pub fn link_vm_module(runtime: VmContextRuntime,
+                      compiled_module: &CompiledModule,
+                      resolver: &ImportResolver)
+                      -> Result<VmModule, String> {
+    let functions_code = link_functions(compiled_module, resolver)?;
+    // The stack limit is read in the VM context of each instance.
+    let code = make_executable(compiled_module, functions_code, None)?;
+    Ok(VmModule {
+           runtime,
+           code,
+           functions_signatures: compiled_module.functions_signatures.clone(),
+           start_index: compiled_module.start_index,
+       })
+}
+
+impl VmModule {
+    /// Creates an instance of the module with its own globals, memories and tables, the imported
+    /// ones being looked up with `resolver`, and executes its start function. The compiled code is
+    /// shared with the other instances.
+    pub fn instantiate(&self, resolver: &ImportResolver) -> Result<VmInstance, ExecutionError> {
+        let mut runtime = self.runtime
+            .instance_runtime()
+            .map_err(ExecutionError::Invalid)?;
+        let functions_addresses: Vec<*const u8> = (0..self.functions_signatures.len())
+            .map(|index| self.code.function_address(index))
+            .collect();
+        // The table elements written by the instantiation refer to the VM context, which is
+        // filled once the imports are resolved.
+        let entries_count = self.runtime.function_imports_entry() +
+                            self.runtime.function_imports.len();
+        let mut vmctx: Vec<usize> = vec![0; entries_count];
+        instantiate(&mut runtime,
+                    resolver,
+                    &functions_addresses,
+                    &self.functions_signatures,
+                    Some(vmctx.as_ptr()))
+                .map_err(ExecutionError::Invalid)?;
+        // The runtime functions are at the same address for all the instances.
+        let stack_limit = Box::new(StackLimit::new(0));
+        let mut entries: Vec<usize> = vec![wasmstandalone_raise_trap as usize,
+                                           wasmstandalone_grow_memory as usize,
+                                           wasmstandalone_current_memory as usize,
+                                           &*stack_limit as *const StackLimit as usize];
+        for sig in self.runtime.signatures.iter() {
+            entries.push(signature_id(sig));
+        }
+        for global_index in 0..self.runtime.globals.len() {
+            entries.push(runtime.global(global_index).0 as usize);
+        }
+        for memory_index in 0..self.runtime.memories.len() {
+            entries.push(runtime.memory(memory_index) as usize);
+        }
+        for table_index in 0..self.runtime.tables.len() {
+            entries.push(runtime.table(table_index) as usize);
+        }
+        for &(ref module, ref field) in self.runtime.function_imports.iter() {
+            let callee_vmctx = resolver
+                .resolve_function_vmctx(module, field)
+                .map_err(ExecutionError::Invalid)?;
+            entries.push(callee_vmctx as usize);
+        }
+        vmctx.copy_from_slice(&entries);
+        let instance = VmInstance {
+            module: self,
+            runtime,
+            vmctx,
+            stack_limit,
+        };
+        if let Some(start_index) = self.start_index {
+            let args = [instance.vmctx_value()];
+            if let Err(err) = call_function_in(&self.code, &instance, start_index, &args) {
+                // The functions written in the tables of other instances can still be called with
+                // the VM context of this one, which then has to outlive them.
+                if instance.runtime.writes_imported_tables() {
+                    mem::forget(instance);
+                }
+                return Err(err);
+            }
+        }
+        Ok(instance)
+    }
+
+    /// Compiled code of the module.
+    pub fn code(&self) -> &ExecutableCode {
+        &self.code
+    }
+}
+
+/// An instance of a `VmModule`.
+pub struct VmInstance<'a> {
+    module: &'a VmModule,
+    // Holds the globals, memories and tables pointed to by the VM context.
+    runtime: StandaloneRuntime,
+    vmctx: Vec<usize>,
+    stack_limit: Box<StackLimit>,
+}
+
+impl<'a> VmInstance<'a> {
+    /// Calls the function exported under the name `name` on this instance, see
+    /// [`invoke`](fn.invoke.html).
+    pub fn invoke(&self,
+                  name: &str,
+                  args: Vec<WasmValue>)
+                  -> Result<Vec<WasmValue>, ExecutionError> {
+        let function_index = match self.module.code.export(name) {
+            Some(Export::Function(index)) => index,
+            _ => {
+                return Err(ExecutionError::Invalid(format!("no exported function named \"{}\"",
+                                                           name)))
+            }
+        };
+        // The VM context is not a wasm argument.
+        let signature = self.module.code.function_signature(function_index);
+        let expected = signature.argument_types.len() - 1;
+        if args.len() != expected {
+            return Err(ExecutionError::Invalid(format!("function {} expects {} arguments, {} \
+                                                        given",
+                                                       function_index,
+                                                       expected,
+                                                       args.len())));
+        }
+        let mut args = args;
+        args.push(self.vmctx_value());
+        call_function_in(&self.module.code, self, function_index, &args)
+    }
+
+    /// Limits the native stack that the code uses when called on this instance, see
+    /// [`StandaloneRuntime::set_max_stack_size`](struct.StandaloneRuntime.html). The stack is not
+    /// limited by default, a size of 0 removing the limit.
+    pub fn set_max_stack_size(&mut self, size: usize) {
+        self.stack_limit.set_max_size(size);
+    }
+
+    /// Runtime holding the globals, tables and memories of the instance.
+    pub fn runtime(&self) -> &StandaloneRuntime {
+        &self.runtime
+    }
+
+    fn vmctx_value(&self) -> WasmValue {
+        WasmValue::I64(self.vmctx.as_ptr() as i64)
+    }
+}
+
+impl<'a> CodeLookup for VmInstance<'a> {
+    fn lookup(&self, pc: usize) -> Option<(FunctionIndex, usize)> {
+        self.module.code.lookup(pc)
+    }
+
+    fn function_name(&self, function_index: FunctionIndex) -> Option<&str> {
+        self.module.code.function_name(function_index)
+    }
+
+    fn stack_limits(&self) -> Vec<&StackLimit> {
+        vec![&*self.stack_limit]
+    }
+}
@@ -1,12 +1,14 @@
-//! CLI tool to use the functions provided by crates [wasm2cretonne](../wasm2cretonne/index.html)
-//! and [wasmstandalone](../wasmstandalone/index.html).
+//! CLI tool to use the functions provided by crates [wasm2cretonne](../wasm2cretonne/index.html),
+//! [wasmstandalone](../wasmstandalone/index.html) and [wasmaot](../wasmaot/index.html).
 //!
 //! Reads Wasm binary files (one Wasm module per file), translates the functions' code to Cretonne
 //! IL. Can also executes the `start` function of the module by laying out the memories, globals
-//! and tables, then emitting the translated code with hardcoded addresses to memory.
+//! and tables, then emitting the translated code with hardcoded addresses to memory, or compile
+//! the module to an object file.
 
 extern crate wasm2cretonne;
 extern crate wasmstandalone;
+extern crate wasmaot;
 extern crate wasmparser;
 extern crate cretonne;
 extern crate wasmtext;
@@ -16,10 +18,15 @@ extern crate serde;
 extern crate serde_derive;
 extern crate term;
 extern crate tempdir;
+extern crate serde_json;
 
-use wasm2cretonne::{translate_module, TranslationResult, FunctionTranslation, DummyRuntime,
-                    WasmRuntime};
-use wasmstandalone::{StandaloneRuntime, compile_module, execute};
+mod wast;
+
+use wasm2cretonne::{translate_module_in_parallel, pointer_type, TranslationResult,
+                    FunctionTranslation, DummyRuntime, WasmRuntime};
+use wasmstandalone::{StandaloneRuntime, NoImports, ModuleCache, CompileOptions, compile_module,
+                     compile_lazily, execute, ExecutionError};
+use wasmaot::{AotRuntime, compile_object};
 use std::path::PathBuf;
 use wasmparser::{Parser, ParserState, WasmDecoder, SectionCode};
 use wasmtext::Writer;
@@ -40,6 +47,8 @@ use std::io::prelude::*;
 use docopt::Docopt;
 use std::path::Path;
 use std::process::Command;
+use std::thread;
+use std::time::Duration;
 use tempdir::TempDir;
 
 macro_rules! vprintln {
@@ -66,7 +75,13 @@ The default is a dummy runtime that produces placeholder values.
 
 Usage:
     wasm2cretonne-util [-vcop] <file>...
-    wasm2cretonne-util -e [-mvcop] <file>...
+    wasm2cretonne-util -e [-mvcop] [--jobs=<n>] [--fuel=<n>] [--stack-size=<n>]
+                       [--timeout=<ms>] [--set=<setting>]... <file>...
+    wasm2cretonne-util -e [-mv] [--jobs=<n>] [--set=<setting>]... --cache=<dir> <file>...
+    wasm2cretonne-util -e -l [-mv] [--set=<setting>]... <file>...
+    wasm2cretonne-util -C [-vp] [--target=<isa>] [--set=<setting>]... <file>...
+    wasm2cretonne-util -s [-v] <file>...
+    wasm2cretonne-util -a [-v] [--set=<setting>]... <file>...
     wasm2cretonne-util --help | --version
 
 Options:
@@ -76,6 +91,17 @@ Options:
     -o, --optimize      runs optimization passes on the translated functions
     -e, --execute       enable the standalone runtime and executes the start function of the module
     -m, --memory        interactive memory inspector after execution
+    -l, --lazy          compiles each function on its first call instead of before execution
+    --cache=<dir>       reuses the code compiled by previous executions, stored in <dir>
+    --jobs=<n>          number of threads translating and compiling, one per CPU by default
+    --fuel=<n>          traps once <n> wasm operators have been executed
+    --stack-size=<n>    traps when the wasm code uses more than <n> bytes of stack [default: 524288]
+    --timeout=<ms>      interrupts the execution after <ms> milliseconds
+    -s, --spec          runs the assertions of a spec test script (.wast or .json)
+    -a, --aot           compiles the module to an ELF object file next to it (.o)
+    -C, --compile       compiles the functions for the target ISA, displaying their code with -p
+    --target=<isa>      target ISA followed by its settings [default: intel,haswell]
+    --set=<setting>     shared Cretonne setting, e.g. opt_level=best or enable_verifier=0
     -h, --help          print this help message
     --version           print the Cretonne version
 ";
@@ -89,6 +115,17 @@ struct Args {
     flag_check: bool,
     flag_optimize: bool,
     flag_print: bool,
+    flag_spec: bool,
+    flag_aot: bool,
+    flag_cache: Option<String>,
+    flag_jobs: Option<usize>,
+    flag_fuel: Option<u64>,
+    flag_stack_size: usize,
+    flag_timeout: Option<u64>,
+    flag_lazy: bool,
+    flag_compile: bool,
+    flag_target: String,
+    flag_set: Vec<String>,
 }
 
 fn read_wasm_file(path: PathBuf) -> Result<Vec<u8>, io::Error> {
@@ -108,6 +145,10 @@ fn main() {
     for filename in args.arg_file.iter() {
         let path = Path::new(&filename);
         let name = String::from(path.as_os_str().to_string_lossy());
+        if args.flag_spec {
+            handle_spec_script(&args, path, name);
+            continue;
+        }
         match handle_module(&args, path.to_path_buf(), name) {
             Ok(()) => {}
             Err(message) => {
@@ -120,6 +161,37 @@ fn main() {
     }
 }
 
+fn handle_spec_script(args: &Args, path: &Path, name: String) {
+    let mut terminal = term::stdout().unwrap();
+    terminal.fg(term::color::YELLOW).unwrap();
+    vprint!(args.flag_verbose, "Running: ");
+    terminal.reset().unwrap();
+    vprintln!(args.flag_verbose, "\"{}\"", name);
+    match wast::run_script(path, args.flag_verbose) {
+        Ok(report) => {
+            terminal
+                .fg(if report.failed == 0 {
+                        term::color::GREEN
+                    } else {
+                        term::color::RED
+                    })
+                .unwrap();
+            print!("{}: ", name);
+            terminal.reset().unwrap();
+            println!("{} passed, {} failed, {} skipped",
+                     report.passed,
+                     report.failed,
+                     report.skipped);
+        }
+        Err(message) => {
+            terminal.fg(term::color::RED).unwrap();
+            print!("{}: ", name);
+            terminal.reset().unwrap();
+            println!("{}", message);
+        }
+    }
+}
+
 fn handle_module(args: &Args, path: PathBuf, name: String) -> Result<(), String> {
     let mut terminal = term::stdout().unwrap();
     terminal.fg(term::color::YELLOW).unwrap();
@@ -171,18 +243,41 @@ fn handle_module(args: &Args, path: PathBuf, name: String) -> Result<(), String>
             }
         }
     };
-    let mut dummy_runtime = DummyRuntime::new();
+    if let Some(ref cache_dir) = args.flag_cache {
+        return execute_cached(args, &data, cache_dir);
+    }
+    if args.flag_lazy {
+        return execute_lazily(args, &data);
+    }
+    // The functions compiled for the target ISA use its addresses.
+    let mut dummy_runtime = if args.flag_compile {
+        let isa = compile_options(args).target_isa()?;
+        DummyRuntime::with_pointer_type(pointer_type(&*isa))
+    } else {
+        DummyRuntime::new()
+    };
     let mut standalone_runtime = StandaloneRuntime::new();
+    if let Some(fuel) = args.flag_fuel {
+        standalone_runtime.enable_fuel_metering(fuel);
+    }
+    standalone_runtime.set_max_stack_size(args.flag_stack_size);
+    if args.flag_timeout.is_some() {
+        standalone_runtime.enable_epoch_interruption();
+    }
+    let mut aot_runtime = AotRuntime::new();
+    let num_threads = compile_options(args).num_threads;
     let translation = {
         let mut runtime: &mut WasmRuntime = if args.flag_execute {
             &mut standalone_runtime
+        } else if args.flag_aot {
+            &mut aot_runtime
         } else {
             &mut dummy_runtime
         };
-        match translate_module(&data, runtime) {
+        match translate_module_in_parallel(&data, runtime, num_threads) {
             Ok(x) => x,
-            Err(string) => {
-                return Err(string);
+            Err(err) => {
+                return Err(err.to_string());
             }
         }
     };
@@ -195,7 +290,7 @@ fn handle_module(args: &Args, path: PathBuf, name: String) -> Result<(), String>
         terminal.reset().unwrap();
         for func in translation.functions.iter() {
             let il = match func {
-                &FunctionTranslation::Import() => continue,
+                &FunctionTranslation::Import { .. } => continue,
                 &FunctionTranslation::Code { ref il, .. } => il.clone(),
             };
             match verifier::verify_function(&il, None) {
@@ -221,7 +316,7 @@ fn handle_module(args: &Args, path: PathBuf, name: String) -> Result<(), String>
         terminal.reset().unwrap();
         for func in translation.functions.iter() {
             let mut il = match func {
-                &FunctionTranslation::Import() => continue,
+                &FunctionTranslation::Import { .. } => continue,
                 &FunctionTranslation::Code { ref il, .. } => il.clone(),
             };
             let mut loop_analysis = LoopAnalysis::new();
@@ -262,11 +357,61 @@ fn handle_module(args: &Args, path: PathBuf, name: String) -> Result<(), String>
         vprintln!(args.flag_verbose, " ok");
         terminal.reset().unwrap();
     }
+    if args.flag_aot {
+        terminal.fg(term::color::MAGENTA).unwrap();
+        vprint!(args.flag_verbose, "Compiling...   ");
+        terminal.reset().unwrap();
+        let isa = compile_options(args).target_isa()?;
+        let object = compile_object(&translation, &aot_runtime, &module_symbol(&path), &*isa)?;
+        let object_path = path.with_extension("o");
+        File::create(&object_path)
+            .and_then(|mut file| file.write_all(&object))
+            .map_err(|err| String::from(err.description()))?;
+        terminal.fg(term::color::GREEN).unwrap();
+        vprintln!(args.flag_verbose, "ok");
+        terminal.reset().unwrap();
+    }
+    if args.flag_compile {
+        terminal.fg(term::color::MAGENTA).unwrap();
+        vprint!(args.flag_verbose, "Compiling...   ");
+        terminal.reset().unwrap();
+        let isa = compile_options(args).target_isa()?;
+        for (function_index, func) in translation.functions.iter().enumerate() {
+            let il = match func {
+                &FunctionTranslation::Import { .. } => continue,
+                &FunctionTranslation::Code { ref il, .. } => il.clone(),
+            };
+            let mut context = Context::new();
+            context.func = il;
+            let code_size = match context.compile(&*isa) {
+                Ok(code_size) => code_size,
+                Err(CtonError::Verifier(err)) => {
+                    return Err(pretty_verifier_error(&context.func, Some(&*isa), err))
+                }
+                Err(err) => return Err(String::from(err.description())),
+            };
+            if args.flag_print {
+                terminal.fg(term::color::CYAN).unwrap();
+                println!("; function {}: {} bytes of {} code",
+                         function_index,
+                         code_size,
+                         isa.name());
+                terminal.reset().unwrap();
+                println!("{}", context.func.display(Some(&*isa)));
+            }
+        }
+        terminal.fg(term::color::GREEN).unwrap();
+        vprintln!(args.flag_verbose, "ok");
+        terminal.reset().unwrap();
+    }
     if args.flag_execute {
         terminal.fg(term::color::MAGENTA).unwrap();
         vprint!(args.flag_verbose, "Compiling...   ");
         terminal.reset().unwrap();
-        match compile_module(&translation) {
+        match compile_module(&translation,
+                             &mut standalone_runtime,
+                             &NoImports,
+                             &compile_options(args)) {
             Ok(exec) => {
                 terminal.fg(term::color::GREEN).unwrap();
                 vprintln!(args.flag_verbose, "ok");
@@ -274,14 +419,27 @@ fn handle_module(args: &Args, path: PathBuf, name: String) -> Result<(), String>
                 terminal.fg(term::color::MAGENTA).unwrap();
                 vprint!(args.flag_verbose, "Executing...   ");
                 terminal.reset().unwrap();
-                match execute(exec) {
+                if let Some(timeout) = args.flag_timeout {
+                    let handle = standalone_runtime.interrupt_handle().unwrap();
+                    thread::spawn(move || {
+                                      thread::sleep(Duration::from_millis(timeout));
+                                      handle.interrupt();
+                                  });
+                }
+                match execute(&exec) {
                     Ok(()) => {
                         terminal.fg(term::color::GREEN).unwrap();
                         vprintln!(args.flag_verbose, "ok");
                         terminal.reset().unwrap();
                     }
-                    Err(s) => {
-                        return Err(s);
+                    Err(ExecutionError::Trap(trap)) => {
+                        return Err(match exec.function_name(trap.function_index) {
+                                       Some(name) => format!("{} ({})", trap, name),
+                                       None => trap.to_string(),
+                                   });
+                    }
+                    Err(err) => {
+                        return Err(err.to_string());
                     }
                 }
             }
@@ -290,44 +448,130 @@ fn handle_module(args: &Args, path: PathBuf, name: String) -> Result<(), String>
             }
         };
         if args.flag_memory {
-            let mut input = String::new();
-            terminal.fg(term::color::YELLOW).unwrap();
-            println!("Inspecting memory");
-            terminal.fg(term::color::MAGENTA).unwrap();
-            println!("Type 'quit' to exit.");
-            terminal.reset().unwrap();
-            loop {
-                input.clear();
-                terminal.fg(term::color::YELLOW).unwrap();
-                print!("Memory index, offset, length (e.g. 0,0,4): ");
-                terminal.reset().unwrap();
-                let _ = stdout().flush();
-                match io::stdin().read_line(&mut input) {
-                    Ok(_) => {
-                        input.pop();
-                        if input == "quit" {
-                            break;
-                        }
-                        let split: Vec<&str> = input.split(",").collect();
-                        if split.len() != 3 {
-                            break;
-                        }
-                        let memory = standalone_runtime
-                            .inspect_memory(str::parse(split[0]).unwrap(),
-                                            str::parse(split[1]).unwrap(),
-                                            str::parse(split[2]).unwrap());
-                        let mut s = memory
-                            .iter()
-                            .fold(String::from("#"), |mut acc, byte| {
-                                acc.push_str(format!("{:02x}_", byte).as_str());
-                                acc
-                            });
-                        s.pop();
-                        println!("{}", s);
-                    }
-                    Err(error) => return Err(String::from(error.description())),
+            inspect_memory(&standalone_runtime)?;
+        }
+    }
+    Ok(())
+}
+
+// Symbol of the module compiled ahead of time from the file at `path`: `<stem>_module`, with the
+// characters that cannot appear in a C identifier replaced by underscores.
+fn module_symbol(path: &Path) -> String {
+    let stem = path
+        .file_stem()
+        .map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+    let mut symbol: String = stem
+        .chars()
+        .map(|c| match c {
+                 'a'...'z' | 'A'...'Z' | '0'...'9' => c,
+                 _ => '_',
+             })
+        .collect();
+    if symbol.chars().next().map_or(true, |c| c.is_digit(10)) {
+        symbol.insert(0, '_');
+    }
+    symbol.push_str("_module");
+    symbol
+}
+
+fn compile_options(args: &Args) -> CompileOptions {
+    let mut options = CompileOptions::default();
+    if let Some(jobs) = args.flag_jobs {
+        options.num_threads = jobs;
+    }
+    let mut target = args.flag_target.split(',').map(String::from);
+    options.isa = target.next().unwrap_or_default();
+    options.isa_settings = target.collect();
+    options.settings.extend(args.flag_set.iter().cloned());
+    options
+}
+
+// Executes the start function of the module compiled by a previous execution if it is in the
+// cache, compiling it and saving it to the cache otherwise.
+fn execute_cached(args: &Args, data: &Vec<u8>, cache_dir: &str) -> Result<(), String> {
+    let mut terminal = term::stdout().unwrap();
+    let cache = ModuleCache::new(cache_dir);
+    let module = cache.compile(data, &NoImports, &compile_options(args))?;
+    terminal.fg(term::color::GREEN).unwrap();
+    vprintln!(args.flag_verbose, " ok");
+    terminal.reset().unwrap();
+    terminal.fg(term::color::MAGENTA).unwrap();
+    vprint!(args.flag_verbose, "Executing...   ");
+    terminal.reset().unwrap();
+    let instance = module
+        .instantiate(&NoImports)
+        .map_err(|err| err.to_string())?;
+    terminal.fg(term::color::GREEN).unwrap();
+    vprintln!(args.flag_verbose, "ok");
+    terminal.reset().unwrap();
+    if args.flag_memory {
+        inspect_memory(instance.runtime())?;
+    }
+    Ok(())
+}
+
+// Executes the start function of the module, translating and compiling the functions on their
+// first call.
+fn execute_lazily(args: &Args, data: &Vec<u8>) -> Result<(), String> {
+    let mut terminal = term::stdout().unwrap();
+    let mut runtime = StandaloneRuntime::new();
+    runtime.set_max_stack_size(args.flag_stack_size);
+    let module = compile_lazily(data, runtime, &NoImports, &compile_options(args))?;
+    terminal.fg(term::color::GREEN).unwrap();
+    vprintln!(args.flag_verbose, " ok");
+    terminal.reset().unwrap();
+    terminal.fg(term::color::MAGENTA).unwrap();
+    vprint!(args.flag_verbose, "Executing...   ");
+    terminal.reset().unwrap();
+    module.execute().map_err(|err| err.to_string())?;
+    terminal.fg(term::color::GREEN).unwrap();
+    vprintln!(args.flag_verbose, "ok");
+    terminal.reset().unwrap();
+    if args.flag_memory {
+        inspect_memory(&module.runtime())?;
+    }
+    Ok(())
+}
+
+// Interactive memory inspector, reading the memories of `runtime`.
+fn inspect_memory(runtime: &StandaloneRuntime) -> Result<(), String> {
+    let mut terminal = term::stdout().unwrap();
+    let mut input = String::new();
+    terminal.fg(term::color::YELLOW).unwrap();
+    println!("Inspecting memory");
+    terminal.fg(term::color::MAGENTA).unwrap();
+    println!("Type 'quit' to exit.");
+    terminal.reset().unwrap();
+    loop {
+        input.clear();
+        terminal.fg(term::color::YELLOW).unwrap();
+        print!("Memory index, offset, length (e.g. 0,0,4): ");
+        terminal.reset().unwrap();
+        let _ = stdout().flush();
+        match io::stdin().read_line(&mut input) {
+            Ok(_) => {
+                input.pop();
+                if input == "quit" {
+                    break;
                 }
+                let split: Vec<&str> = input.split(",").collect();
+                if split.len() != 3 {
+                    break;
+                }
+                let memory = runtime
+                    .inspect_memory(str::parse(split[0]).unwrap(),
+                                    str::parse(split[1]).unwrap(),
+                                    str::parse(split[2]).unwrap());
+                let mut s = memory
+                    .iter()
+                    .fold(String::from("#"), |mut acc, byte| {
+                        acc.push_str(format!("{:02x}_", byte).as_str());
+                        acc
+                    });
+                s.pop();
+                println!("{}", s);
             }
+            Err(error) => return Err(String::from(error.description())),
         }
     }
     Ok(())
@@ -347,7 +591,7 @@ fn pretty_print_translation(filename: &String,
         .functions
         .iter()
         .fold(0, |acc, &ref f| match f {
-            &FunctionTranslation::Import() => acc + 1,
+            &FunctionTranslation::Import { .. } => acc + 1,
             &FunctionTranslation::Code { .. } => acc,
         });
     match parser.read() {
@@ -369,11 +613,27 @@ fn pretty_print_translation(filename: &String,
     loop {
         match parser.read() {
             s @ &ParserState::BeginFunctionBody { .. } => {
+                let names = &translation.names;
+                let index = function_index + imports_count;
                 terminal.fg(term::color::BLUE).unwrap();
-                write!(writer_cretonne,
-                       "====== Function No. {} of module \"{}\" ======\n",
-                       function_index,
-                       filename)?;
+                write!(writer_cretonne, "====== Function No. {}", function_index)?;
+                if let Some(name) = names.functions.get(&index) {
+                    write!(writer_cretonne, " \"{}\"", name)?;
+                }
+                write!(writer_cretonne, " of module \"{}\"", filename)?;
+                if let Some(ref module_name) = names.module {
+                    write!(writer_cretonne, " ({})", module_name)?;
+                }
+                write!(writer_cretonne, " ======\n")?;
+                if let Some(locals) = names.locals.get(&index) {
+                    let mut locals: Vec<(&u32, &String)> = locals.iter().collect();
+                    locals.sort();
+                    let locals: Vec<String> = locals
+                        .iter()
+                        .map(|&(local_index, name)| format!("{}: {}", local_index, name))
+                        .collect();
+                    write!(writer_cretonne, "Locals: {}\n", locals.join(", "))?;
+                }
                 terminal.fg(term::color::CYAN).unwrap();
                 write!(writer_cretonne, "Wast ---------->\n")?;
                 terminal.reset().unwrap();
@@ -402,7 +662,7 @@ fn pretty_print_translation(filename: &String,
             format!("  {}",
                     match translation.functions[function_index + imports_count] {
                             FunctionTranslation::Code { ref il, .. } => il,
-                            FunctionTranslation::Import() => panic!("should not happen"),
+                            FunctionTranslation::Import { .. } => panic!("should not happen"),
                         }
                         .display(None));
         function_string.pop();
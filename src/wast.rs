@@ -0,0 +1,486 @@
+//! Runner for the WebAssembly specification test scripts.
+//!
+//! A `.wast` script is converted by the `wast2wasm --spec` tool of the
+//! [WebAssembly binary toolkit](https://github.com/WebAssembly/wabt) into a JSON file listing the
+//! commands of the script, along with one binary file per module. Each module is translated with
+//! the `StandaloneRuntime`, compiled and instantiated by a `Linker`, then the assertions are
+//! checked against it.
+
+use wasm2cretonne::{translate_module, TranslationError, Global, Table, Memory};
+use wasmstandalone::{StandaloneRuntime, ExecutionError, ImportResolver, Linker, LinearMemory,
+                     FunctionTable, TrapKind, Value, PAGE_SIZE, DEFAULT_MAX_STACK_SIZE,
+                     table_matches_import, memory_matches_import};
+use cretonne::ir::Signature;
+use cretonne::ir::types::*;
+use serde_json;
+use term;
+use tempdir::TempDir;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::mem::transmute;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Contents of the JSON file produced by `wast2wasm --spec`.
+#[derive(Deserialize, Debug)]
+struct Script {
+    commands: Vec<ScriptCommand>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ScriptCommand {
+    #[serde(rename = "type")]
+    ty: String,
+    line: u64,
+    name: Option<String>,
+    filename: Option<String>,
+    #[serde(rename = "as")]
+    as_name: Option<String>,
+    module_type: Option<String>,
+    action: Option<ScriptAction>,
+    expected: Option<Vec<ScriptValue>>,
+    text: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ScriptAction {
+    #[serde(rename = "type")]
+    ty: String,
+    module: Option<String>,
+    field: String,
+    #[serde(default)]
+    args: Vec<ScriptValue>,
+}
+
+/// Values are encoded as the decimal representation of their bits.
+#[derive(Deserialize, Debug)]
+struct ScriptValue {
+    #[serde(rename = "type")]
+    ty: String,
+    value: String,
+}
+
+/// Outcome of a single command of the script.
+enum Outcome {
+    Passed,
+    Failed(String),
+    Skipped(&'static str),
+}
+
+/// Number of assertions that passed, failed or were skipped during the run of a script.
+#[derive(Default)]
+pub struct Report {
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+/// Converts the `.wast` script at `path` (or directly reads the `.json` output of
+/// `wast2wasm --spec`) and runs all its commands.
+pub fn run_script(path: &Path, verbose: bool) -> Result<Report, String> {
+    let tmp_dir = TempDir::new("wasm2cretonne").map_err(|e| String::from(e.description()))?;
+    let json_path = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => path.to_path_buf(),
+        Some("wast") => {
+            let json_path = tmp_dir.path().join("script.json");
+            let output = Command::new("wast2wasm")
+                .arg("--spec")
+                .arg(path)
+                .arg("-o")
+                .arg(json_path.to_str().unwrap())
+                .output()
+                .map_err(|e| if let io::ErrorKind::NotFound = e.kind() {
+                             String::from("wast2wasm not found")
+                         } else {
+                             String::from(e.description())
+                         })?;
+            if !output.status.success() {
+                return Err(format!("wast2wasm failed: {}",
+                                   String::from_utf8_lossy(&output.stderr)));
+            }
+            json_path
+        }
+        _ => return Err(String::from("the file extension is not wast or json")),
+    };
+    let script: Script = {
+        let file = File::open(&json_path).map_err(|e| String::from(e.description()))?;
+        serde_json::from_reader(file).map_err(|e| format!("malformed spec script: {}", e))?
+    };
+    let base_dir = json_path.parent().unwrap_or(Path::new(".")).to_path_buf();
+    let mut linker = Linker::new(Box::new(SpecTest::new()?));
+    // The `assert_exhaustion` commands expect the recursions to trap.
+    linker.set_max_stack_size(Some(DEFAULT_MAX_STACK_SIZE));
+    let mut runner = ScriptRunner {
+        base_dir,
+        linker,
+        current: None,
+        named: HashMap::new(),
+    };
+    let mut report = Report::default();
+    let mut terminal = term::stdout().unwrap();
+    for command in script.commands.iter() {
+        match runner.run_command(command) {
+            Outcome::Passed => report.passed += 1,
+            Outcome::Failed(message) => {
+                report.failed += 1;
+                terminal.fg(term::color::RED).unwrap();
+                print!("line {}: {} failed: ", command.line, command.ty);
+                terminal.reset().unwrap();
+                println!("{}", message);
+            }
+            Outcome::Skipped(reason) => {
+                report.skipped += 1;
+                if verbose {
+                    terminal.fg(term::color::YELLOW).unwrap();
+                    print!("line {}: {} skipped: ", command.line, command.ty);
+                    terminal.reset().unwrap();
+                    println!("{}", reason);
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+struct ScriptRunner {
+    base_dir: PathBuf,
+    linker: Linker,
+    current: Option<usize>,
+    named: HashMap<String, usize>,
+}
+
+// The `print` functions of the `spectest` module used by the scripts take various arguments,
+// which are ignored.
+extern "C" fn spectest_print() {}
+
+/// The `spectest` module imported by the scripts, provided by the host.
+struct SpecTest {
+    memory: LinearMemory,
+    table: FunctionTable,
+    // The global can be imported with any type, its value is 666.
+    global_i32: i32,
+    global_i64: i64,
+    global_f32: f32,
+    global_f64: f64,
+}
+
+impl SpecTest {
+    fn new() -> Result<SpecTest, String> {
+        Ok(SpecTest {
+               memory: LinearMemory::new(1, Some(2), 2 * PAGE_SIZE)?,
+               table: FunctionTable::new(10, Some(20)),
+               global_i32: 666,
+               global_i64: 666,
+               global_f32: 666.0,
+               global_f64: 666.0,
+           })
+    }
+}
+
+impl ImportResolver for SpecTest {
+    fn resolve_function(&self,
+                        module: &str,
+                        field: &str,
+                        _: &Signature)
+                        -> Result<*const u8, String> {
+        if module == "spectest" && field.starts_with("print") {
+            return Ok(spectest_print as *const u8);
+        }
+        Err(format!("unknown import {}.{}", module, field))
+    }
+
+    fn resolve_global(&self,
+                      module: &str,
+                      field: &str,
+                      global: &Global)
+                      -> Result<*mut u8, String> {
+        if module != "spectest" || field != "global" || global.mutability {
+            return Err(format!("unknown import {}.{}", module, field));
+        }
+        let address = match global.ty {
+            I32 => &self.global_i32 as *const i32 as *const u8,
+            I64 => &self.global_i64 as *const i64 as *const u8,
+            F32 => &self.global_f32 as *const f32 as *const u8,
+            F64 => &self.global_f64 as *const f64 as *const u8,
+            _ => return Err(format!("incompatible import type for {}.{}", module, field)),
+        };
+        // The global is immutable, the generated code does not write to it.
+        Ok(address as *mut u8)
+    }
+
+    fn resolve_table(&self,
+                     module: &str,
+                     field: &str,
+                     table: &Table)
+                     -> Result<*mut FunctionTable, String> {
+        if module != "spectest" || field != "table" {
+            return Err(format!("unknown import {}.{}", module, field));
+        }
+        if !table_matches_import(&self.table, table) {
+            return Err(format!("incompatible import type for {}.{}", module, field));
+        }
+        Ok(&self.table as *const FunctionTable as *mut FunctionTable)
+    }
+
+    fn resolve_memory(&self,
+                      module: &str,
+                      field: &str,
+                      memory: &Memory)
+                      -> Result<*mut LinearMemory, String> {
+        if module != "spectest" || field != "memory" {
+            return Err(format!("unknown import {}.{}", module, field));
+        }
+        if !memory_matches_import(&self.memory, memory) {
+            return Err(format!("incompatible import type for {}.{}", module, field));
+        }
+        Ok(&self.memory as *const LinearMemory as *mut LinearMemory)
+    }
+}
+
+impl ScriptRunner {
+    fn run_command(&mut self, command: &ScriptCommand) -> Outcome {
+        match command.ty.as_str() {
+            "module" => {
+                match self.instantiate(command.filename.as_ref().unwrap())
+                          .map_err(|err| err.to_string()) {
+                    Ok(index) => {
+                        self.current = Some(index);
+                        if let Some(ref name) = command.name {
+                            self.named.insert(name.clone(), index);
+                        }
+                        Outcome::Passed
+                    }
+                    Err(message) => {
+                        self.current = None;
+                        Outcome::Failed(message)
+                    }
+                }
+            }
+            "register" => {
+                let index = match command.name {
+                    None => self.current,
+                    Some(ref name) => self.named.get(name).cloned(),
+                };
+                match index {
+                    None => Outcome::Failed(String::from("no module to register")),
+                    Some(index) => {
+                        self.linker
+                            .register(command.as_name.as_ref().unwrap(), index);
+                        Outcome::Passed
+                    }
+                }
+            }
+            "action" => {
+                match self.perform(command.action.as_ref().unwrap()) {
+                    Ok(_) => Outcome::Passed,
+                    Err(err) => Outcome::Failed(err.to_string()),
+                }
+            }
+            "assert_return" => {
+                let expected = match command.expected {
+                    None => Vec::new(),
+                    Some(ref expected) => {
+                        match expected.iter().map(parse_value).collect() {
+                            Ok(values) => values,
+                            Err(message) => return Outcome::Failed(message),
+                        }
+                    }
+                };
+                match self.perform(command.action.as_ref().unwrap()) {
+                    Err(err) => Outcome::Failed(err.to_string()),
+                    Ok(ref results) if same_values(results, &expected) => Outcome::Passed,
+                    Ok(results) => {
+                        Outcome::Failed(format!("got {:?}, expected {:?}", results, expected))
+                    }
+                }
+            }
+            "assert_return_canonical_nan" |
+            "assert_return_arithmetic_nan" => {
+                match self.perform(command.action.as_ref().unwrap()) {
+                    Err(err) => Outcome::Failed(err.to_string()),
+                    Ok(results) => {
+                        let is_nan = results.len() == 1 &&
+                                     match results[0] {
+                                         Value::F32(val) => val.is_nan(),
+                                         Value::F64(val) => val.is_nan(),
+                                         _ => false,
+                                     };
+                        if is_nan {
+                            Outcome::Passed
+                        } else {
+                            Outcome::Failed(format!("got {:?}, expected NaN", results))
+                        }
+                    }
+                }
+            }
+            "assert_trap" => {
+                // Traps at instantiation come from the start function.
+                if let Some(ref filename) = command.filename {
+                    return match self.instantiate(filename) {
+                               Err(ExecutionError::Trap(_)) => Outcome::Passed,
+                               Err(ExecutionError::Invalid(message)) => {
+                                   Outcome::Failed(message)
+                               }
+                               Ok(_) => Outcome::Failed(String::from("module instantiated, \
+                                                                     expected a trap")),
+                           };
+                }
+                match self.perform(command.action.as_ref().unwrap()) {
+                    Err(ExecutionError::Trap(_)) => Outcome::Passed,
+                    Err(ExecutionError::Invalid(message)) => Outcome::Failed(message),
+                    Ok(results) => {
+                        Outcome::Failed(format!("got {:?}, expected \"{}\"",
+                                                results,
+                                                command.text.as_ref().unwrap()))
+                    }
+                }
+            }
+            "assert_exhaustion" => {
+                match self.perform(command.action.as_ref().unwrap()) {
+                    Err(ExecutionError::Trap(ref trap)) if trap.kind ==
+                                                           TrapKind::StackOverflow => {
+                        Outcome::Passed
+                    }
+                    Err(err) => Outcome::Failed(err.to_string()),
+                    Ok(results) => {
+                        Outcome::Failed(format!("got {:?}, expected a stack overflow", results))
+                    }
+                }
+            }
+            "assert_invalid" |
+            "assert_malformed" => {
+                if command.module_type.as_ref().map_or(false, |ty| ty != "binary") {
+                    return Outcome::Skipped("text modules are not supported");
+                }
+                match command.text.as_ref().map(|text| text.as_str()) {
+                    Some("multiple tables") |
+                    Some("multiple memories") => {
+                        return Outcome::Skipped("several tables and memories are supported")
+                    }
+                    _ => (),
+                }
+                let path = self.base_dir.join(command.filename.as_ref().unwrap());
+                let data = match read_file(&path) {
+                    Ok(data) => data,
+                    Err(message) => return Outcome::Failed(message),
+                };
+                let mut runtime = StandaloneRuntime::new();
+                match catch_unwind(AssertUnwindSafe(|| translate_module(&data, &mut runtime))) {
+                    Ok(Ok(_)) => {
+                        Outcome::Failed(format!("module translated, expected \"{}\"",
+                                                command.text.as_ref().unwrap()))
+                    }
+                    // Only a rejection of the module counts, not a missing feature or limit.
+                    Ok(Err(TranslationError::InvalidBinary { .. })) => Outcome::Passed,
+                    Ok(Err(err)) => Outcome::Failed(err.to_string()),
+                    Err(_) => Outcome::Failed(String::from("translation panicked")),
+                }
+            }
+            "assert_unlinkable" => {
+                if command.module_type.as_ref().map_or(false, |ty| ty != "binary") {
+                    return Outcome::Skipped("text modules are not supported");
+                }
+                match self.instantiate(command.filename.as_ref().unwrap()) {
+                    Err(ExecutionError::Invalid(_)) => Outcome::Passed,
+                    Err(ExecutionError::Trap(trap)) => Outcome::Failed(trap.to_string()),
+                    Ok(_) => Outcome::Failed(String::from("module linked, expected an error")),
+                }
+            }
+            "assert_uninstantiable" => {
+                match self.instantiate(command.filename.as_ref().unwrap()) {
+                    Err(ExecutionError::Trap(_)) => Outcome::Passed,
+                    Err(ExecutionError::Invalid(message)) => Outcome::Failed(message),
+                    Ok(_) => Outcome::Failed(String::from("module instantiated, expected a trap")),
+                }
+            }
+            _ => Outcome::Skipped("unknown command"),
+        }
+    }
+
+    // Translates, compiles and instantiates the module contained in `filename`.
+    fn instantiate(&mut self, filename: &str) -> Result<usize, ExecutionError> {
+        let data = read_file(&self.base_dir.join(filename))
+            .map_err(ExecutionError::Invalid)?;
+        self.linker.instantiate(&data)
+    }
+
+    // Invokes the function or reads the global referred to by `action` in the corresponding
+    // module.
+    fn perform(&self, action: &ScriptAction) -> Result<Vec<Value>, ExecutionError> {
+        let index = match action.module {
+            None => self.current,
+            Some(ref name) => self.named.get(name).cloned(),
+        };
+        let index = match index {
+            None => {
+                return Err(ExecutionError::Invalid(String::from("no module instantiated")))
+            }
+            Some(index) => index,
+        };
+        let instance = self.linker.instance(index);
+        match action.ty.as_str() {
+            "invoke" => {
+                let args = action
+                    .args
+                    .iter()
+                    .map(parse_value)
+                    .collect::<Result<Vec<Value>, String>>()
+                    .map_err(ExecutionError::Invalid)?;
+                self.linker.invoke(index, &action.field, args)
+            }
+            "get" => {
+                match instance.global_value(&action.field) {
+                    Some(value) => Ok(vec![value]),
+                    None => {
+                        Err(ExecutionError::Invalid(format!("no exported global named \"{}\"",
+                                                            action.field)))
+                    }
+                }
+            }
+            _ => Err(ExecutionError::Invalid(format!("unsupported action {}", action.ty))),
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    let mut buf: Vec<u8> = Vec::new();
+    let mut file = File::open(path).map_err(|e| String::from(e.description()))?;
+    file.read_to_end(&mut buf)
+        .map_err(|e| String::from(e.description()))?;
+    Ok(buf)
+}
+
+fn parse_value(value: &ScriptValue) -> Result<Value, String> {
+    let bits: u64 = value
+        .value
+        .parse()
+        .map_err(|_| format!("malformed value {}", value.value))?;
+    match value.ty.as_str() {
+        "i32" => Ok(Value::I32(bits as u32 as i32)),
+        "i64" => Ok(Value::I64(bits as i64)),
+        "f32" => Ok(Value::F32(unsafe { transmute(bits as u32) })),
+        "f64" => Ok(Value::F64(unsafe { transmute(bits) })),
+        ty => Err(format!("unsupported value type {}", ty)),
+    }
+}
+
+// Compares the values bit by bit, so that NaNs with the same payload are equal.
+fn same_values(results: &[Value], expected: &[Value]) -> bool {
+    results.len() == expected.len() &&
+    results
+        .iter()
+        .zip(expected.iter())
+        .all(|(result, expected)| match (*result, *expected) {
+                 (Value::F32(x), Value::F32(y)) => unsafe {
+                     transmute::<f32, u32>(x) == transmute::<f32, u32>(y)
+                 },
+                 (Value::F64(x), Value::F64(y)) => unsafe {
+                     transmute::<f64, u64>(x) == transmute::<f64, u64>(y)
+                 },
+                 (x, y) => x == y,
+             })
+}